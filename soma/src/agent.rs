@@ -0,0 +1,276 @@
+use somacommon::checks::{JobResult, PluginResult};
+use somacommon::digest;
+use somacommon::facts::Facts;
+use somacommon::files::{CopyResult, FileChunk};
+use somacommon::packages::PackageReport;
+use somacommon::policy::Denial;
+use somacommon::ports::Listener;
+use somacommon::protocol::{self, ActionResult, Request, Response};
+use somacommon::resources::{Resource, ResourceResult};
+use somacommon::service::{ServiceOperation, ServiceResult};
+use somacommon::status::StatusReport;
+use std::io::{self, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::audit;
+use crate::copy::CopyOptions;
+use crate::inventory::InventoryHost;
+use crate::policy;
+use crate::signing;
+
+/// How long to wait for an agent to accept a connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for an agent to answer a request
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Errors talking to a somasrv agent
+#[derive(Debug)]
+pub enum AgentError {
+    ConnectError(String, io::Error),
+    IoError(String, io::Error),
+    ConnectionClosed(String),
+    UnexpectedResponse(String),
+    Remote(String),
+    /// Refused by soma's or the agent's access policy
+    Denied(Denial),
+    SigningError(io::Error),
+    /// The request would go unrecorded
+    AuditError(io::Error),
+}
+
+impl std::fmt::Display for AgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgentError::ConnectError(address, err) => {
+                write!(f, "Cannot connect to {}: {}", address, err)
+            }
+            AgentError::IoError(address, err) => {
+                write!(f, "Error talking to {}: {}", address, err)
+            }
+            AgentError::ConnectionClosed(address) => {
+                write!(f, "Connection to {} closed before a response", address)
+            }
+            AgentError::UnexpectedResponse(address) => {
+                write!(f, "Unexpected response from {}", address)
+            }
+            AgentError::Remote(message) => write!(f, "{}", message),
+            AgentError::Denied(denial) => write!(f, "Permission denied: {}", denial),
+            AgentError::SigningError(err) => write!(f, "Cannot sign request: {}", err),
+            AgentError::AuditError(err) => write!(f, "Cannot write the audit log: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {}
+
+fn connect(address: &str) -> Result<TcpStream, AgentError> {
+    let addrs = address
+        .to_socket_addrs()
+        .map_err(|e| AgentError::ConnectError(address.to_string(), e))?;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream
+                    .set_read_timeout(Some(READ_TIMEOUT))
+                    .map_err(|e| AgentError::IoError(address.to_string(), e))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(AgentError::ConnectError(address.to_string(), last_error))
+}
+
+/// Send a single request to the agent at `address` and wait for its response
+pub fn request(address: &str, request: &Request) -> Result<Response, AgentError> {
+    let stream = connect(address)?;
+    let mut writer = stream
+        .try_clone()
+        .map_err(|e| AgentError::IoError(address.to_string(), e))?;
+    protocol::write_message(&mut writer, request)
+        .map_err(|e| AgentError::IoError(address.to_string(), e))?;
+    let mut reader = BufReader::new(stream);
+    match protocol::read_message(&mut reader) {
+        Ok(Some(Response::Error { message })) => Err(AgentError::Remote(message)),
+        Ok(Some(Response::Denied(denial))) => Err(AgentError::Denied(denial)),
+        Ok(Some(response)) => Ok(response),
+        Ok(None) => Err(AgentError::ConnectionClosed(address.to_string())),
+        Err(e) => Err(AgentError::IoError(address.to_string(), e)),
+    }
+}
+
+/// Send a request to a host's agent, directly or through the controller it
+/// is connected to, on behalf of the operator and recorded in the audit log.
+/// Requests the access policy does not allow the operator are not sent, nor
+/// are audited ones when the audit log cannot be written. The rest are
+/// signed when soma has a signing key, which is what names the operator to
+/// the agent.
+pub fn request_host(host: &InventoryHost, request: Request) -> Result<Response, AgentError> {
+    let result = policy::check(host, &request)
+        .map_err(AgentError::Denied)
+        .and_then(|()| match request.inner().is_audited() {
+            true => audit::ready().map_err(AgentError::AuditError),
+            false => Ok(()),
+        })
+        .and_then(|()| signing::sign(host, request.clone()).map_err(AgentError::SigningError))
+        .and_then(|signed| {
+            let (address, routed) = host.route(signed);
+            self::request(&address, &routed)
+        });
+    audit::record(&host.name, &request, &result);
+    result
+}
+
+/// Fetch the current status report from a host's agent
+pub fn fetch_status(host: &InventoryHost) -> Result<StatusReport, AgentError> {
+    match request_host(host, Request::Status)? {
+        Response::Status(report) => Ok(report),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Fetch hardware and OS facts from a host's agent
+pub fn fetch_facts(host: &InventoryHost, refresh: bool) -> Result<Facts, AgentError> {
+    match request_host(host, Request::Facts { refresh })? {
+        Response::Facts(facts) => Ok(*facts),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// List the packages installed on a host, only those matching `name` when given
+pub fn fetch_packages(
+    host: &InventoryHost,
+    name: Option<&str>,
+) -> Result<PackageReport, AgentError> {
+    let request = Request::Packages {
+        name: name.map(str::to_string),
+    };
+    match request_host(host, request)? {
+        Response::Packages(report) => Ok(report),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// List the sockets listening on a host
+pub fn fetch_ports(host: &InventoryHost) -> Result<Vec<Listener>, AgentError> {
+    match request_host(host, Request::Ports)? {
+        Response::Ports { listeners } => Ok(listeners),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Report on or change a systemd unit on a host
+pub fn run_service(
+    host: &InventoryHost,
+    unit: &str,
+    operation: ServiceOperation,
+    noaction: bool,
+) -> Result<ServiceResult, AgentError> {
+    let request = Request::Service {
+        unit: unit.to_string(),
+        operation,
+        noaction,
+    };
+    match request_host(host, request)? {
+        Response::Service(result) => Ok(result),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Write a file on a host, which skips the write when the content is unchanged
+pub fn copy_file(
+    host: &InventoryHost,
+    path: &str,
+    content: &[u8],
+    options: &CopyOptions,
+    noaction: bool,
+) -> Result<CopyResult, AgentError> {
+    let request = Request::Copy {
+        path: path.to_string(),
+        content: somacommon::base64::encode(content),
+        sha256: digest::sha256_hex(content),
+        owner: options.owner.clone(),
+        group: options.group.clone(),
+        mode: options.mode,
+        backup: options.backup,
+        noaction,
+    };
+    match request_host(host, request)? {
+        Response::Copy(result) => Ok(result),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Read up to `length` bytes of a file on a host, or the start of its last `tail` lines
+pub fn fetch_chunk(
+    host: &InventoryHost,
+    path: &str,
+    offset: u64,
+    length: u64,
+    tail: Option<u64>,
+) -> Result<FileChunk, AgentError> {
+    let request = Request::Fetch {
+        path: path.to_string(),
+        offset,
+        length: Some(length),
+        tail,
+    };
+    match request_host(host, request)? {
+        Response::Fetch(chunk) => Ok(chunk),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Converge a host towards a list of resources, or with noaction report how it differs
+pub fn apply_state(
+    host: &InventoryHost,
+    resources: &[Resource],
+    noaction: bool,
+) -> Result<Vec<ResourceResult>, AgentError> {
+    let request = Request::Apply {
+        resources: resources.to_vec(),
+        noaction,
+    };
+    match request_host(host, request)? {
+        Response::Apply { results } => Ok(results),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Run one of a host's check plugins
+pub fn run_plugin(host: &InventoryHost, name: &str) -> Result<PluginResult, AgentError> {
+    let request = Request::Plugin {
+        name: name.to_string(),
+    };
+    match request_host(host, request)? {
+        Response::Plugin(result) => Ok(result),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// The latest result of each job a host's agent runs on its own schedule
+pub fn fetch_results(host: &InventoryHost) -> Result<Vec<JobResult>, AgentError> {
+    match request_host(host, Request::Results)? {
+        Response::Results { results } => Ok(results),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
+
+/// Ask a host's agent to run one of its allowlisted actions
+pub fn run_action(
+    host: &InventoryHost,
+    name: &str,
+    noaction: bool,
+) -> Result<ActionResult, AgentError> {
+    let request = Request::Action {
+        name: name.to_string(),
+        noaction,
+    };
+    match request_host(host, request)? {
+        Response::Action(result) => Ok(result),
+        _ => Err(AgentError::UnexpectedResponse(host.name.clone())),
+    }
+}
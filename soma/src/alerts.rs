@@ -0,0 +1,688 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use somacommon::selector::Selectable;
+use somacommon::status::Health;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::inventory::{Inventory, InventoryHost};
+use crate::maintenance::Maintenance;
+use crate::metrics::{Selector, Store, parse_duration};
+use crate::notify::{AlertStatus, Notification, Sink};
+use crate::seen;
+use crate::state;
+
+/// File in the state directory remembering which alerts are firing
+pub const ALERTS_FILE: &str = "alerts.json";
+
+/// Alert rules and the sinks their notifications go to, as given to `soma serve --alerts`
+#[derive(Debug, Deserialize)]
+pub struct AlertConfig {
+    /// Seconds between evaluations of the rules
+    #[serde(default = "default_interval", deserialize_with = "duration")]
+    pub interval: u64,
+    /// How long a firing alert waits before it is notified again; 0 never repeats
+    #[serde(default = "default_repeat", deserialize_with = "duration")]
+    pub repeat: u64,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub sinks: BTreeMap<String, Sink>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    pub name: String,
+    /// Selector for the hosts the rule watches; every host if not given
+    #[serde(default)]
+    pub hosts: Option<String>,
+    /// Sinks to notify; every sink if none are named
+    #[serde(default)]
+    pub sinks: Vec<String>,
+    /// Overrides the file's repeat interval
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub repeat: Option<u64>,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Condition {
+    /// A host's latest status check found it at this health or worse.
+    /// Hosts that did not answer are left to stale rules.
+    Health {
+        #[serde(default = "default_level")]
+        level: Health,
+    },
+    /// The average over the window of each series a metrics query matches
+    /// is above or below a threshold
+    Metric {
+        query: String,
+        #[serde(default)]
+        above: Option<f64>,
+        #[serde(default)]
+        below: Option<f64>,
+        #[serde(default = "default_window", deserialize_with = "duration")]
+        window: u64,
+    },
+    /// A host has not been heard from for `after`, or the inventory's `stale_after`
+    Stale {
+        #[serde(default, deserialize_with = "optional_duration")]
+        after: Option<u64>,
+    },
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_repeat() -> u64 {
+    4 * 3600
+}
+
+fn default_level() -> Health {
+    Health::Warning
+}
+
+fn default_window() -> u64 {
+    300
+}
+
+/// Durations may be given in seconds or as text such as `5m` or `4h`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(u64),
+    Text(String),
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match DurationValue::deserialize(deserializer)? {
+        DurationValue::Seconds(secs) => Ok(secs),
+        DurationValue::Text(text) => parse_duration(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    duration(deserializer).map(Some)
+}
+
+impl AlertConfig {
+    pub fn load(path: &Path) -> Result<AlertConfig, AlertError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| AlertError::ReadError(path.to_path_buf(), e))?;
+        let config: AlertConfig =
+            toml::from_str(&content).map_err(|e| AlertError::ParseError(path.to_path_buf(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check rules and sinks for settings that could never work
+    pub fn validate(&self) -> Result<(), AlertError> {
+        if self.interval == 0 {
+            return Err(AlertError::InvalidInterval);
+        }
+        for (name, sink) in &self.sinks {
+            sink.validate()
+                .map_err(|reason| AlertError::InvalidSink(name.clone(), reason))?;
+        }
+        let mut names = BTreeSet::new();
+        for rule in &self.rules {
+            let invalid =
+                |reason: &str| AlertError::InvalidRule(rule.name.clone(), reason.to_string());
+            if rule.name.trim().is_empty() {
+                return Err(AlertError::MissingName);
+            }
+            if !names.insert(rule.name.as_str()) {
+                return Err(AlertError::DuplicateRule(rule.name.clone()));
+            }
+            if let Some(sink) = rule.sinks.iter().find(|s| !self.sinks.contains_key(*s)) {
+                return Err(invalid(&format!("notifies unknown sink {}", sink)));
+            }
+            match &rule.condition {
+                Condition::Health {
+                    level: Health::Healthy | Health::Unknown,
+                } => return Err(invalid("level must be warning or critical")),
+                Condition::Metric {
+                    query,
+                    above,
+                    below,
+                    window,
+                } => {
+                    query.parse::<Selector>().map_err(|e| invalid(&e))?;
+                    if above.is_none() && below.is_none() {
+                        return Err(invalid("a metric rule needs above or below"));
+                    }
+                    if *window == 0 {
+                        return Err(invalid("window must be at least 1 second"));
+                    }
+                }
+                Condition::Stale { after: Some(0) } => {
+                    return Err(invalid("after must be at least 1 second"));
+                }
+                Condition::Health { .. } | Condition::Stale { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Every condition that holds now, at most one per rule and host or series
+    pub fn evaluate(&self, observed: &Observed) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for rule in &self.rules {
+            let finding = |key: &str, host: Option<&String>, summary: String, value| Finding {
+                rule: rule.name.clone(),
+                key: key.to_string(),
+                host: host.cloned(),
+                summary,
+                value,
+            };
+            match &rule.condition {
+                Condition::Health { level } => {
+                    for (host, health) in observed.health {
+                        if health >= level
+                            && *health != Health::Unknown
+                            && rule.watches(observed.inventory, host)
+                        {
+                            findings.push(finding(
+                                host,
+                                Some(host),
+                                format!("{} is {}", host, health),
+                                None,
+                            ));
+                        }
+                    }
+                }
+                Condition::Metric {
+                    query,
+                    above,
+                    below,
+                    window,
+                } => {
+                    let Ok(selector) = query.parse::<Selector>() else {
+                        continue;
+                    };
+                    let series = match observed
+                        .store
+                        .query(&selector, observed.now.saturating_sub(*window))
+                    {
+                        Ok(series) => series,
+                        Err(e) => {
+                            eprintln!("Warning: Rule {} could not read metrics: {}", rule.name, e);
+                            continue;
+                        }
+                    };
+                    for (key, samples) in series {
+                        let host = samples[0].labels.get("host");
+                        if host.is_some_and(|h| !rule.watches(observed.inventory, h)) {
+                            continue;
+                        }
+                        let count: u64 = samples.iter().map(|s| s.count).sum();
+                        let total: f64 = samples.iter().map(|s| s.value * s.count as f64).sum();
+                        let average = total / count.max(1) as f64;
+                        let breached = match (above, below) {
+                            (Some(limit), _) if average > *limit => Some(("above", limit)),
+                            (_, Some(limit)) if average < *limit => Some(("below", limit)),
+                            _ => None,
+                        };
+                        if let Some((direction, limit)) = breached {
+                            let summary = format!(
+                                "{} averaged {:.2} over {}s, {} {}",
+                                key, average, window, direction, limit
+                            );
+                            findings.push(finding(&key, host, summary, Some(average)));
+                        }
+                    }
+                }
+                Condition::Stale { after } => {
+                    let after = after.unwrap_or(observed.inventory.stale_after);
+                    for host in &observed.inventory.hosts {
+                        let Some(seen) = observed.last_seen.get(&host.name) else {
+                            continue;
+                        };
+                        let age = observed.now.saturating_sub(*seen);
+                        if age > after && rule.watches(observed.inventory, &host.name) {
+                            let summary = format!(
+                                "{} has not been heard from since {} UTC",
+                                host.name,
+                                seen::format_time(*seen)
+                            );
+                            findings.push(finding(
+                                &host.name,
+                                Some(&host.name),
+                                summary,
+                                Some(age as f64),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        findings
+    }
+
+    fn rule(&self, name: &str) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.name == name)
+    }
+
+    /// Deliver notifications to their rules' sinks, warning about any that fail
+    pub fn dispatch(&self, notifications: &[Notification], verbose: bool) {
+        for notification in notifications {
+            let named = self
+                .rule(&notification.rule)
+                .map(|r| r.sinks.as_slice())
+                .unwrap_or_default();
+            let sinks = self
+                .sinks
+                .iter()
+                .filter(|(name, _)| named.is_empty() || named.contains(name));
+            for (name, sink) in sinks {
+                match sink.send(notification) {
+                    Ok(()) if verbose => println!("Notified {} of {}", name, notification.title()),
+                    Ok(()) => {}
+                    Err(e) => eprintln!("Warning: Failed to notify {}: {}", name, e),
+                }
+            }
+        }
+    }
+}
+
+impl Rule {
+    fn watches(&self, inventory: &Inventory, host: &str) -> bool {
+        let Some(selector) = &self.hosts else {
+            return true;
+        };
+        match inventory.get(host) {
+            Some(inventory_host) => inventory_host.selected_by(selector),
+            None => InventoryHost {
+                name: host.to_string(),
+                ..Default::default()
+            }
+            .selected_by(selector),
+        }
+    }
+}
+
+/// What the rules are evaluated against
+pub struct Observed<'a> {
+    pub now: u64,
+    /// The health each host's latest status check found
+    pub health: &'a BTreeMap<String, Health>,
+    pub last_seen: &'a BTreeMap<String, u64>,
+    pub inventory: &'a Inventory,
+    pub store: &'a Store,
+}
+
+/// A rule's condition found to hold for a host or series
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub rule: String,
+    pub key: String,
+    /// The host the finding is about, if it is about one
+    pub host: Option<String>,
+    pub summary: String,
+    pub value: Option<f64>,
+}
+
+/// An alert that is firing and not yet resolved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Active {
+    pub rule: String,
+    pub key: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    pub summary: String,
+    pub value: Option<f64>,
+    pub since: u64,
+    /// When it was last notified
+    pub notified: u64,
+    /// Whether it has been notified as firing at all, which it is not while
+    /// maintenance or a silence suppresses it
+    #[serde(default = "announced")]
+    pub announced: bool,
+}
+
+fn announced() -> bool {
+    true
+}
+
+impl Active {
+    fn notification(&self, status: AlertStatus, at: u64, repeat: bool) -> Notification {
+        Notification {
+            status,
+            rule: self.rule.clone(),
+            key: self.key.clone(),
+            host: self.host.clone(),
+            summary: self.summary.clone(),
+            value: self.value,
+            since: self.since,
+            at,
+            repeat,
+        }
+    }
+}
+
+/// The firing alerts, so each is notified once when it starts, again every
+/// repeat interval and once more when it resolves
+#[derive(Debug, Default)]
+pub struct Alerter {
+    active: BTreeMap<(String, String), Active>,
+}
+
+impl Alerter {
+    /// Alerts left firing by an earlier run. A missing or unreadable file means none.
+    pub fn load() -> Self {
+        let active: Vec<Active> = fs::read_to_string(state::state_dir().join(ALERTS_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Alerter {
+            active: active
+                .into_iter()
+                .map(|a| ((a.rule.clone(), a.key.clone()), a))
+                .collect(),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<PathBuf> {
+        let path = state::state_file(ALERTS_FILE)?;
+        let active: Vec<&Active> = self.active.values().collect();
+        let content = serde_json::to_string_pretty(&active).map_err(io::Error::other)?;
+        state::replace(&path, &content)?;
+        Ok(path)
+    }
+
+    /// Compare the findings with the alerts already firing and return what to notify.
+    ///
+    /// Alerts suppressed by maintenance are tracked but not notified; one that
+    /// still fires when the suppression ends is notified then. Only alerts that
+    /// were notified as firing are notified as resolved.
+    pub fn update(
+        &mut self,
+        config: &AlertConfig,
+        findings: Vec<Finding>,
+        maintenance: &Maintenance,
+        now: u64,
+    ) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        let mut holding = BTreeSet::new();
+        for finding in findings {
+            let id = (finding.rule.clone(), finding.key.clone());
+            if !holding.insert(id.clone()) {
+                continue;
+            }
+            let repeat = config
+                .rule(&finding.rule)
+                .and_then(|r| r.repeat)
+                .unwrap_or(config.repeat);
+            let suppressed =
+                maintenance.suppresses(&finding.rule, &finding.key, finding.host.as_deref(), now);
+            match self.active.get_mut(&id) {
+                Some(active) => {
+                    active.summary = finding.summary;
+                    active.value = finding.value;
+                    if suppressed {
+                        continue;
+                    }
+                    if !active.announced {
+                        active.announced = true;
+                        active.notified = now;
+                        notifications.push(active.notification(AlertStatus::Firing, now, false));
+                    } else if repeat > 0 && now.saturating_sub(active.notified) >= repeat {
+                        active.notified = now;
+                        notifications.push(active.notification(AlertStatus::Firing, now, true));
+                    }
+                }
+                None => {
+                    let active = Active {
+                        rule: finding.rule,
+                        key: finding.key,
+                        host: finding.host,
+                        summary: finding.summary,
+                        value: finding.value,
+                        since: now,
+                        notified: now,
+                        announced: !suppressed,
+                    };
+                    if !suppressed {
+                        notifications.push(active.notification(AlertStatus::Firing, now, false));
+                    }
+                    self.active.insert(id, active);
+                }
+            }
+        }
+
+        let resolved: Vec<_> = self
+            .active
+            .keys()
+            .filter(|id| !holding.contains(*id))
+            .cloned()
+            .collect();
+        for id in resolved {
+            if let Some(active) = self.active.remove(&id)
+                && active.announced
+            {
+                notifications.push(active.notification(AlertStatus::Resolved, now, false));
+            }
+        }
+        notifications
+    }
+}
+
+/// Alert configuration error types
+#[derive(Debug)]
+pub enum AlertError {
+    ReadError(PathBuf, io::Error),
+    ParseError(PathBuf, toml::de::Error),
+    InvalidInterval,
+    MissingName,
+    DuplicateRule(String),
+    InvalidRule(String, String),
+    InvalidSink(String, String),
+}
+
+impl std::fmt::Display for AlertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlertError::ReadError(path, err) => {
+                write!(f, "Failed to read alert rules {}: {}", path.display(), err)
+            }
+            AlertError::ParseError(path, err) => {
+                write!(f, "Failed to parse alert rules {}: {}", path.display(), err)
+            }
+            AlertError::InvalidInterval => write!(f, "Alert interval must be at least 1 second"),
+            AlertError::MissingName => write!(f, "Every alert rule needs a name"),
+            AlertError::DuplicateRule(name) => {
+                write!(f, "More than one alert rule is named {}", name)
+            }
+            AlertError::InvalidRule(name, reason) => write!(f, "Alert rule {}: {}", name, reason),
+            AlertError::InvalidSink(name, reason) => write!(f, "Sink {}: {}", name, reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Sample;
+
+    fn config(content: &str) -> AlertConfig {
+        let config: AlertConfig = toml::from_str(content).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    fn finding(rule: &str, key: &str) -> Finding {
+        Finding {
+            rule: rule.to_string(),
+            key: key.to_string(),
+            host: Some(key.to_string()),
+            summary: format!("{} is critical", key),
+            value: None,
+        }
+    }
+
+    #[test]
+    fn notifies_once_then_repeats_and_resolves() {
+        let config = config(
+            "repeat = \"1h\"\n\n[[rules]]\nname = \"down\"\ntype = \"health\"\n\
+             level = \"critical\"\n",
+        );
+        let mut alerter = Alerter::default();
+        let none = Maintenance::default();
+
+        let sent = alerter.update(&config, vec![finding("down", "web-01"); 2], &none, 1000);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].status, AlertStatus::Firing);
+        assert!(!sent[0].repeat);
+
+        // Still firing, but within the repeat interval
+        assert!(
+            alerter
+                .update(&config, vec![finding("down", "web-01")], &none, 1060)
+                .is_empty()
+        );
+
+        let sent = alerter.update(&config, vec![finding("down", "web-01")], &none, 4600);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].repeat);
+        assert_eq!(sent[0].since, 1000);
+
+        let sent = alerter.update(&config, Vec::new(), &none, 4660);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].status, AlertStatus::Resolved);
+        assert!(alerter.update(&config, Vec::new(), &none, 4720).is_empty());
+    }
+
+    #[test]
+    fn holds_back_suppressed_alerts_until_maintenance_ends() {
+        let config = config("[[rules]]\nname = \"down\"\ntype = \"health\"\n");
+        let mut alerter = Alerter::default();
+        let mut maintenance = Maintenance::default();
+        maintenance.add_window(
+            "web-*",
+            vec!["web-01".to_string()],
+            "reboot",
+            1000,
+            600,
+            false,
+        );
+
+        // Fires and resolves within the window without a word
+        assert!(
+            alerter
+                .update(&config, vec![finding("down", "web-01")], &maintenance, 1000)
+                .is_empty()
+        );
+        assert!(
+            alerter
+                .update(&config, Vec::new(), &maintenance, 1060)
+                .is_empty()
+        );
+
+        // Still firing when the window ends, so it is announced then
+        alerter.update(&config, vec![finding("down", "web-01")], &maintenance, 1120);
+        let sent = alerter.update(&config, vec![finding("down", "web-01")], &maintenance, 1600);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].status, AlertStatus::Firing);
+        assert_eq!(sent[0].since, 1120);
+        assert_eq!(sent[0].host.as_deref(), Some("web-01"));
+    }
+
+    #[test]
+    fn evaluates_health_metric_and_stale_rules() {
+        let temp = std::env::temp_dir().join(format!("soma-alerts-{}", std::process::id()));
+        let store = Store::at(&temp, 86400);
+        let now = 1_000_000;
+        let disk = |host: &str, value: f64, time: u64| Sample {
+            name: "disk_used".to_string(),
+            labels: [("host", host), ("mount", "/")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            time,
+            value,
+            count: 1,
+        };
+        store
+            .append(&[
+                disk("web-01", 88.0, now - 120),
+                disk("web-01", 94.0, now - 60),
+                disk("db-01", 95.0, now - 60),
+                // Outside the window, so it does not count
+                disk("web-02", 99.0, now - 900),
+            ])
+            .unwrap();
+
+        let inventory: Inventory = toml::from_str(
+            "[[hosts]]\nname = \"web-01\"\ngroups = [\"web\"]\n\n\
+             [[hosts]]\nname = \"web-02\"\ngroups = [\"web\"]\n\n\
+             [[hosts]]\nname = \"db-01\"\ngroups = [\"db\"]\n",
+        )
+        .unwrap();
+        let health: BTreeMap<String, Health> = [
+            ("web-01".to_string(), Health::Warning),
+            ("web-02".to_string(), Health::Unknown),
+            ("db-01".to_string(), Health::Critical),
+        ]
+        .into();
+        let last_seen: BTreeMap<String, u64> = [
+            ("web-01".to_string(), now - 30),
+            ("web-02".to_string(), now - 900),
+        ]
+        .into();
+
+        let config = config(
+            "[[rules]]\nname = \"unhealthy\"\ntype = \"health\"\nhosts = \"@web\"\n\n\
+             [[rules]]\nname = \"disk\"\ntype = \"metric\"\nquery = \"disk_used{mount=/}\"\n\
+             above = 90\nwindow = \"5m\"\nhosts = \"web-*\"\n\n\
+             [[rules]]\nname = \"stale\"\ntype = \"stale\"\n",
+        );
+        let findings = config.evaluate(&Observed {
+            now,
+            health: &health,
+            last_seen: &last_seen,
+            inventory: &inventory,
+            store: &store,
+        });
+        let found: Vec<(&str, &str)> = findings
+            .iter()
+            .map(|f| (f.rule.as_str(), f.key.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("unhealthy", "web-01"),
+                ("disk", "disk_used{host=web-01,mount=/}"),
+                ("stale", "web-02"),
+            ]
+        );
+        assert_eq!(findings[1].value, Some(91.0));
+        fs::remove_dir_all(&temp).unwrap();
+    }
+
+    #[test]
+    fn rejects_rules_that_cannot_work() {
+        let invalid = |content: &str| {
+            toml::from_str::<AlertConfig>(content)
+                .unwrap()
+                .validate()
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            invalid("[[rules]]\nname = \"disk\"\ntype = \"metric\"\nquery = \"disk_used\"\n"),
+            "Alert rule disk: a metric rule needs above or below"
+        );
+        assert_eq!(
+            invalid("[[rules]]\nname = \"down\"\ntype = \"health\"\nsinks = [\"pager\"]\n"),
+            "Alert rule down: notifies unknown sink pager"
+        );
+        assert_eq!(
+            invalid("[sinks.hook]\ntype = \"webhook\"\nurl = \"https://hooks.example.com/\"\n"),
+            "Sink hook: https://hooks.example.com/ is not an http:// URL"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use somacommon::resources::{Outcome, Resource, ResourceResult};
+use std::fs;
+use std::path::Path;
+
+use crate::agent::{self, AgentError};
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost};
+use crate::maintenance;
+
+/// A desired-state document: resources applied in the order they are listed
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateDocument {
+    pub resources: Vec<Resource>,
+}
+
+/// Results for one resource on one host
+#[derive(Serialize)]
+struct ApplyRow {
+    hostname: String,
+    #[serde(flatten)]
+    result: ResourceResult,
+}
+
+impl StateDocument {
+    pub fn load(path: &Path) -> Result<StateDocument, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Self::parse(&content, path)
+    }
+
+    /// Parse and validate a document read from `origin`
+    pub fn parse(content: &str, origin: &Path) -> Result<StateDocument, String> {
+        let document: StateDocument = toml::from_str(content)
+            .map_err(|e| format!("Cannot parse {}: {}", origin.display(), e))?;
+        document.validate()?;
+        Ok(document)
+    }
+
+    /// Catch mistakes before anything is sent to an agent
+    pub fn validate(&self) -> Result<(), String> {
+        for resource in &self.resources {
+            match resource {
+                Resource::File { path, .. } | Resource::Line { path, .. }
+                    if !path.starts_with('/') =>
+                {
+                    return Err(format!("{}: path must be absolute", resource));
+                }
+                Resource::File {
+                    mode: Some(mode), ..
+                } if *mode > 0o7777 => {
+                    return Err(format!("{}: mode {:o} is not a file mode", resource, mode));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converge each host towards the resources in parallel
+pub fn apply(
+    hosts: &[InventoryHost],
+    resources: &[Resource],
+    noaction: bool,
+) -> Vec<Result<Vec<ResourceResult>, AgentError>> {
+    fan_out(hosts, DEFAULT_PARALLELISM, |host| {
+        agent::apply_state(host, resources, noaction)
+    })
+}
+
+pub fn handle_apply_command(
+    json: bool,
+    csv: bool,
+    file: &Path,
+    hosts: &[String],
+    force: bool,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing apply command");
+    }
+
+    let document = StateDocument::load(file).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let inventory = Inventory::load_or_exit();
+    let targets = maintenance::skip_blocked(inventory.resolve(hosts), force);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to apply {} to", file.display());
+        std::process::exit(1);
+    }
+
+    // Agents run noaction requests in check mode, reporting differences without changing anything
+    let results = apply(&targets, &document.resources, noaction);
+    let mut rows = Vec::new();
+    for (host, result) in targets.iter().zip(results) {
+        let results = result.unwrap_or_else(|e| {
+            vec![ResourceResult {
+                resource: String::new(),
+                outcome: Outcome::Failed,
+                message: e.to_string(),
+                diff: Vec::new(),
+            }]
+        });
+        rows.extend(results.into_iter().map(|result| ApplyRow {
+            hostname: host.name.clone(),
+            result,
+        }));
+    }
+    let failed = rows.iter().any(|r| r.result.outcome == Outcome::Failed);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("hostname,resource,outcome,message");
+        for row in &rows {
+            println!(
+                "{},{},{},{}",
+                row.hostname, row.result.resource, row.result.outcome, row.result.message
+            );
+        }
+    } else {
+        print_table(&rows);
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn print_table(rows: &[ApplyRow]) {
+    println!(
+        "{:<20} {:<30} {:<10} Message",
+        "Hostname", "Resource", "Outcome"
+    );
+    println!("{:-<20} {:-<30} {:-<10} {:-<30}", "", "", "", "");
+    for row in rows {
+        println!(
+            "{:<20} {:<30} {:<10} {}",
+            row.hostname, row.result.resource, row.result.outcome, row.result.message
+        );
+        for line in &row.result.diff {
+            println!("    {}", line);
+        }
+    }
+    let count = |outcome| rows.iter().filter(|r| r.result.outcome == outcome).count();
+    println!();
+    println!(
+        "{} changed, {} unchanged, {} failed",
+        count(Outcome::Changed),
+        count(Outcome::Unchanged),
+        count(Outcome::Failed)
+    );
+}
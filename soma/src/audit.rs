@@ -0,0 +1,472 @@
+use somacommon::audit::{self, AuditLog, Entry, Outcome, Record};
+use somacommon::glob_match;
+use somacommon::protocol::{self, Request, Response};
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::agent::AgentError;
+use crate::facts::csv_field;
+use crate::seen;
+
+/// Environment variable naming the operator soma acts for, honoured only
+/// when soma runs as root
+pub const OPERATOR_ENV: &str = "SOMA_OPERATOR";
+
+/// Environment variables naming another audit log, key and writer socket,
+/// honoured only when soma runs as root
+pub const AUDIT_LOG_ENV: &str = "SOMA_AUDIT_LOG";
+pub const AUDIT_KEY_ENV: &str = "SOMA_AUDIT_KEY";
+pub const AUDIT_SOCKET_ENV: &str = "SOMA_AUDIT_SOCKET";
+
+/// Where the controller's audit log is kept, out of reach of the operators
+/// it records
+pub const SYSTEM_AUDIT_LOG: &str = "/var/log/soma/audit.jsonl";
+
+/// Where the audit log's key is kept, readable only by root
+pub const SYSTEM_AUDIT_KEY: &str = "/etc/soma/audit.key";
+
+/// Where `soma audit serve` takes entries from operators other than root
+pub const SYSTEM_AUDIT_SOCKET: &str = "/run/soma/audit.sock";
+
+/// Who soma is acting for: the account it runs as, looked up by user id so
+/// the operator cannot simply name someone else.
+///
+/// root may act for another operator named by `SOMA_OPERATOR`, as it could
+/// become them anyway. This is the identity both soma's and the agents'
+/// audit logs record.
+pub fn operator() -> String {
+    let uid = unsafe { libc::getuid() };
+    if is_root()
+        && let Some(operator) = env::var(OPERATOR_ENV).ok().filter(|v| !v.is_empty())
+    {
+        return operator;
+    }
+    user_name(uid).unwrap_or_else(|| uid.to_string())
+}
+
+/// Whether soma runs as root, which may act for anyone
+pub fn is_root() -> bool {
+    unsafe { libc::getuid() == 0 }
+}
+
+/// The name of the account with a user id
+fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf = vec![0; 16 * 1024];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let status =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if status != 0 || found.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// A system path, or another named by an environment variable when soma
+/// runs as root
+fn system_path(var: &str, default: &str) -> PathBuf {
+    match env::var_os(var).filter(|_| is_root()) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(default),
+    }
+}
+
+/// The controller's audit log and its key, kept where operators cannot
+/// rewrite the one or read the other
+pub fn log() -> AuditLog {
+    AuditLog::new(
+        &system_path(AUDIT_LOG_ENV, SYSTEM_AUDIT_LOG),
+        &system_path(AUDIT_KEY_ENV, SYSTEM_AUDIT_KEY),
+    )
+}
+
+/// How entries reach the controller's audit log. root writes it itself;
+/// other operators hand their entries to `soma audit serve`, which records
+/// them as the account they come from.
+enum Writer {
+    Log(AuditLog),
+    Socket(PathBuf),
+}
+
+impl Writer {
+    fn new() -> Self {
+        match env::var_os(AUDIT_SOCKET_ENV).filter(|_| is_root()) {
+            Some(socket) => Writer::Socket(PathBuf::from(socket)),
+            None if is_root() => Writer::Log(log()),
+            None => Writer::Socket(PathBuf::from(SYSTEM_AUDIT_SOCKET)),
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        match self {
+            Writer::Log(log) => log.check_key().map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot read audit key {}: {}", log.key_path().display(), e),
+                )
+            }),
+            Writer::Socket(socket) => self::connect(socket).map(|_| ()),
+        }
+    }
+
+    fn append(&self, record: Record) -> io::Result<()> {
+        match self {
+            Writer::Log(log) => log.append(record).map(|_| ()),
+            Writer::Socket(socket) => {
+                let stream = self::connect(socket)?;
+                protocol::write_message(&mut &stream, &record)?;
+                let reply: Option<Result<u64, String>> =
+                    protocol::read_message(&mut BufReader::new(&stream))?;
+                match reply {
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(message)) => Err(io::Error::other(message)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the audit writer closed the connection",
+                    )),
+                }
+            }
+        }
+    }
+}
+
+fn connect(socket: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(socket).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "cannot reach the audit writer at {}: {}",
+                socket.display(),
+                e
+            ),
+        )
+    })
+}
+
+/// Check that entries can be added to the audit log, so that requests which
+/// must be recorded are not sent when they could not be
+pub fn ready() -> io::Result<()> {
+    Writer::new().check()
+}
+
+/// Record a request sent to a host's agent and what came of it, if it is
+/// one that is audited or it was denied
+pub fn record(host: &str, request: &Request, result: &Result<Response, AgentError>) {
+    let request = request.inner();
+    if !request.is_audited() && !matches!(result, Err(AgentError::Denied(_)))
+        || matches!(result, Err(AgentError::AuditError(_)))
+    {
+        return;
+    }
+    let (outcome, message) = match result {
+        Ok(response) => audit::outcome(response),
+        Err(e) => (Outcome::Failed, Some(e.to_string())),
+    };
+    let record = Record {
+        time: seen::now(),
+        operator: operator(),
+        origin: env::args().collect::<Vec<_>>().join(" "),
+        command: audit::command(request),
+        targets: vec![host.to_string()],
+        parameters: audit::parameters(request),
+        outcome,
+        message,
+    };
+    if let Err(e) = Writer::new().append(record) {
+        eprintln!("Warning: Failed to write the audit log: {}", e);
+    }
+}
+
+/// Which entries `soma audit search` shows
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub operator: Option<String>,
+    pub command: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<Outcome>,
+    /// Only entries at or after this time
+    pub since: Option<u64>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.operator
+            .as_ref()
+            .is_none_or(|p| glob_match(p, &entry.operator))
+            && self
+                .command
+                .as_ref()
+                .is_none_or(|p| glob_match(p, &entry.command))
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|p| entry.targets.iter().any(|t| glob_match(p, t)))
+            && self.outcome.is_none_or(|o| o == entry.outcome)
+            && self.since.is_none_or(|since| entry.time >= since)
+    }
+}
+
+/// Parse an outcome given on the command line
+pub fn parse_outcome(s: &str) -> Result<Outcome, String> {
+    match s {
+        "ok" => Ok(Outcome::Ok),
+        "failed" => Ok(Outcome::Failed),
+        _ => Err(format!("Invalid outcome {}, use ok or failed", s)),
+    }
+}
+
+/// The log named on the command line, keyed with the `.key` file beside it
+/// unless another key is given, or else the controller's own
+fn open(file: &Option<PathBuf>, key: &Option<PathBuf>) -> AuditLog {
+    let log = match file {
+        Some(path) => AuditLog::new(path, &path.with_extension("key")),
+        None => log(),
+    };
+    match key {
+        Some(key) => AuditLog::new(log.path(), key),
+        None => log,
+    }
+}
+
+/// The account at the other end of a connection to the audit writer
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let status = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if status != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Append the entry sent over a connection, timed by the writer and made for
+/// the account the connection comes from unless that is root, which may act
+/// for anyone
+fn take_entry(log: &AuditLog, stream: &UnixStream) -> io::Result<()> {
+    let uid = peer_uid(stream)?;
+    let Some(mut record) = protocol::read_message::<_, Record>(&mut BufReader::new(stream))? else {
+        return Ok(());
+    };
+    record.time = seen::now();
+    if uid != 0 {
+        record.operator = user_name(uid).unwrap_or_else(|| uid.to_string());
+    }
+    let reply: Result<u64, String> = log
+        .append(record)
+        .map(|entry| entry.seq)
+        .map_err(|e| e.to_string());
+    protocol::write_message(&mut &*stream, &reply)
+}
+
+pub fn handle_audit_init_command(verbose: bool, noaction: bool) {
+    if verbose {
+        println!("Executing audit init command");
+    }
+
+    let log = log();
+    if noaction {
+        println!("Would create the audit key {}", log.key_path().display());
+        return;
+    }
+    match log.create_key() {
+        Ok(()) => println!("Created the audit key {}", log.key_path().display()),
+        Err(e) => {
+            eprintln!(
+                "Error: Failed to create the audit key {}: {}",
+                log.key_path().display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn handle_audit_serve_command(socket: &Option<PathBuf>, verbose: bool, noaction: bool) {
+    if verbose {
+        println!("Executing audit serve command");
+    }
+
+    let socket = socket
+        .clone()
+        .unwrap_or_else(|| system_path(AUDIT_SOCKET_ENV, SYSTEM_AUDIT_SOCKET));
+    let log = log();
+    if let Err(e) = log.check_key() {
+        eprintln!(
+            "Error: Cannot read audit key {}: {}, create it with soma audit init",
+            log.key_path().display(),
+            e
+        );
+        std::process::exit(1);
+    }
+    if noaction {
+        println!(
+            "Would write entries sent to {} to the audit log {}",
+            socket.display(),
+            log.path().display()
+        );
+        return;
+    }
+
+    if let Some(dir) = socket.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    match fs::remove_file(&socket) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("Error: Failed to remove {}: {}", socket.display(), e);
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+    let listener = UnixListener::bind(&socket).unwrap_or_else(|e| {
+        eprintln!("Error: Failed to listen on {}: {}", socket.display(), e);
+        std::process::exit(1);
+    });
+    // Any operator may add entries, each recorded as who they are
+    if let Err(e) = fs::set_permissions(&socket, fs::Permissions::from_mode(0o666)) {
+        eprintln!(
+            "Error: Failed to open {} to operators: {}",
+            socket.display(),
+            e
+        );
+        std::process::exit(1);
+    }
+    println!(
+        "Writing entries sent to {} to the audit log {}",
+        socket.display(),
+        log.path().display()
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Warning: Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let log = log.clone();
+        thread::spawn(move || {
+            if let Err(e) = take_entry(&log, &stream) {
+                eprintln!("Warning: Failed to write an audit entry: {}", e);
+            }
+        });
+    }
+}
+
+pub fn handle_audit_verify_command(
+    file: &Option<PathBuf>,
+    key: &Option<PathBuf>,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing audit verify command");
+    }
+
+    let log = open(file, key);
+    if noaction {
+        println!("Would verify the audit log {}", log.path().display());
+        return;
+    }
+    match log.verify() {
+        Ok((count, head)) => println!(
+            "Audit log {} is intact: {} entries, head {}",
+            log.path().display(),
+            count,
+            head
+        ),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn handle_audit_search_command(
+    json: bool,
+    csv: bool,
+    filter: &AuditFilter,
+    file: &Option<PathBuf>,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing audit search command");
+    }
+
+    let log = open(file, &None);
+    if noaction {
+        println!("Would search the audit log {}", log.path().display());
+        return;
+    }
+    let entries = log.entries().unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let found: Vec<&Entry> = entries.iter().filter(|e| filter.matches(e)).collect();
+    print_entries(log.path(), &found, json, csv);
+}
+
+fn print_entries(path: &Path, entries: &[&Entry], json: bool, csv: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(entries).unwrap());
+    } else if csv {
+        println!("seq,timestamp,time,operator,origin,command,targets,outcome,message,parameters");
+        for entry in entries {
+            println!(
+                "{},{},{},{},{},{},{},{},{},{}",
+                entry.seq,
+                entry.time,
+                seen::format_time(entry.time),
+                csv_field(&entry.operator),
+                csv_field(&entry.origin),
+                entry.command,
+                csv_field(&entry.targets.join(" ")),
+                entry.outcome,
+                csv_field(entry.message.as_deref().unwrap_or_default()),
+                csv_field(&entry.parameters.to_string())
+            );
+        }
+    } else if entries.is_empty() {
+        println!("No entries in {} match", path.display());
+    } else {
+        println!(
+            "{:<6} {:<20} {:<12} {:<10} {:<20} {:<8} Origin",
+            "Seq", "Time", "Operator", "Command", "Targets", "Outcome"
+        );
+        println!(
+            "{:-<6} {:-<20} {:-<12} {:-<10} {:-<20} {:-<8} {:-<30}",
+            "", "", "", "", "", "", ""
+        );
+        for entry in entries {
+            println!(
+                "{:<6} {:<20} {:<12} {:<10} {:<20} {:<8} {}",
+                entry.seq,
+                seen::format_time(entry.time),
+                entry.operator,
+                entry.command,
+                entry.targets.join(","),
+                entry.outcome,
+                entry.origin
+            );
+            if let Some(message) = &entry.message {
+                println!("{:<6} {}", "", message);
+            }
+        }
+    }
+}
@@ -1,7 +1,213 @@
+use serde::Serialize;
+use somacommon::checks::{JobResult, PluginResult, PluginState, ProbeResult};
+use somacommon::status::StatusReport;
+use somacommon::{Host, Liveness};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, IsTerminal, Write};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::agent::{self, AgentError};
+use crate::facts::csv_field;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost};
+use crate::maintenance::{Maintenance, Window};
+use crate::seen;
+
+/// Default number of seconds between refreshes in watch mode
+pub const DEFAULT_WATCH_INTERVAL: u64 = 5;
+
+/// One row of the status table
+#[derive(Debug, Clone, Serialize)]
+pub struct HostStatus {
+    pub hostname: String,
+    pub status: String,
+    pub health: String,
+    pub last_seen: String,
+    /// What the agent's probes found, with their latency
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub probes: Vec<ProbeResult>,
+    /// The maintenance window the host is under, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<Window>,
+}
+
+/// Wrapper giving the JSON output its top-level `status_reports` key
+#[derive(Serialize)]
+struct StatusReports<'a> {
+    status_reports: &'a [HostStatus],
+}
+
+/// What `soma check` asks agents for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckSource {
+    /// A fresh status report
+    Status,
+    /// The latest results of their scheduled jobs
+    Cached,
+    /// The result of running one of their check plugins
+    Plugin(String),
+}
+
+/// A plugin's result on one host
+#[derive(Serialize)]
+struct HostPluginResult<'a> {
+    hostname: &'a str,
+    #[serde(flatten)]
+    result: Option<&'a PluginResult>,
+    error: Option<String>,
+}
+
+/// Results a host's agent stored from its scheduled jobs
+#[derive(Serialize)]
+struct CachedResults<'a> {
+    hostname: &'a str,
+    results: &'a [JobResult],
+    error: Option<String>,
+}
+
+/// A single tick of watch mode written as one JSON line
+#[derive(Serialize)]
+struct WatchTick<'a> {
+    timestamp: u64,
+    tick: u64,
+    changed: Vec<&'a str>,
+    status_reports: &'a [HostStatus],
+}
+
+/// Hosts to check. Without an inventory soma runs against demo hosts.
+fn target_hosts(inventory: &Inventory, hosts: &[String]) -> Vec<InventoryHost> {
+    if !inventory.is_empty() {
+        return inventory.resolve(hosts);
+    }
+    let names = if hosts.is_empty() {
+        vec![
+            "host1.example.com".to_string(),
+            "host2.example.com".to_string(),
+            "host3.example.com".to_string(),
+        ]
+    } else {
+        hosts.to_vec()
+    };
+    names
+        .into_iter()
+        .map(|name| InventoryHost {
+            name,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Fetch a status report from every host's agent in parallel, recording
+/// the hosts that answered as seen
+pub fn poll_status(hosts: &[InventoryHost]) -> Vec<Result<StatusReport, AgentError>> {
+    poll_status_timed(hosts)
+        .into_iter()
+        .map(|(result, _)| result)
+        .collect()
+}
+
+/// [`poll_status`], also giving how long each host took to answer or fail
+pub fn poll_status_timed(
+    hosts: &[InventoryHost],
+) -> Vec<(Result<StatusReport, AgentError>, Duration)> {
+    let results = fan_out(hosts, DEFAULT_PARALLELISM, |host| {
+        let started = Instant::now();
+        (agent::fetch_status(host), started.elapsed())
+    });
+    let now = seen::now();
+    let answered: BTreeMap<String, u64> = hosts
+        .iter()
+        .zip(&results)
+        .filter(|(_, (result, _))| result.is_ok())
+        .map(|(host, _)| (host.name.clone(), now))
+        .collect();
+    if !answered.is_empty()
+        && let Err(e) = seen::record(&answered)
+    {
+        eprintln!("Warning: Failed to record last-seen times: {}", e);
+    }
+    results
+}
+
+fn poll_hosts(hosts: &[InventoryHost], inventory: &Inventory) -> Vec<HostStatus> {
+    if inventory.is_empty() {
+        // Mock status data
+        return hosts
+            .iter()
+            .map(|host| HostStatus {
+                hostname: host.name.clone(),
+                status: "online".to_string(),
+                health: "healthy".to_string(),
+                last_seen: "last_seen: 2024-01-01 12:00:00".to_string(),
+                probes: Vec::new(),
+                maintenance: None,
+            })
+            .collect();
+    }
+
+    let results = poll_status(hosts);
+    let last_seen = seen::load();
+    let maintenance = Maintenance::load();
+    let now = seen::now();
+    hosts
+        .iter()
+        .zip(results)
+        .map(|(host, result)| {
+            let seen_host = Host::with_last_seen(&host.name, last_seen.get(&host.name).copied());
+            let last_seen = seen_host
+                .last_seen()
+                .map_or_else(|| "never".to_string(), seen::format_time);
+            let window = maintenance.window_for(&host.name, now).cloned();
+            match result {
+                Ok(report) => HostStatus {
+                    hostname: host.name.clone(),
+                    status: "online".to_string(),
+                    health: report.health.to_string(),
+                    last_seen,
+                    probes: report.probes,
+                    maintenance: window,
+                },
+                Err(AgentError::Denied(_)) => HostStatus {
+                    hostname: host.name.clone(),
+                    status: "denied".to_string(),
+                    health: "unknown".to_string(),
+                    last_seen,
+                    probes: Vec::new(),
+                    maintenance: window,
+                },
+                Err(_) => {
+                    let status = match seen_host.liveness(
+                        now,
+                        inventory.stale_after,
+                        inventory.lost_after,
+                    ) {
+                        Liveness::Stale => "stale",
+                        Liveness::Lost => "lost",
+                        Liveness::Live | Liveness::Never => "offline",
+                    };
+                    HostStatus {
+                        hostname: host.name.clone(),
+                        status: status.to_string(),
+                        health: "unknown".to_string(),
+                        last_seen,
+                        probes: Vec::new(),
+                        maintenance: window,
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_check_command(
     json: bool,
     csv: bool,
     hosts: &[String],
+    watch: Option<Option<u64>>,
+    count: Option<u64>,
+    source: &CheckSource,
     verbose: bool,
     noaction: bool,
 ) {
@@ -9,58 +215,343 @@ pub fn handle_check_command(
         println!("Executing check command");
     }
 
-    let target_hosts = if hosts.is_empty() {
-        vec![
-            "host1.example.com",
-            "host2.example.com",
-            "host3.example.com",
-        ]
-    } else {
-        hosts.iter().map(|s| s.as_str()).collect()
-    };
+    let inventory = Inventory::load_or_exit();
+    let target_hosts = target_hosts(&inventory, hosts);
 
     if noaction {
-        println!("Would check status of hosts: {:?}", target_hosts);
+        let names: Vec<&str> = target_hosts.iter().map(|h| h.name.as_str()).collect();
+        println!("Would check status of hosts: {:?}", names);
+        if let Some(interval) = watch {
+            println!(
+                "Would refresh every {} seconds",
+                interval.unwrap_or(DEFAULT_WATCH_INTERVAL)
+            );
+        }
         return;
     }
 
-    // Mock status data
-    let status_data: Vec<(&str, &str, &str, &str)> = target_hosts
+    match source {
+        CheckSource::Status => {}
+        CheckSource::Cached => return print_cached(&target_hosts, json, csv),
+        CheckSource::Plugin(name) => {
+            let worst = print_plugin(&target_hosts, name, json, csv);
+            std::process::exit(worst.exit_code());
+        }
+    }
+
+    match watch {
+        Some(interval) => {
+            let interval = interval.unwrap_or(DEFAULT_WATCH_INTERVAL);
+            if interval == 0 {
+                eprintln!("Error: Watch interval must be at least 1 second");
+                std::process::exit(1);
+            }
+            watch_hosts(
+                &target_hosts,
+                &inventory,
+                Duration::from_secs(interval),
+                count,
+            );
+        }
+        None => print_status(&poll_hosts(&target_hosts, &inventory), json, csv),
+    }
+}
+
+fn print_status(status_data: &[HostStatus], json: bool, csv: bool) {
+    if json {
+        let reports = StatusReports {
+            status_reports: status_data,
+        };
+        println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    } else if csv {
+        println!("hostname,status,health,last_seen");
+        for row in status_data {
+            println!(
+                "{},{},{},{}",
+                row.hostname, row.status, row.health, row.last_seen
+            );
+        }
+    } else {
+        print_table(status_data, &[]);
+    }
+}
+
+/// Print the results agents stored from their scheduled jobs, without running anything
+fn print_cached(hosts: &[InventoryHost], json: bool, csv: bool) {
+    let results = fan_out(hosts, DEFAULT_PARALLELISM, agent::fetch_results);
+    let rows: Vec<CachedResults> = hosts
         .iter()
-        .map(|host| (*host, "online", "healthy", "last_seen: 2024-01-01 12:00:00"))
+        .zip(&results)
+        .map(|(host, result)| CachedResults {
+            hostname: &host.name,
+            results: result.as_deref().unwrap_or_default(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        })
         .collect();
 
     if json {
-        println!("{{");
-        println!("  \"status_reports\": [");
-        for (i, (hostname, status, health, last_seen)) in status_data.iter().enumerate() {
-            let comma = if i == status_data.len() - 1 { "" } else { "," };
-            println!("    {{");
-            println!("      \"hostname\": \"{}\",", hostname);
-            println!("      \"status\": \"{}\",", status);
-            println!("      \"health\": \"{}\",", health);
-            println!("      \"last_seen\": \"{}\"", last_seen);
-            println!("    }}{}", comma);
-        }
-        println!("  ]");
-        println!("}}");
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
     } else if csv {
-        println!("hostname,status,health,last_seen");
-        for (hostname, status, health, last_seen) in status_data {
-            println!("{},{},{},{}", hostname, status, health, last_seen);
+        println!("hostname,kind,name,scheduled,started,duration_ms,exit_code,catch_up,output");
+        for row in &rows {
+            for result in row.results {
+                println!(
+                    "{},{},{},{},{},{},{},{},{}",
+                    row.hostname,
+                    result.kind,
+                    result.name,
+                    result.scheduled,
+                    result.started,
+                    result.duration_ms,
+                    result.exit_code.map(|c| c.to_string()).unwrap_or_default(),
+                    result.catch_up,
+                    csv_field(&result.output)
+                );
+            }
         }
     } else {
-        println!("Host Status Reports:");
         println!(
+            "{:<20} {:<24} {:<20} {:<6} Output",
+            "Hostname", "Job", "Last Run", "Exit"
+        );
+        println!("{:-<20} {:-<24} {:-<20} {:-<6} {:-<30}", "", "", "", "", "");
+        for row in &rows {
+            if let Some(error) = &row.error {
+                println!("{:<20} {}", row.hostname, error);
+            } else if row.results.is_empty() {
+                println!("{:<20} No scheduled jobs", row.hostname);
+            }
+            for result in row.results {
+                println!(
+                    "{:<20} {:<24} {:<20} {:<6} {}",
+                    row.hostname,
+                    format!("{} {}", result.kind, result.name),
+                    seen::format_time(result.started),
+                    result
+                        .exit_code
+                        .map(|c| c.to_string())
+                        .unwrap_or("-".to_string()),
+                    result.output.lines().next().unwrap_or_default()
+                );
+            }
+        }
+    }
+}
+
+/// Run a plugin on every host and print what it found, returning the worst
+/// state so soma can itself be used as a plugin. An unreachable host is unknown.
+fn print_plugin(hosts: &[InventoryHost], name: &str, json: bool, csv: bool) -> PluginState {
+    let results = fan_out(hosts, DEFAULT_PARALLELISM, |host| {
+        agent::run_plugin(host, name)
+    });
+    let rows: Vec<HostPluginResult> = hosts
+        .iter()
+        .zip(&results)
+        .map(|(host, result)| HostPluginResult {
+            hostname: &host.name,
+            result: result.as_ref().ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("hostname,plugin,state,output,perfdata");
+        for row in &rows {
+            let (state, output, perfdata) = match (row.result, &row.error) {
+                (Some(result), _) => (
+                    result.state,
+                    result.output.clone(),
+                    result
+                        .perfdata
+                        .iter()
+                        .map(|p| p.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                (None, error) => (
+                    PluginState::Unknown,
+                    error.clone().unwrap_or_default(),
+                    String::new(),
+                ),
+            };
+            println!(
+                "{},{},{},{},{}",
+                row.hostname,
+                name,
+                state,
+                csv_field(&output),
+                csv_field(&perfdata)
+            );
+        }
+    } else {
+        println!("Plugin {}:", name);
+        println!("{:<20} {:<10} Output", "Hostname", "State");
+        println!("{:-<20} {:-<10} {:-<30}", "", "", "");
+        for row in &rows {
+            match (row.result, &row.error) {
+                (Some(result), _) => println!(
+                    "{:<20} {:<10} {}",
+                    row.hostname,
+                    result.state.to_string(),
+                    result.output
+                ),
+                (None, error) => println!(
+                    "{:<20} {:<10} {}",
+                    row.hostname,
+                    PluginState::Unknown.to_string(),
+                    error.as_deref().unwrap_or_default()
+                ),
+            }
+        }
+    }
+
+    rows.iter()
+        .map(|row| row.result.map_or(PluginState::Unknown, |r| r.state))
+        .max()
+        .unwrap_or(PluginState::Ok)
+}
+
+fn print_table(status_data: &[HostStatus], changed: &[&str]) {
+    println!("Host Status Reports:");
+    println!(
+        "{:<20} {:<10} {:<10} {:<25}",
+        "Hostname", "Status", "Health", "Last Seen"
+    );
+    println!("{:-<20} {:-<10} {:-<10} {:-<25}", "", "", "", "");
+    for row in status_data {
+        let line = format!(
             "{:<20} {:<10} {:<10} {:<25}",
-            "Hostname", "Status", "Health", "Last Seen"
+            row.hostname, row.status, row.health, row.last_seen
         );
-        println!("{:-<20} {:-<10} {:-<10} {:-<25}", "", "", "", "");
-        for (hostname, status, health, last_seen) in status_data {
+        if changed.contains(&row.hostname.as_str()) {
+            // Reverse video so a health change stands out for one tick
+            println!("\x1b[7m{}\x1b[0m", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+    print_probes(status_data);
+    print_maintenance(status_data);
+}
+
+/// List the hosts' probe results below the status table, if they have any
+fn print_probes(status_data: &[HostStatus]) {
+    if status_data.iter().all(|row| row.probes.is_empty()) {
+        return;
+    }
+    println!();
+    println!("Probes:");
+    println!(
+        "{:<20} {:<20} {:<10} {:<10} Message",
+        "Hostname", "Probe", "State", "Latency"
+    );
+    println!(
+        "{:-<20} {:-<20} {:-<10} {:-<10} {:-<30}",
+        "", "", "", "", ""
+    );
+    for row in status_data {
+        for probe in &row.probes {
+            let name = format!("{} {}", probe.kind, probe.name);
+            let state = probe.state.to_string();
+            let latency = probe
+                .latency_ms
+                .map_or("-".to_string(), |ms| format!("{}ms", ms));
+            println!(
+                "{:<20} {:<20} {:<10} {:<10} {}",
+                row.hostname, name, state, latency, probe.message
+            );
+        }
+    }
+}
+
+/// List the hosts under maintenance below the status table, if any are
+fn print_maintenance(status_data: &[HostStatus]) {
+    let windows: Vec<(&str, &Window)> = status_data
+        .iter()
+        .filter_map(|row| Some((row.hostname.as_str(), row.maintenance.as_ref()?)))
+        .collect();
+    if windows.is_empty() {
+        return;
+    }
+    println!();
+    println!("Maintenance:");
+    println!(
+        "{:<20} {:<4} {:<20} {:<6} Reason",
+        "Hostname", "Id", "Until", "Block"
+    );
+    println!("{:-<20} {:-<4} {:-<20} {:-<6} {:-<30}", "", "", "", "", "");
+    for (hostname, window) in windows {
+        println!(
+            "{:<20} {:<4} {:<20} {:<6} {}",
+            hostname,
+            window.id,
+            seen::format_time(window.end),
+            if window.block { "yes" } else { "no" },
+            window.reason
+        );
+    }
+}
+
+/// Re-poll the hosts every `interval`, redrawing the table in place on a
+/// terminal or appending one JSON line per tick when stdout is redirected.
+fn watch_hosts(
+    hosts: &[InventoryHost],
+    inventory: &Inventory,
+    interval: Duration,
+    count: Option<u64>,
+) {
+    let interactive = io::stdout().is_terminal();
+    let mut previous: HashMap<String, String> = HashMap::new();
+    let mut tick: u64 = 0;
+
+    loop {
+        tick += 1;
+        let status_data = poll_hosts(hosts, inventory);
+        let changed: Vec<&str> = status_data
+            .iter()
+            .filter(|row| {
+                previous
+                    .get(&row.hostname)
+                    .is_some_and(|health| *health != row.health)
+            })
+            .map(|row| row.hostname.as_str())
+            .collect();
+
+        if interactive {
+            // Clear the screen and move the cursor home before redrawing
+            print!("\x1b[2J\x1b[H");
+            let names: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
             println!(
-                "{:<20} {:<10} {:<10} {:<25}",
-                hostname, status, health, last_seen
+                "Every {}s: soma check {}",
+                interval.as_secs(),
+                names.join(" ")
             );
+            println!();
+            print_table(&status_data, &changed);
+        } else {
+            let line = WatchTick {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                tick,
+                changed: changed.clone(),
+                status_reports: &status_data,
+            };
+            println!("{}", serde_json::to_string(&line).unwrap());
+        }
+        let _ = io::stdout().flush();
+
+        previous = status_data
+            .iter()
+            .map(|row| (row.hostname.clone(), row.health.clone()))
+            .collect();
+
+        if count.is_some_and(|count| tick >= count) {
+            break;
         }
+        thread::sleep(interval);
     }
 }
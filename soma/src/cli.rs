@@ -1,3 +1,9 @@
+use crate::audit::parse_outcome;
+use crate::metrics::parse_duration;
+use crate::rollout::Rollout;
+use somacommon::audit::Outcome;
+use somacommon::service::ServiceOperation;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -15,6 +21,10 @@ pub struct Cli {
     #[structopt(long, short)]
     pub verbose: bool,
 
+    /// Act on hosts even when a maintenance window blocks changes to them
+    #[structopt(long)]
+    pub force: bool,
+
     #[structopt(subcommand)]
     pub command: Option<Command>,
 }
@@ -31,6 +41,9 @@ pub enum Command {
         /// Return the information in CSV format
         #[structopt(long)]
         csv: bool,
+        /// Only list hosts that have not been heard from recently
+        #[structopt(long)]
+        stale: bool,
     },
     /// Scan the network for hosts both managed and unmanaged
     Scan {
@@ -40,6 +53,9 @@ pub enum Command {
         /// Return the information in CSV format
         #[structopt(long)]
         csv: bool,
+        /// Report changes since the last stored scan or a saved JSON scan file
+        #[structopt(long)]
+        diff: Option<Option<PathBuf>>,
     },
     /// Request a status report from a host or list of hosts or all hosts if none are specified
     Check {
@@ -49,10 +65,372 @@ pub enum Command {
         /// Return the information in CSV format
         #[structopt(long)]
         csv: bool,
+        /// Re-poll the hosts and redraw the table
+        #[structopt(long)]
+        watch: bool,
+        /// Seconds between refreshes in watch mode (default 5)
+        #[structopt(long, requires = "watch")]
+        interval: Option<u64>,
+        /// Stop watching after this many refreshes
+        #[structopt(long, requires = "watch")]
+        count: Option<u64>,
+        /// Show the latest results of the agents' scheduled checks and actions
+        #[structopt(long, conflicts_with = "watch")]
+        cached: bool,
+        /// Run one of the agents' check plugins, exiting with the worst state's code
+        #[structopt(long, conflicts_with_all = &["watch", "cached"])]
+        plugin: Option<String>,
         /// List of hosts to check (if none specified, check all hosts)
         #[structopt()]
         hosts: Vec<String>,
     },
+    /// Gather hardware and OS facts from hosts as an asset report
+    Facts {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Output format, as an alternative to --json and --csv
+        #[structopt(long, possible_values = &["table", "json", "csv"])]
+        format: Option<String>,
+        /// Ask agents to gather facts afresh rather than answer from their cache
+        #[structopt(long)]
+        refresh: bool,
+        /// Hosts or selectors to report on (all inventory hosts if none are given)
+        #[structopt()]
+        hosts: Vec<String>,
+    },
+    /// List installed packages on hosts and show version skew across them
+    Packages {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Only show packages whose name matches this glob (e.g. openssl or libssl*)
+        #[structopt(long)]
+        name: Option<String>,
+        /// Hosts or selectors to report on (all inventory hosts if none are given)
+        #[structopt()]
+        hosts: Vec<String>,
+    },
+    /// List listening ports on hosts, flagging those not expected by the inventory
+    Ports {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Only show listeners missing from the host's expected ports
+        #[structopt(long)]
+        unexpected: bool,
+        /// Hosts or selectors to report on (all inventory hosts if none are given)
+        #[structopt()]
+        hosts: Vec<String>,
+    },
+    /// Query or change a systemd unit on hosts
+    Service {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Hosts to act on, as a selector (e.g. @web or web-*)
+        #[structopt()]
+        hosts: String,
+        /// What to do with the unit
+        #[structopt(possible_values = ServiceOperation::NAMES)]
+        operation: String,
+        /// The systemd unit, e.g. nginx.service
+        #[structopt()]
+        unit: String,
+        #[structopt(flatten)]
+        rollout: Rollout,
+    },
+    /// Run one of the agents' allowlisted actions on hosts
+    Run {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Hosts to run on, as a selector (e.g. @web or web-*)
+        #[structopt()]
+        hosts: String,
+        /// Name of the action in the agents' configuration
+        #[structopt()]
+        action: String,
+        #[structopt(flatten)]
+        rollout: Rollout,
+    },
+    /// Copy a file to hosts, replacing it atomically and only when it differs
+    Copy {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// User to own the file, by name or uid
+        #[structopt(long)]
+        owner: Option<String>,
+        /// Group to own the file, by name or gid
+        #[structopt(long)]
+        group: Option<String>,
+        /// Permission bits in octal, e.g. 0644
+        #[structopt(long)]
+        mode: Option<String>,
+        /// Fill in {{ key }} placeholders from each host's name, vars and facts
+        #[structopt(long)]
+        template: bool,
+        /// Do not keep a copy of the file being replaced
+        #[structopt(long)]
+        no_backup: bool,
+        /// Local file to copy
+        #[structopt()]
+        src: String,
+        /// Hosts and destination, as <hosts>:<absolute path>
+        #[structopt()]
+        dest: String,
+    },
+    /// Fetch a file from hosts into a directory per host
+    Fetch {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Only fetch the last N lines
+        #[structopt(long, conflicts_with = "range")]
+        tail: Option<u64>,
+        /// Only fetch bytes START up to, not including, END, e.g. 0-4096 or 4096-
+        #[structopt(long)]
+        range: Option<String>,
+        /// Hosts and file, as <hosts>:<absolute path>
+        #[structopt()]
+        src: String,
+        /// Local directory to save the files under, in a directory per host
+        #[structopt()]
+        dest: PathBuf,
+    },
+    /// Converge hosts towards a desired-state document
+    Apply {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// TOML file listing the resources to apply
+        #[structopt()]
+        file: PathBuf,
+        /// Hosts to apply it to, as names or selectors; all hosts when none are given
+        #[structopt()]
+        hosts: Vec<String>,
+    },
+    /// Run a playbook of ordered steps against inventory hosts
+    Play {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Only check that the playbook is valid
+        #[structopt(long)]
+        check: bool,
+        /// TOML playbook file
+        #[structopt()]
+        file: PathBuf,
+        #[structopt(flatten)]
+        rollout: Rollout,
+    },
+    /// Show a live dashboard of the health of inventory hosts
+    Top {
+        /// Seconds between refreshes
+        #[structopt(long, default_value = "5")]
+        interval: u64,
+        /// Only show hosts matching this selector (e.g. @web or web-*)
+        #[structopt()]
+        selector: Option<String>,
+    },
+    /// Accept connections from agents that dial out and relay requests to them
+    Serve {
+        /// Address to listen on for agents and local soma commands
+        #[structopt(long, default_value = "0.0.0.0:7071")]
+        listen: String,
+        /// Secret each agent's own token is derived from, see agent-token
+        #[structopt(long, env = "SOMA_AGENT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        /// Seconds between agent heartbeats
+        #[structopt(long, default_value = "30")]
+        heartbeat: u64,
+        /// Also poll the inventory agents soma dials every this many seconds
+        #[structopt(long)]
+        poll: Option<u64>,
+        /// Store the metrics of every agent every this many seconds
+        #[structopt(long)]
+        scrape: Option<u64>,
+        /// How long to keep scraped metrics (e.g. 30d or 12w)
+        #[structopt(long, default_value = "30d", parse(try_from_str = parse_duration))]
+        retention: u64,
+        /// Serve fleet metrics for Prometheus on http://<address>/metrics
+        #[structopt(long)]
+        http: Option<String>,
+        /// Evaluate the alert rules in this file and notify their sinks
+        #[structopt(long)]
+        alerts: Option<PathBuf>,
+    },
+    /// Query the metrics scraped by soma serve
+    Metrics {
+        #[structopt(subcommand)]
+        command: MetricsCommand,
+    },
+    /// Put hosts under maintenance and silence alerts
+    Maintenance {
+        #[structopt(subcommand)]
+        command: MaintenanceCommand,
+    },
+    /// Check or search the audit log of requests sent to agents
+    Audit {
+        #[structopt(subcommand)]
+        command: AuditCommand,
+    },
+    /// Create the key soma signs requests to agents with
+    Keygen {
+        /// Where to write the key (SOMA_SIGNING_KEY or ~/.config/soma/signing.key if not given)
+        #[structopt(long)]
+        file: Option<PathBuf>,
+    },
+    /// Print the token a push agent registers with soma serve as a host
+    AgentToken {
+        /// The name the agent registers as
+        hostname: String,
+        /// Shared secret soma serve was started with
+        #[structopt(long, env = "SOMA_AGENT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum MetricsCommand {
+    /// Show the series matching a query such as 'disk_used{host=web-01,mount=/}'
+    Query {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// How far back to look (e.g. 90m, 24h or 7d)
+        #[structopt(long, default_value = "1h", parse(try_from_str = parse_duration))]
+        since: u64,
+        /// Metric name with optional label matches, each of which may be a glob
+        #[structopt()]
+        selector: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum MaintenanceCommand {
+    /// Put the hosts a selector picks under maintenance, holding back their alerts
+    Start {
+        /// How long the maintenance lasts (e.g. 30m or 2h)
+        #[structopt(long, default_value = "1h", parse(try_from_str = parse_duration))]
+        duration: u64,
+        /// Why the hosts are under maintenance
+        #[structopt(long)]
+        reason: String,
+        /// Also skip the hosts in run, service, copy, apply and play unless --force is given
+        #[structopt(long)]
+        block: bool,
+        /// Hosts to put under maintenance, as a selector (e.g. @web or web-*)
+        #[structopt()]
+        selector: String,
+    },
+    /// Hold back the alerts matching 'rule=GLOB,key=GLOB,host=GLOB' for a while
+    Silence {
+        /// How long the silence lasts (e.g. 30m or 2h)
+        #[structopt(long, default_value = "1h", parse(try_from_str = parse_duration))]
+        duration: u64,
+        /// Why the alerts are silenced
+        #[structopt(long)]
+        reason: String,
+        /// Comma-separated globs on the alert's rule, key and host
+        #[structopt()]
+        matchers: String,
+    },
+    /// List the maintenance windows and silences in force
+    List {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+    },
+    /// End a maintenance window or silence early
+    End {
+        /// Id shown when it was started and by list
+        #[structopt()]
+        id: u64,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AuditCommand {
+    /// Check that no entry of an audit log was changed, removed or reordered
+    Verify {
+        /// Audit log to check, such as one fetched from an agent (soma's own if not given)
+        #[structopt(long)]
+        file: Option<PathBuf>,
+        /// Key the log's hashes were made with (soma's own key, or the path given to --file
+        /// with a .key extension, if not given)
+        #[structopt(long)]
+        key: Option<PathBuf>,
+    },
+    /// Create the key soma's audit log is chained with, once, as root
+    Init,
+    /// Write the entries of operators other than root to soma's audit log, as root
+    Serve {
+        /// Socket to take entries on (/run/soma/audit.sock if not given)
+        #[structopt(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Show the audit log entries matching all the filters given
+    Search {
+        /// Return the information in JSON format
+        #[structopt(long)]
+        json: bool,
+        /// Return the information in CSV format
+        #[structopt(long)]
+        csv: bool,
+        /// Only entries for operators matching this glob
+        #[structopt(long)]
+        operator: Option<String>,
+        /// Only requests of a kind matching this glob, such as action or copy
+        #[structopt(long)]
+        command: Option<String>,
+        /// Only entries with a target matching this glob
+        #[structopt(long)]
+        target: Option<String>,
+        /// Only entries that ended this way
+        #[structopt(long, possible_values = &["ok", "failed"], parse(try_from_str = parse_outcome))]
+        outcome: Option<Outcome>,
+        /// Only entries from this far back (e.g. 24h or 7d)
+        #[structopt(long, parse(try_from_str = parse_duration))]
+        since: Option<u64>,
+        /// Audit log to search, such as one fetched from an agent (soma's own if not given)
+        #[structopt(long)]
+        file: Option<PathBuf>,
+    },
 }
 
 impl Cli {
@@ -70,6 +448,7 @@ pub fn print_usage() {
     println!("FLAGS:");
     println!("    -n, --noaction    Describe what would be done without doing it");
     println!("    -v, --verbose     Produce verbose output as the process runs");
+    println!("        --force       Act on hosts even when maintenance blocks changes");
     println!("    -h, --help        Prints help information");
     println!("    -V, --version     Prints version information");
     println!();
@@ -78,6 +457,21 @@ pub fn print_usage() {
     println!("    list     List all hosts that can be managed");
     println!("    scan     Scan the network for hosts both managed and unmanaged");
     println!("    check    Request a status report from a host or list of hosts");
+    println!("    facts    Gather hardware and OS facts from hosts");
+    println!("    packages List installed packages and version skew across hosts");
+    println!("    ports    List listening ports and flag unexpected ones");
+    println!("    run      Run an allowlisted action on hosts");
+    println!("    service  Query or change a systemd unit on hosts");
+    println!("    copy     Copy a file or template to hosts");
+    println!("    fetch    Fetch a file or the end of a log from hosts");
+    println!("    apply    Converge hosts towards a desired-state document");
+    println!("    play     Run a playbook of ordered steps against hosts");
+    println!("    top      Show a live dashboard of the health of inventory hosts");
+    println!("    serve    Accept connections from agents that dial out");
+    println!("    metrics  Query the metrics scraped by soma serve");
+    println!("    maintenance  Put hosts under maintenance and silence alerts");
+    println!("    audit    Check or search the audit log of requests sent to agents");
+    println!("    keygen   Create the key soma signs requests to agents with");
     println!();
     println!("Each subcommand supports:");
     println!("    --json    Return information in JSON format");
@@ -0,0 +1,154 @@
+use serde::Serialize;
+use somacommon::files::CopyResult;
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::agent;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, parse_host_path};
+use crate::maintenance;
+use crate::template;
+
+/// How a copied file should end up on the agent
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: Option<u32>,
+    /// Keep the replaced file on the agent
+    pub backup: bool,
+    /// Render the source for each host with [`template::render`] before sending it
+    pub template: bool,
+}
+
+/// One row of the copy report
+#[derive(Serialize)]
+struct CopyRow<'a> {
+    hostname: &'a str,
+    #[serde(flatten)]
+    result: Option<&'a CopyResult>,
+    error: Option<&'a str>,
+}
+
+/// Parse an octal mode such as `644` or `0640`
+pub fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_copy_command(
+    json: bool,
+    csv: bool,
+    src: &str,
+    target: &str,
+    options: &CopyOptions,
+    force: bool,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing copy command");
+    }
+
+    let Some((hosts, dest)) = parse_host_path(target) else {
+        eprintln!(
+            "Error: Destination must be <hosts>:<absolute path>, not {}",
+            target
+        );
+        std::process::exit(1);
+    };
+    let content = fs::read(src).unwrap_or_else(|e| {
+        eprintln!("Error: Cannot read {}: {}", src, e);
+        std::process::exit(1);
+    });
+    let text = if options.template {
+        match String::from_utf8(content.clone()) {
+            Ok(text) => Some(text),
+            Err(_) => {
+                eprintln!("Error: Template {} is not valid UTF-8", src);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let inventory = Inventory::load_or_exit();
+    let targets = maintenance::skip_blocked(inventory.resolve(&[hosts.to_string()]), force);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to copy {} to", src);
+        std::process::exit(1);
+    }
+
+    // Agents answer noaction requests with the change they would make
+    let results = fan_out(&targets, DEFAULT_PARALLELISM, |host| {
+        let content = match &text {
+            Some(text) => template::render(text, host, &BTreeMap::new())?.into_bytes(),
+            None => content.clone(),
+        };
+        agent::copy_file(host, dest, &content, options, noaction).map_err(|e| e.to_string())
+    });
+    let rows: Vec<CopyRow> = targets
+        .iter()
+        .zip(&results)
+        .map(|(host, result)| CopyRow {
+            hostname: &host.name,
+            result: result.as_ref().ok(),
+            error: result.as_ref().err().map(String::as_str),
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("hostname,path,changed,sha256,backup,message");
+        for row in &rows {
+            match (row.result, row.error) {
+                (Some(result), _) => println!(
+                    "{},{},{},{},{},{}",
+                    row.hostname,
+                    result.path,
+                    result.changed,
+                    result.sha256,
+                    result.backup.as_deref().unwrap_or_default(),
+                    result.message
+                ),
+                (None, error) => println!(
+                    "{},{},false,,,{}",
+                    row.hostname,
+                    dest,
+                    error.unwrap_or_default()
+                ),
+            }
+        }
+    } else {
+        println!("{:<20} {:<10} Message", "Hostname", "Result");
+        println!("{:-<20} {:-<10} {:-<30}", "", "", "");
+        for row in &rows {
+            let (outcome, message) = match (row.result, row.error) {
+                (Some(result), _) if result.changed => ("changed", result.message.as_str()),
+                (Some(result), _) => ("unchanged", result.message.as_str()),
+                (None, error) => ("failed", error.unwrap_or_default()),
+            };
+            println!("{:<20} {:<10} {}", row.hostname, outcome, message);
+        }
+    }
+
+    if results.iter().any(|r| r.is_err()) {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(parse_mode("0640"), Some(0o640));
+        assert_eq!(parse_mode("755"), Some(0o755));
+        assert_eq!(parse_mode("888"), None);
+    }
+}
@@ -0,0 +1,158 @@
+use serde::Serialize;
+use somacommon::facts::Facts;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::agent;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::Inventory;
+use crate::state;
+
+/// File in the state directory caching the facts last gathered from each host
+pub const FACTS_FILE: &str = "facts.json";
+
+/// Wrapper giving the JSON output its top-level `facts` key
+#[derive(Serialize)]
+struct FactsReport<'a> {
+    facts: Vec<&'a Facts>,
+}
+
+/// Facts last gathered by host name. A missing or unreadable cache is empty.
+pub fn load_cache() -> BTreeMap<String, Facts> {
+    fs::read_to_string(state::state_dir().join(FACTS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Add freshly gathered facts to the cache, replacing older entries for the same hosts
+pub fn store_cache(gathered: &BTreeMap<String, Facts>) -> io::Result<PathBuf> {
+    let path = state::state_file(FACTS_FILE)?;
+    let mut cache = load_cache();
+    cache.extend(gathered.iter().map(|(k, v)| (k.clone(), v.clone())));
+    let content = serde_json::to_string_pretty(&cache).map_err(io::Error::other)?;
+    state::replace(&path, &content)?;
+    Ok(path)
+}
+
+pub fn handle_facts_command(
+    json: bool,
+    csv: bool,
+    refresh: bool,
+    hosts: &[String],
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing facts command");
+    }
+
+    let inventory = Inventory::load_or_exit();
+    let targets = inventory.resolve(hosts);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to gather facts from");
+        std::process::exit(1);
+    }
+
+    if noaction {
+        let names: Vec<&str> = targets.iter().map(|h| h.name.as_str()).collect();
+        println!("Would gather facts from hosts: {:?}", names);
+        return;
+    }
+
+    let results = fan_out(&targets, DEFAULT_PARALLELISM, |host| {
+        agent::fetch_facts(host, refresh)
+    });
+    let mut gathered = BTreeMap::new();
+    for (host, result) in targets.iter().zip(results) {
+        match result {
+            Ok(facts) => {
+                gathered.insert(host.name.clone(), facts);
+            }
+            Err(e) => eprintln!("Warning: {}: {}", host.name, e),
+        }
+    }
+    if let Err(e) = store_cache(&gathered) {
+        eprintln!("Warning: Failed to cache facts: {}", e);
+    }
+
+    // Keep the order the hosts were asked for
+    let rows: Vec<(&str, &Facts)> = targets
+        .iter()
+        .filter_map(|h| gathered.get(&h.name).map(|f| (h.name.as_str(), f)))
+        .collect();
+    if json {
+        let report = FactsReport {
+            facts: rows.iter().map(|(_, f)| *f).collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else if csv {
+        print_csv(&rows);
+    } else {
+        print_table(&rows);
+    }
+}
+
+/// Quote a CSV field when it contains a separator, quote or line break
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn print_csv(rows: &[(&str, &Facts)]) {
+    println!(
+        "hostname,vendor,product,serial,os_id,os_version,kernel,cpu_model,sockets,cores,threads,memory_kb,disks,disk_bytes,macs"
+    );
+    for (name, facts) in rows {
+        let disk_bytes: u64 = facts.block_devices.iter().map(|d| d.size_bytes).sum();
+        let macs: Vec<&str> = facts
+            .interfaces
+            .iter()
+            .map(|i| i.mac.as_str())
+            .filter(|mac| !mac.is_empty() && *mac != "00:00:00:00:00:00")
+            .collect();
+        let fields = [
+            name.to_string(),
+            facts.dmi.vendor.clone(),
+            facts.dmi.product.clone(),
+            facts.dmi.serial.clone(),
+            facts.os.id.clone(),
+            facts.os.version_id.clone(),
+            facts.os.kernel.clone(),
+            facts.cpu.model.clone(),
+            facts.cpu.sockets.to_string(),
+            facts.cpu.cores.to_string(),
+            facts.cpu.threads.to_string(),
+            facts.memory_kb.to_string(),
+            facts.block_devices.len().to_string(),
+            disk_bytes.to_string(),
+            macs.join(" "),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        println!("{}", fields.join(","));
+    }
+}
+
+fn print_table(rows: &[(&str, &Facts)]) {
+    println!("Host Facts:");
+    println!(
+        "{:<20} {:<30} {:<25} {:<6} {:<10}",
+        "Hostname", "OS", "Product", "CPUs", "Memory"
+    );
+    println!("{:-<20} {:-<30} {:-<25} {:-<6} {:-<10}", "", "", "", "", "");
+    for (name, facts) in rows {
+        println!(
+            "{:<20} {:<30} {:<25} {:<6} {:<10}",
+            name,
+            facts.os.pretty_name,
+            facts.dmi.product,
+            facts.cpu.threads,
+            format!("{} MB", facts.memory_kb / 1024)
+        );
+    }
+}
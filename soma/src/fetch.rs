@@ -0,0 +1,198 @@
+use serde::Serialize;
+use somacommon::base64;
+use somacommon::files::FETCH_CHUNK;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::agent;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost, parse_host_path};
+
+/// Which part of a file to fetch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Span {
+    Whole,
+    /// Bytes from the first offset up to, not including, the second
+    Range(u64, Option<u64>),
+    /// The last lines of the file
+    Tail(u64),
+}
+
+/// A file saved from one host
+#[derive(Serialize)]
+struct Fetched {
+    hostname: String,
+    saved_to: Option<PathBuf>,
+    bytes: u64,
+    error: Option<String>,
+}
+
+/// Parse a byte range such as `1024-2048` or `1024-`
+pub fn parse_range(range: &str) -> Option<Span> {
+    let (start, end) = range.split_once('-')?;
+    let start = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().ok().filter(|end| *end >= start)?),
+    };
+    Some(Span::Range(start, end))
+}
+
+pub fn handle_fetch_command(
+    json: bool,
+    csv: bool,
+    src: &str,
+    dest: &Path,
+    span: Span,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing fetch command");
+    }
+
+    let Some((hosts, path)) = parse_host_path(src) else {
+        eprintln!("Error: Source must be <hosts>:<absolute path>, not {}", src);
+        std::process::exit(1);
+    };
+    let inventory = Inventory::load_or_exit();
+    let targets = inventory.resolve(&[hosts.to_string()]);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to fetch {} from", path);
+        std::process::exit(1);
+    }
+
+    if noaction {
+        let names: Vec<&str> = targets.iter().map(|h| h.name.as_str()).collect();
+        println!(
+            "Would fetch {} into {} from hosts: {:?}",
+            path,
+            dest.display(),
+            names
+        );
+        return;
+    }
+
+    let results = fan_out(&targets, DEFAULT_PARALLELISM, |host| {
+        fetch_file(host, path, dest, span)
+    });
+    let rows: Vec<Fetched> = targets
+        .iter()
+        .zip(results)
+        .map(|(host, result)| match result {
+            Ok((saved_to, bytes)) => Fetched {
+                hostname: host.name.clone(),
+                saved_to: Some(saved_to),
+                bytes,
+                error: None,
+            },
+            Err(e) => Fetched {
+                hostname: host.name.clone(),
+                saved_to: None,
+                bytes: 0,
+                error: Some(e),
+            },
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("hostname,saved_to,bytes,error");
+        for row in &rows {
+            println!(
+                "{},{},{},{}",
+                row.hostname,
+                row.saved_to
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default(),
+                row.bytes,
+                row.error.as_deref().unwrap_or_default()
+            );
+        }
+    } else {
+        println!("{:<20} {:<12} Saved to", "Hostname", "Bytes");
+        println!("{:-<20} {:-<12} {:-<30}", "", "", "");
+        for row in &rows {
+            match (&row.saved_to, &row.error) {
+                (Some(saved_to), _) => println!(
+                    "{:<20} {:<12} {}",
+                    row.hostname,
+                    row.bytes,
+                    saved_to.display()
+                ),
+                (None, error) => println!(
+                    "{:<20} {:<12} {}",
+                    row.hostname,
+                    "-",
+                    error.as_deref().unwrap_or_default()
+                ),
+            }
+        }
+    }
+
+    if rows.iter().any(|r| r.error.is_some()) {
+        std::process::exit(1);
+    }
+}
+
+/// Fetch a file from one host into `<dest>/<host>/<file name>`, a chunk at a
+/// time so that no more than one chunk is held in memory
+fn fetch_file(
+    host: &InventoryHost,
+    path: &str,
+    dest: &Path,
+    span: Span,
+) -> Result<(PathBuf, u64), String> {
+    let name = Path::new(path)
+        .file_name()
+        .ok_or(format!("{} does not name a file", path))?;
+    let dir = dest.join(&host.name);
+    fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    let saved_to = dir.join(name);
+    let mut file = File::create(&saved_to)
+        .map_err(|e| format!("Cannot create {}: {}", saved_to.display(), e))?;
+
+    let (mut offset, end, tail) = match span {
+        Span::Whole => (0, None, None),
+        Span::Range(start, end) => (start, end, None),
+        Span::Tail(lines) => (0, None, Some(lines)),
+    };
+    let mut written = 0;
+    let mut tail = tail;
+    loop {
+        let length = end.map_or(FETCH_CHUNK, |end| (end - offset).min(FETCH_CHUNK));
+        let chunk = agent::fetch_chunk(host, path, offset, length, tail.take())
+            .map_err(|e| e.to_string())?;
+        let data = base64::decode(&chunk.data)?;
+        file.write_all(&data)
+            .map_err(|e| format!("Cannot write {}: {}", saved_to.display(), e))?;
+        written += data.len() as u64;
+        offset = chunk.offset + data.len() as u64;
+        let end = end.unwrap_or(chunk.size).min(chunk.size);
+        if data.is_empty() || offset >= end {
+            break;
+        }
+    }
+    Ok((saved_to, written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sources_and_ranges() {
+        assert_eq!(
+            parse_host_path("@web:/var/log/app.log"),
+            Some(("@web", "/var/log/app.log"))
+        );
+        assert_eq!(parse_host_path("@web:app.log"), None);
+        assert_eq!(parse_range("100-200"), Some(Span::Range(100, Some(200))));
+        assert_eq!(parse_range("100-"), Some(Span::Range(100, None)));
+        assert_eq!(parse_range("200-100"), None);
+        assert_eq!(parse_range("100"), None);
+    }
+}
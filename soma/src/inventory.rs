@@ -0,0 +1,389 @@
+use serde::{Deserialize, Serialize};
+use somacommon::ports::Protocol;
+use somacommon::protocol::{DEFAULT_CONTROLLER_PORT, DEFAULT_PORT, Request};
+use somacommon::selector::Selectable;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the inventory file
+pub const INVENTORY_ENV: &str = "SOMA_INVENTORY";
+
+/// Environment variable holding the secret relays expect from soma
+pub const RELAY_TOKEN_ENV: &str = "SOMA_RELAY_TOKEN";
+
+/// How soma reaches a host's agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectMode {
+    /// soma connects to the agent's listening address
+    #[default]
+    Dial,
+    /// The agent keeps a connection open to `soma serve`, which relays requests
+    Push,
+}
+
+/// A managed host as described in the inventory file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InventoryHost {
+    pub name: String,
+    /// Where somasrv listens, as host or host:port; defaults to the name
+    pub address: Option<String>,
+    pub mode: ConnectMode,
+    /// The `soma serve` a push agent is connected to; defaults to the inventory's
+    pub controller: Option<String>,
+    /// Relay agent to reach this host through, as an inventory host name or address
+    pub via: Option<String>,
+    /// Address of the relay named by `via`, filled in when the inventory is loaded
+    #[serde(skip)]
+    pub relay: Option<String>,
+    pub groups: Vec<String>,
+    /// Free-form key/value pairs usable as selector keys
+    pub vars: BTreeMap<String, String>,
+    /// Ports this host is meant to expose, as `tcp/22`, `udp/53` or a bare TCP port,
+    /// in addition to the inventory-wide list
+    pub expected_ports: Vec<String>,
+    /// Facts last gathered from the agent as dotted keys, also usable as selector keys
+    #[serde(skip)]
+    pub facts: BTreeMap<String, String>,
+}
+
+impl InventoryHost {
+    /// The host:port to connect to for this host's agent
+    pub fn agent_address(&self) -> String {
+        let address = self.address.as_deref().unwrap_or(&self.name);
+        with_default_port(address)
+    }
+
+    /// Where to send a request for this host, and the request to send there
+    pub fn route(&self, request: Request) -> (String, Request) {
+        match self.mode {
+            ConnectMode::Dial => match &self.via {
+                Some(via) => {
+                    let relay = self.relay.clone().unwrap_or_else(|| with_default_port(via));
+                    let relayed = Request::Relay {
+                        token: env::var(RELAY_TOKEN_ENV).unwrap_or_default(),
+                        target: self.agent_address(),
+                        request: Box::new(request),
+                    };
+                    (relay, relayed)
+                }
+                None => (self.agent_address(), request),
+            },
+            ConnectMode::Push => {
+                let controller = self
+                    .controller
+                    .clone()
+                    .unwrap_or_else(|| format!("127.0.0.1:{}", DEFAULT_CONTROLLER_PORT));
+                let forward = Request::Forward {
+                    target: self.name.clone(),
+                    request: Box::new(request),
+                };
+                (controller, forward)
+            }
+        }
+    }
+
+    /// Look up a key for selectors and templates: the host's `name`, then its
+    /// vars, then its cached facts
+    pub fn value(&self, key: &str) -> Option<&str> {
+        match key {
+            "name" => Some(&self.name),
+            _ => self
+                .vars
+                .get(key)
+                .or_else(|| self.facts.get(key))
+                .map(String::as_str),
+        }
+    }
+}
+
+impl Selectable for InventoryHost {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    fn value(&self, key: &str) -> Option<&str> {
+        InventoryHost::value(self, key)
+    }
+}
+
+/// Directory holding soma's configuration: `$XDG_CONFIG_HOME/soma`, or else
+/// `$HOME/.config/soma`
+pub fn config_dir() -> PathBuf {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default();
+    config.join("soma")
+}
+
+/// Split `<hosts>:<path>` into a host selector and an absolute path
+pub fn parse_host_path(target: &str) -> Option<(&str, &str)> {
+    let (hosts, path) = target.split_once(':')?;
+    (!hosts.is_empty() && path.starts_with('/')).then_some((hosts, path))
+}
+
+/// Parse an expected port such as `tcp/22`, `udp/53` or `443` (TCP)
+pub fn parse_port_spec(spec: &str) -> Option<(Protocol, u16)> {
+    let (protocol, port) = match spec.split_once('/') {
+        Some(("tcp", port)) => (Protocol::Tcp, port),
+        Some(("udp", port)) => (Protocol::Udp, port),
+        Some(_) => return None,
+        None => (Protocol::Tcp, spec),
+    };
+    port.parse().ok().map(|port| (protocol, port))
+}
+
+/// Append the default agent port unless the address already carries one
+pub fn with_default_port(address: &str) -> String {
+    let has_port = match address.rsplit_once(':') {
+        // A bare IPv6 address has colons but no port unless it is bracketed
+        Some((host, port)) => {
+            port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']'))
+        }
+        None => false,
+    };
+    if has_port {
+        address.to_string()
+    } else if address.contains(':') {
+        format!("[{}]:{}", address, DEFAULT_PORT)
+    } else {
+        format!("{}:{}", address, DEFAULT_PORT)
+    }
+}
+
+/// The set of hosts soma manages, loaded from a TOML file:
+///
+/// ```toml
+/// [[hosts]]
+/// name = "web-01"
+/// address = "10.0.0.5:7070"
+/// groups = ["web"]
+/// vars = { env = "prod" }
+///
+/// [[hosts]]
+/// name = "laptop-07"
+/// mode = "push"
+///
+/// [[hosts]]
+/// name = "lab-03"
+/// address = "10.9.0.3"
+/// via = "web-01"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Inventory {
+    /// Address of the `soma serve` that push agents connect to
+    pub controller: Option<String>,
+    /// Ports every host is meant to expose, see [`InventoryHost::expected_ports`]
+    pub expected_ports: Vec<String>,
+    /// Seconds without hearing from a host before it is stale
+    pub stale_after: u64,
+    /// Seconds without hearing from a host before it is lost
+    pub lost_after: u64,
+    pub hosts: Vec<InventoryHost>,
+}
+
+impl Default for Inventory {
+    fn default() -> Inventory {
+        Inventory {
+            controller: None,
+            expected_ports: Vec::new(),
+            stale_after: 300,
+            lost_after: 3600,
+            hosts: Vec::new(),
+        }
+    }
+}
+
+impl Inventory {
+    /// Default location of the inventory file
+    pub fn default_path() -> PathBuf {
+        if let Some(path) = env::var_os(INVENTORY_ENV) {
+            return PathBuf::from(path);
+        }
+        config_dir().join("inventory.toml")
+    }
+
+    /// Load the inventory, treating a missing file as an empty inventory
+    pub fn load(path: &Path) -> Result<Self, InventoryError> {
+        if !path.exists() {
+            return Ok(Inventory::default());
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|e| InventoryError::ReadError(path.to_path_buf(), e))?;
+        let mut inventory: Inventory = toml::from_str(&content)
+            .map_err(|e| InventoryError::ParseError(path.to_path_buf(), e))?;
+        inventory.validate()?;
+        let facts = crate::facts::load_cache();
+        let relays: BTreeMap<String, String> = inventory
+            .hosts
+            .iter()
+            .map(|h| (h.name.clone(), h.agent_address()))
+            .collect();
+        for host in &mut inventory.hosts {
+            if host.controller.is_none() {
+                host.controller = inventory.controller.clone();
+            }
+            if let Some(via) = &host.via {
+                host.relay = relays.get(via).cloned();
+            }
+            host.expected_ports
+                .extend(inventory.expected_ports.iter().cloned());
+            if let Some(facts) = facts.get(&host.name) {
+                host.facts = facts.flatten();
+            }
+        }
+        Ok(inventory)
+    }
+
+    /// Load the inventory from the default location, exiting with an error message on failure
+    pub fn load_or_exit() -> Self {
+        Inventory::load(&Inventory::default_path()).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), InventoryError> {
+        if self.stale_after == 0 || self.lost_after <= self.stale_after {
+            return Err(InventoryError::InvalidLiveness(
+                self.stale_after,
+                self.lost_after,
+            ));
+        }
+        let all_specs = self
+            .expected_ports
+            .iter()
+            .chain(self.hosts.iter().flat_map(|h| &h.expected_ports));
+        for spec in all_specs {
+            if parse_port_spec(spec).is_none() {
+                return Err(InventoryError::InvalidPort(spec.clone()));
+            }
+        }
+        let mut seen = Vec::new();
+        for host in &self.hosts {
+            if host.name.is_empty() {
+                return Err(InventoryError::MissingName);
+            }
+            if seen.contains(&&host.name) {
+                return Err(InventoryError::DuplicateHost(host.name.clone()));
+            }
+            seen.push(&host.name);
+        }
+        for host in &self.hosts {
+            let Some(via) = &host.via else {
+                continue;
+            };
+            if *via == host.name {
+                return Err(InventoryError::InvalidRelay(
+                    host.name.clone(),
+                    "a host cannot relay to itself".to_string(),
+                ));
+            }
+            if self.get(via).is_some_and(|relay| relay.via.is_some()) {
+                return Err(InventoryError::InvalidRelay(
+                    host.name.clone(),
+                    format!("relay {} is itself behind a relay", via),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InventoryHost> {
+        self.hosts.iter().find(|h| h.name == name)
+    }
+
+    /// Hosts picked by a selector, see [`Selectable::selected_by`]
+    pub fn select(&self, selector: &str) -> Vec<&InventoryHost> {
+        self.hosts
+            .iter()
+            .filter(|host| host.selected_by(selector))
+            .collect()
+    }
+
+    /// Resolve command line host arguments to inventory hosts.
+    ///
+    /// With no arguments every host is returned. An argument that selects
+    /// nothing is kept as a bare hostname so it can still be contacted.
+    pub fn resolve(&self, args: &[String]) -> Vec<InventoryHost> {
+        if args.is_empty() {
+            return self.hosts.clone();
+        }
+        let mut resolved: Vec<InventoryHost> = Vec::new();
+        for arg in args {
+            let selected = self.select(arg);
+            if selected.is_empty() {
+                if !resolved.iter().any(|h| h.name == *arg) {
+                    resolved.push(InventoryHost {
+                        name: arg.clone(),
+                        ..Default::default()
+                    });
+                }
+                continue;
+            }
+            for host in selected {
+                if !resolved.iter().any(|h| h.name == host.name) {
+                    resolved.push(host.clone());
+                }
+            }
+        }
+        resolved
+    }
+}
+
+/// Inventory error types
+#[derive(Debug)]
+pub enum InventoryError {
+    ReadError(PathBuf, std::io::Error),
+    ParseError(PathBuf, toml::de::Error),
+    MissingName,
+    DuplicateHost(String),
+    InvalidRelay(String, String),
+    InvalidLiveness(u64, u64),
+    InvalidPort(String),
+}
+
+impl std::fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InventoryError::ReadError(path, err) => {
+                write!(f, "Error reading inventory {}: {}", path.display(), err)
+            }
+            InventoryError::ParseError(path, err) => {
+                write!(f, "Error parsing inventory {}: {}", path.display(), err)
+            }
+            InventoryError::MissingName => write!(f, "Inventory host without a name"),
+            InventoryError::DuplicateHost(name) => {
+                write!(f, "Host {} appears more than once in the inventory", name)
+            }
+            InventoryError::InvalidRelay(name, reason) => {
+                write!(f, "Invalid relay for host {}: {}", name, reason)
+            }
+            InventoryError::InvalidLiveness(stale, lost) => write!(
+                f,
+                "stale_after ({}) must be positive and less than lost_after ({})",
+                stale, lost
+            ),
+            InventoryError::InvalidPort(spec) => write!(
+                f,
+                "Invalid expected port {}, use tcp/PORT, udp/PORT or PORT",
+                spec
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InventoryError {}
@@ -1,31 +1,86 @@
 // use serde::{Deserialize, Serialize};
 use serde_json;
-use somacommon::Host;
+use somacommon::{Host, Liveness};
 
-pub fn handle_list_command(json: bool, csv: bool, verbose: bool, noaction: bool) {
+use crate::inventory::Inventory;
+use crate::seen;
+
+pub fn handle_list_command(json: bool, csv: bool, stale: bool, verbose: bool, noaction: bool) {
     if verbose {
         println!("Executing list command");
     }
 
     if noaction {
-        println!("Would list all managed hosts");
+        if stale {
+            println!("Would list managed hosts that have not been heard from recently");
+        } else {
+            println!("Would list all managed hosts");
+        }
         return;
     }
 
-    let hosts: Vec<Host> = Vec::new();
+    let inventory = Inventory::load_or_exit();
+    let last_seen = seen::load();
+    let hosts: Vec<Host> = inventory
+        .hosts
+        .iter()
+        .map(|h| Host::with_last_seen(&h.name, last_seen.get(&h.name).copied()))
+        .collect();
 
-    if json {
+    if stale {
+        print_stale(&inventory, &hosts, json, csv);
+    } else if json {
         let hostlist = serde_json::to_string_pretty(&hosts).unwrap();
         println!("{hostlist}");
-    } else if csv {
+    } else {
+        // A single column reads the same as CSV and as a table
         println!("hostname");
         for hostname in hosts {
             println!("{hostname}");
         }
+    }
+}
+
+/// Print the hosts whose liveness is stale, lost or never seen
+fn print_stale(inventory: &Inventory, hosts: &[Host], json: bool, csv: bool) {
+    let now = seen::now();
+    let stale: Vec<(&Host, Liveness)> = hosts
+        .iter()
+        .map(|h| {
+            (
+                h,
+                h.liveness(now, inventory.stale_after, inventory.lost_after),
+            )
+        })
+        .filter(|(_, liveness)| *liveness != Liveness::Live)
+        .collect();
+    let last_seen = |host: &Host| {
+        host.last_seen()
+            .map_or_else(|| "never".to_string(), seen::format_time)
+    };
+
+    if json {
+        let rows: Vec<serde_json::Value> = stale
+            .iter()
+            .map(|(host, liveness)| {
+                serde_json::json!({
+                    "hostname": host.hostname(),
+                    "liveness": liveness,
+                    "last_seen": host.last_seen(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("hostname,liveness,last_seen");
+        for (host, liveness) in &stale {
+            println!("{},{},{}", host, liveness, last_seen(host));
+        }
     } else {
-        println!("hostname");
-        for hostname in hosts {
-            println!("{hostname}");
+        println!("{:<20} {:<10} {:<25}", "Hostname", "Liveness", "Last Seen");
+        println!("{:-<20} {:-<10} {:-<25}", "", "", "");
+        for (host, liveness) in &stale {
+            println!("{:<20} {:<10} {:<25}", host, liveness, last_seen(host));
         }
     }
 }
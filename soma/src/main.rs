@@ -1,12 +1,59 @@
+pub mod agent;
+pub mod alerts;
+pub mod apply;
+pub mod audit;
 pub mod check;
 pub mod cli;
+pub mod copy;
+pub mod facts;
+pub mod fanout;
+pub mod fetch;
+pub mod inventory;
 pub mod list;
+pub mod maintenance;
+pub mod metrics;
+pub mod notify;
+pub mod packages;
+pub mod play;
+pub mod playbook;
+pub mod policy;
+pub mod ports;
+pub mod rollout;
+pub mod run;
 pub mod scan;
+pub mod seen;
+pub mod serve;
+pub mod service;
+pub mod signing;
+pub mod state;
+pub mod template;
+pub mod top;
 
-use check::handle_check_command;
-use cli::{print_usage, Cli, Command};
+use apply::handle_apply_command;
+use audit::{
+    AuditFilter, handle_audit_init_command, handle_audit_search_command,
+    handle_audit_serve_command, handle_audit_verify_command,
+};
+use check::{CheckSource, handle_check_command};
+use cli::{AuditCommand, Cli, Command, MaintenanceCommand, MetricsCommand, print_usage};
+use copy::{CopyOptions, handle_copy_command, parse_mode};
+use facts::handle_facts_command;
+use fetch::{Span, handle_fetch_command, parse_range};
 use list::handle_list_command;
+use maintenance::{
+    handle_maintenance_end_command, handle_maintenance_list_command,
+    handle_maintenance_silence_command, handle_maintenance_start_command,
+};
+use metrics::handle_metrics_query_command;
+use packages::handle_packages_command;
+use play::handle_play_command;
+use ports::handle_ports_command;
+use run::handle_run_command;
 use scan::handle_scan_command;
+use serve::{handle_agent_token_command, handle_serve_command};
+use service::handle_service_command;
+use signing::handle_keygen_command;
+use top::handle_top_command;
 
 fn main() {
     // Parse command line arguments
@@ -24,14 +71,290 @@ fn main() {
         Some(Command::Help) => {
             print_usage();
         }
-        Some(Command::List { json, csv }) => {
-            handle_list_command(*json, *csv, cli.verbose, cli.noaction);
+        Some(Command::List { json, csv, stale }) => {
+            handle_list_command(*json, *csv, *stale, cli.verbose, cli.noaction);
         }
-        Some(Command::Scan { json, csv }) => {
-            handle_scan_command(*json, *csv, cli.verbose, cli.noaction);
+        Some(Command::Scan { json, csv, diff }) => {
+            handle_scan_command(*json, *csv, diff, cli.verbose, cli.noaction);
         }
-        Some(Command::Check { json, csv, hosts }) => {
-            handle_check_command(*json, *csv, hosts, cli.verbose, cli.noaction);
+        Some(Command::Check {
+            json,
+            csv,
+            watch,
+            interval,
+            count,
+            cached,
+            plugin,
+            hosts,
+        }) => {
+            let source = match plugin {
+                Some(name) => CheckSource::Plugin(name.clone()),
+                None if *cached => CheckSource::Cached,
+                None => CheckSource::Status,
+            };
+            handle_check_command(
+                *json,
+                *csv,
+                hosts,
+                watch.then_some(*interval),
+                *count,
+                &source,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Facts {
+            json,
+            csv,
+            format,
+            refresh,
+            hosts,
+        }) => {
+            let json = *json || format.as_deref() == Some("json");
+            let csv = *csv || format.as_deref() == Some("csv");
+            handle_facts_command(json, csv, *refresh, hosts, cli.verbose, cli.noaction);
+        }
+        Some(Command::Packages {
+            json,
+            csv,
+            name,
+            hosts,
+        }) => {
+            handle_packages_command(*json, *csv, name, hosts, cli.verbose, cli.noaction);
+        }
+        Some(Command::Ports {
+            json,
+            csv,
+            unexpected,
+            hosts,
+        }) => {
+            handle_ports_command(*json, *csv, *unexpected, hosts, cli.verbose, cli.noaction);
+        }
+        Some(Command::Service {
+            json,
+            csv,
+            hosts,
+            operation,
+            unit,
+            rollout,
+        }) => {
+            handle_service_command(
+                *json,
+                *csv,
+                hosts,
+                operation,
+                unit,
+                rollout,
+                cli.force,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Copy {
+            json,
+            csv,
+            owner,
+            group,
+            mode,
+            template,
+            no_backup,
+            src,
+            dest,
+        }) => {
+            let mode = mode.as_deref().map(|m| {
+                parse_mode(m).unwrap_or_else(|| {
+                    eprintln!("Error: Invalid mode {}", m);
+                    std::process::exit(1);
+                })
+            });
+            let options = CopyOptions {
+                owner: owner.clone(),
+                group: group.clone(),
+                mode,
+                backup: !no_backup,
+                template: *template,
+            };
+            handle_copy_command(
+                *json,
+                *csv,
+                src,
+                dest,
+                &options,
+                cli.force,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Fetch {
+            json,
+            csv,
+            tail,
+            range,
+            src,
+            dest,
+        }) => {
+            let span = match (tail, range) {
+                (Some(lines), _) => Span::Tail(*lines),
+                (None, Some(range)) => parse_range(range).unwrap_or_else(|| {
+                    eprintln!("Error: Invalid byte range {}", range);
+                    std::process::exit(1);
+                }),
+                (None, None) => Span::Whole,
+            };
+            handle_fetch_command(*json, *csv, src, dest, span, cli.verbose, cli.noaction);
+        }
+        Some(Command::Apply {
+            json,
+            csv,
+            file,
+            hosts,
+        }) => {
+            handle_apply_command(
+                *json,
+                *csv,
+                file,
+                hosts,
+                cli.force,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Run {
+            json,
+            csv,
+            hosts,
+            action,
+            rollout,
+        }) => {
+            handle_run_command(
+                *json,
+                *csv,
+                hosts,
+                action,
+                rollout,
+                cli.force,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Play {
+            json,
+            csv,
+            check,
+            file,
+            rollout,
+        }) => {
+            handle_play_command(
+                *json,
+                *csv,
+                file,
+                *check,
+                rollout,
+                cli.force,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Top { interval, selector }) => {
+            handle_top_command(selector, *interval, cli.verbose, cli.noaction);
+        }
+        Some(Command::Serve {
+            listen,
+            token,
+            heartbeat,
+            poll,
+            scrape,
+            retention,
+            http,
+            alerts,
+        }) => {
+            handle_serve_command(
+                listen,
+                token,
+                *heartbeat,
+                *poll,
+                *scrape,
+                *retention,
+                http,
+                alerts,
+                cli.verbose,
+                cli.noaction,
+            );
+        }
+        Some(Command::Metrics {
+            command:
+                MetricsCommand::Query {
+                    json,
+                    csv,
+                    since,
+                    selector,
+                },
+        }) => {
+            handle_metrics_query_command(*json, *csv, selector, *since, cli.verbose, cli.noaction);
+        }
+        Some(Command::Maintenance { command }) => match command {
+            MaintenanceCommand::Start {
+                duration,
+                reason,
+                block,
+                selector,
+            } => handle_maintenance_start_command(
+                selector,
+                *duration,
+                reason,
+                *block,
+                cli.verbose,
+                cli.noaction,
+            ),
+            MaintenanceCommand::Silence {
+                duration,
+                reason,
+                matchers,
+            } => handle_maintenance_silence_command(
+                matchers,
+                *duration,
+                reason,
+                cli.verbose,
+                cli.noaction,
+            ),
+            MaintenanceCommand::List { json, csv } => {
+                handle_maintenance_list_command(*json, *csv, cli.verbose, cli.noaction)
+            }
+            MaintenanceCommand::End { id } => {
+                handle_maintenance_end_command(*id, cli.verbose, cli.noaction)
+            }
+        },
+        Some(Command::Audit { command }) => match command {
+            AuditCommand::Verify { file, key } => {
+                handle_audit_verify_command(file, key, cli.verbose, cli.noaction)
+            }
+            AuditCommand::Init => handle_audit_init_command(cli.verbose, cli.noaction),
+            AuditCommand::Serve { socket } => {
+                handle_audit_serve_command(socket, cli.verbose, cli.noaction)
+            }
+            AuditCommand::Search {
+                json,
+                csv,
+                operator,
+                command,
+                target,
+                outcome,
+                since,
+                file,
+            } => {
+                let filter = AuditFilter {
+                    operator: operator.clone(),
+                    command: command.clone(),
+                    target: target.clone(),
+                    outcome: *outcome,
+                    since: since.map(|since| seen::now().saturating_sub(since)),
+                };
+                handle_audit_search_command(*json, *csv, &filter, file, cli.verbose, cli.noaction);
+            }
+        },
+        Some(Command::Keygen { file }) => handle_keygen_command(file, cli.verbose, cli.noaction),
+        Some(Command::AgentToken { hostname, token }) => {
+            handle_agent_token_command(hostname, token, cli.verbose, cli.noaction);
         }
         None => {
             println!("No subcommand specified. Use 'soma help' for usage information.");
@@ -0,0 +1,488 @@
+use serde::{Deserialize, Serialize};
+use somacommon::glob_match;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::facts::csv_field;
+use crate::inventory::{Inventory, InventoryHost};
+use crate::seen;
+use crate::state;
+
+/// File in the state directory holding maintenance windows and silences
+pub const MAINTENANCE_FILE: &str = "maintenance.json";
+
+/// Fields a silence can match on
+const MATCHER_FIELDS: [&str; 3] = ["rule", "key", "host"];
+
+/// Hosts under maintenance: their alerts are not notified, `check` shows the
+/// window and, when it blocks, changing commands leave them alone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    pub id: u64,
+    /// The selector the window was started with
+    pub selector: String,
+    /// Inventory hosts the selector picked when the window started
+    pub hosts: Vec<String>,
+    pub reason: String,
+    pub start: u64,
+    pub end: u64,
+    /// Whether run, service, copy, apply and play skip the hosts without --force
+    #[serde(default)]
+    pub block: bool,
+}
+
+/// Alerts that are not notified while the silence lasts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Silence {
+    pub id: u64,
+    /// Globs an alert's rule, key and host must all match
+    pub matchers: BTreeMap<String, String>,
+    pub reason: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The windows and silences that have not yet expired
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Maintenance {
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    #[serde(default)]
+    pub silences: Vec<Silence>,
+}
+
+/// One row of `soma maintenance list`
+#[derive(Serialize)]
+struct MaintenanceRow<'a> {
+    id: u64,
+    kind: &'static str,
+    target: String,
+    block: bool,
+    start: u64,
+    end: u64,
+    reason: &'a str,
+}
+
+impl Maintenance {
+    /// Windows and silences still in force. A missing or unreadable file means none.
+    pub fn load() -> Self {
+        let mut maintenance: Maintenance =
+            fs::read_to_string(state::state_dir().join(MAINTENANCE_FILE))
+                .ok()
+                .and_then(|content| serde_json::from_str(&content).ok())
+                .unwrap_or_default();
+        maintenance.expire(seen::now());
+        maintenance
+    }
+
+    pub fn save(&self) -> io::Result<PathBuf> {
+        let path = state::state_file(MAINTENANCE_FILE)?;
+        let content = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        state::replace(&path, &content)?;
+        Ok(path)
+    }
+
+    /// Drop whatever has ended by `now`
+    pub fn expire(&mut self, now: u64) {
+        self.windows.retain(|w| w.end > now);
+        self.silences.retain(|s| s.end > now);
+    }
+
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub fn add_window(
+        &mut self,
+        selector: &str,
+        hosts: Vec<String>,
+        reason: &str,
+        start: u64,
+        duration: u64,
+        block: bool,
+    ) -> &Window {
+        let window = Window {
+            id: self.take_id(),
+            selector: selector.to_string(),
+            hosts,
+            reason: reason.to_string(),
+            start,
+            end: start + duration,
+            block,
+        };
+        self.windows.push(window);
+        self.windows.last().unwrap()
+    }
+
+    pub fn add_silence(
+        &mut self,
+        matchers: BTreeMap<String, String>,
+        reason: &str,
+        start: u64,
+        duration: u64,
+    ) -> &Silence {
+        let silence = Silence {
+            id: self.take_id(),
+            matchers,
+            reason: reason.to_string(),
+            start,
+            end: start + duration,
+        };
+        self.silences.push(silence);
+        self.silences.last().unwrap()
+    }
+
+    /// Remove a window or silence, returning whether there was one with the id
+    pub fn remove(&mut self, id: u64) -> bool {
+        let before = self.windows.len() + self.silences.len();
+        self.windows.retain(|w| w.id != id);
+        self.silences.retain(|s| s.id != id);
+        self.windows.len() + self.silences.len() < before
+    }
+
+    /// The window a host is under at `now`, the longest lasting if several are.
+    /// A blocking window is preferred so it is never hidden behind another.
+    pub fn window_for(&self, host: &str, now: u64) -> Option<&Window> {
+        self.windows
+            .iter()
+            .filter(|w| w.start <= now && now < w.end && w.hosts.iter().any(|h| h == host))
+            .max_by_key(|w| (w.block, w.end))
+    }
+
+    /// Whether an alert should not be notified at `now`, because its host is
+    /// under maintenance or a silence matches it
+    pub fn suppresses(&self, rule: &str, key: &str, host: Option<&str>, now: u64) -> bool {
+        if host.is_some_and(|h| self.window_for(h, now).is_some()) {
+            return true;
+        }
+        self.silences.iter().any(|silence| {
+            silence.start <= now
+                && now < silence.end
+                && silence.matchers.iter().all(|(field, pattern)| {
+                    let value = match field.as_str() {
+                        "rule" => Some(rule),
+                        "key" => Some(key),
+                        "host" => host,
+                        _ => None,
+                    };
+                    value.is_some_and(|v| glob_match(pattern, v))
+                })
+        })
+    }
+}
+
+/// Parse silence matchers such as `rule=disk*,host=web-01` into field and glob
+pub fn parse_matchers(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut matchers = BTreeMap::new();
+    for term in text.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let (field, pattern) = term
+            .split_once('=')
+            .ok_or_else(|| format!("Matcher {} is not field=glob", term))?;
+        let field = field.trim();
+        if !MATCHER_FIELDS.contains(&field) {
+            return Err(format!(
+                "Cannot match on {}, use {}",
+                field,
+                MATCHER_FIELDS.join(", ")
+            ));
+        }
+        if matchers
+            .insert(field.to_string(), pattern.trim().to_string())
+            .is_some()
+        {
+            return Err(format!("{} is matched more than once", field));
+        }
+    }
+    if matchers.is_empty() {
+        return Err("A silence needs at least one matcher".to_string());
+    }
+    Ok(matchers)
+}
+
+/// Leave out hosts under a blocking maintenance window unless `force` is
+/// given, telling the user which were skipped
+pub fn skip_blocked(hosts: Vec<InventoryHost>, force: bool) -> Vec<InventoryHost> {
+    let maintenance = Maintenance::load();
+    let now = seen::now();
+    hosts
+        .into_iter()
+        .filter(|host| match maintenance.window_for(&host.name, now) {
+            Some(window) if window.block && !force => {
+                eprintln!(
+                    "Skipping {}: under maintenance until {} UTC ({}), use --force to act anyway",
+                    host.name,
+                    seen::format_time(window.end),
+                    window.reason
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+pub fn handle_maintenance_start_command(
+    selector: &str,
+    duration: u64,
+    reason: &str,
+    block: bool,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing maintenance start command");
+    }
+
+    if duration == 0 {
+        eprintln!("Error: Maintenance must last at least 1 second");
+        std::process::exit(1);
+    }
+    let inventory = Inventory::load_or_exit();
+    let hosts: Vec<String> = inventory
+        .select(selector)
+        .into_iter()
+        .map(|h| h.name.clone())
+        .collect();
+    if hosts.is_empty() {
+        eprintln!("Error: No inventory hosts match {}", selector);
+        std::process::exit(1);
+    }
+    let now = seen::now();
+    if noaction {
+        println!(
+            "Would put {} under maintenance until {} UTC",
+            hosts.join(", "),
+            seen::format_time(now + duration)
+        );
+        return;
+    }
+
+    let mut maintenance = Maintenance::load();
+    let window = maintenance
+        .add_window(selector, hosts, reason, now, duration, block)
+        .clone();
+    save_or_exit(&maintenance);
+    println!(
+        "Maintenance {} started on {} until {} UTC{}",
+        window.id,
+        window.hosts.join(", "),
+        seen::format_time(window.end),
+        if window.block {
+            ", blocking changes"
+        } else {
+            ""
+        }
+    );
+}
+
+pub fn handle_maintenance_silence_command(
+    matchers: &str,
+    duration: u64,
+    reason: &str,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing maintenance silence command");
+    }
+
+    if duration == 0 {
+        eprintln!("Error: A silence must last at least 1 second");
+        std::process::exit(1);
+    }
+    let matchers = parse_matchers(matchers).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let now = seen::now();
+    if noaction {
+        println!(
+            "Would silence alerts matching {} until {} UTC",
+            format_matchers(&matchers),
+            seen::format_time(now + duration)
+        );
+        return;
+    }
+
+    let mut maintenance = Maintenance::load();
+    let silence = maintenance
+        .add_silence(matchers, reason, now, duration)
+        .clone();
+    save_or_exit(&maintenance);
+    println!(
+        "Silence {} started for {} until {} UTC",
+        silence.id,
+        format_matchers(&silence.matchers),
+        seen::format_time(silence.end)
+    );
+}
+
+pub fn handle_maintenance_list_command(json: bool, csv: bool, verbose: bool, _noaction: bool) {
+    if verbose {
+        println!("Executing maintenance list command");
+    }
+
+    let maintenance = Maintenance::load();
+    let mut rows: Vec<MaintenanceRow> = maintenance
+        .windows
+        .iter()
+        .map(|w| MaintenanceRow {
+            id: w.id,
+            kind: "maintenance",
+            target: w.hosts.join(","),
+            block: w.block,
+            start: w.start,
+            end: w.end,
+            reason: &w.reason,
+        })
+        .chain(maintenance.silences.iter().map(|s| MaintenanceRow {
+            id: s.id,
+            kind: "silence",
+            target: format_matchers(&s.matchers),
+            block: false,
+            start: s.start,
+            end: s.end,
+            reason: &s.reason,
+        }))
+        .collect();
+    rows.sort_by_key(|row| row.id);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("id,kind,target,block,start,end,reason");
+        for row in &rows {
+            println!(
+                "{},{},{},{},{},{},{}",
+                row.id,
+                row.kind,
+                csv_field(&row.target),
+                row.block,
+                row.start,
+                row.end,
+                csv_field(row.reason)
+            );
+        }
+    } else if rows.is_empty() {
+        println!("No maintenance windows or silences");
+    } else {
+        println!(
+            "{:<4} {:<12} {:<30} {:<6} {:<20} Reason",
+            "Id", "Kind", "Target", "Block", "Until"
+        );
+        println!(
+            "{:-<4} {:-<12} {:-<30} {:-<6} {:-<20} {:-<20}",
+            "", "", "", "", "", ""
+        );
+        for row in &rows {
+            println!(
+                "{:<4} {:<12} {:<30} {:<6} {:<20} {}",
+                row.id,
+                row.kind,
+                row.target,
+                if row.block { "yes" } else { "no" },
+                seen::format_time(row.end),
+                row.reason
+            );
+        }
+    }
+}
+
+pub fn handle_maintenance_end_command(id: u64, verbose: bool, noaction: bool) {
+    if verbose {
+        println!("Executing maintenance end command");
+    }
+
+    let mut maintenance = Maintenance::load();
+    if !maintenance.remove(id) {
+        eprintln!("Error: No maintenance window or silence {}", id);
+        std::process::exit(1);
+    }
+    if noaction {
+        println!("Would end {}", id);
+        return;
+    }
+    save_or_exit(&maintenance);
+    println!("Ended {}", id);
+}
+
+fn save_or_exit(maintenance: &Maintenance) {
+    if let Err(e) = maintenance.save() {
+        eprintln!("Error: Failed to record maintenance: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn format_matchers(matchers: &BTreeMap<String, String>) -> String {
+    matchers
+        .iter()
+        .map(|(field, pattern)| format!("{}={}", field, pattern))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_and_silences_suppress_until_they_end() {
+        let mut maintenance = Maintenance::default();
+        let window = maintenance
+            .add_window(
+                "@web",
+                vec!["web-01".to_string()],
+                "kernel",
+                1000,
+                3600,
+                true,
+            )
+            .id;
+        let silence = maintenance
+            .add_silence(parse_matchers("rule=disk*").unwrap(), "resize", 1000, 600)
+            .id;
+        assert_eq!((window, silence), (1, 2));
+
+        assert!(maintenance.window_for("web-01", 1000).unwrap().block);
+        assert!(maintenance.window_for("web-02", 1000).is_none());
+        assert!(maintenance.suppresses("down", "web-01", Some("web-01"), 2000));
+        assert!(!maintenance.suppresses("down", "web-02", Some("web-02"), 2000));
+        assert!(maintenance.suppresses("disk_full", "disk_used{host=db-01}", Some("db-01"), 1500));
+        assert!(!maintenance.suppresses("disk_full", "disk_used{host=db-01}", Some("db-01"), 1600));
+        assert!(!maintenance.suppresses("down", "web-01", Some("web-01"), 4600));
+
+        maintenance.expire(1600);
+        assert!(maintenance.silences.is_empty());
+        assert!(maintenance.remove(window));
+        assert!(!maintenance.remove(window));
+    }
+
+    #[test]
+    fn silences_need_known_fields() {
+        let matchers = parse_matchers("rule=disk, host=web-*").unwrap();
+        assert_eq!(matchers["host"], "web-*");
+        assert!(
+            !Maintenance {
+                silences: vec![Silence {
+                    id: 1,
+                    matchers,
+                    reason: String::new(),
+                    start: 0,
+                    end: 10,
+                }],
+                ..Default::default()
+            }
+            .suppresses("disk", "disk_used", None, 5)
+        );
+        assert_eq!(
+            parse_matchers("env=prod").unwrap_err(),
+            "Cannot match on env, use rule, key, host"
+        );
+        assert!(parse_matchers("").is_err());
+    }
+}
@@ -0,0 +1,586 @@
+use serde::{Deserialize, Serialize};
+use somacommon::glob_match;
+use somacommon::status::{Health, StatusReport};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::facts::csv_field;
+use crate::seen;
+use crate::state;
+
+/// Directory in the state directory holding the metrics store
+pub const METRICS_DIR: &str = "metrics";
+
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * HOUR;
+
+/// Retention used when `soma serve` is not told otherwise
+pub const DEFAULT_RETENTION: u64 = 30 * DAY;
+
+/// One resolution of the store: samples older than `keep` are averaged into
+/// the next tier's buckets, or dropped from the last one
+struct Tier {
+    file: &'static str,
+    step: u64,
+    keep: u64,
+}
+
+/// Raw scrapes for a day, five-minute averages for a week, then hourly averages
+const TIERS: [Tier; 3] = [
+    Tier {
+        file: "raw.jsonl",
+        step: 0,
+        keep: DAY,
+    },
+    Tier {
+        file: "5m.jsonl",
+        step: 300,
+        keep: 7 * DAY,
+    },
+    Tier {
+        file: "1h.jsonl",
+        step: HOUR,
+        keep: u64::MAX,
+    },
+];
+
+/// A value of one series at one time. Downsampled values are the mean of
+/// `count` scraped values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub time: u64,
+    pub value: f64,
+    #[serde(default = "one")]
+    pub count: u64,
+}
+
+fn one() -> u64 {
+    1
+}
+
+impl Sample {
+    fn new(name: &str, labels: &[(&str, &str)], time: u64, value: f64) -> Self {
+        Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            time,
+            value,
+            count: 1,
+        }
+    }
+
+    /// The series this sample belongs to, as `name{label=value,...}`
+    pub fn series(&self) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        format!("{}{{{}}}", self.name, labels.join(","))
+    }
+}
+
+/// A query such as `disk_used{host=web-01,mount=/}`. The name and label
+/// values may be globs, and a series must carry every label given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, labels) = match s.split_once('{') {
+            Some((name, rest)) => {
+                let labels = rest
+                    .strip_suffix('}')
+                    .ok_or_else(|| format!("{} is missing a closing }}", s))?;
+                (name.trim(), labels)
+            }
+            None => (s, ""),
+        };
+        if name.is_empty() {
+            return Err("A metric name is required".to_string());
+        }
+        let labels = labels
+            .split(',')
+            .filter(|l| !l.trim().is_empty())
+            .map(|label| {
+                let (key, value) = label
+                    .split_once('=')
+                    .ok_or_else(|| format!("Label {} is not key=value", label.trim()))?;
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                Ok((key.trim().to_string(), value.to_string()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Selector {
+            name: name.to_string(),
+            labels,
+        })
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        write!(f, "{}{{{}}}", self.name, labels.join(","))
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, sample: &Sample) -> bool {
+        glob_match(&self.name, &sample.name)
+            && self.labels.iter().all(|(key, pattern)| {
+                sample
+                    .labels
+                    .get(key)
+                    .is_some_and(|value| glob_match(pattern, value))
+            })
+    }
+}
+
+/// Parse a duration such as `90s`, `15m`, `24h`, `7d` or `2w` into seconds.
+/// A bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration {}", s))?;
+    let unit = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => HOUR,
+        "d" => DAY,
+        "w" => 7 * DAY,
+        _ => return Err(format!("Invalid duration {}, use s, m, h, d or w", s)),
+    };
+    Ok(number * unit)
+}
+
+/// The numeric metrics in a status report, labelled with the host they came from
+pub fn samples(host: &str, report: &StatusReport, time: u64) -> Vec<Sample> {
+    let h = ("host", host);
+    let health = match report.health {
+        Health::Healthy => 0.0,
+        Health::Warning => 1.0,
+        Health::Critical => 2.0,
+        Health::Unknown => 3.0,
+    };
+    let mut samples = vec![
+        Sample::new("health", &[h], time, health),
+        Sample::new("uptime_secs", &[h], time, report.uptime_secs as f64),
+        Sample::new("load1", &[h], time, report.load.one),
+        Sample::new("load5", &[h], time, report.load.five),
+        Sample::new("load15", &[h], time, report.load.fifteen),
+        Sample::new("memory_used", &[h], time, report.memory.used_percent()),
+        Sample::new(
+            "memory_available_kb",
+            &[h],
+            time,
+            report.memory.available_kb as f64,
+        ),
+    ];
+    for mount in &report.mounts {
+        let labels = [h, ("mount", mount.mount.as_str())];
+        samples.push(Sample::new(
+            "disk_used",
+            &labels,
+            time,
+            mount.used_percent(),
+        ));
+        samples.push(Sample::new(
+            "disk_free_kb",
+            &labels,
+            time,
+            mount.total_kb.saturating_sub(mount.used_kb) as f64,
+        ));
+    }
+    for interface in &report.interfaces {
+        let labels = [h, ("interface", interface.name.as_str())];
+        samples.push(Sample::new(
+            "net_rx_bytes",
+            &labels,
+            time,
+            interface.rx_bytes as f64,
+        ));
+        samples.push(Sample::new(
+            "net_tx_bytes",
+            &labels,
+            time,
+            interface.tx_bytes as f64,
+        ));
+    }
+    for plugin in &report.plugins {
+        let labels = [h, ("plugin", plugin.name.as_str())];
+        samples.push(Sample::new(
+            "plugin_state",
+            &labels,
+            time,
+            plugin.state.exit_code() as f64,
+        ));
+        for perfdata in &plugin.perfdata {
+            samples.push(Sample::new(
+                &metric_name(&perfdata.label),
+                &labels,
+                time,
+                perfdata.value,
+            ));
+        }
+    }
+    for probe in &report.probes {
+        let labels = [h, ("probe", probe.name.as_str())];
+        samples.push(Sample::new(
+            "probe_state",
+            &labels,
+            time,
+            probe.state.exit_code() as f64,
+        ));
+        if let Some(latency) = probe.latency_ms {
+            samples.push(Sample::new(
+                "probe_latency_ms",
+                &labels,
+                time,
+                latency as f64,
+            ));
+        }
+    }
+    samples
+}
+
+/// Perfdata labels are free text, so keep only characters safe in a query
+fn metric_name(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// The metrics store, one JSON-lines file per resolution
+pub struct Store {
+    dir: PathBuf,
+    retention: u64,
+}
+
+impl Store {
+    /// The store in the state directory, without creating it
+    pub fn new(retention: u64) -> Self {
+        Store::at(state::state_dir().join(METRICS_DIR), retention)
+    }
+
+    pub fn at(dir: impl Into<PathBuf>, retention: u64) -> Self {
+        Store {
+            dir: dir.into(),
+            retention,
+        }
+    }
+
+    /// Add freshly scraped samples at full resolution
+    pub fn append(&self, samples: &[Sample]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(TIERS[0].file))?;
+        let mut lines = String::new();
+        for sample in samples {
+            lines.push_str(&serde_json::to_string(sample).map_err(io::Error::other)?);
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())
+    }
+
+    /// Move samples that have outlived their tier into the next, coarser
+    /// one and drop those past the retention period
+    pub fn compact(&self, now: u64) -> io::Result<()> {
+        let oldest = now.saturating_sub(self.retention);
+        let mut carried: Vec<Sample> = Vec::new();
+        for (index, tier) in TIERS.iter().enumerate() {
+            let path = self.dir.join(tier.file);
+            let mut samples = read_samples(&path)?;
+            let arrived = !carried.is_empty();
+            if arrived {
+                // Merge into buckets already holding part of the same interval
+                samples.append(&mut carried);
+                samples = downsample(samples, tier.step);
+            }
+
+            let cutoff = now.saturating_sub(tier.keep).max(oldest);
+            let (expired, kept): (Vec<_>, Vec<_>) =
+                samples.into_iter().partition(|s| s.time < cutoff);
+            if arrived || !expired.is_empty() {
+                write_samples(&path, &kept)?;
+            }
+            if index + 1 < TIERS.len() {
+                carried = expired.into_iter().filter(|s| s.time >= oldest).collect();
+            }
+        }
+        Ok(())
+    }
+
+    /// Every sample matching the selector from `since` onwards, by series
+    pub fn query(
+        &self,
+        selector: &Selector,
+        since: u64,
+    ) -> io::Result<BTreeMap<String, Vec<Sample>>> {
+        let mut series: BTreeMap<String, Vec<Sample>> = BTreeMap::new();
+        for tier in &TIERS {
+            for sample in read_samples(&self.dir.join(tier.file))? {
+                if sample.time >= since && selector.matches(&sample) {
+                    series.entry(sample.series()).or_default().push(sample);
+                }
+            }
+        }
+        for samples in series.values_mut() {
+            samples.sort_by_key(|s| s.time);
+        }
+        Ok(series)
+    }
+}
+
+/// Average samples into buckets of `step` seconds, weighting each by its count
+fn downsample(samples: Vec<Sample>, step: u64) -> Vec<Sample> {
+    if step == 0 {
+        return samples;
+    }
+    let mut buckets: BTreeMap<(String, u64), Sample> = BTreeMap::new();
+    for sample in samples {
+        let time = sample.time / step * step;
+        match buckets.entry((sample.series(), time)) {
+            std::collections::btree_map::Entry::Occupied(mut entry) => {
+                let bucket = entry.get_mut();
+                let count = bucket.count + sample.count;
+                bucket.value = (bucket.value * bucket.count as f64
+                    + sample.value * sample.count as f64)
+                    / count as f64;
+                bucket.count = count;
+            }
+            std::collections::btree_map::Entry::Vacant(entry) => {
+                entry.insert(Sample { time, ..sample });
+            }
+        }
+    }
+    buckets.into_values().collect()
+}
+
+/// Samples in a store file. A missing file holds none, and a line cut short
+/// by a crash mid-append is skipped.
+fn read_samples(path: &Path) -> io::Result<Vec<Sample>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut samples = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(sample) = serde_json::from_str(&line?) {
+            samples.push(sample);
+        }
+    }
+    Ok(samples)
+}
+
+/// Replace a store file with a rename so queries never see it half written
+fn write_samples(path: &Path, samples: &[Sample]) -> io::Result<()> {
+    let mut content = String::new();
+    for sample in samples {
+        content.push_str(&serde_json::to_string(sample).map_err(io::Error::other)?);
+        content.push('\n');
+    }
+    state::replace(path, &content)
+}
+
+#[derive(Serialize)]
+struct SeriesOutput<'a> {
+    series: &'a str,
+    name: &'a str,
+    labels: &'a BTreeMap<String, String>,
+    points: Vec<(u64, f64)>,
+}
+
+pub fn handle_metrics_query_command(
+    json: bool,
+    csv: bool,
+    selector: &str,
+    since: u64,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing metrics query command");
+    }
+
+    let selector: Selector = selector.parse().unwrap_or_else(|e| {
+        eprintln!("Error: Invalid query: {}", e);
+        std::process::exit(1);
+    });
+    let store = Store::new(DEFAULT_RETENTION);
+    if noaction {
+        println!(
+            "Would query {} over the last {} seconds from {}",
+            selector,
+            since,
+            store.dir.display()
+        );
+        return;
+    }
+
+    let series = store
+        .query(&selector, seen::now().saturating_sub(since))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: Failed to read the metrics store: {}", e);
+            std::process::exit(1);
+        });
+
+    if json {
+        let output: Vec<SeriesOutput> = series
+            .iter()
+            .map(|(key, samples)| SeriesOutput {
+                series: key,
+                name: &samples[0].name,
+                labels: &samples[0].labels,
+                points: samples.iter().map(|s| (s.time, s.value)).collect(),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else if csv {
+        println!("series,timestamp,time,value");
+        for (key, samples) in &series {
+            for sample in samples {
+                println!(
+                    "{},{},{},{}",
+                    csv_field(key),
+                    sample.time,
+                    seen::format_time(sample.time),
+                    sample.value
+                );
+            }
+        }
+    } else if series.is_empty() {
+        println!("No series match {}", selector);
+    } else {
+        for (key, samples) in &series {
+            println!("{}", key);
+            println!("{:<20} {:>14}", "Time", "Value");
+            println!("{:-<20} {:->14}", "", "");
+            for sample in samples {
+                println!(
+                    "{:<20} {:>14}",
+                    seen::format_time(sample.time),
+                    sample.value
+                );
+            }
+            println!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_selectors_and_durations() {
+        let selector: Selector = "disk_used{host=web-01, mount=\"/\"}".parse().unwrap();
+        assert_eq!(selector.name, "disk_used");
+        assert_eq!(
+            selector.labels,
+            vec![
+                ("host".to_string(), "web-01".to_string()),
+                ("mount".to_string(), "/".to_string())
+            ]
+        );
+        assert_eq!("load5".parse::<Selector>().unwrap().labels, vec![]);
+        assert!("{host=web-01}".parse::<Selector>().is_err());
+        assert!("load5{host}".parse::<Selector>().is_err());
+        assert_eq!(parse_duration("24h"), Ok(86400));
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert!(parse_duration("1y").is_err());
+    }
+
+    #[test]
+    fn selectors_match_globs_on_labels() {
+        let sample = Sample::new("disk_used", &[("host", "web-01"), ("mount", "/")], 0, 1.0);
+        assert!(
+            "disk_used{host=web-*}"
+                .parse::<Selector>()
+                .unwrap()
+                .matches(&sample)
+        );
+        assert!("disk_*".parse::<Selector>().unwrap().matches(&sample));
+        assert!(
+            !"disk_used{host=db-*}"
+                .parse::<Selector>()
+                .unwrap()
+                .matches(&sample)
+        );
+        assert!(
+            !"disk_used{device=sda}"
+                .parse::<Selector>()
+                .unwrap()
+                .matches(&sample)
+        );
+        assert_eq!(sample.series(), "disk_used{host=web-01,mount=/}");
+    }
+
+    #[test]
+    fn compaction_downsamples_and_expires() {
+        let temp = std::env::temp_dir().join(format!("soma-metrics-{}", std::process::id()));
+        let store = Store::at(&temp, 10 * DAY);
+        let now = 100 * DAY;
+        let host = [("host", "web-01")];
+        store
+            .append(&[
+                // Two scrapes in the same five minutes, more than a day ago
+                Sample::new("load1", &host, now - 2 * DAY, 1.0),
+                Sample::new("load1", &host, now - 2 * DAY + 60, 3.0),
+                // Recent, so kept as scraped
+                Sample::new("load1", &host, now - 60, 5.0),
+                // Past retention
+                Sample::new("load1", &host, now - 11 * DAY, 9.0),
+            ])
+            .unwrap();
+        store.compact(now).unwrap();
+
+        let selector: Selector = "load1".parse().unwrap();
+        let series = store.query(&selector, 0).unwrap();
+        let points: Vec<(u64, f64, u64)> = series["load1{host=web-01}"]
+            .iter()
+            .map(|s| (s.time, s.value, s.count))
+            .collect();
+        assert_eq!(points, vec![(now - 2 * DAY, 2.0, 2), (now - 60, 5.0, 1)]);
+
+        // A week on, the five-minute average becomes an hourly one
+        store.compact(now + 7 * DAY).unwrap();
+        let series = store.query(&selector, 0).unwrap();
+        assert_eq!(
+            series["load1{host=web-01}"][0].time,
+            (now - 2 * DAY) / HOUR * HOUR
+        );
+        assert_eq!(series["load1{host=web-01}"][0].count, 2);
+        assert_eq!(fs::read_to_string(temp.join(TIERS[0].file)).unwrap(), "");
+        fs::remove_dir_all(&temp).unwrap();
+    }
+}
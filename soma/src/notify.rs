@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::seen;
+
+/// How long a sink may take to connect, answer or, for commands, finish
+const SINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether a notification announces an alert or its end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// What is sent to sinks, as JSON for all but email
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub status: AlertStatus,
+    pub rule: String,
+    /// What fired within the rule: a host name or a metric series
+    pub key: String,
+    /// The host the alert is about, if it is about one
+    #[serde(default)]
+    pub host: Option<String>,
+    pub summary: String,
+    #[serde(default)]
+    pub value: Option<f64>,
+    /// When the alert began firing
+    pub since: u64,
+    /// When this notification was sent
+    pub at: u64,
+    /// Whether this is a reminder of an alert already notified
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+impl Notification {
+    /// One line for email subjects and logs
+    pub fn title(&self) -> String {
+        let status = match self.status {
+            AlertStatus::Firing => "FIRING",
+            AlertStatus::Resolved => "RESOLVED",
+        };
+        format!("[{}] {} {}", status, self.rule, self.key)
+    }
+}
+
+/// Where notifications are delivered
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Sink {
+    /// POST the notification as JSON to an http:// URL
+    Webhook { url: String },
+    /// Mail the notification through an SMTP relay that needs no authentication
+    Smtp {
+        /// Relay address as host or host:port (port 25 if not given)
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+    /// Run a command with the notification as JSON on standard input
+    Command { command: Vec<String> },
+    /// Append the notification as a JSON line to a file
+    File { path: PathBuf },
+}
+
+impl Sink {
+    /// Check settings that would make every delivery fail
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Sink::Webhook { url } => parse_http_url(url).map(|_| ()),
+            Sink::Smtp { server, from, to } => {
+                if server.is_empty() || from.is_empty() {
+                    Err("server and from are required".to_string())
+                } else if to.is_empty() {
+                    Err("at least one recipient is required".to_string())
+                } else {
+                    Ok(())
+                }
+            }
+            Sink::Command { command } if command.is_empty() => {
+                Err("command cannot be empty".to_string())
+            }
+            Sink::Command { .. } | Sink::File { .. } => Ok(()),
+        }
+    }
+
+    /// Deliver a notification
+    pub fn send(&self, notification: &Notification) -> Result<(), String> {
+        let json = serde_json::to_string(notification).map_err(|e| e.to_string())?;
+        match self {
+            Sink::Webhook { url } => post(url, &json),
+            Sink::Smtp { server, from, to } => mail(server, from, to, notification),
+            Sink::Command { command } => run(command, &json),
+            Sink::File { path } => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", json))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Split an http:// URL into the address to connect to, the Host header and the path
+fn parse_http_url(url: &str) -> Result<(String, String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("{} is not an http:// URL", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("{} has no host", url));
+    }
+    let address = if host
+        .rsplit_once(':')
+        .is_some_and(|(_, p)| p.parse::<u16>().is_ok())
+    {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((address, host.to_string(), path.to_string()))
+}
+
+fn connect(address: &str) -> Result<TcpStream, String> {
+    let addr = address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("Cannot resolve {}", address))?;
+    let stream = TcpStream::connect_timeout(&addr, SINK_TIMEOUT)
+        .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+    stream
+        .set_read_timeout(Some(SINK_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+fn post(url: &str, json: &str) -> Result<(), String> {
+    let (address, host, path) = parse_http_url(url)?;
+    let mut stream = connect(&address)?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        json.len(),
+        json
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| format!("Failed to send to {}: {}", url, e))?;
+    let mut status = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status)
+        .map_err(|e| format!("No answer from {}: {}", url, e))?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("{} answered {}", url, status.trim())),
+    }
+}
+
+fn mail(
+    server: &str,
+    from: &str,
+    to: &[String],
+    notification: &Notification,
+) -> Result<(), String> {
+    let address = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:25", server)
+    };
+    let stream = connect(&address)?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+
+    let mut step = |command: Option<String>, expect: char| -> Result<(), String> {
+        if let Some(command) = &command {
+            writer
+                .write_all(format!("{}\r\n", command).as_bytes())
+                .map_err(|e| format!("Failed to talk to {}: {}", server, e))?;
+        }
+        // Replies may run over several lines, all but the last with a - after the code
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader
+                .read_line(&mut line)
+                .map_err(|e| format!("No answer from {}: {}", server, e))?;
+            if line.is_empty() {
+                return Err(format!("{} closed the connection", server));
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        if line.starts_with(expect) {
+            Ok(())
+        } else {
+            Err(format!("{} refused the mail: {}", server, line.trim()))
+        }
+    };
+
+    step(None, '2')?;
+    step(Some("HELO soma".to_string()), '2')?;
+    step(Some(format!("MAIL FROM:<{}>", from)), '2')?;
+    for recipient in to {
+        step(Some(format!("RCPT TO:<{}>", recipient)), '2')?;
+    }
+    step(Some("DATA".to_string()), '3')?;
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n\r\nRule: {}\r\nKey: {}\r\nSince: {} UTC\r\n",
+        from,
+        to.join(", "),
+        notification.title(),
+        notification.summary,
+        notification.rule,
+        notification.key,
+        seen::format_time(notification.since)
+    );
+    // Lines starting with a dot are doubled so they cannot end the message early
+    let message = format!("{}.", message.replace("\r\n.", "\r\n.."));
+    step(Some(message), '2')?;
+    step(Some("QUIT".to_string()), '2')
+}
+
+fn run(command: &[String], json: &str) -> Result<(), String> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", command[0], e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(json.as_bytes());
+    }
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < SINK_TIMEOUT => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} timed out", command[0]));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+    if status.success() {
+        return Ok(());
+    }
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let _ = pipe.read_to_string(&mut stderr);
+    }
+    Err(format!(
+        "{} failed with {}: {}",
+        command[0],
+        status,
+        stderr.trim()
+    ))
+}
+
+impl std::fmt::Display for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sink::Webhook { url } => write!(f, "webhook {}", url),
+            Sink::Smtp { server, .. } => write!(f, "smtp {}", server),
+            Sink::Command { command } => {
+                write!(f, "command {}", command.first().map_or("", String::as_str))
+            }
+            Sink::File { path } => write!(f, "file {}", path.display()),
+        }
+    }
+}
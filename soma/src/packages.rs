@@ -0,0 +1,147 @@
+use serde::Serialize;
+use somacommon::packages::PackageReport;
+use std::collections::BTreeMap;
+
+use crate::agent;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::Inventory;
+
+/// Packages reported by one host
+#[derive(Serialize)]
+struct HostPackages<'a> {
+    hostname: &'a str,
+    #[serde(flatten)]
+    report: &'a PackageReport,
+}
+
+/// Hosts by installed version, for each package installed in more than one version
+type VersionSkew<'a> = BTreeMap<&'a str, BTreeMap<&'a str, Vec<&'a str>>>;
+
+#[derive(Serialize)]
+struct PackagesOutput<'a> {
+    hosts: Vec<HostPackages<'a>>,
+    skew: VersionSkew<'a>,
+}
+
+pub fn handle_packages_command(
+    json: bool,
+    csv: bool,
+    name: &Option<String>,
+    hosts: &[String],
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing packages command");
+    }
+
+    let inventory = Inventory::load_or_exit();
+    let targets = inventory.resolve(hosts);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to list packages on");
+        std::process::exit(1);
+    }
+
+    if noaction {
+        let names: Vec<&str> = targets.iter().map(|h| h.name.as_str()).collect();
+        match name {
+            Some(name) => println!(
+                "Would list packages matching {} on hosts: {:?}",
+                name, names
+            ),
+            None => println!("Would list installed packages on hosts: {:?}", names),
+        }
+        return;
+    }
+
+    let results = fan_out(&targets, DEFAULT_PARALLELISM, |host| {
+        agent::fetch_packages(host, name.as_deref())
+    });
+    let mut reports = Vec::new();
+    for (host, result) in targets.iter().zip(results) {
+        match result {
+            Ok(report) => reports.push((host.name.as_str(), report)),
+            Err(e) => eprintln!("Warning: {}: {}", host.name, e),
+        }
+    }
+    let skew = version_skew(&reports);
+
+    if json {
+        let output = PackagesOutput {
+            hosts: reports
+                .iter()
+                .map(|(hostname, report)| HostPackages { hostname, report })
+                .collect(),
+            skew,
+        };
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else if csv {
+        println!("hostname,name,version,architecture");
+        for (hostname, report) in &reports {
+            for package in &report.packages {
+                println!(
+                    "{},{},{},{}",
+                    hostname, package.name, package.version, package.architecture
+                );
+            }
+        }
+    } else {
+        print_table(&reports, &skew, name.is_none());
+    }
+}
+
+/// Packages whose installed version differs between hosts
+fn version_skew<'a>(reports: &'a [(&'a str, PackageReport)]) -> VersionSkew<'a> {
+    let mut versions: VersionSkew = BTreeMap::new();
+    for (hostname, report) in reports {
+        for package in &report.packages {
+            versions
+                .entry(&package.name)
+                .or_default()
+                .entry(&package.version)
+                .or_default()
+                .push(hostname);
+        }
+    }
+    versions.retain(|_, by_version| by_version.len() > 1);
+    versions
+}
+
+fn print_table(reports: &[(&str, PackageReport)], skew: &VersionSkew, updates: bool) {
+    println!("Installed Packages:");
+    println!(
+        "{:<20} {:<30} {:<30} {:<10}",
+        "Hostname", "Package", "Version", "Arch"
+    );
+    println!("{:-<20} {:-<30} {:-<30} {:-<10}", "", "", "", "");
+    for (hostname, report) in reports {
+        for package in &report.packages {
+            println!(
+                "{:<20} {:<30} {:<30} {:<10}",
+                hostname, package.name, package.version, package.architecture
+            );
+        }
+    }
+
+    if updates {
+        println!();
+        println!("Pending Updates:");
+        for (hostname, report) in reports {
+            let upgradable = report
+                .upgradable
+                .map_or_else(|| "unknown".to_string(), |n| n.to_string());
+            println!("{:<20} {}", hostname, upgradable);
+        }
+    }
+
+    if !skew.is_empty() {
+        println!();
+        println!("Version Skew:");
+        for (name, by_version) in skew {
+            println!("{}", name);
+            for (version, hosts) in by_version {
+                println!("    {:<30} {}", version, hosts.join(", "));
+            }
+        }
+    }
+}
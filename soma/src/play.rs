@@ -0,0 +1,451 @@
+use serde::Serialize;
+use somacommon::resources::Outcome;
+use somacommon::selector::Selectable;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::agent;
+use crate::apply::StateDocument;
+use crate::copy::CopyOptions;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost};
+use crate::maintenance;
+use crate::playbook::{Condition, FailurePolicy, Playbook, StepState, Task};
+use crate::rollout::{self, Rollout, RolloutSummary};
+use crate::template;
+
+/// How a step or handler ended on one host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskOutcome {
+    Unchanged,
+    /// Changed, or with noaction would have
+    Changed,
+    Failed,
+    /// Not run because the step's condition was not met
+    Skipped,
+}
+
+impl fmt::Display for TaskOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TaskOutcome::Unchanged => "unchanged",
+            TaskOutcome::Changed => "changed",
+            TaskOutcome::Failed => "failed",
+            TaskOutcome::Skipped => "skipped",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostOutcome {
+    pub hostname: String,
+    pub outcome: TaskOutcome,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepReport {
+    pub step: String,
+    pub handler: bool,
+    pub hosts: Vec<HostOutcome>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayReport {
+    pub playbook: String,
+    pub steps: Vec<StepReport>,
+    /// The step whose failure ended the play early
+    pub stopped_after: Option<String>,
+}
+
+impl PlayReport {
+    pub fn failed(&self) -> bool {
+        self.stopped_after.is_some()
+            || self
+                .steps
+                .iter()
+                .flat_map(|s| &s.hosts)
+                .any(|h| h.outcome == TaskOutcome::Failed)
+    }
+}
+
+/// Run a playbook's steps in order and then the handlers they notified,
+/// passing each step's report to `on_step` as soon as it completes
+pub fn run_play(
+    playbook: &Playbook,
+    inventory: &Inventory,
+    noaction: bool,
+    mut on_step: impl FnMut(&StepReport),
+) -> PlayReport {
+    let mut report = PlayReport {
+        playbook: playbook.name.clone(),
+        steps: Vec::new(),
+        stopped_after: None,
+    };
+    let mut outcomes: HashMap<&str, HashMap<String, TaskOutcome>> = HashMap::new();
+    let mut notified: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+
+    for step in &playbook.steps {
+        let selected: Vec<InventoryHost> =
+            inventory.select(&step.hosts).into_iter().cloned().collect();
+        let (eligible, skipped): (Vec<InventoryHost>, Vec<InventoryHost>) =
+            selected.into_iter().partition(|host| match &step.when {
+                Some(condition) => condition_met(condition, &outcomes, &host.name),
+                None => true,
+            });
+
+        let mut hosts: Vec<HostOutcome> = fan_out(&eligible, DEFAULT_PARALLELISM, |host| {
+            let (outcome, message) = match run_task(playbook, &step.task, host, noaction) {
+                Ok(result) => result,
+                Err(message) => (TaskOutcome::Failed, message),
+            };
+            HostOutcome {
+                hostname: host.name.clone(),
+                outcome,
+                message,
+            }
+        });
+        hosts.extend(skipped.iter().map(|host| HostOutcome {
+            hostname: host.name.clone(),
+            outcome: TaskOutcome::Skipped,
+            message: "Condition not met".to_string(),
+        }));
+
+        let step_outcomes = outcomes.entry(&step.name).or_default();
+        for host in &hosts {
+            step_outcomes.insert(host.hostname.clone(), host.outcome);
+            if host.outcome == TaskOutcome::Changed {
+                for handler in &step.notify {
+                    notified
+                        .entry(handler)
+                        .or_default()
+                        .insert(host.hostname.clone());
+                }
+            }
+        }
+
+        let failed = hosts.iter().any(|h| h.outcome == TaskOutcome::Failed);
+        let step_report = StepReport {
+            step: step.name.clone(),
+            handler: false,
+            hosts,
+        };
+        on_step(&step_report);
+        report.steps.push(step_report);
+        if failed && step.on_failure.unwrap_or(playbook.on_failure) == FailurePolicy::Stop {
+            report.stopped_after = Some(step.name.clone());
+            return report;
+        }
+    }
+
+    for handler in &playbook.handlers {
+        let Some(names) = notified.get(handler.name.as_str()) else {
+            continue;
+        };
+        let hosts: Vec<InventoryHost> = inventory
+            .hosts
+            .iter()
+            .filter(|h| names.contains(&h.name))
+            .cloned()
+            .collect();
+        let hosts = fan_out(&hosts, DEFAULT_PARALLELISM, |host| {
+            let (outcome, message) = match run_task(playbook, &handler.task, host, noaction) {
+                Ok(result) => result,
+                Err(message) => (TaskOutcome::Failed, message),
+            };
+            HostOutcome {
+                hostname: host.name.clone(),
+                outcome,
+                message,
+            }
+        });
+        let step_report = StepReport {
+            step: handler.name.clone(),
+            handler: true,
+            hosts,
+        };
+        on_step(&step_report);
+        report.steps.push(step_report);
+    }
+    report
+}
+
+fn condition_met(
+    condition: &Condition,
+    outcomes: &HashMap<&str, HashMap<String, TaskOutcome>>,
+    host: &str,
+) -> bool {
+    let outcome = outcomes
+        .get(condition.step.as_str())
+        .and_then(|hosts| hosts.get(host))
+        .copied()
+        .unwrap_or(TaskOutcome::Skipped);
+    let matched = match condition.state {
+        StepState::Ok => matches!(outcome, TaskOutcome::Unchanged | TaskOutcome::Changed),
+        StepState::Changed => outcome == TaskOutcome::Changed,
+        StepState::Failed => outcome == TaskOutcome::Failed,
+        StepState::Skipped => outcome == TaskOutcome::Skipped,
+    };
+    matched != condition.negate
+}
+
+/// Run a task on one host, filling in placeholders from the host and the playbook's vars
+fn run_task(
+    playbook: &Playbook,
+    task: &Task,
+    host: &InventoryHost,
+    noaction: bool,
+) -> Result<(TaskOutcome, String), String> {
+    let render = |text: &str| template::render(text, host, &playbook.vars);
+    let changed = |changed| {
+        if changed {
+            TaskOutcome::Changed
+        } else {
+            TaskOutcome::Unchanged
+        }
+    };
+    match task {
+        Task::Run(action) => {
+            let result =
+                agent::run_action(host, &render(action)?, noaction).map_err(|e| e.to_string())?;
+            match result.exit_code {
+                _ if noaction => Ok((TaskOutcome::Changed, result.stdout)),
+                Some(0) => Ok((
+                    TaskOutcome::Changed,
+                    result.stdout.lines().last().unwrap_or("Done").to_string(),
+                )),
+                Some(code) => Err(format!("Exited with {}: {}", code, result.stderr.trim())),
+                None => Err(format!("Did not finish: {}", result.stderr.trim())),
+            }
+        }
+        Task::Copy(copy) => {
+            let src = playbook.path(&copy.src);
+            let content =
+                fs::read(&src).map_err(|e| format!("Cannot read {}: {}", src.display(), e))?;
+            let content = if copy.template {
+                let text = String::from_utf8(content)
+                    .map_err(|_| format!("Template {} is not valid UTF-8", src.display()))?;
+                render(&text)?.into_bytes()
+            } else {
+                content
+            };
+            let options = CopyOptions {
+                owner: copy.owner.clone(),
+                group: copy.group.clone(),
+                mode: copy.mode,
+                backup: copy.backup,
+                template: copy.template,
+            };
+            let result = agent::copy_file(host, &render(&copy.dest)?, &content, &options, noaction)
+                .map_err(|e| e.to_string())?;
+            Ok((changed(result.changed), result.message))
+        }
+        Task::Service(service) => {
+            let result =
+                agent::run_service(host, &render(&service.unit)?, service.operation, noaction)
+                    .map_err(|e| e.to_string())?;
+            Ok((changed(result.changed), result.message))
+        }
+        Task::State(file) => {
+            let path = playbook.path(file);
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            let document = StateDocument::parse(&render(&content)?, &path)?;
+            let results = agent::apply_state(host, &document.resources, noaction)
+                .map_err(|e| e.to_string())?;
+            let count = |outcome| results.iter().filter(|r| r.outcome == outcome).count();
+            let summary = format!(
+                "{} changed, {} unchanged, {} failed",
+                count(Outcome::Changed),
+                count(Outcome::Unchanged),
+                count(Outcome::Failed)
+            );
+            if count(Outcome::Failed) > 0 {
+                Ok((TaskOutcome::Failed, summary))
+            } else {
+                Ok((changed(count(Outcome::Changed) > 0), summary))
+            }
+        }
+    }
+}
+
+/// Results of a play run in batches with the hosts it got through
+#[derive(Serialize)]
+struct StagedReport<'a> {
+    batches: &'a [PlayReport],
+    rollout: &'a RolloutSummary,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_play_command(
+    json: bool,
+    csv: bool,
+    file: &Path,
+    check: bool,
+    rollout: &Rollout,
+    force: bool,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing play command");
+    }
+
+    let playbook = Playbook::load(file).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    if check {
+        println!(
+            "Playbook {} is valid: {} steps, {} handlers",
+            file.display(),
+            playbook.steps.len(),
+            playbook.handlers.len()
+        );
+        return;
+    }
+
+    let mut inventory = Inventory::load_or_exit();
+    let selected: Vec<InventoryHost> = inventory
+        .hosts
+        .iter()
+        .filter(|host| playbook.steps.iter().any(|s| host.selected_by(&s.hosts)))
+        .cloned()
+        .collect();
+    let allowed = maintenance::skip_blocked(selected, force);
+    inventory
+        .hosts
+        .retain(|host| allowed.iter().any(|h| h.name == host.name));
+    if csv {
+        println!("step,hostname,outcome,message");
+    } else if !json && noaction {
+        println!("Dry run: agents report what they would change");
+    }
+
+    if !rollout.is_staged() {
+        let report = run_play(&playbook, &inventory, noaction, |step| {
+            print_step(step, json, csv)
+        });
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else if !csv {
+            print_recap(std::slice::from_ref(&report));
+        }
+        if report.failed() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Each batch runs the whole playbook against its hosts only
+    let hosts: Vec<InventoryHost> = inventory
+        .hosts
+        .iter()
+        .filter(|host| playbook.steps.iter().any(|s| host.selected_by(&s.hosts)))
+        .cloned()
+        .collect();
+    let mut reports = Vec::new();
+    let summary = rollout.run(&hosts, |index, batch| {
+        if !json && !csv {
+            let names: Vec<&str> = batch.iter().map(|h| h.name.as_str()).collect();
+            println!();
+            println!("Batch {}: {}", index + 1, names.join(", "));
+        }
+        let mut staged = inventory.clone();
+        staged.hosts = batch.to_vec();
+        let report = run_play(&playbook, &staged, noaction, |step| {
+            print_step(step, json, csv)
+        });
+        let failed: BTreeSet<&str> = report
+            .steps
+            .iter()
+            .flat_map(|s| &s.hosts)
+            .filter(|h| h.outcome == TaskOutcome::Failed)
+            .map(|h| h.hostname.as_str())
+            .collect();
+        let result = match &report.stopped_after {
+            Some(step) => Err(format!("Step {} failed", step)),
+            None => Ok(failed.len()),
+        };
+        reports.push(report);
+        result
+    });
+
+    if json {
+        let report = StagedReport {
+            batches: &reports,
+            rollout: &summary,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        if !csv {
+            print_recap(&reports);
+        }
+        rollout::print_summary(&summary);
+    }
+    if summary.aborted.is_some() || reports.iter().any(PlayReport::failed) {
+        std::process::exit(1);
+    }
+}
+
+/// Print a step's outcomes as they come in, unless everything goes out as JSON at the end
+fn print_step(step: &StepReport, json: bool, csv: bool) {
+    if json {
+        return;
+    }
+    if csv {
+        for host in &step.hosts {
+            println!(
+                "{},{},{},{}",
+                step.step, host.hostname, host.outcome, host.message
+            );
+        }
+        return;
+    }
+    let kind = if step.handler { "Handler" } else { "Step" };
+    println!();
+    println!("{}: {}", kind, step.step);
+    if step.hosts.is_empty() {
+        println!("    No hosts selected");
+    }
+    for host in &step.hosts {
+        println!(
+            "    {:<20} {:<10} {}",
+            host.hostname, host.outcome, host.message
+        );
+    }
+}
+
+fn print_recap(reports: &[PlayReport]) {
+    // Changed, unchanged, failed and skipped counts per host
+    let mut recap: BTreeMap<&str, [usize; 4]> = BTreeMap::new();
+    for host in reports.iter().flat_map(|r| &r.steps).flat_map(|s| &s.hosts) {
+        let counts = recap.entry(&host.hostname).or_default();
+        let index = match host.outcome {
+            TaskOutcome::Changed => 0,
+            TaskOutcome::Unchanged => 1,
+            TaskOutcome::Failed => 2,
+            TaskOutcome::Skipped => 3,
+        };
+        counts[index] += 1;
+    }
+    println!();
+    println!(
+        "{:<20} {:<10} {:<10} {:<10} Skipped",
+        "Recap", "Changed", "Unchanged", "Failed"
+    );
+    for (host, [changed, unchanged, failed, skipped]) in &recap {
+        println!(
+            "{:<20} {:<10} {:<10} {:<10} {}",
+            host, changed, unchanged, failed, skipped
+        );
+    }
+    for step in reports.iter().filter_map(|r| r.stopped_after.as_ref()) {
+        println!();
+        println!("Stopped after step {} failed", step);
+    }
+}
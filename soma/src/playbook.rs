@@ -0,0 +1,477 @@
+//! Playbooks: ordered steps run against inventory hosts by `soma play`.
+//!
+//! ```toml
+//! name = "web rollout"
+//! vars = { release = "1.4.2" }
+//!
+//! [[steps]]
+//! name = "config"
+//! hosts = "@web"
+//! copy = { src = "files/app.conf", dest = "/etc/app/app.conf", template = true }
+//! notify = ["restart app"]
+//!
+//! [[steps]]
+//! name = "migrate"
+//! hosts = "db-01"
+//! run = "migrate"
+//! when = "config.changed"
+//!
+//! [[handlers]]
+//! name = "restart app"
+//! service = { unit = "app.service", operation = "restart" }
+//! ```
+
+use serde::Deserialize;
+use somacommon::service::ServiceOperation;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::apply::StateDocument;
+
+/// What to do when a step fails on any host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Finish the step on every host, then end the play
+    #[default]
+    Stop,
+    /// Carry on with the next step
+    Continue,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Playbook {
+    #[serde(default)]
+    pub name: String,
+    /// Defaults for `{{ key }}` placeholders, overridden by host vars and facts
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+    pub steps: Vec<Step>,
+    #[serde(default)]
+    pub handlers: Vec<Handler>,
+    /// Directory relative paths in the playbook are resolved against
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawStep")]
+pub struct Step {
+    pub name: String,
+    /// Selector picking the hosts the step runs on
+    #[serde(default = "all_hosts")]
+    pub hosts: String,
+    /// Only run on hosts where an earlier step ended a certain way
+    #[serde(default)]
+    pub when: Option<Condition>,
+    /// Handlers to run at the end of the play on hosts where this step changed something
+    #[serde(default)]
+    pub notify: Vec<String>,
+    /// Overrides the playbook's failure policy for this step
+    #[serde(default)]
+    pub on_failure: Option<FailurePolicy>,
+    pub task: Task,
+}
+
+/// A task run once at the end of the play on the hosts that notified it
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawHandler")]
+pub struct Handler {
+    pub name: String,
+    pub task: Task,
+}
+
+/// A step as written, with each kind of task a field of its own so that
+/// unknown keys are still refused
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStep {
+    name: String,
+    #[serde(default = "all_hosts")]
+    hosts: String,
+    #[serde(default)]
+    when: Option<Condition>,
+    #[serde(default)]
+    notify: Vec<String>,
+    #[serde(default)]
+    on_failure: Option<FailurePolicy>,
+    #[serde(default)]
+    run: Option<String>,
+    #[serde(default)]
+    copy: Option<CopyTask>,
+    #[serde(default)]
+    service: Option<ServiceTask>,
+    #[serde(default)]
+    state: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHandler {
+    name: String,
+    #[serde(default)]
+    run: Option<String>,
+    #[serde(default)]
+    copy: Option<CopyTask>,
+    #[serde(default)]
+    service: Option<ServiceTask>,
+    #[serde(default)]
+    state: Option<PathBuf>,
+}
+
+impl TryFrom<RawStep> for Step {
+    type Error = String;
+
+    fn try_from(raw: RawStep) -> Result<Self, Self::Error> {
+        let task = one_task(&raw.name, raw.run, raw.copy, raw.service, raw.state)?;
+        Ok(Step {
+            name: raw.name,
+            hosts: raw.hosts,
+            when: raw.when,
+            notify: raw.notify,
+            on_failure: raw.on_failure,
+            task,
+        })
+    }
+}
+
+impl TryFrom<RawHandler> for Handler {
+    type Error = String;
+
+    fn try_from(raw: RawHandler) -> Result<Self, Self::Error> {
+        let task = one_task(&raw.name, raw.run, raw.copy, raw.service, raw.state)?;
+        Ok(Handler {
+            name: raw.name,
+            task,
+        })
+    }
+}
+
+/// The one task a step or handler names
+fn one_task(
+    name: &str,
+    run: Option<String>,
+    copy: Option<CopyTask>,
+    service: Option<ServiceTask>,
+    state: Option<PathBuf>,
+) -> Result<Task, String> {
+    let mut tasks = [
+        run.map(Task::Run),
+        copy.map(Task::Copy),
+        service.map(Task::Service),
+        state.map(Task::State),
+    ]
+    .into_iter()
+    .flatten();
+    match (tasks.next(), tasks.next()) {
+        (Some(task), None) => Ok(task),
+        (None, _) => Err(format!(
+            "Step {} needs one of run, copy, service or state",
+            name
+        )),
+        (Some(_), Some(_)) => Err(format!(
+            "Step {} names more than one of run, copy, service and state",
+            name
+        )),
+    }
+}
+
+fn all_hosts() -> String {
+    "*".to_string()
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// The work a step does; each step names exactly one
+#[derive(Debug, Clone)]
+pub enum Task {
+    /// Run one of the agent's allowlisted actions
+    Run(String),
+    Copy(CopyTask),
+    Service(ServiceTask),
+    /// Apply a desired-state document, see `soma apply`
+    State(PathBuf),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CopyTask {
+    pub src: PathBuf,
+    pub dest: String,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub template: bool,
+    #[serde(default = "yes")]
+    pub backup: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceTask {
+    pub unit: String,
+    pub operation: ServiceOperation,
+}
+
+/// How an earlier step ended on a host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepState {
+    /// Succeeded, whether or not it changed anything
+    Ok,
+    Changed,
+    Failed,
+    /// Did not run on the host
+    Skipped,
+}
+
+/// `[!]<step>.<ok|changed|failed|skipped>`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Condition {
+    pub step: String,
+    pub state: StepState,
+    pub negate: bool,
+}
+
+impl TryFrom<String> for Condition {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let (negate, rest) = match text.trim().strip_prefix('!') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, text.trim()),
+        };
+        let invalid = || {
+            format!(
+                "Invalid condition {:?}, expected <step>.ok, .changed, .failed or .skipped",
+                text
+            )
+        };
+        let (step, state) = rest.rsplit_once('.').ok_or_else(invalid)?;
+        let state = match state {
+            "ok" => StepState::Ok,
+            "changed" => StepState::Changed,
+            "failed" => StepState::Failed,
+            "skipped" => StepState::Skipped,
+            _ => return Err(invalid()),
+        };
+        if step.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Condition {
+            step: step.to_string(),
+            state,
+            negate,
+        })
+    }
+}
+
+impl Playbook {
+    pub fn load(path: &Path) -> Result<Playbook, PlaybookError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| PlaybookError::ReadError(path.to_path_buf(), e))?;
+        let mut playbook: Playbook = toml::from_str(&content)
+            .map_err(|e| PlaybookError::ParseError(path.to_path_buf(), e))?;
+        playbook.dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        playbook.validate()?;
+        Ok(playbook)
+    }
+
+    /// Resolve a path given in the playbook
+    pub fn path(&self, path: &Path) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    /// Check references between steps and handlers, and that the files used exist
+    pub fn validate(&self) -> Result<(), PlaybookError> {
+        if self.steps.is_empty() {
+            return Err(PlaybookError::NoSteps);
+        }
+        let handlers: BTreeSet<&str> = self.handlers.iter().map(|h| h.name.as_str()).collect();
+        if handlers.len() != self.handlers.len() {
+            let duplicate = self
+                .handlers
+                .iter()
+                .find(|h| self.handlers.iter().filter(|o| o.name == h.name).count() > 1)
+                .map(|h| h.name.clone())
+                .unwrap_or_default();
+            return Err(PlaybookError::DuplicateName(duplicate));
+        }
+
+        let mut earlier: BTreeSet<&str> = BTreeSet::new();
+        for step in &self.steps {
+            let invalid = |reason: String| PlaybookError::InvalidStep(step.name.clone(), reason);
+            if step.name.trim().is_empty() {
+                return Err(PlaybookError::MissingName);
+            }
+            if earlier.contains(step.name.as_str()) {
+                return Err(PlaybookError::DuplicateName(step.name.clone()));
+            }
+            if let Some(condition) = &step.when
+                && !earlier.contains(condition.step.as_str())
+            {
+                return Err(invalid(format!(
+                    "condition refers to {}, which is not an earlier step",
+                    condition.step
+                )));
+            }
+            if let Some(handler) = step.notify.iter().find(|n| !handlers.contains(n.as_str())) {
+                return Err(invalid(format!("notifies unknown handler {}", handler)));
+            }
+            self.validate_task(&step.task).map_err(invalid)?;
+            earlier.insert(&step.name);
+        }
+        for handler in &self.handlers {
+            self.validate_task(&handler.task)
+                .map_err(|reason| PlaybookError::InvalidStep(handler.name.clone(), reason))?;
+        }
+        Ok(())
+    }
+
+    fn validate_task(&self, task: &Task) -> Result<(), String> {
+        match task {
+            Task::Run(action) if action.trim().is_empty() => {
+                Err("run needs an action name".to_string())
+            }
+            Task::Run(_) | Task::Service(_) => Ok(()),
+            Task::Copy(copy) => {
+                if !copy.dest.starts_with('/') && !copy.dest.starts_with("{{") {
+                    return Err(format!("destination {} is not absolute", copy.dest));
+                }
+                if copy.mode.is_some_and(|mode| mode > 0o7777) {
+                    return Err(format!("mode {:o} is not a file mode", copy.mode.unwrap()));
+                }
+                let src = self.path(&copy.src);
+                if !src.is_file() {
+                    return Err(format!("{} does not exist", src.display()));
+                }
+                Ok(())
+            }
+            Task::State(file) => StateDocument::load(&self.path(file)).map(|_| ()),
+        }
+    }
+}
+
+/// Playbook error types
+#[derive(Debug)]
+pub enum PlaybookError {
+    ReadError(PathBuf, std::io::Error),
+    ParseError(PathBuf, toml::de::Error),
+    NoSteps,
+    MissingName,
+    DuplicateName(String),
+    InvalidStep(String, String),
+}
+
+impl std::fmt::Display for PlaybookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaybookError::ReadError(path, err) => {
+                write!(f, "Failed to read playbook {}: {}", path.display(), err)
+            }
+            PlaybookError::ParseError(path, err) => {
+                write!(f, "Failed to parse playbook {}: {}", path.display(), err)
+            }
+            PlaybookError::NoSteps => write!(f, "Playbook has no steps"),
+            PlaybookError::MissingName => write!(f, "Every step needs a name"),
+            PlaybookError::DuplicateName(name) => {
+                write!(f, "More than one step or handler is named {}", name)
+            }
+            PlaybookError::InvalidStep(name, reason) => write!(f, "Step {}: {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for PlaybookError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Playbook, PlaybookError> {
+        let playbook: Playbook = toml::from_str(content)
+            .map_err(|e| PlaybookError::ParseError(PathBuf::from("test.toml"), e))?;
+        playbook.validate()?;
+        Ok(playbook)
+    }
+
+    #[test]
+    fn parses_steps_and_handlers() {
+        let playbook = parse(
+            "[[steps]]\nname = \"restart\"\nhosts = \"@web\"\n\
+             service = { unit = \"app.service\", operation = \"restart\" }\n\
+             notify = [\"announce\"]\n\n\
+             [[steps]]\nname = \"smoke\"\nrun = \"smoke-test\"\nwhen = \"!restart.failed\"\n\
+             on_failure = \"continue\"\n\n\
+             [[handlers]]\nname = \"announce\"\nrun = \"announce\"\n",
+        )
+        .unwrap();
+        assert!(matches!(playbook.steps[0].task, Task::Service(_)));
+        assert_eq!(playbook.steps[1].hosts, "*");
+        assert_eq!(
+            playbook.steps[1].when,
+            Some(Condition {
+                step: "restart".to_string(),
+                state: StepState::Failed,
+                negate: true,
+            })
+        );
+        assert_eq!(playbook.steps[1].on_failure, Some(FailurePolicy::Continue));
+    }
+
+    #[test]
+    fn rejects_bad_references() {
+        let later = parse(
+            "[[steps]]\nname = \"a\"\nrun = \"x\"\nwhen = \"b.changed\"\n\n\
+             [[steps]]\nname = \"b\"\nrun = \"y\"\n",
+        );
+        assert!(matches!(later, Err(PlaybookError::InvalidStep(..))));
+
+        let handler = parse("[[steps]]\nname = \"a\"\nrun = \"x\"\nnotify = [\"nope\"]\n");
+        assert!(matches!(handler, Err(PlaybookError::InvalidStep(..))));
+
+        let duplicate =
+            parse("[[steps]]\nname = \"a\"\nrun = \"x\"\n\n[[steps]]\nname = \"a\"\nrun = \"y\"\n");
+        assert!(matches!(duplicate, Err(PlaybookError::DuplicateName(_))));
+
+        let condition = parse("[[steps]]\nname = \"a\"\nrun = \"x\"\nwhen = \"a.done\"\n");
+        assert!(matches!(condition, Err(PlaybookError::ParseError(..))));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_more_than_one_task() {
+        let typo = parse("[[steps]]\nname = \"a\"\nrun = \"x\"\nnotfy = [\"h\"]\n");
+        let Err(PlaybookError::ParseError(_, e)) = typo else {
+            panic!("{:?}", typo);
+        };
+        assert!(e.to_string().contains("notfy"), "{}", e);
+
+        let both = parse(
+            "[[steps]]\nname = \"a\"\nrun = \"x\"\n\
+             service = { unit = \"app.service\", operation = \"restart\" }\n",
+        );
+        let Err(PlaybookError::ParseError(_, e)) = both else {
+            panic!("{:?}", both);
+        };
+        assert!(e.to_string().contains("more than one"), "{}", e);
+
+        let none = parse("[[steps]]\nname = \"a\"\nhosts = \"@web\"\n");
+        assert!(matches!(none, Err(PlaybookError::ParseError(..))));
+
+        let handler = parse(
+            "[[steps]]\nname = \"a\"\nrun = \"x\"\n\n\
+             [[handlers]]\nname = \"h\"\nrun = \"y\"\nhosts = \"@web\"\n",
+        );
+        assert!(matches!(handler, Err(PlaybookError::ParseError(..))));
+    }
+}
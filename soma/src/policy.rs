@@ -0,0 +1,107 @@
+use somacommon::policy::{Denial, Policy};
+use somacommon::protocol::Request;
+use somacommon::selector::Selectable;
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::audit;
+use crate::inventory::InventoryHost;
+
+/// Environment variable naming another access policy file, honoured only
+/// when soma runs as root
+pub const POLICY_ENV: &str = "SOMA_POLICY";
+
+/// Where the access policy is kept, out of reach of the operators it limits
+pub const SYSTEM_POLICY: &str = "/etc/soma/policy.toml";
+
+/// Location of the access policy file
+pub fn default_path() -> PathBuf {
+    match env::var_os(POLICY_ENV).filter(|_| audit::is_root()) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(SYSTEM_POLICY),
+    }
+}
+
+/// Load an access policy, `None` when there is no file. The file must be
+/// owned by root and writable by no one else, so operators cannot grant
+/// themselves more.
+pub fn load(path: &Path) -> Result<Option<Policy>, PolicyError> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(PolicyError::ReadError(path.to_path_buf(), e)),
+    };
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        return Err(PolicyError::Invalid(
+            path.to_path_buf(),
+            "must be owned by root and writable by no one else".to_string(),
+        ));
+    }
+    let content =
+        fs::read_to_string(path).map_err(|e| PolicyError::ReadError(path.to_path_buf(), e))?;
+    let policy: Policy =
+        toml::from_str(&content).map_err(|e| PolicyError::ParseError(path.to_path_buf(), e))?;
+    policy
+        .validate()
+        .map_err(|e| PolicyError::Invalid(path.to_path_buf(), e))?;
+    Ok(Some(policy))
+}
+
+/// The policy in the default location, loaded once, exiting with an error
+/// message when it cannot be read
+fn policy() -> Option<&'static Policy> {
+    static POLICY: OnceLock<Option<Policy>> = OnceLock::new();
+    POLICY
+        .get_or_init(|| {
+            load(&default_path()).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            })
+        })
+        .as_ref()
+}
+
+/// Check that the operator soma acts for may make a request of a host.
+/// Without a policy only root may make requests.
+///
+/// This only spares operators requests that would be refused: the agents
+/// given a copy of the policy enforce it, against the operator whose key
+/// signed each request.
+pub fn check(host: &InventoryHost, request: &Request) -> Result<(), Denial> {
+    match policy() {
+        Some(policy) => policy.check(&audit::operator(), request, &host.name, &|selector| {
+            host.selected_by(selector)
+        }),
+        None if audit::is_root() => Ok(()),
+        None => Policy::default().check(&audit::operator(), request, &host.name, &|_| false),
+    }
+}
+
+/// Access policy error types
+#[derive(Debug)]
+pub enum PolicyError {
+    ReadError(PathBuf, std::io::Error),
+    ParseError(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::ReadError(path, err) => {
+                write!(f, "Error reading policy {}: {}", path.display(), err)
+            }
+            PolicyError::ParseError(path, err) => {
+                write!(f, "Error parsing policy {}: {}", path.display(), err)
+            }
+            PolicyError::Invalid(path, reason) => {
+                write!(f, "Invalid policy {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
@@ -0,0 +1,158 @@
+use serde::Serialize;
+use somacommon::ports::Listener;
+
+use crate::agent;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost, parse_port_spec};
+
+/// How a listener compares with the ports the inventory expects the host to expose
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Exposure {
+    /// Declared in the host's expected ports
+    Expected,
+    /// Reachable from other hosts but not declared
+    Unexpected,
+    /// Bound to a loopback address, so not exposed
+    Local,
+    /// The host declares no expected ports to compare with
+    Undeclared,
+}
+
+impl std::fmt::Display for Exposure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Exposure::Expected => "expected",
+            Exposure::Unexpected => "unexpected",
+            Exposure::Local => "local",
+            Exposure::Undeclared => "undeclared",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Serialize)]
+struct PortRow<'a> {
+    hostname: &'a str,
+    #[serde(flatten)]
+    listener: &'a Listener,
+    exposure: Exposure,
+}
+
+#[derive(Serialize)]
+struct PortsReport<'a> {
+    listeners: &'a [PortRow<'a>],
+}
+
+fn exposure(host: &InventoryHost, listener: &Listener) -> Exposure {
+    if listener.is_loopback() {
+        return Exposure::Local;
+    }
+    if host.expected_ports.is_empty() {
+        return Exposure::Undeclared;
+    }
+    let declared = host
+        .expected_ports
+        .iter()
+        .filter_map(|spec| parse_port_spec(spec))
+        .any(|(protocol, port)| protocol == listener.protocol && port == listener.port);
+    if declared {
+        Exposure::Expected
+    } else {
+        Exposure::Unexpected
+    }
+}
+
+pub fn handle_ports_command(
+    json: bool,
+    csv: bool,
+    unexpected: bool,
+    hosts: &[String],
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing ports command");
+    }
+
+    let inventory = Inventory::load_or_exit();
+    let targets = inventory.resolve(hosts);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to list ports on");
+        std::process::exit(1);
+    }
+
+    if noaction {
+        let names: Vec<&str> = targets.iter().map(|h| h.name.as_str()).collect();
+        println!("Would list listening ports on hosts: {:?}", names);
+        return;
+    }
+
+    let results = fan_out(&targets, DEFAULT_PARALLELISM, agent::fetch_ports);
+    let mut listeners = Vec::new();
+    for (host, result) in targets.iter().zip(results) {
+        match result {
+            Ok(found) => listeners.push((host, found)),
+            Err(e) => eprintln!("Warning: {}: {}", host.name, e),
+        }
+    }
+    let rows: Vec<PortRow> = listeners
+        .iter()
+        .flat_map(|(host, found)| {
+            found.iter().map(|listener| PortRow {
+                hostname: &host.name,
+                listener,
+                exposure: exposure(host, listener),
+            })
+        })
+        .filter(|row| !unexpected || row.exposure == Exposure::Unexpected)
+        .collect();
+
+    if json {
+        let report = PortsReport { listeners: &rows };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else if csv {
+        println!("hostname,protocol,address,port,pid,process,exposure");
+        for row in &rows {
+            println!(
+                "{},{},{},{},{},{},{}",
+                row.hostname,
+                row.listener.protocol,
+                row.listener.address,
+                row.listener.port,
+                row.listener.pid.map(|p| p.to_string()).unwrap_or_default(),
+                row.listener.process.as_deref().unwrap_or_default(),
+                row.exposure
+            );
+        }
+    } else {
+        print_table(&rows);
+    }
+}
+
+fn print_table(rows: &[PortRow]) {
+    println!("Listening Ports:");
+    println!(
+        "{:<20} {:<6} {:<25} {:<6} {:<20} {:<10}",
+        "Hostname", "Proto", "Address", "Port", "Process", "Exposure"
+    );
+    println!(
+        "{:-<20} {:-<6} {:-<25} {:-<6} {:-<20} {:-<10}",
+        "", "", "", "", "", ""
+    );
+    for row in rows {
+        let process = match (&row.listener.process, row.listener.pid) {
+            (Some(name), Some(pid)) => format!("{} ({})", name, pid),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:<20} {:<6} {:<25} {:<6} {:<20} {:<10}",
+            row.hostname,
+            row.listener.protocol,
+            row.listener.address,
+            row.listener.port,
+            process,
+            row.exposure
+        );
+    }
+}
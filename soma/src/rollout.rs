@@ -0,0 +1,258 @@
+use serde::Serialize;
+use somacommon::status::Health;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+use crate::agent;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::InventoryHost;
+
+/// One entry of a `--serial` list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchSize {
+    Hosts(usize),
+    /// A percentage of all the hosts, rounded up
+    Percent(usize),
+}
+
+/// Batch sizes in order; the last size repeats until every host is covered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Serial(Vec<BatchSize>);
+
+impl FromStr for Serial {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sizes = s
+            .split(',')
+            .map(|size| {
+                let size = size.trim();
+                let parsed = match size.strip_suffix('%') {
+                    Some(percent) => percent
+                        .parse()
+                        .ok()
+                        .filter(|p| (1..=100).contains(p))
+                        .map(BatchSize::Percent),
+                    None => size.parse().ok().filter(|n| *n > 0).map(BatchSize::Hosts),
+                };
+                parsed.ok_or(format!("Invalid batch size {:?}", size))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Serial(sizes))
+    }
+}
+
+impl Serial {
+    /// Sizes of the batches `total` hosts are split into
+    pub fn batch_sizes(&self, total: usize) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut remaining = total;
+        let mut specs = self.0.iter();
+        let mut spec = self.0[0];
+        while remaining > 0 {
+            spec = *specs.next().unwrap_or(&spec);
+            let size = match spec {
+                BatchSize::Hosts(n) => n,
+                BatchSize::Percent(p) => (total * p).div_ceil(100).max(1),
+            };
+            let size = size.min(remaining);
+            sizes.push(size);
+            remaining -= size;
+        }
+        sizes
+    }
+}
+
+/// How to work through hosts: all at once, or in batches that stop when things go wrong
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct Rollout {
+    /// Work through hosts in batches, e.g. 10%, 3 or 1,5,25%
+    #[structopt(long)]
+    pub serial: Option<Serial>,
+    /// Require the first batch to succeed and report healthy before going on; without
+    /// --serial a single host goes first
+    #[structopt(long)]
+    pub canary: bool,
+    /// Abort once more than this many hosts have failed
+    #[structopt(long)]
+    pub max_fail: Option<usize>,
+}
+
+/// What a rollout got through
+#[derive(Debug, Default, Serialize)]
+pub struct RolloutSummary {
+    pub batches: usize,
+    pub completed: usize,
+    pub touched: Vec<String>,
+    pub untouched: Vec<String>,
+    /// Why the rollout stopped early
+    pub aborted: Option<String>,
+}
+
+impl Rollout {
+    /// Whether hosts are worked through in more than one go
+    pub fn is_staged(&self) -> bool {
+        self.serial.is_some() || self.canary || self.max_fail.is_some()
+    }
+
+    fn batches(&self, hosts: &[InventoryHost]) -> Vec<Vec<InventoryHost>> {
+        let sizes = match &self.serial {
+            Some(serial) => serial.batch_sizes(hosts.len()),
+            None if self.canary && hosts.len() > 1 => vec![1, hosts.len() - 1],
+            None => vec![hosts.len()],
+        };
+        let mut batches = Vec::new();
+        let mut start = 0;
+        for size in sizes {
+            batches.push(hosts[start..start + size].to_vec());
+            start += size;
+        }
+        batches
+    }
+
+    /// Work through the hosts a batch at a time. `run_batch` returns how many
+    /// hosts in the batch failed, or a reason to abort the rollout there.
+    pub fn run<F>(&self, hosts: &[InventoryHost], mut run_batch: F) -> RolloutSummary
+    where
+        F: FnMut(usize, &[InventoryHost]) -> Result<usize, String>,
+    {
+        let batches = self.batches(hosts);
+        let mut summary = RolloutSummary {
+            batches: batches.len(),
+            ..Default::default()
+        };
+        let mut failures = 0;
+        for (index, batch) in batches.iter().enumerate() {
+            let result = run_batch(index, batch);
+            summary.completed += 1;
+            summary.touched.extend(batch.iter().map(|h| h.name.clone()));
+            let more = index + 1 < batches.len();
+            match result {
+                Err(reason) => summary.aborted = Some(reason),
+                Ok(failed) => {
+                    failures += failed;
+                    if let Some(max) = self.max_fail
+                        && failures > max
+                    {
+                        summary.aborted = Some(format!(
+                            "{} hosts failed, more than the {} allowed",
+                            failures, max
+                        ));
+                    } else if index == 0 && self.canary && more && failed > 0 {
+                        summary.aborted = Some(format!(
+                            "Canary batch failed on {} of {} hosts",
+                            failed,
+                            batch.len()
+                        ));
+                    } else if index == 0
+                        && self.canary
+                        && more
+                        && let Err(reason) = canary_check(batch)
+                    {
+                        summary.aborted = Some(reason);
+                    }
+                }
+            }
+            if summary.aborted.is_some() {
+                if more {
+                    summary.untouched = batches[index + 1..]
+                        .iter()
+                        .flatten()
+                        .map(|h| h.name.clone())
+                        .collect();
+                }
+                break;
+            }
+        }
+        summary
+    }
+}
+
+/// Every canary host must answer with a healthy status report
+fn canary_check(hosts: &[InventoryHost]) -> Result<(), String> {
+    let reports = fan_out(hosts, DEFAULT_PARALLELISM, agent::fetch_status);
+    let problems: Vec<String> = hosts
+        .iter()
+        .zip(reports)
+        .filter_map(|(host, report)| match report {
+            Ok(report) if report.health == Health::Healthy => None,
+            Ok(report) => Some(format!("{} is {}", host.name, report.health)),
+            Err(e) => Some(format!("{}: {}", host.name, e)),
+        })
+        .collect();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Canary health check failed: {}",
+            problems.join(", ")
+        ))
+    }
+}
+
+/// Describe an aborted rollout, naming the hosts it did and did not reach
+pub fn print_summary(summary: &RolloutSummary) {
+    let Some(reason) = &summary.aborted else {
+        return;
+    };
+    eprintln!(
+        "Rollout aborted after batch {} of {}: {}",
+        summary.completed, summary.batches, reason
+    );
+    eprintln!("Touched hosts: {}", summary.touched.join(", "));
+    if !summary.untouched.is_empty() {
+        eprintln!("Untouched hosts: {}", summary.untouched.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_hosts_into_batches() {
+        let serial: Serial = "10%".parse().unwrap();
+        assert_eq!(
+            serial.batch_sizes(25),
+            vec![3; 8].into_iter().chain([1]).collect::<Vec<_>>()
+        );
+
+        let serial: Serial = "1,5,25%".parse().unwrap();
+        assert_eq!(serial.batch_sizes(20), vec![1, 5, 5, 5, 4]);
+
+        let serial: Serial = "2".parse().unwrap();
+        assert_eq!(serial.batch_sizes(3), vec![2, 1]);
+        assert_eq!(serial.batch_sizes(0), Vec::<usize>::new());
+
+        assert!("0".parse::<Serial>().is_err());
+        assert!("150%".parse::<Serial>().is_err());
+        assert!("1,,2".parse::<Serial>().is_err());
+    }
+
+    #[test]
+    fn failed_canaries_abort_the_rollout() {
+        let hosts: Vec<InventoryHost> = ["web-01", "web-02", "web-03"]
+            .iter()
+            .map(|name| InventoryHost {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let rollout = Rollout {
+            canary: true,
+            ..Default::default()
+        };
+        let mut ran = Vec::new();
+        let summary = rollout.run(&hosts, |index, _| {
+            ran.push(index);
+            Ok(1)
+        });
+        assert_eq!(ran, vec![0]);
+        assert_eq!(summary.touched, vec!["web-01"]);
+        assert_eq!(summary.untouched, vec!["web-02", "web-03"]);
+        assert_eq!(
+            summary.aborted.as_deref(),
+            Some("Canary batch failed on 1 of 1 hosts")
+        );
+    }
+}
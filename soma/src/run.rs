@@ -0,0 +1,148 @@
+use serde::Serialize;
+use somacommon::protocol::ActionResult;
+
+use crate::agent::{self, AgentError};
+use crate::facts::csv_field;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost};
+use crate::maintenance;
+use crate::rollout::{self, Rollout, RolloutSummary};
+
+/// One row of the run report
+#[derive(Serialize)]
+struct RunRow<'a> {
+    hostname: &'a str,
+    #[serde(flatten)]
+    result: Option<&'a ActionResult>,
+    error: Option<String>,
+}
+
+/// Results of a staged run with the hosts it got through
+#[derive(Serialize)]
+struct StagedReport<'a> {
+    results: Vec<RunRow<'a>>,
+    rollout: RolloutSummary,
+}
+
+/// Whether an action did not run to a successful finish
+fn action_failed(result: &Result<ActionResult, AgentError>, noaction: bool) -> bool {
+    match result {
+        Ok(result) => !noaction && result.exit_code != Some(0),
+        Err(_) => true,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_run_command(
+    json: bool,
+    csv: bool,
+    hosts: &str,
+    action: &str,
+    rollout: &Rollout,
+    force: bool,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing run command");
+    }
+
+    let inventory = Inventory::load_or_exit();
+    let targets = maintenance::skip_blocked(inventory.resolve(&[hosts.to_string()]), force);
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to run {} on", action);
+        std::process::exit(1);
+    }
+
+    // Agents answer noaction requests with the command they would run
+    let mut touched: Vec<(String, Result<ActionResult, AgentError>)> = Vec::new();
+    let summary = rollout.run(&targets, |_, batch: &[InventoryHost]| {
+        let results = fan_out(batch, DEFAULT_PARALLELISM, |host| {
+            agent::run_action(host, action, noaction)
+        });
+        let failed = results
+            .iter()
+            .filter(|r| action_failed(r, noaction))
+            .count();
+        touched.extend(batch.iter().map(|h| h.name.clone()).zip(results));
+        Ok(failed)
+    });
+    let failed = summary.aborted.is_some()
+        || touched
+            .iter()
+            .any(|(_, result)| action_failed(result, noaction));
+    let rows: Vec<RunRow> = touched
+        .iter()
+        .map(|(hostname, result)| RunRow {
+            hostname,
+            result: result.as_ref().ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        })
+        .collect();
+
+    if json {
+        if rollout.is_staged() {
+            let report = StagedReport {
+                results: rows,
+                rollout: summary,
+            };
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else {
+            println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+        }
+    } else if csv {
+        println!("hostname,exit_code,stdout,stderr");
+        for row in &rows {
+            let (exit_code, stdout, stderr) = match (row.result, &row.error) {
+                (Some(result), _) => (
+                    result.exit_code.map(|c| c.to_string()).unwrap_or_default(),
+                    result.stdout.as_str(),
+                    result.stderr.as_str(),
+                ),
+                (None, error) => (String::new(), "", error.as_deref().unwrap_or_default()),
+            };
+            println!(
+                "{},{},{},{}",
+                row.hostname,
+                exit_code,
+                csv_field(stdout),
+                csv_field(stderr)
+            );
+        }
+        rollout::print_summary(&summary);
+    } else {
+        println!("{:<20} {:<6} Output", "Hostname", "Exit");
+        println!("{:-<20} {:-<6} {:-<30}", "", "", "");
+        for row in &rows {
+            match (row.result, &row.error) {
+                (Some(result), _) => {
+                    let output = if result.stdout.trim().is_empty() {
+                        result.stderr.trim()
+                    } else {
+                        result.stdout.trim()
+                    };
+                    println!(
+                        "{:<20} {:<6} {}",
+                        row.hostname,
+                        result
+                            .exit_code
+                            .map(|c| c.to_string())
+                            .unwrap_or("-".to_string()),
+                        output.lines().next().unwrap_or_default()
+                    );
+                }
+                (None, error) => println!(
+                    "{:<20} {:<6} {}",
+                    row.hostname,
+                    "-",
+                    error.as_deref().unwrap_or_default()
+                ),
+            }
+        }
+        rollout::print_summary(&summary);
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
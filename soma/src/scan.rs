@@ -1,13 +1,137 @@
-pub fn handle_scan_command(json: bool, csv: bool, verbose: bool, noaction: bool) {
-    if verbose {
-        println!("Executing scan command");
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::check::poll_status;
+use crate::inventory::{Inventory, InventoryHost};
+use crate::state;
+
+/// File in the state directory holding the most recent scan
+const LAST_SCAN_FILE: &str = "last_scan.json";
+
+/// A single host discovered on the network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanResult {
+    pub hostname: String,
+    pub ip: String,
+    pub status: String,
+}
+
+/// The full result of a scan, in the same shape as `soma scan --json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScanReport {
+    pub scan_results: Vec<ScanResult>,
+}
+
+/// A host whose hostname or managed status differs between two scans
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanChange {
+    pub ip: String,
+    pub hostname: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Differences between a baseline scan and the current one, keyed by IP address
+#[derive(Debug, Default, Serialize)]
+pub struct ScanDiff {
+    pub new: Vec<ScanResult>,
+    pub vanished: Vec<ScanResult>,
+    pub hostname_changed: Vec<ScanChange>,
+    pub status_changed: Vec<ScanChange>,
+}
+
+impl ScanDiff {
+    pub fn between(baseline: &[ScanResult], current: &[ScanResult]) -> Self {
+        let before: BTreeMap<&str, &ScanResult> =
+            baseline.iter().map(|r| (r.ip.as_str(), r)).collect();
+        let after: BTreeMap<&str, &ScanResult> =
+            current.iter().map(|r| (r.ip.as_str(), r)).collect();
+
+        let mut diff = ScanDiff::default();
+        for (ip, now) in &after {
+            match before.get(ip) {
+                None => diff.new.push((*now).clone()),
+                Some(then) => {
+                    if then.hostname != now.hostname {
+                        diff.hostname_changed.push(ScanChange {
+                            ip: ip.to_string(),
+                            hostname: now.hostname.clone(),
+                            old: then.hostname.clone(),
+                            new: now.hostname.clone(),
+                        });
+                    }
+                    if then.status != now.status {
+                        diff.status_changed.push(ScanChange {
+                            ip: ip.to_string(),
+                            hostname: now.hostname.clone(),
+                            old: then.status.clone(),
+                            new: now.status.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        for (ip, then) in &before {
+            if !after.contains_key(ip) {
+                diff.vanished.push((*then).clone());
+            }
+        }
+        diff
     }
 
-    if noaction {
-        println!("Would scan network for managed and unmanaged hosts");
-        return;
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty()
+            && self.vanished.is_empty()
+            && self.hostname_changed.is_empty()
+            && self.status_changed.is_empty()
     }
+}
+
+/// Scan error types
+#[derive(Debug)]
+pub enum ScanError {
+    ReadError(PathBuf, std::io::Error),
+    ParseError(PathBuf, serde_json::Error),
+    WriteError(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::ReadError(path, err) => {
+                write!(f, "Error reading scan file {}: {}", path.display(), err)
+            }
+            ScanError::ParseError(path, err) => {
+                write!(f, "Error parsing scan file {}: {}", path.display(), err)
+            }
+            ScanError::WriteError(path, err) => {
+                write!(f, "Error writing scan file {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
 
+/// Load a scan previously written by `soma scan --json` or stored after a scan
+pub fn load_scan(path: &Path) -> Result<ScanReport, ScanError> {
+    let content =
+        fs::read_to_string(path).map_err(|e| ScanError::ReadError(path.to_path_buf(), e))?;
+    serde_json::from_str(&content).map_err(|e| ScanError::ParseError(path.to_path_buf(), e))
+}
+
+/// Store a scan as the baseline for the next `--diff`
+pub fn store_scan(report: &ScanReport) -> Result<PathBuf, ScanError> {
+    let path = state::state_file(LAST_SCAN_FILE)
+        .map_err(|e| ScanError::WriteError(state::state_dir(), e))?;
+    let content = serde_json::to_string_pretty(report).unwrap();
+    state::replace(&path, &content).map_err(|e| ScanError::WriteError(path.clone(), e))?;
+    Ok(path)
+}
+
+fn scan_network() -> ScanReport {
     // Mock data for demonstration
     let hosts = vec![
         ("host1.example.com", "192.168.1.10", "managed"),
@@ -16,30 +140,190 @@ pub fn handle_scan_command(json: bool, csv: bool, verbose: bool, noaction: bool)
         ("printer", "192.168.1.20", "unmanaged"),
     ];
 
+    ScanReport {
+        scan_results: hosts
+            .into_iter()
+            .map(|(hostname, ip, status)| ScanResult {
+                hostname: hostname.to_string(),
+                ip: ip.to_string(),
+                status: status.to_string(),
+            })
+            .collect(),
+    }
+}
+
+/// Inventory hosts behind relays, which a scan of the local network cannot
+/// reach, checked through their relays instead
+fn scan_relayed(inventory: &Inventory) -> Vec<ScanResult> {
+    let hosts: Vec<InventoryHost> = inventory
+        .hosts
+        .iter()
+        .filter(|h| h.via.is_some())
+        .cloned()
+        .collect();
+    hosts
+        .iter()
+        .zip(poll_status(&hosts))
+        .map(|(host, result)| {
+            let address = host.address.as_deref().unwrap_or(&host.name);
+            let ip = address
+                .rsplit_once(':')
+                .filter(|(_, port)| port.parse::<u16>().is_ok())
+                .map_or(address, |(ip, _)| ip)
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            ScanResult {
+                hostname: host.name.clone(),
+                ip: ip.to_string(),
+                status: if result.is_ok() {
+                    "managed"
+                } else {
+                    "unreachable"
+                }
+                .to_string(),
+            }
+        })
+        .collect()
+}
+
+pub fn handle_scan_command(
+    json: bool,
+    csv: bool,
+    diff: &Option<Option<PathBuf>>,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing scan command");
+    }
+
+    if noaction {
+        println!("Would scan network for managed and unmanaged hosts");
+        if let Some(baseline) = diff {
+            match baseline {
+                Some(path) => println!("Would compare against {}", path.display()),
+                None => println!("Would compare against the last stored scan"),
+            }
+        }
+        return;
+    }
+
+    let mut report = scan_network();
+    report
+        .scan_results
+        .extend(scan_relayed(&Inventory::load_or_exit()));
+
+    if let Some(baseline) = diff {
+        let (path, required) = match baseline {
+            Some(path) => (path.clone(), true),
+            None => (state::state_dir().join(LAST_SCAN_FILE), false),
+        };
+        if verbose {
+            println!("Comparing against {}", path.display());
+        }
+        let previous = if !required && !path.exists() {
+            eprintln!("No previous scan recorded, treating every host as new");
+            ScanReport::default()
+        } else {
+            load_scan(&path).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            })
+        };
+        let changes = ScanDiff::between(&previous.scan_results, &report.scan_results);
+        print_diff(&changes, json, csv);
+    } else {
+        print_report(&report, json, csv);
+    }
+
+    match store_scan(&report) {
+        Ok(path) => {
+            if verbose {
+                println!("Stored scan results in {}", path.display());
+            }
+        }
+        Err(e) => eprintln!("Warning: {}", e),
+    }
+}
+
+fn print_report(report: &ScanReport, json: bool, csv: bool) {
     if json {
-        println!("{{");
-        println!("  \"scan_results\": [");
-        for (i, (hostname, ip, status)) in hosts.iter().enumerate() {
-            let comma = if i == hosts.len() - 1 { "" } else { "," };
-            println!("    {{");
-            println!("      \"hostname\": \"{}\",", hostname);
-            println!("      \"ip\": \"{}\",", ip);
-            println!("      \"status\": \"{}\"", status);
-            println!("    }}{}", comma);
-        }
-        println!("  ]");
-        println!("}}");
+        println!("{}", serde_json::to_string_pretty(report).unwrap());
     } else if csv {
         println!("hostname,ip,status");
-        for (hostname, ip, status) in hosts {
-            println!("{},{},{}", hostname, ip, status);
+        for host in &report.scan_results {
+            println!("{},{},{}", host.hostname, host.ip, host.status);
         }
     } else {
         println!("Network Scan Results:");
         println!("{:<20} {:<15} {:<10}", "Hostname", "IP Address", "Status");
         println!("{:-<20} {:-<15} {:-<10}", "", "", "");
-        for (hostname, ip, status) in hosts {
-            println!("{:<20} {:<15} {:<10}", hostname, ip, status);
+        for host in &report.scan_results {
+            println!("{:<20} {:<15} {:<10}", host.hostname, host.ip, host.status);
+        }
+    }
+}
+
+fn print_diff(diff: &ScanDiff, json: bool, csv: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(diff).unwrap());
+    } else if csv {
+        println!("change,ip,hostname,old,new");
+        for host in &diff.new {
+            println!("new,{},{},,{}", host.ip, host.hostname, host.status);
+        }
+        for host in &diff.vanished {
+            println!("vanished,{},{},{},", host.ip, host.hostname, host.status);
+        }
+        for change in &diff.hostname_changed {
+            println!(
+                "hostname,{},{},{},{}",
+                change.ip, change.hostname, change.old, change.new
+            );
+        }
+        for change in &diff.status_changed {
+            println!(
+                "status,{},{},{},{}",
+                change.ip, change.hostname, change.old, change.new
+            );
+        }
+    } else {
+        println!("Network Scan Changes:");
+        if diff.is_empty() {
+            println!("No changes since the previous scan");
+            return;
+        }
+        println!(
+            "{:<10} {:<15} {:<20} {:<20} {:<20}",
+            "Change", "IP Address", "Hostname", "Old", "New"
+        );
+        println!(
+            "{:-<10} {:-<15} {:-<20} {:-<20} {:-<20}",
+            "", "", "", "", ""
+        );
+        for host in &diff.new {
+            println!(
+                "{:<10} {:<15} {:<20} {:<20} {:<20}",
+                "new", host.ip, host.hostname, "", host.status
+            );
+        }
+        for host in &diff.vanished {
+            println!(
+                "{:<10} {:<15} {:<20} {:<20} {:<20}",
+                "vanished", host.ip, host.hostname, host.status, ""
+            );
+        }
+        for change in &diff.hostname_changed {
+            println!(
+                "{:<10} {:<15} {:<20} {:<20} {:<20}",
+                "hostname", change.ip, change.hostname, change.old, change.new
+            );
+        }
+        for change in &diff.status_changed {
+            println!(
+                "{:<10} {:<15} {:<20} {:<20} {:<20}",
+                "status", change.ip, change.hostname, change.old, change.new
+            );
         }
     }
 }
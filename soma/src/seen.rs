@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state;
+
+/// File in the state directory recording when each host was last heard from
+pub const LAST_SEEN_FILE: &str = "last_seen.json";
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Last-seen times by host name. A missing or unreadable file means nothing has been seen.
+pub fn load() -> BTreeMap<String, u64> {
+    fs::read_to_string(state::state_dir().join(LAST_SEEN_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Record hosts as seen, keeping the later time when a host is already recorded.
+///
+/// `soma serve` and interactive commands both record here, so the file is
+/// re-read before merging and replaced with a rename to avoid torn writes.
+/// Threads record one at a time so none loses another's update.
+pub fn record(seen: &BTreeMap<String, u64>) -> io::Result<PathBuf> {
+    static RECORDING: Mutex<()> = Mutex::new(());
+    let _recording = RECORDING.lock().unwrap();
+    let path = state::state_file(LAST_SEEN_FILE)?;
+    let mut merged = load();
+    for (host, at) in seen {
+        let entry = merged.entry(host.clone()).or_default();
+        *entry = (*entry).max(*at);
+    }
+    let content = serde_json::to_string_pretty(&merged).map_err(io::Error::other)?;
+    state::replace(&path, &content)?;
+    Ok(path)
+}
+
+/// Format a Unix timestamp as a UTC date and time
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc_times() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(1704110400), "2024-01-01 12:00:00");
+        assert_eq!(format_time(1709210096), "2024-02-29 12:34:56");
+    }
+}
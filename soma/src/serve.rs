@@ -0,0 +1,690 @@
+use somacommon::audit;
+use somacommon::prometheus::{self, Exposition, Family, MetricType};
+use somacommon::protocol::{self, AgentMessage, ControllerMessage, Request, Response};
+use somacommon::status::{Health, StatusReport};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::agent::READ_TIMEOUT;
+use crate::alerts::{AlertConfig, Alerter, Observed};
+use crate::check::poll_status_timed;
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{ConnectMode, Inventory, InventoryHost};
+use crate::maintenance::Maintenance;
+use crate::metrics::{self, Store};
+use crate::seen;
+use crate::signing;
+
+/// Heartbeats an agent may miss before its connection is dropped
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// Seconds between passes moving old metrics into coarser resolutions
+const COMPACT_INTERVAL: u64 = 600;
+
+// The fleet metric families exposed on /metrics. Dashboards and alerts are
+// built on these names and labels, so change them only with care.
+
+const AGENTS_CONNECTED: Family = Family {
+    name: "soma_agents_connected",
+    kind: MetricType::Gauge,
+    help: "Agents currently dialled in to the controller",
+    labels: &[],
+};
+/// Every health state is listed, with 0 for those no host is in
+const HOSTS: Family = Family {
+    name: "soma_hosts",
+    kind: MetricType::Gauge,
+    help: "Hosts by the health their latest status check found",
+    labels: &["health"],
+};
+const CHECK_DURATION: Family = Family {
+    name: "soma_check_duration_seconds",
+    kind: MetricType::Gauge,
+    help: "How long the latest status check of a host took, answered or not",
+    labels: &["host"],
+};
+const LAST_SEEN: Family = Family {
+    name: "soma_host_last_seen_timestamp_seconds",
+    kind: MetricType::Gauge,
+    help: "When a host's agent last answered or heartbeated",
+    labels: &["host"],
+};
+/// `request` is the request's type, e.g. `status` or `action`, and
+/// `outcome` is `ok`, `error` or `denied`
+const JOBS: Family = Family {
+    name: "soma_jobs_total",
+    kind: MetricType::Counter,
+    help: "Requests forwarded to agents on behalf of soma commands",
+    labels: &["request", "outcome"],
+};
+
+/// What the latest status check of a host found
+struct Checked {
+    health: Health,
+    duration: Duration,
+    report: Option<StatusReport>,
+}
+
+/// A connection an agent opened to the controller
+struct AgentConnection {
+    writer: Mutex<TcpStream>,
+    pending: Mutex<HashMap<u64, Sender<Response>>>,
+    last_heard: Mutex<Instant>,
+}
+
+impl AgentConnection {
+    /// Send a request to the agent and wait for its reply
+    fn request(&self, id: u64, request: Request) -> Response {
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let message = ControllerMessage::Request { id, request };
+        if let Err(e) = protocol::write_message(&mut *self.writer.lock().unwrap(), &message) {
+            self.pending.lock().unwrap().remove(&id);
+            return Response::Error {
+                message: format!("Failed to reach agent: {}", e),
+            };
+        }
+
+        match rx.recv_timeout(READ_TIMEOUT) {
+            Ok(response) => response,
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Response::Error {
+                    message: "Agent did not answer in time".to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// State shared between every connection handled by the controller
+pub struct Controller {
+    token: String,
+    heartbeat: Duration,
+    agents: Mutex<HashMap<String, Arc<AgentConnection>>>,
+    next_id: AtomicU64,
+    verbose: bool,
+    checked: Mutex<BTreeMap<String, Checked>>,
+    jobs: Mutex<BTreeMap<(String, &'static str), u64>>,
+}
+
+impl Controller {
+    pub fn new(token: &str, heartbeat: Duration, verbose: bool) -> Self {
+        Controller {
+            token: token.to_string(),
+            heartbeat,
+            agents: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            verbose,
+            checked: Mutex::new(BTreeMap::new()),
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record when each connected agent was last heard from, once per heartbeat interval
+    pub fn track_agents(self: Arc<Self>) {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(self.heartbeat);
+                let now = seen::now();
+                let heard: BTreeMap<String, u64> = self
+                    .agents
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, connection)| {
+                        let age = connection.last_heard.lock().unwrap().elapsed().as_secs();
+                        (name.clone(), now.saturating_sub(age))
+                    })
+                    .collect();
+                if !heard.is_empty()
+                    && let Err(e) = seen::record(&heard)
+                {
+                    eprintln!("Warning: Failed to record last-seen times: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Accept agents and local clients on `listener` until the process is stopped
+    pub fn serve(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let controller = Arc::clone(&self);
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = controller.handle_connection(stream)
+                    && controller.verbose
+                {
+                    eprintln!("Connection from {:?} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// The first message tells agents dialling in apart from soma clients
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let peer = stream.peer_addr()?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        if let Ok(AgentMessage::Hello { hostname, token }) = serde_json::from_str(&line) {
+            if !somacommon::tokens_match(&token, &protocol::agent_token(&self.token, &hostname)) {
+                let rejected = ControllerMessage::Rejected {
+                    message: "Invalid token".to_string(),
+                };
+                eprintln!("Rejected agent {} from {}: invalid token", hostname, peer);
+                return protocol::write_message(&mut writer, &rejected);
+            }
+            return self.handle_agent(hostname, peer, writer, reader);
+        }
+
+        match serde_json::from_str::<Request>(&line) {
+            Ok(request) => self.handle_client(peer, request, writer, reader),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    fn handle_agent(
+        &self,
+        hostname: String,
+        peer: SocketAddr,
+        writer: TcpStream,
+        mut reader: BufReader<TcpStream>,
+    ) -> io::Result<()> {
+        reader
+            .get_ref()
+            .set_read_timeout(Some(self.heartbeat * MISSED_HEARTBEATS))?;
+        let connection = Arc::new(AgentConnection {
+            writer: Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            last_heard: Mutex::new(Instant::now()),
+        });
+
+        // A live connection keeps its host name until it drops or misses its
+        // heartbeats, and a second agent claiming the name is turned away.
+        // The writer stays locked until the agent is welcomed, so no request
+        // reaches it first.
+        {
+            let mut writer = connection.writer.lock().unwrap();
+            let mut agents = self.agents.lock().unwrap();
+            if agents.contains_key(&hostname) {
+                drop(agents);
+                eprintln!(
+                    "Rejected agent {} from {}: already connected",
+                    hostname, peer
+                );
+                let rejected = ControllerMessage::Rejected {
+                    message: format!("An agent is already connected as {}", hostname),
+                };
+                return protocol::write_message(&mut *writer, &rejected);
+            }
+            agents.insert(hostname.clone(), Arc::clone(&connection));
+            drop(agents);
+            if let Err(e) = protocol::write_message(&mut *writer, &ControllerMessage::Welcome) {
+                self.agents.lock().unwrap().remove(&hostname);
+                return Err(e);
+            }
+        }
+        if self.verbose {
+            println!("Agent {} connected from {}", hostname, peer);
+        }
+
+        let result = loop {
+            match protocol::read_message::<_, AgentMessage>(&mut reader) {
+                Ok(Some(message)) => {
+                    *connection.last_heard.lock().unwrap() = Instant::now();
+                    if let AgentMessage::Reply { id, response } = message
+                        && let Some(tx) = connection.pending.lock().unwrap().remove(&id)
+                    {
+                        let _ = tx.send(*response);
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        // Only forget the agent if the name is still registered to it
+        let mut agents = self.agents.lock().unwrap();
+        if agents
+            .get(&hostname)
+            .is_some_and(|c| Arc::ptr_eq(c, &connection))
+        {
+            agents.remove(&hostname);
+        }
+        if self.verbose {
+            println!("Agent {} disconnected", hostname);
+        }
+        result
+    }
+
+    /// Forward requests from a local soma client to the agent they name
+    fn handle_client(
+        &self,
+        peer: SocketAddr,
+        first: Request,
+        mut writer: TcpStream,
+        mut reader: BufReader<TcpStream>,
+    ) -> io::Result<()> {
+        let mut next = Some(first);
+        while let Some(request) = next {
+            let response = if peer.ip().is_loopback() {
+                self.forward(request)
+            } else {
+                Response::Error {
+                    message: "Requests are only accepted from the local machine".to_string(),
+                }
+            };
+            protocol::write_message(&mut writer, &response)?;
+            next = protocol::read_message(&mut reader)?;
+        }
+        Ok(())
+    }
+
+    /// Remember what a status check of a host found, for /metrics
+    fn record_check(&self, host: &str, report: Option<&StatusReport>, duration: Duration) {
+        let checked = Checked {
+            health: report.map_or(Health::Unknown, |r| r.health),
+            duration,
+            report: report.cloned(),
+        };
+        self.checked
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), checked);
+    }
+
+    /// Status reports from the agents soma dials that answer, by host name
+    fn check_dialled(&self, hosts: &[InventoryHost]) -> Vec<(String, StatusReport)> {
+        hosts
+            .iter()
+            .zip(poll_status_timed(hosts))
+            .filter_map(|(host, (result, duration))| {
+                self.record_check(&host.name, result.as_ref().ok(), duration);
+                result.ok().map(|report| (host.name.clone(), report))
+            })
+            .collect()
+    }
+
+    /// The reports the latest status checks of some hosts found, for those
+    /// that answered
+    fn latest(&self, hosts: &[InventoryHost]) -> Vec<(String, StatusReport)> {
+        let checked = self.checked.lock().unwrap();
+        hosts
+            .iter()
+            .filter_map(|host| {
+                let report = checked.get(&host.name)?.report.clone()?;
+                Some((host.name.clone(), report))
+            })
+            .collect()
+    }
+
+    /// Status reports from every connected agent that answers, by agent name
+    fn statuses(&self) -> Vec<(String, StatusReport)> {
+        let agents: Vec<(String, Arc<AgentConnection>)> = self
+            .agents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, connection)| (name.clone(), Arc::clone(connection)))
+            .collect();
+        fan_out(&agents, DEFAULT_PARALLELISM, |(name, connection)| {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            let started = Instant::now();
+            let response = match signing::sign_for(name, Request::Status) {
+                Ok(request) => connection.request(id, request),
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            };
+            let report = match response {
+                Response::Status(report) => Some(report),
+                _ => None,
+            };
+            self.record_check(name, report.as_ref(), started.elapsed());
+            report.map(|report| (name.clone(), report))
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn forward(&self, request: Request) -> Response {
+        let Request::Forward { target, request } = request else {
+            return Response::Error {
+                message: "The controller only forwards requests to agents".to_string(),
+            };
+        };
+        let kind = audit::command(request.inner());
+        let connection = self.agents.lock().unwrap().get(&target).cloned();
+        let response = match connection {
+            Some(connection) => {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                connection.request(id, *request)
+            }
+            None => Response::Error {
+                message: format!("Agent {} is not connected", target),
+            },
+        };
+        let outcome = match response {
+            Response::Error { .. } => "error",
+            Response::Denied(_) => "denied",
+            _ => "ok",
+        };
+        *self
+            .jobs
+            .lock()
+            .unwrap()
+            .entry((kind, outcome))
+            .or_default() += 1;
+        response
+    }
+
+    /// The fleet's /metrics page
+    fn exposition(&self) -> String {
+        let mut page = Exposition::new();
+        page.add_one(&AGENTS_CONNECTED, self.agents.lock().unwrap().len() as f64);
+
+        let checked = self.checked.lock().unwrap();
+        let states = [
+            Health::Healthy,
+            Health::Warning,
+            Health::Critical,
+            Health::Unknown,
+        ];
+        page.add(
+            &HOSTS,
+            states.iter().map(|state| {
+                let hosts = checked.values().filter(|c| c.health == *state).count();
+                (vec![state.to_string()], hosts as f64)
+            }),
+        );
+        page.add(
+            &CHECK_DURATION,
+            checked
+                .iter()
+                .map(|(host, c)| (vec![host.clone()], c.duration.as_secs_f64())),
+        );
+        drop(checked);
+
+        page.add(
+            &LAST_SEEN,
+            seen::load()
+                .into_iter()
+                .map(|(host, at)| (vec![host], at as f64)),
+        );
+        page.add(
+            &JOBS,
+            self.jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|((kind, outcome), n)| (vec![kind.clone(), outcome.to_string()], *n as f64)),
+        );
+        page.into_text()
+    }
+}
+
+pub fn handle_agent_token_command(
+    hostname: &str,
+    token: &Option<String>,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing agent-token command");
+    }
+
+    let Some(token) = token.as_ref().filter(|t| !t.is_empty()) else {
+        eprintln!(
+            "Error: The controller's agent token is required, use --token or SOMA_AGENT_TOKEN"
+        );
+        std::process::exit(1);
+    };
+    if hostname.trim().is_empty() {
+        eprintln!("Error: A host name is required");
+        std::process::exit(1);
+    }
+    if noaction {
+        println!("Would print the token for agent {}", hostname);
+        return;
+    }
+    println!("{}", protocol::agent_token(token, hostname));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_serve_command(
+    listen: &str,
+    token: &Option<String>,
+    heartbeat: u64,
+    poll: Option<u64>,
+    scrape: Option<u64>,
+    retention: u64,
+    http: &Option<String>,
+    alerts: &Option<PathBuf>,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing serve command");
+    }
+
+    let Some(token) = token.as_ref().filter(|t| !t.is_empty()) else {
+        eprintln!("Error: An agent token is required, use --token or SOMA_AGENT_TOKEN");
+        std::process::exit(1);
+    };
+    if heartbeat == 0 {
+        eprintln!("Error: Heartbeat interval must be at least 1 second");
+        std::process::exit(1);
+    }
+
+    if poll == Some(0) {
+        eprintln!("Error: Poll interval must be at least 1 second");
+        std::process::exit(1);
+    }
+    if scrape == Some(0) {
+        eprintln!("Error: Scrape interval must be at least 1 second");
+        std::process::exit(1);
+    }
+
+    let alerts = alerts.as_ref().map(|path| {
+        AlertConfig::load(path).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+    });
+
+    if noaction {
+        println!("Would listen on {} for agent connections", listen);
+        if let Some(poll) = poll {
+            println!("Would poll inventory agents every {} seconds", poll);
+        }
+        if let Some(scrape) = scrape {
+            println!(
+                "Would scrape agent metrics every {} seconds, keeping them for {} days",
+                scrape,
+                retention / 86400
+            );
+        }
+        if let Some(http) = http {
+            println!("Would serve fleet metrics on http://{}/metrics", http);
+        }
+        if let Some(alerts) = &alerts {
+            println!(
+                "Would evaluate {} alert rules every {} seconds, notifying {} sinks",
+                alerts.rules.len(),
+                alerts.interval,
+                alerts.sinks.len()
+            );
+        }
+        return;
+    }
+
+    let listener = TcpListener::bind(listen).unwrap_or_else(|e| {
+        eprintln!("Error: Failed to listen on {}: {}", listen, e);
+        std::process::exit(1);
+    });
+    println!(
+        "Listening on {} for agent connections",
+        listener
+            .local_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default()
+    );
+
+    let controller = Arc::new(Controller::new(
+        token,
+        Duration::from_secs(heartbeat),
+        verbose,
+    ));
+    Arc::clone(&controller).track_agents();
+    if let Some(http) = http {
+        let listener = TcpListener::bind(http).unwrap_or_else(|e| {
+            eprintln!("Error: Failed to listen on {}: {}", http, e);
+            std::process::exit(1);
+        });
+        let controller = Arc::clone(&controller);
+        thread::spawn(move || prometheus::serve(listener, move || controller.exposition()));
+    }
+    if let Some(poll) = poll {
+        poll_inventory(Arc::clone(&controller), Duration::from_secs(poll), verbose);
+    }
+    if let Some(scrape) = scrape {
+        scrape_agents(
+            Arc::clone(&controller),
+            Duration::from_secs(scrape),
+            poll.is_some(),
+            Store::new(retention),
+            verbose,
+        );
+    }
+    if let Some(alerts) = alerts {
+        watch_alerts(
+            Arc::clone(&controller),
+            alerts,
+            Store::new(retention),
+            verbose,
+        );
+    }
+    controller.serve(listener);
+}
+
+/// Poll the agents soma dials on a schedule so their last-seen times stay current
+fn poll_inventory(controller: Arc<Controller>, interval: Duration, verbose: bool) {
+    let inventory = Inventory::load_or_exit();
+    let hosts: Vec<_> = inventory
+        .hosts
+        .into_iter()
+        .filter(|h| h.mode == ConnectMode::Dial)
+        .collect();
+    thread::spawn(move || {
+        loop {
+            let answered = controller.check_dialled(&hosts).len();
+            if verbose {
+                println!("Polled {} agents, {} answered", hosts.len(), answered);
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+/// Store the metrics of every agent, dialled or connected, on a schedule.
+/// When the dialled agents are `polled` anyway, their latest reports are
+/// stored rather than asking them again.
+fn scrape_agents(
+    controller: Arc<Controller>,
+    interval: Duration,
+    polled: bool,
+    store: Store,
+    verbose: bool,
+) {
+    let inventory = Inventory::load_or_exit();
+    let hosts: Vec<_> = inventory
+        .hosts
+        .into_iter()
+        .filter(|h| h.mode == ConnectMode::Dial)
+        .collect();
+    thread::spawn(move || {
+        let mut compacted = 0;
+        loop {
+            let now = seen::now();
+            let mut reports = if polled {
+                controller.latest(&hosts)
+            } else {
+                controller.check_dialled(&hosts)
+            };
+            reports.extend(controller.statuses());
+            let samples: Vec<_> = reports
+                .iter()
+                .flat_map(|(host, report)| metrics::samples(host, report, now))
+                .collect();
+            if let Err(e) = store.append(&samples) {
+                eprintln!("Warning: Failed to store metrics: {}", e);
+            }
+            if now >= compacted + COMPACT_INTERVAL {
+                if let Err(e) = store.compact(now) {
+                    eprintln!("Warning: Failed to compact metrics: {}", e);
+                }
+                compacted = now;
+            }
+            if verbose {
+                println!(
+                    "Scraped {} samples from {} agents",
+                    samples.len(),
+                    reports.len()
+                );
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+/// Evaluate the alert rules on a schedule and notify their sinks of changes
+fn watch_alerts(controller: Arc<Controller>, config: AlertConfig, store: Store, verbose: bool) {
+    let inventory = Inventory::load_or_exit();
+    let mut alerter = Alerter::load();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(config.interval));
+            let health: BTreeMap<String, Health> = controller
+                .checked
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(host, checked)| (host.clone(), checked.health))
+                .collect();
+            let now = seen::now();
+            let findings = config.evaluate(&Observed {
+                now,
+                health: &health,
+                last_seen: &seen::load(),
+                inventory: &inventory,
+                store: &store,
+            });
+            let notifications = alerter.update(&config, findings, &Maintenance::load(), now);
+            if notifications.is_empty() {
+                continue;
+            }
+            config.dispatch(&notifications, verbose);
+            if let Err(e) = alerter.save() {
+                eprintln!("Warning: Failed to record firing alerts: {}", e);
+            }
+        }
+    });
+}
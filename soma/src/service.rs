@@ -0,0 +1,174 @@
+use serde::Serialize;
+use somacommon::service::{ServiceOperation, ServiceResult};
+
+use crate::agent::{self, AgentError};
+use crate::fanout::{DEFAULT_PARALLELISM, fan_out};
+use crate::inventory::{Inventory, InventoryHost};
+use crate::maintenance;
+use crate::rollout::{self, Rollout, RolloutSummary};
+
+/// One row of the service table
+#[derive(Serialize)]
+struct ServiceRow<'a> {
+    hostname: &'a str,
+    #[serde(flatten)]
+    result: Option<&'a ServiceResult>,
+    error: Option<String>,
+}
+
+/// Results of a staged run with the hosts it got through
+#[derive(Serialize)]
+struct StagedReport<'a> {
+    results: Vec<ServiceRow<'a>>,
+    rollout: RolloutSummary,
+}
+
+/// Run a service operation on every host in parallel
+pub fn run_service(
+    hosts: &[InventoryHost],
+    unit: &str,
+    operation: ServiceOperation,
+    noaction: bool,
+) -> Vec<Result<ServiceResult, AgentError>> {
+    fan_out(hosts, DEFAULT_PARALLELISM, |host| {
+        agent::run_service(host, unit, operation, noaction)
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_service_command(
+    json: bool,
+    csv: bool,
+    hosts: &str,
+    operation: &str,
+    unit: &str,
+    rollout: &Rollout,
+    force: bool,
+    verbose: bool,
+    noaction: bool,
+) {
+    if verbose {
+        println!("Executing service command");
+    }
+
+    let operation: ServiceOperation = operation.parse().unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let inventory = Inventory::load_or_exit();
+    let mut targets = inventory.resolve(&[hosts.to_string()]);
+    // Asking for a unit's status changes nothing, so maintenance does not block it
+    if operation != ServiceOperation::Status {
+        targets = maintenance::skip_blocked(targets, force);
+    }
+    if targets.is_empty() {
+        eprintln!("Error: No hosts to manage {} on", unit);
+        std::process::exit(1);
+    }
+
+    // Agents answer noaction requests with the current state and the change they would make
+    let mut touched: Vec<(String, Result<ServiceResult, AgentError>)> = Vec::new();
+    let summary = rollout.run(&targets, |_, batch| {
+        let results = run_service(batch, unit, operation, noaction);
+        let failed = results.iter().filter(|r| r.is_err()).count();
+        touched.extend(batch.iter().map(|h| h.name.clone()).zip(results));
+        Ok(failed)
+    });
+    let rows: Vec<ServiceRow> = touched
+        .iter()
+        .map(|(hostname, result)| ServiceRow {
+            hostname,
+            result: result.as_ref().ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        })
+        .collect();
+    let failed = summary.aborted.is_some() || rows.iter().any(|r| r.error.is_some());
+
+    if json && rollout.is_staged() {
+        let report = StagedReport {
+            results: rows,
+            rollout: summary,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+    } else if csv {
+        println!("hostname,unit,active,sub,since,main_pid,enabled,changed,message");
+        for row in &rows {
+            match (row.result, &row.error) {
+                (Some(result), _) => {
+                    let state = &result.state;
+                    println!(
+                        "{},{},{},{},{},{},{},{},{}",
+                        row.hostname,
+                        state.unit,
+                        state.active_state,
+                        state.sub_state,
+                        state.since,
+                        state.main_pid.map(|p| p.to_string()).unwrap_or_default(),
+                        state.unit_file_state,
+                        result.changed,
+                        result.message
+                    );
+                }
+                (None, error) => println!(
+                    "{},{},,,,,,false,{}",
+                    row.hostname,
+                    unit,
+                    error.as_deref().unwrap_or_default()
+                ),
+            }
+        }
+    } else {
+        print_table(&rows);
+    }
+    rollout::print_summary(&summary);
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn print_table(rows: &[ServiceRow]) {
+    println!(
+        "{:<20} {:<10} {:<10} {:<10} {:<8} Result",
+        "Hostname", "Active", "Sub", "Enabled", "PID"
+    );
+    println!(
+        "{:-<20} {:-<10} {:-<10} {:-<10} {:-<8} {:-<30}",
+        "", "", "", "", "", ""
+    );
+    for row in rows {
+        match (row.result, &row.error) {
+            (Some(result), _) => {
+                let state = &result.state;
+                println!(
+                    "{:<20} {:<10} {:<10} {:<10} {:<8} {}",
+                    row.hostname,
+                    state.active_state,
+                    state.sub_state,
+                    state.unit_file_state,
+                    state
+                        .main_pid
+                        .map(|p| p.to_string())
+                        .unwrap_or("-".to_string()),
+                    result.message
+                );
+            }
+            (None, error) => println!(
+                "{:<20} {:<10} {:<10} {:<10} {:<8} {}",
+                row.hostname,
+                "-",
+                "-",
+                "-",
+                "-",
+                error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
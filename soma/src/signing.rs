@@ -0,0 +1,161 @@
+use somacommon::protocol::Request;
+use somacommon::signing::{self, KEY_LENGTH, SigningKey, decode_key, encode_key, public_key};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::audit;
+use crate::inventory::{self, InventoryHost};
+use crate::seen;
+
+/// Environment variable naming the file holding soma's signing key
+pub const SIGNING_KEY_ENV: &str = "SOMA_SIGNING_KEY";
+
+/// Default location of the signing key
+pub fn default_path() -> PathBuf {
+    match env::var_os(SIGNING_KEY_ENV) {
+        Some(path) => PathBuf::from(path),
+        None => inventory::config_dir().join("signing.key"),
+    }
+}
+
+/// Read `N` bytes from the system's random number generator
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Load the signing key, `None` when there is no file and requests are
+/// sent unsigned
+pub fn load(path: &Path) -> Result<Option<SigningKey>, KeyError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        fs::read_to_string(path).map_err(|e| KeyError::ReadError(path.to_path_buf(), e))?;
+    let seed = decode_key(&content).map_err(|e| KeyError::Invalid(path.to_path_buf(), e))?;
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        eprintln!(
+            "Warning: Signing key {} can be read by other users",
+            path.display()
+        );
+    }
+    Ok(Some(SigningKey::from_bytes(&seed)))
+}
+
+/// The signing key in the default location, loaded once, exiting with an
+/// error message when it cannot be read
+fn key() -> Option<&'static SigningKey> {
+    static KEY: OnceLock<Option<SigningKey>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        load(&default_path()).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+    })
+    .as_ref()
+}
+
+/// Sign a request for a host with soma's key, if it has one. Requests to
+/// hosts reached through a relay must be signed, so the relay cannot alter
+/// them or make up its own.
+pub fn sign(host: &InventoryHost, request: Request) -> io::Result<Request> {
+    if key().is_none()
+        && let Some(via) = &host.via
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} is reached through {}, which needs a signing key; create one with 'soma keygen'",
+                host.name, via
+            ),
+        ));
+    }
+    sign_for(&host.name, request)
+}
+
+/// Sign a request for the host named `host` with soma's key, leaving it
+/// unsigned when there is none
+pub fn sign_for(host: &str, request: Request) -> io::Result<Request> {
+    let Some(key) = key() else {
+        return Ok(request);
+    };
+    let nonce = somacommon::digest::to_hex(&random::<16>()?);
+    Ok(signing::sign(key, host, &nonce, seen::now(), request))
+}
+
+pub fn handle_keygen_command(file: &Option<PathBuf>, verbose: bool, noaction: bool) {
+    if verbose {
+        println!("Executing keygen command");
+    }
+
+    let path = file.clone().unwrap_or_else(default_path);
+    if path.exists() {
+        eprintln!(
+            "Error: {} already exists; remove it first to replace the key agents trust",
+            path.display()
+        );
+        std::process::exit(1);
+    }
+    if noaction {
+        println!("Would write a new signing key to {}", path.display());
+        return;
+    }
+
+    let key = random::<KEY_LENGTH>()
+        .map(|seed| SigningKey::from_bytes(&seed))
+        .and_then(|key| write_key(&path, &key).map(|()| key))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: Failed to write {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+    println!("Wrote signing key to {}", path.display());
+    println!("Add its public key to each agent's configuration, as the operator it signs for:");
+    println!();
+    println!("[signing.operators]");
+    println!(
+        "\"{}\" = \"{}\"",
+        audit::operator(),
+        encode_key(&public_key(&key))
+    );
+}
+
+fn write_key(path: &Path, key: &SigningKey) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", encode_key(&key.to_bytes()))
+}
+
+/// Signing key error types
+#[derive(Debug)]
+pub enum KeyError {
+    ReadError(PathBuf, io::Error),
+    Invalid(PathBuf, String),
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::ReadError(path, err) => {
+                write!(f, "Error reading signing key {}: {}", path.display(), err)
+            }
+            KeyError::Invalid(path, reason) => {
+                write!(f, "Invalid signing key {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
@@ -0,0 +1,83 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Environment variable that overrides where soma keeps its local state
+pub const STATE_DIR_ENV: &str = "SOMA_STATE_DIR";
+
+/// Directory where soma keeps state between runs (previous scans and the like).
+///
+/// `SOMA_STATE_DIR` takes precedence, then `$XDG_STATE_HOME/soma`, then
+/// `$HOME/.local/state/soma`, falling back to `./.soma` if none are set.
+pub fn state_dir() -> PathBuf {
+    if let Some(dir) = env::var_os(STATE_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+        return PathBuf::from(dir).join("soma");
+    }
+    if let Some(home) = env::var_os("HOME") {
+        return PathBuf::from(home).join(".local/state/soma");
+    }
+    PathBuf::from(".soma")
+}
+
+/// Path of a named file inside the state directory, creating the directory if needed
+pub fn state_file(name: &str) -> io::Result<PathBuf> {
+    let dir = state_dir();
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(name))
+}
+
+/// Replace a file with a rename so readers never see it half written.
+///
+/// Each write goes through its own temporary file next to `path`, so threads
+/// and processes saving the same file at once never share one.
+pub fn replace(path: &Path, content: &str) -> io::Result<()> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(
+        ".{}.{}.{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .and_then(|()| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_replaces_use_their_own_temp_files() {
+        let dir = env::temp_dir().join(format!("soma-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("last_seen.json");
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        replace(path, &format!("{{\"writer\":{}}}", i)).unwrap();
+                    }
+                });
+            }
+        });
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("{\"writer\":"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
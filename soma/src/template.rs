@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use crate::inventory::InventoryHost;
+
+/// Substitute `{{ key }}` placeholders with the host's values, see
+/// [`InventoryHost::value`], falling back to `defaults`. An unknown key is an
+/// error rather than left blank.
+pub fn render(
+    template: &str,
+    host: &InventoryHost,
+    defaults: &BTreeMap<String, String>,
+) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            return Err("Unclosed {{ in template".to_string());
+        };
+        let key = rest[start + 2..start + end].trim();
+        match host.value(key).or(defaults.get(key).map(String::as_str)) {
+            Some(value) => out.push_str(value),
+            None => return Err(format!("{} has no value for {}", host.name, key)),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_host_values() {
+        let mut host = InventoryHost {
+            name: "web-01".to_string(),
+            ..Default::default()
+        };
+        host.vars.insert("env".to_string(), "prod".to_string());
+        host.facts
+            .insert("cpu.threads".to_string(), "8".to_string());
+        let none = BTreeMap::new();
+        assert_eq!(
+            render(
+                "server {{ name }} # {{env}}, {{ cpu.threads }} workers\n",
+                &host,
+                &none
+            )
+            .unwrap(),
+            "server web-01 # prod, 8 workers\n"
+        );
+        assert!(render("{{ missing }}", &host, &none).is_err());
+        assert!(render("{{ name", &host, &none).is_err());
+
+        let defaults = BTreeMap::from([
+            ("env".to_string(), "staging".to_string()),
+            ("release".to_string(), "1.2".to_string()),
+        ]);
+        assert_eq!(
+            render("{{ env }} {{ release }}", &host, &defaults).unwrap(),
+            "prod 1.2"
+        );
+    }
+}
@@ -0,0 +1,735 @@
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+use somacommon::protocol::ActionResult;
+use somacommon::selector::Selectable;
+use somacommon::status::{Health, StatusReport};
+use std::io::{self, IsTerminal, Stdout, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::agent::{self, AgentError};
+use crate::check::poll_status;
+use crate::inventory::{Inventory, InventoryHost};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+    Host,
+    Health,
+    Load,
+    Memory,
+    Disk,
+}
+
+impl SortKey {
+    fn next(self) -> Self {
+        match self {
+            SortKey::Host => SortKey::Health,
+            SortKey::Health => SortKey::Load,
+            SortKey::Load => SortKey::Memory,
+            SortKey::Memory => SortKey::Disk,
+            SortKey::Disk => SortKey::Host,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SortKey::Host => "host",
+            SortKey::Health => "health",
+            SortKey::Load => "load",
+            SortKey::Memory => "memory",
+            SortKey::Disk => "disk",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum View {
+    Table,
+    Detail,
+    Filter(String),
+    Actions,
+    Confirm(String),
+}
+
+/// Messages from the background workers to the UI
+enum Update {
+    Status(Vec<Result<StatusReport, AgentError>>),
+    Action(String, Result<ActionResult, AgentError>),
+}
+
+struct Row {
+    host: InventoryHost,
+    report: Option<StatusReport>,
+    error: Option<String>,
+}
+
+impl Row {
+    fn health(&self) -> Health {
+        self.report.as_ref().map_or(Health::Unknown, |r| r.health)
+    }
+
+    fn load(&self) -> f64 {
+        self.report.as_ref().map_or(0.0, |r| r.load.one)
+    }
+
+    fn memory(&self) -> f64 {
+        self.report
+            .as_ref()
+            .map_or(0.0, |r| r.memory.used_percent())
+    }
+
+    fn disk(&self) -> f64 {
+        self.report.as_ref().map_or(0.0, |r| r.disk_used_percent())
+    }
+}
+
+struct App {
+    rows: Vec<Row>,
+    filter: String,
+    sort: SortKey,
+    selected: usize,
+    view: View,
+    message: String,
+    updated: Option<Instant>,
+    noaction: bool,
+}
+
+impl App {
+    fn new(hosts: Vec<InventoryHost>, noaction: bool) -> Self {
+        App {
+            rows: hosts
+                .into_iter()
+                .map(|host| Row {
+                    host,
+                    report: None,
+                    error: None,
+                })
+                .collect(),
+            filter: String::new(),
+            sort: SortKey::Host,
+            selected: 0,
+            view: View::Table,
+            message: "Polling agents...".to_string(),
+            updated: None,
+            noaction,
+        }
+    }
+
+    /// Indexes of the rows passing the filter, in display order
+    fn visible(&self) -> Vec<usize> {
+        let mut visible: Vec<usize> = (0..self.rows.len())
+            .filter(|i| self.filter.is_empty() || self.rows[*i].host.selected_by(&self.filter))
+            .collect();
+        let rows = &self.rows;
+        match self.sort {
+            SortKey::Host => visible.sort_by(|a, b| rows[*a].host.name.cmp(&rows[*b].host.name)),
+            SortKey::Health => visible.sort_by_key(|i| std::cmp::Reverse(rows[*i].health())),
+            SortKey::Load => visible.sort_by(|a, b| rows[*b].load().total_cmp(&rows[*a].load())),
+            SortKey::Memory => {
+                visible.sort_by(|a, b| rows[*b].memory().total_cmp(&rows[*a].memory()))
+            }
+            SortKey::Disk => visible.sort_by(|a, b| rows[*b].disk().total_cmp(&rows[*a].disk())),
+        }
+        visible
+    }
+
+    fn current(&self) -> Option<&Row> {
+        let visible = self.visible();
+        visible.get(self.selected).map(|i| &self.rows[*i])
+    }
+
+    fn apply(&mut self, results: Vec<Result<StatusReport, AgentError>>) {
+        for (row, result) in self.rows.iter_mut().zip(results) {
+            match result {
+                Ok(report) => {
+                    row.report = Some(report);
+                    row.error = None;
+                }
+                Err(e) => {
+                    row.report = None;
+                    row.error = Some(e.to_string());
+                }
+            }
+        }
+        self.updated = Some(Instant::now());
+    }
+}
+
+/// Restores the terminal when the dashboard exits, including on panic
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter(stdout: &mut Stdout) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub fn handle_top_command(selector: &Option<String>, interval: u64, verbose: bool, noaction: bool) {
+    if verbose {
+        println!("Executing top command");
+    }
+
+    let inventory = Inventory::load_or_exit();
+    let hosts: Vec<InventoryHost> = match selector {
+        Some(selector) => inventory.select(selector).into_iter().cloned().collect(),
+        None => inventory.hosts.clone(),
+    };
+
+    if hosts.is_empty() {
+        eprintln!("Error: No inventory hosts to show");
+        std::process::exit(1);
+    }
+    if interval == 0 {
+        eprintln!("Error: Refresh interval must be at least 1 second");
+        std::process::exit(1);
+    }
+    if !io::stdout().is_terminal() {
+        eprintln!("Error: soma top needs a terminal, use 'soma check --watch' instead");
+        std::process::exit(1);
+    }
+
+    if let Err(e) = run(hosts, Duration::from_secs(interval), noaction) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(hosts: Vec<InventoryHost>, interval: Duration, noaction: bool) -> io::Result<()> {
+    let (updates_tx, updates) = mpsc::channel();
+    let (refresh_tx, refresh) = mpsc::channel();
+    spawn_poller(hosts.clone(), interval, updates_tx.clone(), refresh);
+
+    let mut app = App::new(hosts, noaction);
+
+    let mut stdout = io::stdout();
+    let _guard = TerminalGuard::enter(&mut stdout)?;
+
+    loop {
+        while let Ok(update) = updates.try_recv() {
+            match update {
+                Update::Status(results) => app.apply(results),
+                Update::Action(host, Ok(result)) => {
+                    app.message = describe_action(&host, &result);
+                }
+                Update::Action(host, Err(e)) => {
+                    app.message = format!("{}: {}", host, e);
+                }
+            }
+        }
+
+        draw(&mut stdout, &app)?;
+
+        if !event::poll(Duration::from_millis(250))? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if !handle_key(&mut app, key, &refresh_tx, &updates_tx) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Poll every agent on a timer, or immediately when asked through `refresh`
+fn spawn_poller(
+    hosts: Vec<InventoryHost>,
+    interval: Duration,
+    updates: Sender<Update>,
+    refresh: Receiver<()>,
+) {
+    thread::spawn(move || {
+        loop {
+            if updates.send(Update::Status(poll_status(&hosts))).is_err() {
+                break;
+            }
+            match refresh.recv_timeout(interval) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+fn describe_action(host: &str, result: &ActionResult) -> String {
+    let output = if result.stderr.trim().is_empty() {
+        result.stdout.trim()
+    } else {
+        result.stderr.trim()
+    };
+    let first_line = output.lines().next().unwrap_or_default();
+    match result.exit_code {
+        Some(code) => format!("{}: {} exited {} {}", host, result.name, code, first_line),
+        None => format!("{}: {} {}", host, result.name, first_line),
+    }
+}
+
+/// Handle a key press, returning false when the dashboard should exit
+fn handle_key(
+    app: &mut App,
+    key: KeyEvent,
+    refresh: &Sender<()>,
+    updates: &Sender<Update>,
+) -> bool {
+    if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+        return false;
+    }
+
+    match app.view.clone() {
+        View::Filter(mut input) => match key.code {
+            KeyCode::Enter => {
+                app.filter = input;
+                app.selected = 0;
+                app.view = View::Table;
+            }
+            KeyCode::Esc => app.view = View::Table,
+            KeyCode::Backspace => {
+                input.pop();
+                app.view = View::Filter(input);
+            }
+            KeyCode::Char(c) => {
+                input.push(c);
+                app.view = View::Filter(input);
+            }
+            _ => {}
+        },
+        View::Actions => {
+            let actions = app
+                .current()
+                .and_then(|row| row.report.as_ref())
+                .map(|r| r.actions.clone())
+                .unwrap_or_default();
+            match key.code {
+                KeyCode::Char(c) if c.is_ascii_digit() => {
+                    let index = c.to_digit(10).unwrap() as usize;
+                    if let Some(name) = index.checked_sub(1).and_then(|i| actions.get(i)) {
+                        app.view = View::Confirm(name.clone());
+                    }
+                }
+                KeyCode::Esc | KeyCode::Char('q') => app.view = View::Table,
+                _ => {}
+            }
+        }
+        View::Confirm(name) => {
+            if key.code == KeyCode::Char('y') {
+                if let Some(row) = app.current() {
+                    let host = row.host.clone();
+                    let noaction = app.noaction;
+                    let updates = updates.clone();
+                    app.message = format!("{}: running {}...", host.name, name);
+                    let name = name.clone();
+                    thread::spawn(move || {
+                        let result = agent::run_action(&host, &name, noaction);
+                        let _ = updates.send(Update::Action(host.name, result));
+                    });
+                }
+            } else {
+                app.message = "Action cancelled".to_string();
+            }
+            app.view = View::Table;
+        }
+        View::Detail => match key.code {
+            KeyCode::Esc | KeyCode::Backspace | KeyCode::Enter => app.view = View::Table,
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('c') => {
+                let _ = refresh.send(());
+                app.message = "Checking...".to_string();
+            }
+            KeyCode::Char('a') => app.view = View::Actions,
+            _ => {}
+        },
+        View::Table => match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up | KeyCode::Char('k') => app.selected = app.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                let last = app.visible().len().saturating_sub(1);
+                app.selected = (app.selected + 1).min(last);
+            }
+            KeyCode::Char('s') => app.sort = app.sort.next(),
+            KeyCode::Char('/') => app.view = View::Filter(app.filter.clone()),
+            KeyCode::Enter if app.current().is_some() => app.view = View::Detail,
+            KeyCode::Char('c') => {
+                let _ = refresh.send(());
+                app.message = "Checking...".to_string();
+            }
+            KeyCode::Char('a') if app.current().is_some_and(|row| row.report.is_some()) => {
+                app.view = View::Actions;
+            }
+            _ => {}
+        },
+    }
+    true
+}
+
+fn format_uptime(secs: u64) -> String {
+    let days = secs / 86400;
+    let hours = secs % 86400 / 3600;
+    let minutes = secs % 3600 / 60;
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else {
+        format!("{}h {}m", hours, minutes)
+    }
+}
+
+/// Fit a line to the terminal width
+fn fit(line: String, width: usize) -> String {
+    line.chars().take(width).collect()
+}
+
+fn draw(stdout: &mut Stdout, app: &App) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let (width, height) = (width as usize, height as usize);
+    queue!(stdout, cursor::MoveTo(0, 0), Clear(ClearType::All))?;
+
+    let updated = match app.updated {
+        Some(at) => format!("updated {}s ago", at.elapsed().as_secs()),
+        None => "waiting for agents".to_string(),
+    };
+    let filter = if app.filter.is_empty() {
+        "all".to_string()
+    } else {
+        app.filter.clone()
+    };
+    let mut lines: Vec<(String, bool)> = vec![(
+        format!(
+            "soma top - {} hosts - filter: {} - sort: {} - {}{}",
+            app.rows.len(),
+            filter,
+            app.sort.label(),
+            updated,
+            if app.noaction { " - no-action" } else { "" }
+        ),
+        false,
+    )];
+    lines.push((String::new(), false));
+
+    match &app.view {
+        View::Detail | View::Actions | View::Confirm(_) => detail_lines(app, &mut lines),
+        View::Table | View::Filter(_) => table_lines(app, &mut lines),
+    }
+
+    for (row, (line, highlight)) in lines.into_iter().take(height.saturating_sub(2)).enumerate() {
+        queue!(stdout, cursor::MoveTo(0, row as u16))?;
+        if highlight {
+            queue!(
+                stdout,
+                SetAttribute(Attribute::Reverse),
+                Print(fit(line, width)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        } else {
+            queue!(stdout, Print(fit(line, width)))?;
+        }
+    }
+
+    let footer = match &app.view {
+        View::Table => {
+            "q quit  j/k move  enter detail  s sort  / filter  c check  a action".to_string()
+        }
+        View::Detail => "esc back  c check  a action  q quit".to_string(),
+        View::Filter(input) => format!("filter (selector): {}_", input),
+        View::Actions => "press the number of the action to run, esc to cancel".to_string(),
+        View::Confirm(name) => format!("run {} on the selected host? y/n", name),
+    };
+    queue!(
+        stdout,
+        cursor::MoveTo(0, height.saturating_sub(2) as u16),
+        Print(fit(app.message.clone(), width)),
+        cursor::MoveTo(0, height.saturating_sub(1) as u16),
+        SetAttribute(Attribute::Reverse),
+        Print(fit(format!("{:<width$}", footer, width = width), width)),
+        SetAttribute(Attribute::Reset)
+    )?;
+    stdout.flush()
+}
+
+fn table_lines(app: &App, lines: &mut Vec<(String, bool)>) {
+    lines.push((
+        format!(
+            "{:<20} {:<9} {:>6} {:>6} {:>6} {:>9}  {}",
+            "Host", "Health", "Load", "Mem%", "Disk%", "Uptime", "Status"
+        ),
+        false,
+    ));
+    for (position, index) in app.visible().into_iter().enumerate() {
+        let row = &app.rows[index];
+        let line = match &row.report {
+            Some(report) => format!(
+                "{:<20} {:<9} {:>6.2} {:>6.1} {:>6.1} {:>9}  online",
+                row.host.name,
+                report.health,
+                report.load.one,
+                report.memory.used_percent(),
+                report.disk_used_percent(),
+                format_uptime(report.uptime_secs)
+            ),
+            None => format!(
+                "{:<20} {:<9} {:>6} {:>6} {:>6} {:>9}  {}",
+                row.host.name,
+                Health::Unknown,
+                "-",
+                "-",
+                "-",
+                "-",
+                row.error.as_deref().unwrap_or("waiting")
+            ),
+        };
+        lines.push((line, position == app.selected));
+    }
+}
+
+fn detail_lines(app: &App, lines: &mut Vec<(String, bool)>) {
+    let Some(row) = app.current() else {
+        return;
+    };
+    let Some(report) = &row.report else {
+        lines.push((
+            format!(
+                "{}: {}",
+                row.host.name,
+                row.error.as_deref().unwrap_or("no report yet")
+            ),
+            false,
+        ));
+        return;
+    };
+
+    lines.push((
+        format!(
+            "{} ({}) - {} - up {} - {} cpus - load {:.2} {:.2} {:.2} - memory {:.1}%",
+            row.host.name,
+            report.hostname,
+            report.health,
+            format_uptime(report.uptime_secs),
+            report.cpus,
+            report.load.one,
+            report.load.five,
+            report.load.fifteen,
+            report.memory.used_percent()
+        ),
+        false,
+    ));
+
+    if let View::Actions = app.view {
+        lines.push((String::new(), false));
+        lines.push(("Actions".to_string(), true));
+        if report.actions.is_empty() {
+            lines.push(("  no actions are allowed on this host".to_string(), false));
+        }
+        for (i, action) in report.actions.iter().enumerate() {
+            lines.push((format!("  {}. {}", i + 1, action), false));
+        }
+        return;
+    }
+
+    lines.push((String::new(), false));
+    lines.push((
+        format!(
+            "{:<30} {:<10} {:>12} {:>12} {:>6}",
+            "Mount", "Type", "Size (MB)", "Used (MB)", "Use%"
+        ),
+        true,
+    ));
+    for mount in &report.mounts {
+        lines.push((
+            format!(
+                "{:<30} {:<10} {:>12} {:>12} {:>6.1}",
+                mount.mount,
+                mount.filesystem,
+                mount.total_kb / 1024,
+                mount.used_kb / 1024,
+                mount.used_percent()
+            ),
+            false,
+        ));
+    }
+
+    lines.push((String::new(), false));
+    lines.push((
+        format!(
+            "{:<16} {:>14} {:>14}  {}",
+            "Interface", "RX bytes", "TX bytes", "Addresses"
+        ),
+        true,
+    ));
+    for interface in &report.interfaces {
+        lines.push((
+            format!(
+                "{:<16} {:>14} {:>14}  {}",
+                interface.name,
+                interface.rx_bytes,
+                interface.tx_bytes,
+                interface.addresses.join(", ")
+            ),
+            false,
+        ));
+    }
+
+    lines.push((String::new(), false));
+    lines.push((
+        format!(
+            "{:>8} {:<24} {:>12} {:>10}",
+            "PID", "Process", "RSS (MB)", "CPU s"
+        ),
+        true,
+    ));
+    for process in &report.processes {
+        lines.push((
+            format!(
+                "{:>8} {:<24} {:>12} {:>10.1}",
+                process.pid,
+                process.name,
+                process.rss_kb / 1024,
+                process.cpu_secs
+            ),
+            false,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use somacommon::status::{MemoryUsage, MountUsage};
+
+    fn host(name: &str, group: &str) -> InventoryHost {
+        InventoryHost {
+            name: name.to_string(),
+            groups: vec![group.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn report(health: Health, load: f64, memory_used: u64, disk_used: u64) -> StatusReport {
+        let mut report = StatusReport {
+            health,
+            memory: MemoryUsage {
+                total_kb: 100,
+                available_kb: 100 - memory_used,
+            },
+            mounts: vec![MountUsage {
+                mount: "/".to_string(),
+                filesystem: "ext4".to_string(),
+                total_kb: 100,
+                used_kb: disk_used,
+            }],
+            actions: vec!["deploy".to_string(), "restart".to_string()],
+            ..Default::default()
+        };
+        report.load.one = load;
+        report
+    }
+
+    /// web-01 is busy on memory, web-02 on load and disk, db-01 never answered
+    fn app() -> App {
+        let mut app = App::new(
+            vec![
+                host("web-02", "web"),
+                host("db-01", "db"),
+                host("web-01", "web"),
+            ],
+            false,
+        );
+        app.apply(vec![
+            Ok(report(Health::Critical, 4.0, 20, 95)),
+            Err(AgentError::ConnectionClosed("db-01".to_string())),
+            Ok(report(Health::Warning, 0.5, 90, 10)),
+        ]);
+        app
+    }
+
+    fn names(app: &App) -> Vec<String> {
+        app.visible()
+            .into_iter()
+            .map(|i| app.rows[i].host.name.clone())
+            .collect()
+    }
+
+    fn press(app: &mut App, code: KeyCode) -> bool {
+        let (refresh, _) = mpsc::channel();
+        let (updates, _) = mpsc::channel();
+        handle_key(
+            app,
+            KeyEvent::new(code, KeyModifiers::NONE),
+            &refresh,
+            &updates,
+        )
+    }
+
+    #[test]
+    fn sorts_worst_first_and_filters_by_selector() {
+        let mut app = app();
+        assert_eq!(names(&app), ["db-01", "web-01", "web-02"]);
+        for (sort, order) in [
+            (SortKey::Health, ["db-01", "web-02", "web-01"]),
+            (SortKey::Load, ["web-02", "web-01", "db-01"]),
+            (SortKey::Memory, ["web-01", "web-02", "db-01"]),
+            (SortKey::Disk, ["web-02", "web-01", "db-01"]),
+        ] {
+            press(&mut app, KeyCode::Char('s'));
+            assert_eq!(app.sort, sort);
+            assert_eq!(names(&app), order);
+        }
+
+        press(&mut app, KeyCode::Char('/'));
+        for c in "@web".chars() {
+            press(&mut app, KeyCode::Char(c));
+        }
+        assert_eq!(app.view, View::Filter("@web".to_string()));
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.filter, "@web");
+        assert_eq!(names(&app), ["web-02", "web-01"]);
+
+        // Moving stops at the last visible row
+        for _ in 0..5 {
+            press(&mut app, KeyCode::Down);
+        }
+        assert_eq!(app.current().unwrap().host.name, "web-01");
+    }
+
+    #[test]
+    fn drills_down_into_a_host_and_its_actions() {
+        let mut app = app();
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.view, View::Detail);
+        let mut lines = Vec::new();
+        detail_lines(&app, &mut lines);
+        assert!(lines[0].0.starts_with("db-01: "));
+
+        press(&mut app, KeyCode::Esc);
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Enter);
+        let mut lines = Vec::new();
+        detail_lines(&app, &mut lines);
+        assert!(lines[0].0.starts_with("web-01 () - warning"));
+        assert!(lines.iter().any(|(line, _)| line.starts_with("/ ")));
+
+        press(&mut app, KeyCode::Char('a'));
+        assert_eq!(app.view, View::Actions);
+        press(&mut app, KeyCode::Char('9'));
+        assert_eq!(app.view, View::Actions);
+        press(&mut app, KeyCode::Char('2'));
+        assert_eq!(app.view, View::Confirm("restart".to_string()));
+        press(&mut app, KeyCode::Char('n'));
+        assert_eq!(app.view, View::Table);
+        assert_eq!(app.message, "Action cancelled");
+
+        assert!(!press(&mut app, KeyCode::Char('q')));
+    }
+}
@@ -1,5 +1,70 @@
 use assert_cmd::Command;
+use assert_fs::prelude::*;
 use predicates::prelude::*;
+use somacommon::protocol::{self, Request, Response};
+use somacommon::status::{Health, StatusReport};
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::thread;
+
+/// Start a stand-in agent that answers every request using `answer`
+fn spawn_agent<F>(answer: F) -> String
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            while let Ok(Some(request)) = protocol::read_message::<_, Request>(&mut reader) {
+                // soma says who each request is made for, and may sign it; the
+                // answer does not depend on either
+                let request = request.inner().clone();
+                protocol::write_message(&mut writer, &answer(request)).unwrap();
+            }
+        }
+    });
+    address
+}
+
+/// A status report with the given health
+fn status_report(hostname: &str, health: Health) -> StatusReport {
+    StatusReport {
+        hostname: hostname.to_string(),
+        health,
+        cpus: 1,
+        ..Default::default()
+    }
+}
+
+/// Write an inventory file with one line per host: `name address groups`
+/// Environment pointing soma at an audit log in `temp`, with its key made
+fn audit_env(temp: &assert_fs::TempDir) -> [(&'static str, std::path::PathBuf); 2] {
+    let key = temp.path().join("audit.key");
+    if !key.exists() {
+        std::fs::write(&key, [7; 32]).unwrap();
+    }
+    [
+        ("SOMA_AUDIT_LOG", temp.path().join("audit.jsonl")),
+        ("SOMA_AUDIT_KEY", key),
+    ]
+}
+
+fn write_inventory(temp: &assert_fs::TempDir, hosts: &[(&str, &str, &str)]) -> std::path::PathBuf {
+    let mut content = String::new();
+    for (name, address, groups) in hosts {
+        content.push_str(&format!(
+            "[[hosts]]\nname = \"{}\"\naddress = \"{}\"\ngroups = [{}]\n\n",
+            name, address, groups
+        ));
+    }
+    let inventory = temp.child("inventory.toml");
+    inventory.write_str(&content).unwrap();
+    inventory.path().to_path_buf()
+}
 
 /// Test that the soma binary can be executed without arguments
 #[test]
@@ -326,6 +391,78 @@ fn test_soma_scan_subcommand_noaction() {
         ));
 }
 
+/// Test scan diff against a saved scan file
+#[test]
+fn test_soma_scan_diff_against_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let baseline = temp.child("baseline.json");
+    baseline
+        .write_str(
+            r#"{"scan_results": [
+                {"hostname": "host1.example.com", "ip": "192.168.1.10", "status": "unmanaged"},
+                {"hostname": "lp", "ip": "192.168.1.20", "status": "unmanaged"},
+                {"hostname": "old-box", "ip": "192.168.1.99", "status": "managed"}
+            ]}"#,
+        )
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .args(&["scan", "--csv", "--diff", baseline.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("change,ip,hostname,old,new"))
+        .stdout(predicate::str::contains(
+            "new,192.168.1.11,host2.example.com,,managed",
+        ))
+        .stdout(predicate::str::contains(
+            "vanished,192.168.1.99,old-box,managed,",
+        ))
+        .stdout(predicate::str::contains(
+            "hostname,192.168.1.20,printer,lp,printer",
+        ))
+        .stdout(predicate::str::contains(
+            "status,192.168.1.10,host1.example.com,unmanaged,managed",
+        ));
+}
+
+/// Test scan diff against the last stored scan reports no changes on a repeat scan
+#[test]
+fn test_soma_scan_diff_against_last_scan() {
+    let temp = assert_fs::TempDir::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .arg("scan")
+        .assert()
+        .success();
+    temp.child("last_scan.json")
+        .assert(predicate::str::contains("\"scan_results\""));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .args(&["scan", "--diff", "--json"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"new\": []"))
+        .stdout(predicate::str::contains("\"vanished\": []"))
+        .stdout(predicate::str::contains("\"hostname_changed\": []"))
+        .stdout(predicate::str::contains("\"status_changed\": []"));
+}
+
+/// Test scan diff with a missing baseline file fails cleanly
+#[test]
+fn test_soma_scan_diff_missing_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .args(&["scan", "--diff", "/nonexistent/scan.json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Error reading scan file"));
+}
+
 /// Test check subcommand without hosts
 #[test]
 fn test_soma_check_subcommand_no_hosts() {
@@ -390,6 +527,156 @@ fn test_soma_check_subcommand_noaction() {
         .stdout(predicate::str::contains("Would check status of hosts"));
 }
 
+/// Test check watch mode appends JSON lines when stdout is not a terminal
+#[test]
+fn test_soma_check_watch_json_lines() {
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    let output = cmd
+        .args(&[
+            "check",
+            "--watch",
+            "--interval",
+            "1",
+            "--count",
+            "2",
+            "host1.example.com",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("\"tick\":1"));
+    assert!(lines[1].contains("\"tick\":2"));
+    assert!(lines[1].contains("\"changed\":[]"));
+    assert!(lines[1].contains("\"hostname\":\"host1.example.com\""));
+}
+
+/// Test check watch mode with noaction flag
+#[test]
+fn test_soma_check_watch_noaction() {
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.args(&["--noaction", "check", "--watch"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Would check status of hosts"))
+        .stdout(predicate::str::contains("Would refresh every 5 seconds"));
+}
+
+/// Test check watch mode takes hosts after the flag
+#[test]
+fn test_soma_check_watch_hosts() {
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.args(&["--noaction", "check", "--watch", "host1.example.com"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("host1.example.com"))
+        .stdout(predicate::str::contains("Would refresh every 5 seconds"));
+}
+
+/// Test check watch mode rejects a zero interval
+#[test]
+fn test_soma_check_watch_zero_interval() {
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.args(&["check", "--watch", "--interval", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("at least 1 second"));
+}
+
+/// Test check polls the agents listed in the inventory
+#[test]
+fn test_soma_check_polls_inventory_agents() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let web = spawn_agent(|_| Response::Status(status_report("web-01", Health::Healthy)));
+    let db = spawn_agent(|_| Response::Status(status_report("db-01", Health::Critical)));
+    let inventory = write_inventory(
+        &temp,
+        &[
+            ("web-01", &web, "\"web\""),
+            ("db-01", &db, "\"db\""),
+            ("gone-01", "127.0.0.1:1", "\"web\""),
+        ],
+    );
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .env("SOMA_INVENTORY", &inventory)
+        .args(&["check", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("web-01,online,healthy"))
+        .stdout(predicate::str::contains("db-01,online,critical"))
+        .stdout(predicate::str::contains("gone-01,offline,unknown"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .env("SOMA_INVENTORY", &inventory)
+        .args(&["check", "--csv", "@db"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("db-01,online,critical"))
+        .stdout(predicate::str::contains("web-01").not());
+}
+
+/// Test list shows the inventory hosts
+#[test]
+fn test_soma_list_inventory_hosts() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", "127.0.0.1:1", "\"web\"")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["list", "--json"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"hostname\": \"web-01\""));
+}
+
+/// Test an unreadable inventory is reported cleanly
+#[test]
+fn test_soma_invalid_inventory() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = temp.child("inventory.toml");
+    inventory.write_str("[[hosts]]\naddress = 5\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Error parsing inventory"));
+}
+
+/// Test top refuses to run without a terminal
+#[test]
+fn test_soma_top_needs_terminal() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", "127.0.0.1:1", "\"web\"")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .arg("top")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("needs a terminal"));
+}
+
+/// Test top with a selector that matches nothing
+#[test]
+fn test_soma_top_no_hosts() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", "127.0.0.1:1", "\"web\"")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["top", "@db"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No inventory hosts"));
+}
+
 /// Test invalid subcommand
 #[test]
 fn test_soma_invalid_subcommand() {
@@ -486,3 +773,1960 @@ fn test_json_with_empty_list_when_no_hosts_are_managed() {
         .success()
         .stdout("[]\n");
 }
+
+/// Test serve refuses to start without an agent token
+#[test]
+fn test_soma_serve_requires_token() {
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env_remove("SOMA_AGENT_TOKEN")
+        .arg("serve")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("An agent token is required"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.args(&[
+        "-n",
+        "serve",
+        "--listen",
+        "127.0.0.1:7171",
+        "--token",
+        "secret",
+    ])
+    .assert()
+    .success()
+    .stdout(predicate::str::contains(
+        "Would listen on 127.0.0.1:7171 for agent connections",
+    ));
+}
+
+/// Test check reaches agents that dialled in to soma serve
+#[test]
+fn test_soma_check_push_agents() {
+    use somacommon::protocol::{AgentMessage, ControllerMessage};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let controller = format!("127.0.0.1:{}", port);
+    let mut serve = std::process::Command::new(assert_cmd::cargo::cargo_bin("soma"))
+        .args(&["serve", "--listen", &controller, "--token", "secret"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(s) = TcpStream::connect(&controller) {
+            stream = Some(s);
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let stream = stream.expect("soma serve did not start listening");
+
+    let hello = |hostname: &str, token: &str| {
+        let mut stream = TcpStream::connect(&controller).unwrap();
+        let hello = AgentMessage::Hello {
+            hostname: hostname.to_string(),
+            token: token.to_string(),
+        };
+        protocol::write_message(&mut stream, &hello).unwrap();
+        let mut reader = BufReader::new(stream);
+        let reply: Option<ControllerMessage> = protocol::read_message(&mut reader).unwrap();
+        reply
+    };
+    let token = |hostname: &str| {
+        let output = Command::cargo_bin("soma")
+            .unwrap()
+            .env("SOMA_AGENT_TOKEN", "secret")
+            .args(&["agent-token", hostname])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    };
+
+    // Agents with a wrong token, the shared secret itself, or another
+    // host's token are turned away
+    for (hostname, presented) in [
+        ("laptop-07", "guess".to_string()),
+        ("laptop-07", "secret".to_string()),
+        ("laptop-07", token("laptop-08")),
+    ] {
+        let reply = hello(hostname, &presented);
+        assert!(matches!(reply, Some(ControllerMessage::Rejected { .. })));
+    }
+
+    let mut writer = stream.try_clone().unwrap();
+    let hello_message = AgentMessage::Hello {
+        hostname: "laptop-07".to_string(),
+        token: token("laptop-07"),
+    };
+    protocol::write_message(&mut writer, &hello_message).unwrap();
+    let mut reader = BufReader::new(stream);
+    let reply: Option<ControllerMessage> = protocol::read_message(&mut reader).unwrap();
+    assert!(matches!(reply, Some(ControllerMessage::Welcome)));
+
+    // A second agent cannot take over the name while the first is connected
+    let reply = hello("laptop-07", &token("laptop-07"));
+    assert!(
+        matches!(reply, Some(ControllerMessage::Rejected { ref message }) if message.contains("already connected")),
+        "{:?}",
+        reply
+    );
+    thread::spawn(move || {
+        while let Ok(Some(ControllerMessage::Request { id, .. })) =
+            protocol::read_message(&mut reader)
+        {
+            let response = Response::Status(status_report("laptop-07", Health::Warning));
+            protocol::write_message(
+                &mut writer,
+                &AgentMessage::Reply {
+                    id,
+                    response: Box::new(response),
+                },
+            )
+            .unwrap();
+        }
+    });
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = temp.child("inventory.toml");
+    inventory
+        .write_str(&format!(
+            "controller = \"{}\"\n\n[[hosts]]\nname = \"laptop-07\"\nmode = \"push\"\n\n\
+             [[hosts]]\nname = \"laptop-08\"\nmode = \"push\"\n",
+            controller
+        ))
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    let result = cmd
+        .env("SOMA_INVENTORY", inventory.path())
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["check", "--csv"])
+        .assert();
+    serve.kill().unwrap();
+    serve.wait().unwrap();
+    result
+        .success()
+        .stdout(predicate::str::contains("laptop-07,online,warning"))
+        .stdout(predicate::str::contains("laptop-08,offline,unknown"));
+}
+
+/// Test check and scan reach hosts behind a relay named in the inventory,
+/// with requests the relay cannot alter
+#[test]
+fn test_soma_check_through_relay() {
+    let relay = spawn_agent(|request| match request {
+        Request::Relay {
+            token,
+            target,
+            request,
+        } if token == "secret"
+            && target == "10.9.0.3:7070"
+            && matches!(*request, Request::Signed { ref host, .. } if host == "lab-03")
+            && *request.inner() == Request::Status =>
+        {
+            Response::Status(status_report("lab-03", Health::Healthy))
+        }
+        Request::Status => Response::Status(status_report("jump-01", Health::Warning)),
+        _ => Response::Error {
+            message: "Invalid relay token".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = temp.child("inventory.toml");
+    inventory
+        .write_str(&format!(
+            "[[hosts]]\nname = \"jump-01\"\naddress = \"{}\"\n\n\
+             [[hosts]]\nname = \"lab-03\"\naddress = \"10.9.0.3\"\nvia = \"jump-01\"\n",
+            relay
+        ))
+        .unwrap();
+    let key = temp.child("signing.key");
+    let soma = |token: &str| {
+        let mut cmd = Command::cargo_bin("soma").unwrap();
+        cmd.env("SOMA_STATE_DIR", temp.path())
+            .env("SOMA_INVENTORY", inventory.path())
+            .env("SOMA_SIGNING_KEY", key.path())
+            .env("SOMA_RELAY_TOKEN", token);
+        cmd
+    };
+
+    // Relayed requests are only sent signed
+    soma("secret")
+        .args(&["check", "--csv", "lab-03"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("lab-03,offline,unknown"));
+    soma("secret")
+        .args(&["check", "--cached", "lab-03"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "lab-03 is reached through jump-01, which needs a signing key",
+        ));
+
+    soma("secret").arg("keygen").assert().success();
+    soma("secret")
+        .args(&["check", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("jump-01,online,warning"))
+        .stdout(predicate::str::contains("lab-03,online,healthy"));
+    soma("secret")
+        .args(&["scan", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("lab-03,10.9.0.3,managed"));
+
+    soma("guess")
+        .args(&["check", "--csv", "lab-03"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("lab-03,offline,unknown"));
+    soma("guess")
+        .args(&["scan", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("lab-03,10.9.0.3,unreachable"));
+}
+
+/// Test a relay that is itself behind a relay is refused
+#[test]
+fn test_soma_inventory_rejects_relay_chains() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = temp.child("inventory.toml");
+    inventory
+        .write_str("[[hosts]]\nname = \"a\"\nvia = \"b\"\n\n[[hosts]]\nname = \"b\"\nvia = \"c\"\n")
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid relay for host a: relay b is itself behind a relay",
+        ));
+}
+
+/// Test check and list report hosts that have not been heard from recently
+#[test]
+fn test_soma_stale_hosts() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let web = spawn_agent(|_| Response::Status(status_report("web-01", Health::Healthy)));
+    let inventory = write_inventory(
+        &temp,
+        &[
+            ("web-01", &web, "\"web\""),
+            ("db-01", "127.0.0.1:1", "\"db\""),
+            ("old-01", "127.0.0.1:1", "\"db\""),
+            ("new-01", "127.0.0.1:1", "\"db\""),
+        ],
+    );
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    temp.child("state/last_seen.json")
+        .write_str(&format!(
+            "{{\"db-01\": {}, \"old-01\": {}}}",
+            now - 600,
+            now - 7200
+        ))
+        .unwrap();
+    let state = temp.child("state");
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", state.path())
+        .args(&["check", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("web-01,online,healthy"))
+        .stdout(predicate::str::contains("db-01,stale,unknown"))
+        .stdout(predicate::str::contains("old-01,lost,unknown"))
+        .stdout(predicate::str::contains("new-01,offline,unknown,never"));
+
+    // The check recorded web-01 as seen, so only the others are listed
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", state.path())
+        .args(&["list", "--stale", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("hostname,liveness,last_seen"))
+        .stdout(predicate::str::contains("db-01,stale,"))
+        .stdout(predicate::str::contains("old-01,lost,"))
+        .stdout(predicate::str::contains("new-01,never,never"))
+        .stdout(predicate::str::contains("web-01").not());
+}
+
+/// Test facts produces an asset report and makes facts usable in selectors
+#[test]
+fn test_soma_facts_report_and_selectors() {
+    use somacommon::facts::{Facts, OsFacts};
+
+    let facts_agent = |hostname: &'static str, os: &'static str| {
+        spawn_agent(move |request| match request {
+            Request::Facts { .. } => Response::Facts(Box::new(Facts {
+                hostname: hostname.to_string(),
+                os: OsFacts {
+                    id: os.to_string(),
+                    pretty_name: format!("{} Linux, stable", os),
+                    ..Default::default()
+                },
+                memory_kb: 2048,
+                ..Default::default()
+            })),
+            _ => Response::Status(status_report(hostname, Health::Healthy)),
+        })
+    };
+    let web = facts_agent("web-01", "debian");
+    let db = facts_agent("db-01", "rocky");
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &web, ""), ("db-01", &db, "")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["facts", "--format", "csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("hostname,vendor,product"))
+        .stdout(predicate::str::contains("web-01,,,,debian,"))
+        .stdout(predicate::str::contains(",2048,0,0,"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["facts", "--json", "db-01"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "\"pretty_name\": \"rocky Linux, stable\"",
+        ))
+        .stdout(predicate::str::contains("web-01").not());
+
+    // The cached facts now select hosts
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["check", "--csv", "os.id=debian"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("web-01,online,healthy"))
+        .stdout(predicate::str::contains("db-01").not());
+}
+
+/// Test packages shows version skew across hosts
+#[test]
+fn test_soma_packages_version_skew() {
+    use somacommon::packages::{Package, PackageReport};
+
+    let packages_agent = |version: &'static str| {
+        spawn_agent(move |request| match request {
+            Request::Packages { name } => {
+                assert_eq!(name.as_deref(), Some("openssl"));
+                Response::Packages(PackageReport {
+                    manager: "dpkg".to_string(),
+                    packages: vec![Package {
+                        name: "openssl".to_string(),
+                        version: version.to_string(),
+                        architecture: "amd64".to_string(),
+                    }],
+                    upgradable: Some(3),
+                })
+            }
+            _ => Response::Error {
+                message: "unexpected".to_string(),
+            },
+        })
+    };
+    let web = packages_agent("3.0.15-1");
+    let db = packages_agent("3.0.11-1");
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &web, ""), ("db-01", &db, "")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["packages", "--name", "openssl"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Version Skew:"))
+        .stdout(predicate::str::contains("3.0.11-1"))
+        .stdout(predicate::str::contains("db-01"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["packages", "--csv", "--name", "openssl", "web-01"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("web-01,openssl,3.0.15-1,amd64"))
+        .stdout(predicate::str::contains("db-01").not());
+}
+
+/// Test ports flags listeners missing from the inventory's expected ports
+#[test]
+fn test_soma_ports_flags_unexpected() {
+    use somacommon::ports::{Listener, Protocol};
+
+    let agent = spawn_agent(|_| {
+        let listener = |protocol, address: &str, port| Listener {
+            protocol,
+            address: address.to_string(),
+            port,
+            pid: Some(100),
+            process: Some("daemon".to_string()),
+        };
+        Response::Ports {
+            listeners: vec![
+                listener(Protocol::Tcp, "0.0.0.0", 22),
+                listener(Protocol::Tcp, "0.0.0.0", 6379),
+                listener(Protocol::Udp, "::", 53),
+                listener(Protocol::Tcp, "127.0.0.1", 5432),
+            ],
+        }
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = temp.child("inventory.toml");
+    inventory
+        .write_str(&format!(
+            "expected_ports = [\"22\"]\n\n[[hosts]]\nname = \"web-01\"\naddress = \"{}\"\n\
+             expected_ports = [\"udp/53\"]\n",
+            agent
+        ))
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .args(&["ports", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "web-01,tcp,0.0.0.0,22,100,daemon,expected",
+        ))
+        .stdout(predicate::str::contains(
+            "web-01,tcp,0.0.0.0,6379,100,daemon,unexpected",
+        ))
+        .stdout(predicate::str::contains(
+            "web-01,udp,::,53,100,daemon,expected",
+        ))
+        .stdout(predicate::str::contains(
+            "web-01,tcp,127.0.0.1,5432,100,daemon,local",
+        ));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .args(&["ports", "--csv", "--unexpected"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("6379"))
+        .stdout(predicate::str::contains(",22,").not());
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .arg("ports")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "web-01               udp    ::                        53     daemon (100)         expected  \n",
+        ));
+}
+
+/// Test service operations reach agents with the noaction flag and are reported
+#[test]
+fn test_soma_service_operations() {
+    use somacommon::service::{ServiceOperation, ServiceResult, UnitState};
+
+    let agent = spawn_agent(|request| match request {
+        Request::Service {
+            unit,
+            operation,
+            noaction,
+        } => {
+            assert_eq!(operation, ServiceOperation::Restart);
+            Response::Service(ServiceResult {
+                state: UnitState {
+                    unit: unit.clone(),
+                    load_state: "loaded".to_string(),
+                    active_state: "active".to_string(),
+                    sub_state: "running".to_string(),
+                    main_pid: Some(812),
+                    unit_file_state: "enabled".to_string(),
+                    ..Default::default()
+                },
+                changed: true,
+                message: if noaction {
+                    format!("Would restart {}", unit)
+                } else {
+                    format!("restart {}", unit)
+                },
+            })
+        }
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &agent, "\"web\"")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["--noaction", "service", "@web", "restart", "nginx.service"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Would restart nginx.service"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["service", "--csv", "@web", "restart", "nginx.service"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "web-01,nginx.service,active,running,,812,enabled,true,restart nginx.service",
+        ));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["service", "@web", "reload", "nginx.service"])
+        .assert()
+        .failure();
+}
+
+/// Test copies render templates per host and pass noaction on to agents
+#[test]
+fn test_soma_copy_templates() {
+    use somacommon::files::CopyResult;
+    use somacommon::{base64, digest};
+
+    let agent = spawn_agent(|request| match request {
+        Request::Copy {
+            path,
+            content,
+            sha256,
+            mode,
+            noaction,
+            ..
+        } => {
+            let content = String::from_utf8(base64::decode(&content).unwrap()).unwrap();
+            assert_eq!(digest::sha256_hex(content.as_bytes()), sha256);
+            assert_eq!(mode, Some(0o640));
+            Response::Copy(CopyResult {
+                path,
+                changed: true,
+                sha256,
+                backup: None,
+                message: format!("{}{}", if noaction { "Would write " } else { "" }, content),
+            })
+        }
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = temp.child("inventory.toml");
+    inventory
+        .write_str(&format!(
+            "[[hosts]]\nname = \"web-01\"\naddress = \"{}\"\nvars = {{ env = \"prod\" }}\n",
+            agent
+        ))
+        .unwrap();
+    let source = temp.child("motd");
+    source.write_str("{{ name }} ({{ env }})").unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .envs(audit_env(&temp))
+        .args(&["--noaction", "copy", "--template", "--mode", "0640"])
+        .arg(source.path())
+        .arg("web-*:/etc/motd")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Would write web-01 (prod)"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .envs(audit_env(&temp))
+        .args(&["copy", "--csv", "--mode", "0640"])
+        .arg(source.path())
+        .arg("web-*:/etc/motd")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("web-01,/etc/motd,true,"))
+        .stdout(predicate::str::contains("{{ name }} ({{ env }})"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", inventory.path())
+        .envs(audit_env(&temp))
+        .args(&["copy"])
+        .arg(source.path())
+        .arg("web-*:etc/motd")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("absolute path"));
+}
+
+/// Test fetched files are pulled a chunk at a time into a directory per host
+#[test]
+fn test_soma_fetch_into_host_directories() {
+    use somacommon::base64;
+    use somacommon::files::{FETCH_CHUNK, FileChunk};
+
+    let log: Vec<u8> = (0..600_000u32).map(|i| b'a' + (i % 26) as u8).collect();
+    let served = log.clone();
+    let agent = spawn_agent(move |request| match request {
+        Request::Fetch {
+            path,
+            offset,
+            length,
+            tail,
+        } => {
+            assert!(length.unwrap() <= FETCH_CHUNK);
+            let start = match tail {
+                Some(_) => served.len() as u64 - 10,
+                None => offset,
+            } as usize;
+            let end = (start + length.unwrap() as usize).min(served.len());
+            Response::Fetch(FileChunk {
+                path,
+                size: served.len() as u64,
+                offset: start as u64,
+                data: base64::encode(&served[start..end]),
+            })
+        }
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &agent, "\"web\"")]);
+    let collected = temp.child("collected");
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["fetch", "--csv", "@web:/var/log/app.log"])
+        .arg(collected.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(",600000,"));
+    let saved = collected.child("web-01").child("app.log");
+    assert_eq!(std::fs::read(saved.path()).unwrap(), log);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["fetch", "--range", "26-52", "@web:/var/log/app.log"])
+        .arg(collected.path())
+        .assert()
+        .success();
+    saved.assert("abcdefghijklmnopqrstuvwxyz");
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["fetch", "--tail", "1", "@web:/var/log/app.log"])
+        .arg(collected.path())
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(saved.path()).unwrap(), &log[log.len() - 10..]);
+}
+
+/// Test state documents are sent in order and check mode reaches the agent
+#[test]
+fn test_soma_apply_state_document() {
+    use somacommon::resources::{Outcome, Resource, ResourceResult};
+
+    let agent = spawn_agent(|request| match request {
+        Request::Apply {
+            resources,
+            noaction,
+        } => {
+            assert!(matches!(
+                resources[0],
+                Resource::Package { present: true, .. }
+            ));
+            assert!(matches!(
+                resources[1],
+                Resource::Service {
+                    running: Some(true),
+                    enabled: None,
+                    ..
+                }
+            ));
+            let results = resources
+                .iter()
+                .map(|resource| ResourceResult {
+                    resource: resource.to_string(),
+                    outcome: if noaction {
+                        Outcome::Changed
+                    } else {
+                        Outcome::Failed
+                    },
+                    message: if noaction { "Would change" } else { "Broken" }.to_string(),
+                    diff: vec!["+nginx".to_string()],
+                })
+                .collect();
+            Response::Apply { results }
+        }
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &agent, "\"web\"")]);
+    let document = temp.child("web.toml");
+    document
+        .write_str(
+            "[[resources]]\ntype = \"package\"\nname = \"nginx\"\n\n\
+             [[resources]]\ntype = \"service\"\nunit = \"nginx.service\"\nrunning = true\n",
+        )
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["--noaction", "apply"])
+        .arg(document.path())
+        .arg("@web")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("package nginx"))
+        .stdout(predicate::str::contains("    +nginx"))
+        .stdout(predicate::str::contains("2 changed, 0 unchanged, 0 failed"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["apply", "--csv"])
+        .arg(document.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "web-01,service nginx.service,failed,Broken",
+        ));
+
+    let relative = temp.child("relative.toml");
+    relative
+        .write_str("[[resources]]\ntype = \"file\"\npath = \"motd\"\ncontent = \"\"\n")
+        .unwrap();
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .arg("apply")
+        .arg(relative.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("file motd: path must be absolute"));
+}
+
+/// A stand-in agent for playbook steps, reporting copies as changed or not
+fn spawn_play_agent(copy_changes: bool) -> String {
+    use somacommon::files::CopyResult;
+    use somacommon::protocol::ActionResult;
+    use somacommon::service::{ServiceResult, UnitState};
+
+    spawn_agent(move |request| match request {
+        Request::Copy {
+            path,
+            content,
+            sha256,
+            ..
+        } => {
+            let content = somacommon::base64::decode(&content).unwrap();
+            assert_eq!(content, b"release=1.4\n");
+            Response::Copy(CopyResult {
+                message: format!("copied {}", path),
+                path,
+                changed: copy_changes,
+                sha256,
+                backup: None,
+            })
+        }
+        Request::Action { name, .. } => Response::Action(ActionResult {
+            exit_code: Some(if name == "broken" { 1 } else { 0 }),
+            stdout: format!("ran {}\n", name),
+            stderr: "it broke".to_string(),
+            name,
+        }),
+        Request::Service {
+            unit, operation, ..
+        } => Response::Service(ServiceResult {
+            state: UnitState {
+                unit: unit.clone(),
+                ..Default::default()
+            },
+            changed: true,
+            message: format!("{} {}", operation, unit),
+        }),
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    })
+}
+
+/// Test playbooks run steps in order with conditions, handlers and failure policies
+#[test]
+fn test_soma_play_playbook() {
+    let changes = spawn_play_agent(true);
+    let steady = spawn_play_agent(false);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[
+            ("web-01", &changes, "\"web\""),
+            ("web-02", &steady, "\"web\""),
+        ],
+    );
+    temp.child("app.conf")
+        .write_str("release={{ release }}\n")
+        .unwrap();
+    let playbook = temp.child("site.toml");
+    playbook
+        .write_str(
+            "name = \"site\"\nvars = { release = \"1.4\" }\n\n\
+             [[steps]]\nname = \"config\"\nhosts = \"@web\"\n\
+             copy = { src = \"app.conf\", dest = \"/etc/app.conf\", template = true }\n\
+             notify = [\"restart\"]\n\n\
+             [[steps]]\nname = \"migrate\"\nhosts = \"@web\"\nrun = \"migrate\"\n\
+             when = \"config.changed\"\n\n\
+             [[handlers]]\nname = \"restart\"\n\
+             service = { unit = \"app.service\", operation = \"restart\" }\n",
+        )
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["play", "--check"])
+        .arg(playbook.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("is valid: 2 steps, 1 handlers"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["play", "--csv"])
+        .arg(playbook.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "config,web-01,changed,copied /etc/app.conf",
+        ))
+        .stdout(predicate::str::contains("config,web-02,unchanged,"))
+        .stdout(predicate::str::contains(
+            "migrate,web-01,changed,ran migrate",
+        ))
+        .stdout(predicate::str::contains("migrate,web-02,skipped,"))
+        .stdout(predicate::str::contains(
+            "restart,web-01,changed,restart app.service",
+        ))
+        .stdout(predicate::str::contains("restart,web-02").not());
+
+    let failing = temp.child("failing.toml");
+    failing
+        .write_str(
+            "[[steps]]\nname = \"break\"\nhosts = \"web-01\"\nrun = \"broken\"\n\n\
+             [[steps]]\nname = \"after\"\nrun = \"after\"\n",
+        )
+        .unwrap();
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .arg("play")
+        .arg(failing.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("Exited with 1: it broke"))
+        .stdout(predicate::str::contains("Stopped after step break failed"))
+        .stdout(predicate::str::contains("Step: after").not());
+
+    let invalid = temp.child("invalid.toml");
+    invalid
+        .write_str("[[steps]]\nname = \"a\"\nrun = \"x\"\nnotify = [\"missing\"]\n")
+        .unwrap();
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["play", "--check"])
+        .arg(invalid.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("notifies unknown handler missing"));
+}
+
+/// A stand-in agent that runs actions with the given exit code and reports the given health
+fn spawn_rollout_agent(hostname: &'static str, exit_code: i32, health: Health) -> String {
+    use somacommon::protocol::ActionResult;
+
+    spawn_agent(move |request| match request {
+        Request::Action { name, .. } => Response::Action(ActionResult {
+            name,
+            exit_code: Some(exit_code),
+            stdout: format!("deployed on {}\n", hostname),
+            stderr: String::new(),
+        }),
+        _ => Response::Status(status_report(hostname, health)),
+    })
+}
+
+/// Test staged runs stop once the failure budget is spent and name untouched hosts
+#[test]
+fn test_soma_rollout_failure_budget() {
+    let web1 = spawn_rollout_agent("web-01", 0, Health::Healthy);
+    let web2 = spawn_rollout_agent("web-02", 1, Health::Healthy);
+    let web3 = spawn_rollout_agent("web-03", 0, Health::Healthy);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[
+            ("web-01", &web1, "\"web\""),
+            ("web-02", &web2, "\"web\""),
+            ("web-03", &web3, "\"web\""),
+        ],
+    );
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["run", "--serial", "1", "--max-fail", "0", "@web", "deploy"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("deployed on web-01"))
+        .stdout(predicate::str::contains("web-03").not())
+        .stderr(predicate::str::contains(
+            "Rollout aborted after batch 2 of 3: 1 hosts failed, more than the 0 allowed",
+        ))
+        .stderr(predicate::str::contains("Touched hosts: web-01, web-02"))
+        .stderr(predicate::str::contains("Untouched hosts: web-03"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&[
+            "run",
+            "--serial",
+            "2",
+            "--max-fail",
+            "1",
+            "--csv",
+            "@web",
+            "deploy",
+        ])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("web-02,1,"))
+        .stdout(predicate::str::contains("web-03,0,"))
+        .stderr(predicate::str::contains("Rollout aborted").not());
+}
+
+/// Test a canary that is not healthy after its batch stops the rollout
+#[test]
+fn test_soma_rollout_canary() {
+    let web1 = spawn_rollout_agent("web-01", 0, Health::Critical);
+    let web2 = spawn_rollout_agent("web-02", 0, Health::Healthy);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[("web-01", &web1, "\"web\""), ("web-02", &web2, "\"web\"")],
+    );
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["run", "--canary", "@web", "deploy"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Canary health check failed: web-01 is critical",
+        ))
+        .stderr(predicate::str::contains("Untouched hosts: web-02"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["run", "--canary", "web-02", "deploy"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("deployed on web-02"));
+}
+
+/// Test check --cached shows the results agents stored from scheduled jobs
+#[test]
+fn test_soma_check_cached_results() {
+    use somacommon::checks::{JobKind, JobResult};
+
+    let agent = spawn_agent(|request| match request {
+        Request::Results => Response::Results {
+            results: vec![JobResult {
+                name: "raid".to_string(),
+                kind: JobKind::Check,
+                scheduled: 1_709_251_200,
+                started: 1_709_251_201,
+                duration_ms: 40,
+                exit_code: Some(1),
+                output: "md0 degraded, rebuilding".to_string(),
+                catch_up: false,
+            }],
+        },
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("db-01", &agent, "\"db\"")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["check", "--cached", "@db"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("check raid"))
+        .stdout(predicate::str::contains("2024-03-01 00:00:01"))
+        .stdout(predicate::str::contains("md0 degraded, rebuilding"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["check", "--cached", "--csv", "@db"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "db-01,check,raid,1709251200,1709251201,40,1,false,\"md0 degraded, rebuilding\"",
+        ));
+}
+
+/// Test check --plugin runs a plugin across hosts and exits with the worst state
+#[test]
+fn test_soma_check_plugin() {
+    use somacommon::checks::{Perfdata, PluginResult, PluginState};
+
+    let plugin_agent = |state: PluginState, output: &'static str| {
+        spawn_agent(move |request| match request {
+            Request::Plugin { name } => Response::Plugin(PluginResult {
+                name,
+                state,
+                output: output.to_string(),
+                perfdata: vec![Perfdata {
+                    label: "degraded".to_string(),
+                    value: if state == PluginState::Ok { 0.0 } else { 1.0 },
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            _ => Response::Error {
+                message: "unexpected request".to_string(),
+            },
+        })
+    };
+    let web1 = plugin_agent(PluginState::Ok, "RAID OK");
+    let web2 = plugin_agent(PluginState::Warning, "RAID WARNING - rebuilding");
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[("web-01", &web1, "\"web\""), ("web-02", &web2, "\"web\"")],
+    );
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["check", "--plugin", "disk_raid", "web-*"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains(
+            "web-01               ok         RAID OK",
+        ))
+        .stdout(predicate::str::contains("RAID WARNING - rebuilding"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .args(&["check", "--plugin", "disk_raid", "--csv", "web-01"])
+        .assert()
+        .code(0)
+        .stdout(predicate::str::contains(
+            "web-01,disk_raid,ok,RAID OK,degraded=0",
+        ));
+}
+
+/// Test check lists the probes agents ran along with their latency
+#[test]
+fn test_soma_check_probes() {
+    use somacommon::checks::{PluginState, ProbeResult};
+
+    let agent = spawn_agent(|request| match request {
+        Request::Status => {
+            let mut report = status_report("web-01", Health::Critical);
+            report.probes = vec![
+                ProbeResult {
+                    name: "api".to_string(),
+                    kind: "http".to_string(),
+                    target: "http://127.0.0.1/health".to_string(),
+                    state: PluginState::Ok,
+                    message: "HTTP 200".to_string(),
+                    latency_ms: Some(12),
+                },
+                ProbeResult {
+                    name: "db".to_string(),
+                    kind: "tcp".to_string(),
+                    target: "db-01:5432".to_string(),
+                    state: PluginState::Critical,
+                    message: "Timed out connecting to db-01:5432".to_string(),
+                    latency_ms: None,
+                },
+            ];
+            Response::Status(report)
+        }
+        _ => Response::Error {
+            message: "unexpected request".to_string(),
+        },
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &agent, "\"web\"")]);
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_INVENTORY", &inventory)
+        .args(&["check", "web-01"])
+        .assert()
+        .stdout(predicate::str::contains("Probes:"))
+        .stdout(predicate::str::contains(
+            "web-01               http api             ok         12ms       HTTP 200",
+        ))
+        .stdout(predicate::str::contains(
+            "tcp db               critical   -          Timed out connecting",
+        ));
+}
+
+/// Test serve scrapes agent metrics into a store that metrics query reads
+#[test]
+fn test_soma_serve_scrapes_metrics() {
+    use somacommon::status::MountUsage;
+    use std::time::Duration;
+
+    let web = spawn_agent(|_| {
+        let mut report = status_report("web-01", Health::Healthy);
+        report.mounts = vec![MountUsage {
+            mount: "/".to_string(),
+            filesystem: "ext4".to_string(),
+            total_kb: 1000,
+            used_kb: 250,
+        }];
+        Response::Status(report)
+    });
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &web, "\"web\"")]);
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut serve = std::process::Command::new(assert_cmd::cargo::cargo_bin("soma"))
+        .env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["serve", "--token", "secret", "--scrape", "1"])
+        .args(&["--listen", &format!("127.0.0.1:{}", port)])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let raw = temp.child("metrics/raw.jsonl");
+    for _ in 0..50 {
+        if std::fs::read_to_string(raw.path()).is_ok_and(|s| s.lines().count() > 0) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    serve.kill().unwrap();
+    serve.wait().unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .args(&["metrics", "query", "disk_used{host=web-01,mount=/}"])
+        .args(&["--since", "24h", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("series,timestamp,time,value\n"))
+        .stdout(predicate::str::contains(
+            "\"disk_used{host=web-01,mount=/}\",",
+        ))
+        .stdout(predicate::str::contains(",25\n"));
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.env("SOMA_STATE_DIR", temp.path())
+        .args(&["metrics", "query", "load*{host=db-*}"])
+        .assert()
+        .success()
+        .stdout("No series match load*{host=db-*}\n");
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.args(&["metrics", "query", "load1", "--since", "1y"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid duration 1y"));
+}
+
+/// Test serve exposes fleet metrics from its polls in Prometheus format
+#[test]
+fn test_soma_serve_exposes_fleet_metrics() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    let web = spawn_agent(|_| Response::Status(status_report("web-01", Health::Warning)));
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[
+            ("web-01", &web, "\"web\""),
+            ("gone-01", "127.0.0.1:1", "\"web\""),
+        ],
+    );
+    let free_port = || {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    };
+    let http = format!("127.0.0.1:{}", free_port());
+    let mut serve = std::process::Command::new(assert_cmd::cargo::cargo_bin("soma"))
+        .env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["serve", "--token", "secret", "--poll", "1", "--http", &http])
+        .args(&["--listen", &format!("127.0.0.1:{}", free_port())])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut page = String::new();
+    for _ in 0..50 {
+        if let Ok(mut stream) = TcpStream::connect(&http) {
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: soma\r\n\r\n")
+                .unwrap();
+            page.clear();
+            stream.read_to_string(&mut page).unwrap();
+            if page.contains("soma_check_duration_seconds{host=\"gone-01\"}") {
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    serve.kill().unwrap();
+    serve.wait().unwrap();
+
+    assert!(page.contains("soma_agents_connected 0\n"), "{}", page);
+    assert!(page.contains("soma_hosts{health=\"warning\"} 1\n"));
+    assert!(page.contains("soma_hosts{health=\"unknown\"} 1\n"));
+    assert!(page.contains("soma_hosts{health=\"critical\"} 0\n"));
+    assert!(page.contains("soma_check_duration_seconds{host=\"web-01\"} "));
+    assert!(page.contains("soma_host_last_seen_timestamp_seconds{host=\"web-01\"} "));
+}
+
+/// Test serve notifies every kind of sink when a host turns critical and again when it recovers
+#[test]
+fn test_soma_serve_alerts_sinks() {
+    use std::io::{BufRead, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Critical for the first few polls, then healthy again
+    let polls = AtomicUsize::new(0);
+    let web = spawn_agent(move |_| {
+        let health = if polls.fetch_add(1, Ordering::SeqCst) < 3 {
+            Health::Critical
+        } else {
+            Health::Healthy
+        };
+        Response::Status(status_report("web-01", health))
+    });
+
+    // A webhook receiver keeping the bodies it is sent
+    let hooks = Arc::new(Mutex::new(Vec::new()));
+    let webhook = TcpListener::bind("127.0.0.1:0").unwrap();
+    let webhook_url = format!("http://{}/alerts", webhook.local_addr().unwrap());
+    let received = Arc::clone(&hooks);
+    thread::spawn(move || {
+        for stream in webhook.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            received
+                .lock()
+                .unwrap()
+                .push(String::from_utf8(body).unwrap());
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+        }
+    });
+
+    // An SMTP relay keeping the messages it accepts
+    let mails = Arc::new(Mutex::new(Vec::new()));
+    let smtp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let smtp_server = smtp.local_addr().unwrap().to_string();
+    let accepted = Arc::clone(&mails);
+    thread::spawn(move || {
+        for stream in smtp.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 relay ready\r\n").unwrap();
+            let mut message = String::new();
+            let mut in_data = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let reply: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        accepted.lock().unwrap().push(std::mem::take(&mut message));
+                        b"250 queued\r\n"
+                    } else {
+                        message.push_str(&line);
+                        b""
+                    }
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).unwrap();
+                line.clear();
+            }
+        }
+    });
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &web, "\"web\"")]);
+    let command_log = temp.child("command.jsonl");
+    let file_log = temp.child("alerts.jsonl");
+    let alerts = temp.child("alerts.toml");
+    alerts
+        .write_str(&format!(
+            "interval = 1\n\n\
+             [[rules]]\nname = \"host-critical\"\ntype = \"health\"\nlevel = \"critical\"\n\
+             hosts = \"@web\"\n\n\
+             [sinks.hook]\ntype = \"webhook\"\nurl = \"{}\"\n\n\
+             [sinks.mail]\ntype = \"smtp\"\nserver = \"{}\"\nfrom = \"soma@example.com\"\n\
+             to = [\"ops@example.com\"]\n\n\
+             [sinks.script]\ntype = \"command\"\n\
+             command = [\"sh\", \"-c\", \"cat >> {}; echo >> {}\"]\n\n\
+             [sinks.log]\ntype = \"file\"\npath = \"{}\"\n",
+            webhook_url,
+            smtp_server,
+            command_log.path().display(),
+            command_log.path().display(),
+            file_log.path().display()
+        ))
+        .unwrap();
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut serve = std::process::Command::new(assert_cmd::cargo::cargo_bin("soma"))
+        .env("SOMA_INVENTORY", &inventory)
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["serve", "--token", "secret", "--poll", "1"])
+        .args(&["--listen", &format!("127.0.0.1:{}", port)])
+        .arg("--alerts")
+        .arg(alerts.path())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    // Sinks are notified in name order, so the script hears last
+    for _ in 0..100 {
+        if std::fs::read_to_string(command_log.path()).is_ok_and(|s| s.lines().count() >= 2) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    serve.kill().unwrap();
+    serve.wait().unwrap();
+
+    let logged = std::fs::read_to_string(file_log.path()).unwrap();
+    let lines: Vec<&str> = logged.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", logged);
+    assert!(lines[0].contains("\"status\":\"firing\""));
+    assert!(lines[0].contains("\"rule\":\"host-critical\",\"key\":\"web-01\""));
+    assert!(lines[0].contains("\"summary\":\"web-01 is critical\""));
+    assert!(lines[1].contains("\"status\":\"resolved\""));
+
+    assert_eq!(std::fs::read_to_string(command_log.path()).unwrap(), logged);
+    let hooks = hooks.lock().unwrap();
+    assert_eq!(hooks.len(), 2);
+    assert_eq!(hooks[0], lines[0]);
+    let mails = mails.lock().unwrap();
+    assert_eq!(mails.len(), 2);
+    assert!(mails[0].contains("Subject: [FIRING] host-critical web-01\r\n"));
+    assert!(mails[0].contains("To: ops@example.com\r\n"));
+    assert!(mails[1].contains("Subject: [RESOLVED] host-critical web-01\r\n"));
+}
+
+/// Test serve refuses alert rules that could never work
+#[test]
+fn test_soma_serve_rejects_invalid_alerts() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let alerts = temp.child("alerts.toml");
+    alerts
+        .write_str("[[rules]]\nname = \"disk\"\ntype = \"metric\"\nquery = \"disk_used{host}\"\nabove = 90\n")
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("soma").unwrap();
+    cmd.args(&["-n", "serve", "--token", "secret", "--alerts"])
+        .arg(alerts.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Alert rule disk: Label host is not key=value",
+        ));
+}
+
+/// Test maintenance windows block changes, annotate checks and can be ended early
+#[test]
+fn test_soma_maintenance_blocks_and_annotates() {
+    let web1 = spawn_play_agent(true);
+    let web2 = spawn_play_agent(true);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[("web-01", &web1, "\"web\""), ("web-02", &web2, "\"web\"")],
+    );
+    let soma = || {
+        let mut cmd = Command::cargo_bin("soma").unwrap();
+        cmd.env("SOMA_INVENTORY", &inventory)
+            .envs(audit_env(&temp))
+            .env("SOMA_STATE_DIR", temp.path());
+        cmd
+    };
+
+    soma()
+        .args(&["maintenance", "start", "web-01", "--duration", "2h"])
+        .args(&["--reason", "kernel upgrade", "--block"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "Maintenance 1 started on web-01 until",
+        ))
+        .stdout(predicate::str::contains("blocking changes"));
+    soma()
+        .args(&["maintenance", "silence", "rule=disk*", "--reason", "resize"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "Silence 2 started for rule=disk* until",
+        ));
+    soma()
+        .args(&["maintenance", "list", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "id,kind,target,block,start,end,reason\n1,maintenance,web-01,true,",
+        ))
+        .stdout(predicate::str::contains("\n2,silence,rule=disk*,false,"))
+        .stdout(predicate::str::contains(",kernel upgrade\n"));
+
+    soma()
+        .args(&["run", "--json", "@web", "deploy"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Skipping web-01: under maintenance until",
+        ))
+        .stdout(predicate::str::contains("web-02"))
+        .stdout(predicate::str::contains("web-01").not());
+    soma()
+        .args(&["--force", "run", "--json", "@web", "deploy"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipping").not())
+        .stdout(predicate::str::contains("web-01"));
+    // Reading a unit's status is never blocked
+    soma()
+        .args(&["service", "--json", "web-01", "status", "nginx.service"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipping").not());
+
+    soma()
+        .args(&["check", "--json", "@web"])
+        .assert()
+        .stdout(predicate::str::contains("\"reason\": \"kernel upgrade\""))
+        .stdout(predicate::str::contains("\"block\": true"));
+    soma()
+        .args(&["check", "@web"])
+        .assert()
+        .stdout(predicate::str::contains("Maintenance:"));
+
+    soma()
+        .args(&["maintenance", "end", "1"])
+        .assert()
+        .success()
+        .stdout("Ended 1\n");
+    soma()
+        .args(&["maintenance", "end", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Error: No maintenance window or silence 1",
+        ));
+    soma()
+        .args(&["run", "--json", "@web", "deploy"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipping").not())
+        .stdout(predicate::str::contains("web-01"));
+}
+
+/// Test silences only match on an alert's rule, key and host
+#[test]
+fn test_soma_maintenance_rejects_invalid_silence() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    Command::cargo_bin("soma")
+        .unwrap()
+        .env("SOMA_STATE_DIR", temp.path())
+        .args(&["maintenance", "silence", "env=prod", "--reason", "noise"])
+        .assert()
+        .failure()
+        .stderr("Error: Cannot match on env, use rule, key, host\n");
+}
+
+/// Test requests sent to agents are audited and the log can be searched and verified
+#[test]
+fn test_soma_audit_search_and_verify() {
+    let web = spawn_play_agent(true);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[
+            ("web-01", &web, "\"web\""),
+            ("web-02", "127.0.0.1:1", "\"web\""),
+        ],
+    );
+    let audit = audit_env(&temp);
+    let soma = || {
+        let mut cmd = Command::cargo_bin("soma").unwrap();
+        cmd.env("SOMA_INVENTORY", &inventory)
+            .envs(audit.clone())
+            .env("SOMA_STATE_DIR", temp.path())
+            .env("SOMA_OPERATOR", "alice");
+        cmd
+    };
+
+    soma().args(&["run", "@web", "deploy"]).assert().failure();
+    // Status reports are not audited
+    soma().args(&["check", "@web"]).assert().success();
+    soma()
+        .args(&["run", "web-01", "broken"])
+        .env("SOMA_OPERATOR", "bob")
+        .assert()
+        .failure();
+
+    soma()
+        .args(&["audit", "search", "--csv", "--operator", "alice"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "seq,timestamp,time,operator,origin,command,targets,outcome,message,parameters\n",
+        ))
+        .stdout(predicate::str::contains(",alice,"))
+        .stdout(predicate::str::contains(",action,web-01,ok,,"))
+        .stdout(predicate::str::contains(
+            ",action,web-02,failed,Cannot connect",
+        ))
+        .stdout(predicate::str::contains("bob").not());
+    soma()
+        .args(&[
+            "audit",
+            "search",
+            "--outcome",
+            "failed",
+            "--target",
+            "web-01",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("bob"))
+        .stdout(predicate::str::contains("exit code 1"))
+        .stdout(predicate::str::contains("alice").not());
+    soma()
+        .args(&["audit", "search", "--command", "copy"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No entries in"));
+
+    soma()
+        .args(&["audit", "verify"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("is intact: 3 entries, head "));
+
+    let log = temp.child("audit.jsonl");
+    let content = std::fs::read_to_string(log.path()).unwrap();
+    log.write_str(&content.replacen("\"bob\"", "\"alice\"", 1))
+        .unwrap();
+    soma()
+        .args(&["audit", "verify", "--file"])
+        .arg(log.path())
+        .assert()
+        .failure()
+        .stderr("Error: Audit log is broken at line 3: entry was changed after it was written\n");
+
+    // The hashes are keyed, so verifying with another key fails too
+    log.write_str(&content).unwrap();
+    let other = temp.child("other.key");
+    other.write_binary(&[0; 32]).unwrap();
+    soma()
+        .args(&["audit", "verify", "--key"])
+        .arg(other.path())
+        .assert()
+        .failure()
+        .stderr("Error: Audit log is broken at line 1: entry was changed after it was written\n");
+    let key = temp.child("audit.key");
+    std::fs::rename(key.path(), other.path()).unwrap();
+    soma()
+        .args(&["audit", "verify"])
+        .env("SOMA_AUDIT_KEY", other.path())
+        .assert()
+        .success();
+    soma()
+        .args(&["audit", "verify"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::starts_with(
+            "Error: Failed to read audit key",
+        ));
+
+    // Without the key, audited requests are refused rather than sent
+    // unrecorded, and no key is made in its place
+    soma()
+        .args(&["run", "web-01", "deploy"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("Cannot write the audit log"));
+    assert!(!key.path().exists());
+    soma()
+        .args(&["audit", "verify"])
+        .env("SOMA_AUDIT_KEY", other.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("is intact: 3 entries, head "));
+}
+
+/// Test the audit key is made once and entries reach the log through the writer
+#[test]
+fn test_soma_audit_init_and_serve() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    let web = spawn_play_agent(true);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &web, "\"web\"")]);
+    let key = temp.child("keys/audit.key");
+    let socket = temp.child("audit.sock");
+    let soma = || {
+        let mut cmd = Command::cargo_bin("soma").unwrap();
+        cmd.env("SOMA_INVENTORY", &inventory)
+            .env("SOMA_AUDIT_LOG", temp.path().join("audit.jsonl"))
+            .env("SOMA_AUDIT_KEY", key.path())
+            .env("SOMA_OPERATOR", "alice");
+        cmd
+    };
+
+    soma()
+        .args(&["audit", "serve", "--socket"])
+        .arg(socket.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("create it with soma audit init"));
+    soma()
+        .args(&["audit", "init"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Created the audit key"));
+    let metadata = std::fs::metadata(key.path()).unwrap();
+    assert_eq!(metadata.len(), 32);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    soma()
+        .args(&["audit", "init"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::starts_with(
+            "Error: Failed to create the audit key",
+        ));
+
+    // Operators other than root hand their entries to the writer
+    let mut serve = std::process::Command::new(assert_cmd::cargo::cargo_bin("soma"))
+        .env("SOMA_AUDIT_LOG", temp.path().join("audit.jsonl"))
+        .env("SOMA_AUDIT_KEY", key.path())
+        .args(&["audit", "serve", "--socket"])
+        .arg(socket.path())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..50 {
+        if socket.path().exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let result = soma()
+        .args(&["run", "web-01", "deploy"])
+        .env("SOMA_AUDIT_SOCKET", socket.path())
+        .env("SOMA_AUDIT_KEY", temp.path().join("missing.key"))
+        .assert()
+        .success();
+    serve.kill().unwrap();
+    serve.wait().unwrap();
+    result.stderr(predicate::str::contains("Warning").not());
+    soma()
+        .args(&["audit", "search", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(",alice,"))
+        .stdout(predicate::str::contains(",action,web-01,ok,,"));
+
+    // With the writer gone, requests are refused
+    soma()
+        .args(&["run", "web-01", "deploy"])
+        .env("SOMA_AUDIT_SOCKET", socket.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("cannot reach the audit writer"));
+}
+
+/// Test the operator is the account soma runs as, which only root may override
+#[test]
+fn test_soma_audit_records_the_account() {
+    let web = spawn_play_agent(true);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &web, "\"web\"")]);
+    let account = std::process::Command::new("id")
+        .arg("-un")
+        .output()
+        .unwrap();
+    let account = String::from_utf8(account.stdout).unwrap();
+    Command::cargo_bin("soma")
+        .unwrap()
+        .env("SOMA_INVENTORY", &inventory)
+        .envs(audit_env(&temp))
+        .env("SOMA_STATE_DIR", temp.path())
+        .env_remove("SOMA_OPERATOR")
+        .env("USER", "mallory")
+        .env("LOGNAME", "mallory")
+        .args(&["run", "web-01", "deploy"])
+        .assert()
+        .success();
+    Command::cargo_bin("soma")
+        .unwrap()
+        .envs(audit_env(&temp))
+        .args(&["audit", "search", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(",{},", account.trim())))
+        .stdout(predicate::str::contains("mallory").not());
+}
+
+/// Test the access policy limits operators to their roles, actions and hosts
+#[test]
+fn test_soma_policy_denies_requests() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let web = spawn_play_agent(true);
+    let db = spawn_play_agent(true);
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(
+        &temp,
+        &[("web-01", &web, "\"web\""), ("db-01", &db, "\"db\"")],
+    );
+    let policy = temp.child("policy.toml");
+    policy
+        .write_str(
+            "[[grants]]\noperators = [\"oncall-*\"]\nrole = \"operator\"\nhosts = \"@web\"\n\
+             actions = [\"check\", \"service restart\"]\n\n\
+             [[grants]]\noperators = [\"alice\"]\nrole = \"admin\"\n",
+        )
+        .unwrap();
+    let soma = |operator: &str| {
+        let mut cmd = Command::cargo_bin("soma").unwrap();
+        cmd.env("SOMA_INVENTORY", &inventory)
+            .envs(audit_env(&temp))
+            .env("SOMA_STATE_DIR", temp.path())
+            .env("SOMA_POLICY", policy.path())
+            .env("SOMA_OPERATOR", operator);
+        cmd
+    };
+
+    soma("oncall-bob")
+        .args(&["service", "web-01", "restart", "nginx.service"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("restart nginx.service"));
+    soma("oncall-bob")
+        .args(&["run", "web-01", "deploy"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Permission denied: oncall-bob is not allowed to run deploy on web-01",
+        ));
+    soma("oncall-bob")
+        .args(&["service", "db-01", "restart", "postgresql.service"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Permission denied: oncall-bob is not allowed to service restart on db-01",
+        ));
+    soma("alice")
+        .args(&["run", "@db", "deploy"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ran deploy"));
+
+    soma("oncall-bob")
+        .args(&["check", "db-01"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("db-01                denied"));
+
+    // Denials are audited, even of requests that are not otherwise
+    soma("alice")
+        .args(&["audit", "search", "--outcome", "failed", "--csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            ",action,web-01,failed,Permission denied: oncall-bob is not allowed to run deploy on web-01,",
+        ))
+        .stdout(predicate::str::contains(",status,db-01,failed,"));
+
+    policy
+        .write_str("[[grants]]\noperators = []\nrole = \"viewer\"\n")
+        .unwrap();
+    soma("alice")
+        .args(&["run", "web-01", "deploy"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid policy"))
+        .stderr(predicate::str::contains("grant 1 names no operators"));
+
+    // A policy others could change grants nothing
+    policy
+        .write_str("[[grants]]\noperators = [\"*\"]\nrole = \"admin\"\n")
+        .unwrap();
+    std::fs::set_permissions(policy.path(), std::fs::Permissions::from_mode(0o666)).unwrap();
+    soma("oncall-bob")
+        .args(&["run", "web-01", "deploy"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "must be owned by root and writable by no one else",
+        ));
+}
+
+/// Test soma signs requests once it has a key, for the host they are sent to
+#[test]
+fn test_soma_signs_requests() {
+    use somacommon::signing;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let request: Request = protocol::read_message(&mut reader).unwrap().unwrap();
+            let response = Response::Action(somacommon::protocol::ActionResult {
+                name: "deploy".to_string(),
+                exit_code: Some(0),
+                ..Default::default()
+            });
+            protocol::write_message(&mut writer, &response).unwrap();
+            tx.send(request).unwrap();
+        }
+    });
+
+    let temp = assert_fs::TempDir::new().unwrap();
+    let inventory = write_inventory(&temp, &[("web-01", &address, "\"web\"")]);
+    let key = temp.child("keys/signing.key");
+    let soma = || {
+        let mut cmd = Command::cargo_bin("soma").unwrap();
+        cmd.env("SOMA_INVENTORY", &inventory)
+            .envs(audit_env(&temp))
+            .env("SOMA_STATE_DIR", temp.path())
+            .env("SOMA_SIGNING_KEY", key.path())
+            .env("SOMA_OPERATOR", "alice");
+        cmd
+    };
+
+    let output = soma().arg("keygen").assert().success().get_output().clone();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let public_key = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("\"alice\" = \""))
+        .and_then(|rest| rest.strip_suffix('"'))
+        .unwrap();
+    let public_key = signing::decode_key(public_key).unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(
+        std::fs::metadata(key.path()).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+    soma()
+        .arg("keygen")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+
+    soma().args(&["run", "web-01", "deploy"]).assert().success();
+    let request = rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+    assert!(signing::verify(&public_key, &request));
+    let Request::Signed { host, .. } = &request else {
+        panic!("Unsigned request {:?}", request);
+    };
+    assert_eq!(host, "web-01");
+    assert_eq!(
+        *request.inner(),
+        Request::Action {
+            name: "deploy".to_string(),
+            noaction: false,
+        }
+    );
+
+    key.write_str("not a key\n").unwrap();
+    soma()
+        .args(&["run", "web-01", "deploy"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid signing key"));
+}
@@ -0,0 +1,519 @@
+//! Append-only, hash-chained audit logs.
+//!
+//! soma and somasrv each keep their own log as JSON lines. Every entry
+//! carries the hash of the one before it and a hash over itself, so editing,
+//! removing or reordering entries breaks the chain from that point on. The
+//! hashes are HMACs keyed with a secret kept apart from the log, so the chain
+//! cannot be rebuilt after an edit without the key. Removing only the newest
+//! entries cannot be detected from the log alone; comparing the head hash
+//! with one recorded elsewhere catches that.
+
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::digest;
+use crate::protocol::{Request, Response};
+
+/// Name of the audit log file in somasrv's state directory
+pub const AUDIT_FILE: &str = "audit.jsonl";
+
+/// Length of the secret an audit log's hashes are keyed with
+pub const KEY_LENGTH: usize = 32;
+
+/// What the first entry of a log chains to
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Ok => f.pad("ok"),
+            Outcome::Failed => f.pad("failed"),
+        }
+    }
+}
+
+/// What is being recorded, before it is placed in the chain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub time: u64,
+    /// Who the command was run for
+    pub operator: String,
+    /// Where the command came from: the soma command line on the controller,
+    /// the connecting address on an agent
+    pub origin: String,
+    /// The kind of request, such as `action` or `copy`
+    pub command: String,
+    pub targets: Vec<String>,
+    pub parameters: Value,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// One line of an audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Position in the log, from 1
+    pub seq: u64,
+    pub time: u64,
+    pub operator: String,
+    pub origin: String,
+    pub command: String,
+    pub targets: Vec<String>,
+    pub parameters: Value,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Hash of the entry before, or [`GENESIS`]
+    pub prev: String,
+    /// Hex HMAC-SHA256 over this entry as JSON with an empty hash, keyed
+    /// with the log's key
+    pub hash: String,
+}
+
+impl Entry {
+    fn chain(record: Record, seq: u64, prev: String, key: &[u8]) -> Entry {
+        let mut entry = Entry {
+            seq,
+            time: record.time,
+            operator: record.operator,
+            origin: record.origin,
+            command: record.command,
+            targets: record.targets,
+            parameters: record.parameters,
+            outcome: record.outcome,
+            message: record.message,
+            prev,
+            hash: String::new(),
+        };
+        entry.hash = entry.digest(key);
+        entry
+    }
+
+    /// The hash this entry should carry in a log with this key
+    pub fn digest(&self, key: &[u8]) -> String {
+        let unsealed = Entry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(
+            serde_json::to_string(&unsealed)
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        digest::to_hex(&mac.finalize().into_bytes())
+    }
+}
+
+/// Why a log failed verification
+#[derive(Debug)]
+pub enum AuditError {
+    ReadError(PathBuf, io::Error),
+    KeyError(PathBuf, io::Error),
+    /// The line at which the chain breaks, counted from 1, and how
+    Broken(usize, String),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditError::ReadError(path, err) => {
+                write!(f, "Failed to read audit log {}: {}", path.display(), err)
+            }
+            AuditError::KeyError(path, err) => {
+                write!(f, "Failed to read audit key {}: {}", path.display(), err)
+            }
+            AuditError::Broken(line, reason) => {
+                write!(f, "Audit log is broken at line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// An audit log file and the file holding its key. The log is created on
+/// the first append; the key never is, see [`AuditLog::create_key`].
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path, key_path: &Path) -> Self {
+        AuditLog {
+            path: path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn key_path(&self) -> &Path {
+        &self.key_path
+    }
+
+    /// Add a record to the end of the chain.
+    ///
+    /// The file is locked while the last entry is read and the new one
+    /// written, so processes and threads appending at once keep one chain.
+    /// Nothing is written, and no log created, without the key.
+    pub fn append(&self, record: Record) -> io::Result<Entry> {
+        let key = self.key().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot read audit key {}: {}", self.key_path.display(), e),
+            )
+        })?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = open_for_append(&self.path)?;
+        file.lock()?;
+        let entry = match last_line(&mut file)? {
+            Some(line) => {
+                let last: Entry = serde_json::from_str(&line).map_err(io::Error::other)?;
+                Entry::chain(record, last.seq + 1, last.hash, &key)
+            }
+            None => Entry::chain(record, 1, GENESIS.to_string(), &key),
+        };
+        let mut line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(entry)
+    }
+
+    /// Every entry in order. A line that is not an entry is an error.
+    pub fn entries(&self) -> Result<Vec<Entry>, AuditError> {
+        let mut entries = Vec::new();
+        self.walk(|_, entry| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Check every link of the chain, returning the number of entries and the
+    /// head hash. A log that does not exist yet is empty and intact.
+    pub fn verify(&self) -> Result<(u64, String), AuditError> {
+        let mut head = (0, GENESIS.to_string());
+        let mut key = None;
+        self.walk(|line, entry| {
+            if entry.seq != head.0 + 1 {
+                return Err(AuditError::Broken(
+                    line,
+                    format!("entry {} follows entry {}", entry.seq, head.0),
+                ));
+            }
+            if entry.prev != head.1 {
+                return Err(AuditError::Broken(
+                    line,
+                    "does not chain to the entry before".to_string(),
+                ));
+            }
+            // Read only once there is an entry, so an empty log needs no key
+            let key = match &key {
+                Some(key) => key,
+                None => key.insert(
+                    self.key()
+                        .map_err(|e| AuditError::KeyError(self.key_path.clone(), e))?,
+                ),
+            };
+            if entry.hash != entry.digest(key) {
+                return Err(AuditError::Broken(
+                    line,
+                    "entry was changed after it was written".to_string(),
+                ));
+            }
+            head = (entry.seq, entry.hash);
+            Ok(())
+        })?;
+        Ok(head)
+    }
+
+    /// Whether the key can be read and is a key, so entries can be appended
+    pub fn check_key(&self) -> io::Result<()> {
+        self.key().map(|_| ())
+    }
+
+    fn key(&self) -> io::Result<Vec<u8>> {
+        let key = fs::read(&self.key_path)?;
+        if key.len() != KEY_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} bytes, found {}", KEY_LENGTH, key.len()),
+            ));
+        }
+        Ok(key)
+    }
+
+    /// Make the key from the system's random number generator, readable
+    /// only by its owner. An existing key is never replaced, since entries
+    /// already chained with it could no longer be verified.
+    pub fn create_key(&self) -> io::Result<()> {
+        let mut key = vec![0; KEY_LENGTH];
+        File::open("/dev/urandom")?.read_exact(&mut key)?;
+        if let Some(dir) = self.key_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = create_private(&self.key_path)?;
+        file.write_all(&key)?;
+        file.sync_all()
+    }
+
+    fn walk<F>(&self, mut visit: F) -> Result<(), AuditError>
+    where
+        F: FnMut(usize, Entry) -> Result<(), AuditError>,
+    {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(AuditError::ReadError(self.path.clone(), e)),
+        };
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| AuditError::ReadError(self.path.clone(), e))?;
+            let entry: Entry = serde_json::from_str(&line)
+                .map_err(|e| AuditError::Broken(index + 1, format!("not an audit entry: {}", e)))?;
+            visit(index + 1, entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn open_for_append(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_for_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// The last non-empty line of a file, read backwards from the end
+fn last_line(file: &mut File) -> io::Result<Option<String>> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut tail: Vec<u8> = Vec::new();
+    let mut block = [0; 4096];
+    while end > 0 {
+        let take = end.min(block.len() as u64);
+        end -= take;
+        file.seek(SeekFrom::Start(end))?;
+        file.read_exact(&mut block[..take as usize])?;
+        tail.splice(0..0, block[..take as usize].iter().copied());
+        let trimmed = tail.trim_ascii_end();
+        if let Some(newline) = trimmed.iter().rposition(|b| *b == b'\n') {
+            let line = &trimmed[newline + 1..];
+            return Ok(Some(String::from_utf8_lossy(line).into_owned()));
+        }
+    }
+    let trimmed = tail.trim_ascii_end();
+    Ok((!trimmed.is_empty()).then(|| String::from_utf8_lossy(trimmed).into_owned()))
+}
+
+/// The kind of a request as named on the wire, such as `action`
+pub fn command(request: &Request) -> String {
+    serde_json::to_value(request)
+        .ok()
+        .and_then(|v| v.get("type")?.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// A request's fields for the audit log, without its type, file contents or tokens
+pub fn parameters(request: &Request) -> Value {
+    let mut value = serde_json::to_value(request).unwrap_or_default();
+    if let Value::Object(fields) = &mut value {
+        fields.remove("type");
+    }
+    strip_secrets(&mut value);
+    value
+}
+
+/// File contents are large and may be secret, so only their hashes are kept
+fn strip_secrets(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.remove("content");
+            fields.remove("token");
+            fields.values_mut().for_each(strip_secrets);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_secrets),
+        _ => {}
+    }
+}
+
+/// Whether an agent's answer means the request failed, and why
+pub fn outcome(response: &Response) -> (Outcome, Option<String>) {
+    match response {
+        Response::Error { message } => (Outcome::Failed, Some(message.clone())),
+        Response::Denied(denial) => (Outcome::Failed, Some(denial.to_string())),
+        Response::Action(result) => match result.exit_code {
+            Some(0) => (Outcome::Ok, None),
+            Some(code) => (Outcome::Failed, Some(format!("exit code {}", code))),
+            // Dry runs are answered without an exit code
+            None if result.stderr.is_empty() => (Outcome::Ok, None),
+            None => (Outcome::Failed, Some(result.stderr.clone())),
+        },
+        Response::Apply { results } => match results
+            .iter()
+            .find(|r| r.outcome == crate::resources::Outcome::Failed)
+        {
+            Some(failed) => (
+                Outcome::Failed,
+                Some(format!("{}: {}", failed.resource, failed.message)),
+            ),
+            None => (Outcome::Ok, None),
+        },
+        _ => (Outcome::Ok, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(command: &str) -> Record {
+        Record {
+            time: 1_700_000_000,
+            operator: "alice".to_string(),
+            origin: "soma run @web deploy".to_string(),
+            command: command.to_string(),
+            targets: vec!["web-01".to_string()],
+            parameters: serde_json::json!({"name": "deploy"}),
+            outcome: Outcome::Ok,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn chains_entries_and_detects_tampering() {
+        let dir = std::env::temp_dir().join(format!("somacommon-audit-{}", std::process::id()));
+        let log = AuditLog::new(&dir.join(AUDIT_FILE), &dir.join("audit.key"));
+        assert_eq!(log.verify().unwrap(), (0, GENESIS.to_string()));
+        assert!(log.append(record("action")).is_err());
+        assert!(!log.path().exists());
+        log.create_key().unwrap();
+        assert!(log.create_key().is_err());
+
+        let first = log.append(record("action")).unwrap();
+        let second = log.append(record("copy")).unwrap();
+        assert_eq!(first.prev, GENESIS);
+        assert_eq!(second.prev, first.hash);
+        assert_eq!(log.verify().unwrap(), (2, second.hash.clone()));
+        assert_eq!(log.entries().unwrap(), vec![first, second]);
+
+        let content = std::fs::read_to_string(log.path()).unwrap();
+        std::fs::write(log.path(), content.replace("alice", "mallory")).unwrap();
+        assert_eq!(
+            log.verify().unwrap_err().to_string(),
+            "Audit log is broken at line 1: entry was changed after it was written"
+        );
+
+        let second_line = content.lines().nth(1).unwrap();
+        std::fs::write(log.path(), format!("{}\n", second_line)).unwrap();
+        assert_eq!(
+            log.verify().unwrap_err().to_string(),
+            "Audit log is broken at line 1: entry 2 follows entry 0"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_rehashed_without_the_key_are_detected() {
+        let dir = std::env::temp_dir().join(format!("somacommon-audit-key-{}", std::process::id()));
+        let log = AuditLog::new(&dir.join(AUDIT_FILE), &dir.join("secret.key"));
+        log.create_key().unwrap();
+        log.append(record("action")).unwrap();
+        let key = std::fs::read(log.key_path()).unwrap();
+        assert_eq!(key.len(), KEY_LENGTH);
+
+        // Rewriting an entry and sealing it with a plain hash or another key
+        // does not pass
+        let mut entry = log.entries().unwrap().remove(0);
+        entry.operator = "mallory".to_string();
+        entry.hash = entry.digest(&[0; KEY_LENGTH]);
+        let line = serde_json::to_string(&entry).unwrap();
+        std::fs::write(log.path(), format!("{}\n", line)).unwrap();
+        assert_eq!(
+            log.verify().unwrap_err().to_string(),
+            "Audit log is broken at line 1: entry was changed after it was written"
+        );
+        entry.hash = entry.digest(&key);
+        let line = serde_json::to_string(&entry).unwrap();
+        std::fs::write(log.path(), format!("{}\n", line)).unwrap();
+        assert_eq!(log.verify().unwrap().0, 1);
+
+        // A log with entries is neither verified nor extended without its key
+        std::fs::remove_file(log.key_path()).unwrap();
+        assert!(
+            log.verify()
+                .unwrap_err()
+                .to_string()
+                .starts_with("Failed to read audit key")
+        );
+        assert!(log.append(record("copy")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_file_contents_out() {
+        let request = Request::Copy {
+            path: "/etc/motd".to_string(),
+            content: "aGVsbG8K".to_string(),
+            sha256: "abc".to_string(),
+            owner: None,
+            group: None,
+            mode: Some(0o644),
+            backup: true,
+            noaction: false,
+        };
+        let parameters = parameters(&request);
+        assert_eq!(parameters["path"], "/etc/motd");
+        assert_eq!(parameters["sha256"], "abc");
+        assert!(parameters.get("content").is_none());
+        assert!(parameters.get("type").is_none());
+    }
+}
@@ -0,0 +1,34 @@
+//! Standard base64 with padding, for carrying file contents and keys in JSON messages.
+
+use ::base64::Engine;
+use ::base64::engine::general_purpose::STANDARD;
+
+pub fn encode(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+pub fn decode(text: &str) -> Result<Vec<u8>, String> {
+    STANDARD
+        .decode(text)
+        .map_err(|e| format!("Invalid base64: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(encode(data), encoded);
+            assert_eq!(decode(encoded).unwrap(), data);
+        }
+        assert!(decode("Zm9v!").is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::status::Health;
+
+/// What a scheduled job runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// A command from the agent's `[checks]`
+    Check,
+    /// An allowlisted action from the agent's `[actions]`
+    Action,
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            JobKind::Check => "check",
+            JobKind::Action => "action",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The latest run of a job the agent runs on its own schedule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobResult {
+    pub name: String,
+    pub kind: JobKind,
+    /// Seconds since the Unix epoch of the run this was, as scheduled
+    pub scheduled: u64,
+    /// Seconds since the Unix epoch when the run started
+    pub started: u64,
+    pub duration_ms: u64,
+    /// `None` when the command could not be run or was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Standard output followed by standard error, trimmed
+    pub output: String,
+    /// Run late to make up for a run missed while the agent was down
+    #[serde(default)]
+    pub catch_up: bool,
+}
+
+impl JobResult {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Result of a Nagios-compatible plugin, from its exit code
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginState {
+    Ok,
+    Warning,
+    Critical,
+    /// Exit code 3, any other exit code, or the plugin could not be run
+    #[default]
+    Unknown,
+}
+
+impl PluginState {
+    pub fn from_exit_code(code: Option<i32>) -> PluginState {
+        match code {
+            Some(0) => PluginState::Ok,
+            Some(1) => PluginState::Warning,
+            Some(2) => PluginState::Critical,
+            _ => PluginState::Unknown,
+        }
+    }
+
+    /// The exit code a plugin reports this state with
+    pub fn exit_code(&self) -> i32 {
+        *self as i32
+    }
+
+    /// How the state counts towards a host's health. A plugin that cannot
+    /// tell is a warning rather than making the whole host unknown.
+    pub fn health(&self) -> Health {
+        match self {
+            PluginState::Ok => Health::Healthy,
+            PluginState::Warning | PluginState::Unknown => Health::Warning,
+            PluginState::Critical => Health::Critical,
+        }
+    }
+}
+
+impl fmt::Display for PluginState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PluginState::Ok => "ok",
+            PluginState::Warning => "warning",
+            PluginState::Critical => "critical",
+            PluginState::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One performance data item, `'label'=value[unit];[warn];[crit];[min];[max]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Perfdata {
+    pub label: String,
+    pub value: f64,
+    #[serde(default)]
+    pub unit: String,
+    /// Warning and critical ranges, as the plugin gave them
+    #[serde(default)]
+    pub warning: Option<String>,
+    #[serde(default)]
+    pub critical: Option<String>,
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+impl fmt::Display for Perfdata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.label.contains([' ', '=', '\'']) {
+            write!(f, "'{}'", self.label.replace('\'', "''"))?;
+        } else {
+            write!(f, "{}", self.label)?;
+        }
+        write!(f, "={}{}", self.value, self.unit)?;
+        let min = self.min.map(|v| v.to_string());
+        let max = self.max.map(|v| v.to_string());
+        let rest = [&self.warning, &self.critical, &min, &max];
+        let used = rest.iter().rposition(|v| v.is_some()).map_or(0, |i| i + 1);
+        for value in &rest[..used] {
+            write!(f, ";{}", value.as_deref().unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+/// What a plugin found when run on a host
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PluginResult {
+    pub name: String,
+    pub state: PluginState,
+    /// The first line of output, without performance data
+    pub output: String,
+    /// Any further lines of output
+    #[serde(default)]
+    pub long_output: String,
+    #[serde(default)]
+    pub perfdata: Vec<Perfdata>,
+    pub duration_ms: u64,
+}
+
+/// What a built-in probe found, checked from the agent's side of the network
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub name: String,
+    /// `http`, `tcp`, `dns` or `tls`
+    pub kind: String,
+    /// The URL, address or name probed
+    pub target: String,
+    pub state: PluginState,
+    pub message: String,
+    /// How long the request, connection, lookup or handshake took
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+}
+
+/// Split plugin output into its first line, the lines after it and the
+/// performance data following a `|` on the first line or in the long output
+pub fn parse_plugin_output(text: &str) -> (String, String, Vec<Perfdata>) {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default();
+    let (output, mut perfdata) = match first.split_once('|') {
+        Some((output, perf)) => (output, parse_perfdata(perf)),
+        None => (first, Vec::new()),
+    };
+    let mut long_output = Vec::new();
+    let mut in_perfdata = false;
+    for line in lines {
+        if in_perfdata {
+            perfdata.extend(parse_perfdata(line));
+        } else if let Some((text, perf)) = line.split_once('|') {
+            long_output.push(text);
+            perfdata.extend(parse_perfdata(perf));
+            in_perfdata = true;
+        } else {
+            long_output.push(line);
+        }
+    }
+    (
+        output.trim().to_string(),
+        long_output.join("\n").trim().to_string(),
+        perfdata,
+    )
+}
+
+/// Parse space-separated performance data items, skipping any that do not parse
+pub fn parse_perfdata(text: &str) -> Vec<Perfdata> {
+    let mut items = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        // Quoted labels may contain spaces, with '' standing for a quote
+        let (label, after) = if let Some(quoted) = rest.strip_prefix('\'') {
+            let mut label = String::new();
+            let mut chars = quoted.char_indices().peekable();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                if c == '\'' {
+                    if chars.peek().is_some_and(|(_, c)| *c == '\'') {
+                        chars.next();
+                    } else {
+                        end = i + 1;
+                        break;
+                    }
+                }
+                label.push(c);
+            }
+            (label, &quoted[end..])
+        } else {
+            let end = rest.find('=').unwrap_or(rest.len());
+            (rest[..end].to_string(), &rest[end..])
+        };
+        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+        if let Some(data) = after[..end].strip_prefix('=')
+            && let Some(item) = parse_perfdata_item(label, data)
+        {
+            items.push(item);
+        }
+        rest = after[end..].trim_start();
+    }
+    items
+}
+
+fn parse_perfdata_item(label: String, data: &str) -> Option<Perfdata> {
+    let mut fields = data.split(';');
+    let value = fields.next()?;
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E')))
+        .unwrap_or(value.len());
+    let optional = |field: Option<&str>| field.filter(|f| !f.is_empty()).map(str::to_string);
+    let warning = optional(fields.next());
+    let critical = optional(fields.next());
+    let min = optional(fields.next()).and_then(|v| v.parse().ok());
+    let max = optional(fields.next()).and_then(|v| v.parse().ok());
+    Some(Perfdata {
+        label,
+        value: value[..split].parse().ok()?,
+        unit: value[split..].to_string(),
+        warning,
+        critical,
+        min,
+        max,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plugin_output() {
+        let (output, long_output, perfdata) = parse_plugin_output(
+            "DISK WARNING - free space: / 3326 MB (9%) | /=2643MB;5948;5958;0;5968\n\
+             / 15272 MB (77%);\n\
+             /boot 68 MB (69%); | /boot=68MB;88;93;0;98\n\
+             'home dir'=69.5%;80;90 bogus in=U",
+        );
+        assert_eq!(output, "DISK WARNING - free space: / 3326 MB (9%)");
+        assert_eq!(long_output, "/ 15272 MB (77%);\n/boot 68 MB (69%);");
+        assert_eq!(perfdata.len(), 3);
+        assert_eq!(perfdata[0].label, "/");
+        assert_eq!(perfdata[0].value, 2643.0);
+        assert_eq!(perfdata[0].unit, "MB");
+        assert_eq!(perfdata[0].critical.as_deref(), Some("5958"));
+        assert_eq!(perfdata[0].max, Some(5968.0));
+        assert_eq!(perfdata[2].label, "home dir");
+        assert_eq!(perfdata[2].to_string(), "'home dir'=69.5%;80;90");
+        assert_eq!(perfdata[1].to_string(), "/boot=68MB;88;93;0;98");
+
+        let (output, long_output, perfdata) = parse_plugin_output("OK\n");
+        assert_eq!((output.as_str(), long_output.as_str()), ("OK", ""));
+        assert!(perfdata.is_empty());
+    }
+
+    #[test]
+    fn maps_exit_codes_to_states() {
+        assert_eq!(PluginState::from_exit_code(Some(0)), PluginState::Ok);
+        assert_eq!(PluginState::from_exit_code(Some(2)), PluginState::Critical);
+        assert_eq!(PluginState::from_exit_code(Some(3)), PluginState::Unknown);
+        assert_eq!(PluginState::from_exit_code(Some(127)), PluginState::Unknown);
+        assert_eq!(PluginState::from_exit_code(None), PluginState::Unknown);
+        assert_eq!(PluginState::Unknown.health(), Health::Warning);
+    }
+}
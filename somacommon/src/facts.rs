@@ -0,0 +1,133 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Operating system details from /etc/os-release and the kernel
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OsFacts {
+    /// Lower-case distribution identifier, e.g. `debian`
+    pub id: String,
+    pub version_id: String,
+    pub pretty_name: String,
+    pub kernel: String,
+}
+
+/// Hardware identity from /sys/class/dmi/id
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DmiFacts {
+    pub vendor: String,
+    pub product: String,
+    /// Only readable by root, so often empty
+    pub serial: String,
+    pub bios_version: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CpuFacts {
+    pub model: String,
+    pub sockets: usize,
+    pub cores: usize,
+    pub threads: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockDevice {
+    pub name: String,
+    pub size_bytes: u64,
+    pub model: String,
+    pub rotational: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub mac: String,
+    pub addresses: Vec<String>,
+}
+
+/// Slow-changing hardware and OS facts about a host
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Facts {
+    pub hostname: String,
+    pub os: OsFacts,
+    pub dmi: DmiFacts,
+    pub cpu: CpuFacts,
+    pub memory_kb: u64,
+    pub block_devices: Vec<BlockDevice>,
+    pub interfaces: Vec<NetworkInterface>,
+    /// Seconds since the Unix epoch when the facts were gathered
+    pub collected_at: u64,
+}
+
+impl Facts {
+    /// Facts as dotted keys, e.g. `os.id` or `net.eth0.mac`, for use in selectors
+    pub fn flatten(&self) -> BTreeMap<String, String> {
+        let mut keys = BTreeMap::new();
+        let mut put = |key: String, value: String| {
+            keys.insert(key, value);
+        };
+        put("hostname".to_string(), self.hostname.clone());
+        put("os.id".to_string(), self.os.id.clone());
+        put("os.version_id".to_string(), self.os.version_id.clone());
+        put("os.pretty_name".to_string(), self.os.pretty_name.clone());
+        put("os.kernel".to_string(), self.os.kernel.clone());
+        put("dmi.vendor".to_string(), self.dmi.vendor.clone());
+        put("dmi.product".to_string(), self.dmi.product.clone());
+        put("dmi.serial".to_string(), self.dmi.serial.clone());
+        put(
+            "dmi.bios_version".to_string(),
+            self.dmi.bios_version.clone(),
+        );
+        put("cpu.model".to_string(), self.cpu.model.clone());
+        put("cpu.sockets".to_string(), self.cpu.sockets.to_string());
+        put("cpu.cores".to_string(), self.cpu.cores.to_string());
+        put("cpu.threads".to_string(), self.cpu.threads.to_string());
+        put("memory.total_kb".to_string(), self.memory_kb.to_string());
+        for device in &self.block_devices {
+            let prefix = format!("disk.{}", device.name);
+            put(
+                format!("{}.size_bytes", prefix),
+                device.size_bytes.to_string(),
+            );
+            put(format!("{}.model", prefix), device.model.clone());
+            put(
+                format!("{}.rotational", prefix),
+                device.rotational.to_string(),
+            );
+        }
+        for interface in &self.interfaces {
+            let prefix = format!("net.{}", interface.name);
+            put(format!("{}.mac", prefix), interface.mac.clone());
+            put(
+                format!("{}.addresses", prefix),
+                interface.addresses.join(" "),
+            );
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn facts_flatten_to_selector_keys() {
+        let facts = Facts {
+            os: OsFacts {
+                id: "debian".to_string(),
+                ..Default::default()
+            },
+            interfaces: vec![NetworkInterface {
+                name: "eth0".to_string(),
+                mac: "52:54:00:12:34:56".to_string(),
+                addresses: vec!["10.0.0.5".to_string()],
+            }],
+            ..Default::default()
+        };
+        let keys = facts.flatten();
+        assert_eq!(keys["os.id"], "debian");
+        assert_eq!(keys["net.eth0.mac"], "52:54:00:12:34:56");
+        assert_eq!(keys["cpu.threads"], "0");
+    }
+}
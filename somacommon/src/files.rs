@@ -0,0 +1,29 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Outcome of writing a file on an agent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CopyResult {
+    pub path: String,
+    /// Whether the content, mode or ownership was (or with noaction would be) changed
+    pub changed: bool,
+    /// Hex SHA-256 of the file's content after the copy
+    pub sha256: String,
+    /// Where the replaced file was kept, when one was replaced
+    pub backup: Option<String>,
+    pub message: String,
+}
+
+/// Most bytes an agent returns for one fetch request; larger files take several requests
+pub const FETCH_CHUNK: u64 = 256 * 1024;
+
+/// Part of a file read from an agent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub path: String,
+    /// Size of the whole file when the chunk was read
+    pub size: u64,
+    /// Where in the file the chunk starts
+    pub offset: u64,
+    /// The chunk's bytes in base64; empty at the end of the file
+    pub data: String,
+}
@@ -1,9 +1,95 @@
+pub mod audit;
+pub mod base64;
+pub mod checks;
+pub mod digest;
+pub mod facts;
+pub mod files;
+pub mod packages;
+pub mod policy;
+pub mod ports;
+pub mod prometheus;
+pub mod protocol;
+pub mod resources;
+pub mod selector;
+pub mod service;
+pub mod signing;
+pub mod status;
+
 use serde_derive::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Host {
     hostname: String,
+    /// Seconds since the Unix epoch when the host's agent last answered or heartbeated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_seen: Option<u64>,
+}
+
+/// How recently a host has been heard from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Liveness {
+    Live,
+    /// Not heard from for longer than the stale interval
+    Stale,
+    /// Not heard from for longer than the lost interval
+    Lost,
+    /// Never heard from
+    Never,
+}
+
+impl fmt::Display for Liveness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Liveness::Live => "live",
+            Liveness::Stale => "stale",
+            Liveness::Lost => "lost",
+            Liveness::Never => "never",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Host {
+    pub fn new(hostname: &str) -> Self {
+        Host {
+            hostname: hostname.to_string(),
+            last_seen: None,
+        }
+    }
+
+    pub fn with_last_seen(hostname: &str, last_seen: Option<u64>) -> Self {
+        Host {
+            hostname: hostname.to_string(),
+            last_seen,
+        }
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn last_seen(&self) -> Option<u64> {
+        self.last_seen
+    }
+
+    /// Liveness at time `now`, given the stale and lost intervals in seconds
+    pub fn liveness(&self, now: u64, stale_after: u64, lost_after: u64) -> Liveness {
+        match self.last_seen {
+            None => Liveness::Never,
+            Some(seen) => {
+                let age = now.saturating_sub(seen);
+                if age > lost_after {
+                    Liveness::Lost
+                } else if age > stale_after {
+                    Liveness::Stale
+                } else {
+                    Liveness::Live
+                }
+            }
+        }
+    }
 }
 
 impl fmt::Display for Host {
@@ -12,6 +98,41 @@ impl fmt::Display for Host {
     }
 }
 
+/// Match `text` against a pattern where `*` matches any run of characters and `?` one character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Compare tokens without stopping at the first difference
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -21,8 +142,21 @@ mod tests {
         let mut hosts: Vec<Host> = Vec::new();
         hosts.push(Host {
             hostname: "example.com".to_string(),
+            last_seen: None,
         });
         assert!(hosts.len() == 1);
         assert!(hosts[0].hostname == "example.com");
     }
+
+    #[test]
+    fn liveness_follows_last_seen() {
+        let host = Host::with_last_seen("example.com", Some(1000));
+        assert_eq!(host.liveness(1100, 300, 3600), Liveness::Live);
+        assert_eq!(host.liveness(1400, 300, 3600), Liveness::Stale);
+        assert_eq!(host.liveness(5000, 300, 3600), Liveness::Lost);
+        assert_eq!(
+            Host::new("example.com").liveness(1000, 300, 3600),
+            Liveness::Never
+        );
+    }
 }
@@ -0,0 +1,20 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// An installed package as reported by the host's package database
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub architecture: String,
+}
+
+/// Installed packages on a host
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageReport {
+    /// Package database the list came from, `dpkg` or `rpm`, empty when none was found
+    pub manager: String,
+    pub packages: Vec<Package>,
+    /// Number of packages with an update available, when the package tool could
+    /// say; only counted when all packages are listed
+    pub upgradable: Option<usize>,
+}
@@ -0,0 +1,233 @@
+//! Role-based access control for operators.
+//!
+//! A policy is a list of grants, each giving operators matching a glob a
+//! role on the hosts picked by a selector, optionally narrowed to some
+//! actions. soma checks its policy before contacting agents, so operators
+//! learn early what they may not do. What enforces it is an agent given a
+//! copy: it refuses requests its grants do not allow the operator whose key
+//! signed them, whatever path they took to reach it.
+
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::glob_match;
+use crate::protocol::Request;
+use crate::service::ServiceOperation;
+
+/// What an operator may do, each role allowing everything the one before does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Status reports, checks, facts, packages, ports and unit states
+    Viewer,
+    /// Also actions, service changes and file fetches
+    Operator,
+    /// Also file copies and desired-state documents
+    Admin,
+}
+
+impl Role {
+    /// Whether the role allows an action named by [`action`]
+    pub fn allows(self, action: &str) -> bool {
+        let command = action.split(' ').next().unwrap_or_default();
+        let viewer = matches!(command, "check" | "facts" | "packages" | "ports")
+            || action == format!("service {}", ServiceOperation::Status);
+        match self {
+            Role::Viewer => viewer,
+            Role::Operator => viewer || matches!(command, "run" | "service" | "fetch"),
+            Role::Admin => true,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// A role given to some operators on some hosts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    /// Operator name globs
+    pub operators: Vec<String>,
+    pub role: Role,
+    /// Host selector the grant covers; every host when not given
+    #[serde(default)]
+    pub hosts: Option<String>,
+    /// Globs over the actions allowed, such as `check` or `service restart`,
+    /// matched against the whole action or its first word; everything the
+    /// role allows when empty
+    #[serde(default)]
+    pub actions: Vec<String>,
+}
+
+impl Grant {
+    fn allows(&self, operator: &str, action: &str, selected: &dyn Fn(&str) -> bool) -> bool {
+        let command = action.split(' ').next().unwrap_or_default();
+        self.operators.iter().any(|p| glob_match(p, operator))
+            && self.hosts.as_deref().is_none_or(selected)
+            && self.role.allows(action)
+            && (self.actions.is_empty()
+                || self
+                    .actions
+                    .iter()
+                    .any(|p| glob_match(p, action) || glob_match(p, command)))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+}
+
+impl Policy {
+    /// Check that the policy lets `operator` make `request` of `host`, where
+    /// `selected` tells whether a grant's host selector picks the host
+    pub fn check(
+        &self,
+        operator: &str,
+        request: &Request,
+        host: &str,
+        selected: &dyn Fn(&str) -> bool,
+    ) -> Result<(), Denial> {
+        let action = action(request);
+        if self
+            .grants
+            .iter()
+            .any(|grant| grant.allows(operator, &action, selected))
+        {
+            Ok(())
+        } else {
+            Err(Denial {
+                operator: operator.to_string(),
+                action,
+                host: host.to_string(),
+            })
+        }
+    }
+
+    /// Check that every grant names operators
+    pub fn validate(&self) -> Result<(), String> {
+        match self.grants.iter().position(|g| g.operators.is_empty()) {
+            Some(index) => Err(format!("grant {} names no operators", index + 1)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A request refused by a policy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Denial {
+    pub operator: String,
+    pub action: String,
+    pub host: String,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is not allowed to {} on {}",
+            self.operator, self.action, self.host
+        )
+    }
+}
+
+impl std::error::Error for Denial {}
+
+/// The action a request asks for, in the words of the soma command making it,
+/// such as `check`, `run deploy` or `service restart`
+pub fn action(request: &Request) -> String {
+    match request {
+        Request::Status | Request::Results => "check".to_string(),
+        Request::Plugin { name } => format!("check {}", name),
+        Request::Facts { .. } => "facts".to_string(),
+        Request::Packages { .. } => "packages".to_string(),
+        Request::Ports => "ports".to_string(),
+        Request::Action { name, .. } => format!("run {}", name),
+        Request::Service { operation, .. } => format!("service {}", operation),
+        Request::Copy { path, .. } => format!("copy {}", path),
+        Request::Fetch { path, .. } => format!("fetch {}", path),
+        Request::Apply { .. } => "apply".to_string(),
+        Request::Forward { request, .. }
+        | Request::Relay { request, .. }
+        | Request::Signed { request, .. } => action(request),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            grants: vec![
+                Grant {
+                    operators: vec!["oncall-*".to_string()],
+                    role: Role::Operator,
+                    hosts: Some("@web".to_string()),
+                    actions: vec!["check".to_string(), "service restart".to_string()],
+                },
+                Grant {
+                    operators: vec!["alice".to_string()],
+                    role: Role::Admin,
+                    hosts: None,
+                    actions: Vec::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn grants_limit_actions_and_hosts() {
+        let policy = policy();
+        let web = |selector: &str| selector == "@web";
+        let db = |_: &str| false;
+        let restart = Request::Service {
+            unit: "nginx.service".to_string(),
+            operation: ServiceOperation::Restart,
+            noaction: false,
+        };
+        let run = Request::Action {
+            name: "deploy".to_string(),
+            noaction: false,
+        };
+        let plugin = Request::Plugin {
+            name: "raid".to_string(),
+        };
+
+        assert!(policy.check("oncall-bob", &restart, "web-01", &web).is_ok());
+        assert!(policy.check("oncall-bob", &plugin, "web-01", &web).is_ok());
+        assert!(policy.check("oncall-bob", &restart, "db-01", &db).is_err());
+        assert!(policy.check("alice", &run, "db-01", &db).is_ok());
+        assert_eq!(
+            policy.check("oncall-bob", &run, "web-01", &web),
+            Err(Denial {
+                operator: "oncall-bob".to_string(),
+                action: "run deploy".to_string(),
+                host: "web-01".to_string(),
+            })
+        );
+        assert!(
+            policy
+                .check("mallory", &Request::Status, "web-01", &web)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn roles_build_on_each_other() {
+        assert!(Role::Viewer.allows("check"));
+        assert!(Role::Viewer.allows("service status"));
+        assert!(!Role::Viewer.allows("service restart"));
+        assert!(Role::Operator.allows("run deploy"));
+        assert!(!Role::Operator.allows("copy /etc/motd"));
+        assert!(Role::Admin.allows("apply"));
+    }
+}
@@ -0,0 +1,39 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => f.pad("tcp"),
+            Protocol::Udp => f.pad("udp"),
+        }
+    }
+}
+
+/// A socket accepting connections or datagrams on a host
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Listener {
+    pub protocol: Protocol,
+    /// Local address the socket is bound to; `0.0.0.0` or `::` for all addresses
+    pub address: String,
+    pub port: u16,
+    /// Owning process, when the agent may see it
+    pub pid: Option<u32>,
+    pub process: Option<String>,
+}
+
+impl Listener {
+    /// Whether the socket only accepts traffic from the host itself
+    pub fn is_loopback(&self) -> bool {
+        self.address
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+    }
+}
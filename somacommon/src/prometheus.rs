@@ -0,0 +1,229 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// How long a scraper may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+}
+
+/// A metric family as exposed: its name, type, help text and the names of
+/// the labels each of its samples carries, in order
+#[derive(Debug)]
+pub struct Family {
+    pub name: &'static str,
+    pub kind: MetricType,
+    pub help: &'static str,
+    pub labels: &'static [&'static str],
+}
+
+/// A page of metrics in the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Exposition::default()
+    }
+
+    /// Add a family with samples given as label values, in the order of the
+    /// family's labels, and a value. A family without samples is left out.
+    pub fn add<I>(&mut self, family: &Family, samples: I)
+    where
+        I: IntoIterator<Item = (Vec<String>, f64)>,
+    {
+        let mut samples = samples.into_iter().peekable();
+        if samples.peek().is_none() {
+            return;
+        }
+        let kind = match family.kind {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+        };
+        let _ = writeln!(self.text, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(self.text, "# TYPE {} {}", family.name, kind);
+        for (values, value) in samples {
+            debug_assert_eq!(values.len(), family.labels.len());
+            let labels: Vec<String> = family
+                .labels
+                .iter()
+                .zip(&values)
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            if labels.is_empty() {
+                let _ = writeln!(self.text, "{} {}", family.name, format_value(value));
+            } else {
+                let _ = writeln!(
+                    self.text,
+                    "{}{{{}}} {}",
+                    family.name,
+                    labels.join(","),
+                    format_value(value)
+                );
+            }
+        }
+    }
+
+    /// Add a family with a single unlabelled sample
+    pub fn add_one(&mut self, family: &Family, value: f64) {
+        self.add(family, [(Vec::new(), value)]);
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Answer `GET /metrics` on `listener` with the page `render` produces when
+/// asked, one thread per connection. Every other path is not found.
+pub fn serve<F>(listener: TcpListener, render: F)
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let render = Arc::new(render);
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let render = Arc::clone(&render);
+        thread::spawn(move || {
+            let _ = handle_connection(stream, &*render);
+        });
+    }
+}
+
+fn handle_connection(stream: TcpStream, render: &dyn Fn() -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are not needed, but must be read before answering
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    let (status, content_type, body) = match (method, path) {
+        ("GET" | "HEAD", "/metrics") => ("200 OK", CONTENT_TYPE, render()),
+        ("GET" | "HEAD", _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    writer.write_all(head.as_bytes())?;
+    if method != "HEAD" {
+        writer.write_all(body.as_bytes())?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const DISK: Family = Family {
+        name: "test_disk_used_bytes",
+        kind: MetricType::Gauge,
+        help: "Bytes used on a mount",
+        labels: &["mount"],
+    };
+
+    #[test]
+    fn writes_families_with_escaped_labels() {
+        let mut page = Exposition::new();
+        page.add(
+            &DISK,
+            vec![
+                (vec!["/".to_string()], 1024.0),
+                (vec!["/mnt/\"odd\"".to_string()], 0.5),
+            ],
+        );
+        page.add(&DISK, Vec::new());
+        page.add_one(
+            &Family {
+                name: "test_up",
+                kind: MetricType::Counter,
+                help: "Always up",
+                labels: &[],
+            },
+            f64::INFINITY,
+        );
+        assert_eq!(
+            page.into_text(),
+            "# HELP test_disk_used_bytes Bytes used on a mount\n\
+             # TYPE test_disk_used_bytes gauge\n\
+             test_disk_used_bytes{mount=\"/\"} 1024\n\
+             test_disk_used_bytes{mount=\"/mnt/\\\"odd\\\"\"} 0.5\n\
+             # HELP test_up Always up\n\
+             # TYPE test_up counter\n\
+             test_up +Inf\n"
+        );
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, || "test_up 1\n".to_string()));
+
+        let get = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("GET /metrics HTTP/1.1\r\nHost: agent\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.ends_with("\r\n\r\ntest_up 1\n"));
+        assert!(get("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(get("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+    }
+}
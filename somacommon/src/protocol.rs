@@ -0,0 +1,306 @@
+//! Messages exchanged between soma and somasrv.
+//!
+//! Every message is a single line of JSON, so a connection can carry any
+//! number of requests and responses in sequence.
+//!
+//! Agents normally listen and soma dials them. An agent behind NAT instead
+//! dials `soma serve`, introduces itself with [`AgentMessage::Hello`], and
+//! then answers [`ControllerMessage::Request`]s arriving over that connection.
+//!
+//! Agents on segments soma cannot reach are addressed through a relay: an
+//! agent configured to pass [`Request::Relay`]s on to its neighbours.
+//!
+//! With a signing key soma wraps each request in a [`Request::Signed`], see
+//! [`signing`](crate::signing). The key it is signed with tells agents which
+//! operator it is made for, for their audit logs and access policies.
+
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::{self, BufRead, Write};
+
+use crate::checks::{JobResult, PluginResult};
+use crate::facts::Facts;
+use crate::files::{CopyResult, FileChunk};
+use crate::packages::PackageReport;
+use crate::policy::Denial;
+use crate::ports::Listener;
+use crate::resources::{Resource, ResourceResult};
+use crate::service::{ServiceOperation, ServiceResult};
+use crate::status::StatusReport;
+
+/// Port somasrv listens on when none is given
+pub const DEFAULT_PORT: u16 = 7070;
+
+/// Port `soma serve` listens on for agents dialling in
+pub const DEFAULT_CONTROLLER_PORT: u16 = 7071;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// Ask for a current status report
+    Status,
+    /// Run one of the actions allowlisted in the agent configuration
+    Action { name: String, noaction: bool },
+    /// Ask for hardware and OS facts, gathered afresh when `refresh` is set
+    Facts {
+        #[serde(default)]
+        refresh: bool,
+    },
+    /// List installed packages, only those whose name matches `name` when given
+    Packages {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// List sockets listening for TCP connections or UDP datagrams
+    Ports,
+    /// Report on or change a systemd unit
+    Service {
+        unit: String,
+        operation: ServiceOperation,
+        noaction: bool,
+    },
+    /// Write a file, replacing any existing one atomically
+    Copy {
+        /// Absolute destination path on the agent
+        path: String,
+        /// The file's content in base64
+        content: String,
+        /// Hex SHA-256 of the content, checked before anything is written
+        sha256: String,
+        /// Owning user and group, by name or number; kept as they are when not given
+        #[serde(default)]
+        owner: Option<String>,
+        #[serde(default)]
+        group: Option<String>,
+        /// Permission bits; a replaced file's are kept when not given
+        #[serde(default)]
+        mode: Option<u32>,
+        /// Keep the replaced file alongside the new one
+        #[serde(default)]
+        backup: bool,
+        noaction: bool,
+    },
+    /// Read part of a file, at most [`FETCH_CHUNK`](crate::files::FETCH_CHUNK) bytes
+    Fetch {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        length: Option<u64>,
+        /// Start at the beginning of the last `tail` lines instead of at `offset`
+        #[serde(default)]
+        tail: Option<u64>,
+    },
+    /// Converge the host towards a list of resources, in order. With noaction
+    /// nothing is changed and the differences are reported instead.
+    Apply {
+        resources: Vec<Resource>,
+        noaction: bool,
+    },
+    /// Run one of the check plugins in the agent configuration
+    Plugin { name: String },
+    /// Ask for the latest result of each job the agent runs on its own schedule
+    Results,
+    /// Pass a request on to the host named `target`
+    Forward {
+        target: String,
+        request: Box<Request>,
+    },
+    /// Ask a relay agent to pass a request on to the agent at `target`
+    Relay {
+        /// Shared secret proving the request comes from the controller
+        token: String,
+        /// host:port of the agent on the relay's segment
+        target: String,
+        request: Box<Request>,
+    },
+    /// A request signed for the host named `host`, by the controller or by an
+    /// operator, whose key names them to the agent
+    Signed {
+        host: String,
+        /// Random hex, never used twice
+        nonce: String,
+        /// Seconds since the epoch when the request was signed
+        timestamp: u64,
+        /// Ed25519 signature in base64
+        signature: String,
+        request: Box<Request>,
+    },
+}
+
+impl Request {
+    /// The request without its signature
+    pub fn inner(&self) -> &Request {
+        match self {
+            Request::Signed { request, .. } => request,
+            request => request,
+        }
+    }
+
+    /// Whether the request changes a host, runs something on it or reads its
+    /// files, and so is written to the audit logs. Status reports and other
+    /// inventory reads are asked for too often to be worth recording.
+    pub fn is_audited(&self) -> bool {
+        match self {
+            Request::Status
+            | Request::Facts { .. }
+            | Request::Packages { .. }
+            | Request::Ports
+            | Request::Results => false,
+            Request::Forward { request, .. }
+            | Request::Relay { request, .. }
+            | Request::Signed { request, .. } => request.is_audited(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Status(StatusReport),
+    Action(ActionResult),
+    Facts(Box<Facts>),
+    Packages(PackageReport),
+    Ports {
+        listeners: Vec<Listener>,
+    },
+    Service(ServiceResult),
+    Copy(CopyResult),
+    Fetch(FileChunk),
+    Apply {
+        results: Vec<ResourceResult>,
+    },
+    Results {
+        results: Vec<JobResult>,
+    },
+    Plugin(PluginResult),
+    Error {
+        message: String,
+    },
+    /// The request was refused by the agent's access policy
+    Denied(Denial),
+}
+
+/// Outcome of running an allowlisted action
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ActionResult {
+    pub name: String,
+    /// `None` when the action was not run or was killed by a signal
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Messages sent by an agent over a connection it opened to the controller
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentMessage {
+    /// First message on the connection, identifying and authenticating the
+    /// agent with the [`agent_token`] for its hostname
+    Hello { hostname: String, token: String },
+    /// Sent periodically so the controller knows the agent is still there
+    Heartbeat,
+    /// The answer to the controller request with the same id
+    Reply { id: u64, response: Box<Response> },
+}
+
+/// Messages sent by the controller over a connection an agent opened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControllerMessage {
+    /// The agent was accepted and should wait for requests
+    Welcome,
+    /// The agent was refused and the connection will be closed
+    Rejected {
+        message: String,
+    },
+    Request {
+        id: u64,
+        request: Request,
+    },
+}
+
+/// The token the agent registering as `hostname` presents to a controller
+/// started with `secret`. Each host's token is its own, so an agent cannot
+/// register under another host's name.
+pub fn agent_token(secret: &str, hostname: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(b"soma agent\n");
+    mac.update(hostname.as_bytes());
+    crate::digest::to_hex(&mac.finalize().into_bytes())
+}
+
+/// Write a message as one line of JSON and flush it
+pub fn write_message<W: Write, T: serde::Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    writer.flush()
+}
+
+/// Read one JSON line, returning `None` once the peer has closed the connection
+pub fn read_message<R: BufRead, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let message = serde_json::from_str(&line)?;
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Request::Status).unwrap();
+        write_message(
+            &mut buf,
+            &Request::Action {
+                name: "restart-web".to_string(),
+                noaction: true,
+            },
+        )
+        .unwrap();
+
+        let mut reader = io::Cursor::new(buf);
+        let first: Option<Request> = read_message(&mut reader).unwrap();
+        let second: Option<Request> = read_message(&mut reader).unwrap();
+        let end: Option<Request> = read_message(&mut reader).unwrap();
+        assert_eq!(first, Some(Request::Status));
+        assert!(matches!(
+            second,
+            Some(Request::Action { noaction: true, .. })
+        ));
+        assert!(end.is_none());
+    }
+
+    #[test]
+    fn forwarded_requests_nest() {
+        let request = Request::Forward {
+            target: "web-01".to_string(),
+            request: Box::new(Request::Status),
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"forward","target":"web-01","request":{"type":"status"}}"#
+        );
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+    }
+
+    #[test]
+    fn agent_tokens_belong_to_one_host() {
+        let token = agent_token("secret", "laptop-07");
+        assert_eq!(token.len(), 64);
+        assert_eq!(token, agent_token("secret", "laptop-07"));
+        assert_ne!(token, agent_token("secret", "laptop-08"));
+        assert_ne!(token, agent_token("other", "laptop-07"));
+    }
+}
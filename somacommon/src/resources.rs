@@ -0,0 +1,145 @@
+//! Declarative resources an agent converges its host towards.
+
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+fn present() -> bool {
+    true
+}
+
+/// Something that should be true of a host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Resource {
+    /// A file with exactly this content
+    File {
+        path: String,
+        content: String,
+        #[serde(default)]
+        owner: Option<String>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        mode: Option<u32>,
+    },
+    /// A package installed or not
+    Package {
+        name: String,
+        #[serde(default = "present")]
+        present: bool,
+    },
+    /// A systemd unit running and/or enabled, or not; unset fields are left alone
+    Service {
+        unit: String,
+        #[serde(default)]
+        running: Option<bool>,
+        #[serde(default)]
+        enabled: Option<bool>,
+    },
+    /// A local user account existing or not
+    User {
+        name: String,
+        #[serde(default = "present")]
+        present: bool,
+        /// Create the account as a system account
+        #[serde(default)]
+        system: bool,
+        #[serde(default)]
+        shell: Option<String>,
+    },
+    /// A local group existing or not
+    Group {
+        name: String,
+        #[serde(default = "present")]
+        present: bool,
+        #[serde(default)]
+        system: bool,
+    },
+    /// A line in a file, replacing the first line matching `pattern` when given
+    Line {
+        path: String,
+        line: String,
+        /// Pattern where `*` matches anything; matching lines are replaced, or
+        /// removed when the line should not be present
+        #[serde(default)]
+        pattern: Option<String>,
+        #[serde(default = "present")]
+        present: bool,
+    },
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Resource::File { path, .. } => format!("file {}", path),
+            Resource::Package { name, .. } => format!("package {}", name),
+            Resource::Service { unit, .. } => format!("service {}", unit),
+            Resource::User { name, .. } => format!("user {}", name),
+            Resource::Group { name, .. } => format!("group {}", name),
+            Resource::Line { path, .. } => format!("line in {}", path),
+        };
+        f.pad(&name)
+    }
+}
+
+/// What applying a resource did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Unchanged,
+    /// Changed, or with noaction would have been
+    Changed,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Outcome::Unchanged => "unchanged",
+            Outcome::Changed => "changed",
+            Outcome::Failed => "failed",
+        };
+        f.pad(name)
+    }
+}
+
+/// Result of converging one resource
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceResult {
+    /// The resource as displayed, e.g. `package nginx`
+    pub resource: String,
+    pub outcome: Outcome,
+    pub message: String,
+    /// Lines prefixed `-` and `+` describing what changed or would change
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diff: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resources_and_outcomes_pad_to_width() {
+        let resource = Resource::Package {
+            name: "nginx".to_string(),
+            present: true,
+        };
+        assert_eq!(format!("{:<15}|", resource), "package nginx  |");
+        assert_eq!(format!("{:<10}|", Outcome::Changed), "changed   |");
+    }
+
+    #[test]
+    fn resources_default_to_present() {
+        let resource: Resource =
+            serde_json::from_str(r#"{"type":"package","name":"nginx"}"#).unwrap();
+        assert_eq!(
+            resource,
+            Resource::Package {
+                name: "nginx".to_string(),
+                present: true
+            }
+        );
+        assert_eq!(resource.to_string(), "package nginx");
+    }
+}
@@ -0,0 +1,45 @@
+//! Host selectors, as given to soma commands and in access policy grants.
+//!
+//! A selector is a comma-separated list of terms; a host is selected when
+//! any term matches. Terms are a name glob (`web-*`), a group (`@web`), or
+//! a key/value pair (`env=prod`). A term prefixed with `!` excludes hosts.
+
+use crate::glob_match;
+
+/// A host as selectors see it
+pub trait Selectable {
+    fn name(&self) -> &str;
+
+    fn groups(&self) -> &[String];
+
+    /// Look up a key for `key=value` terms
+    fn value(&self, key: &str) -> Option<&str>;
+
+    /// Check whether this host is picked by a selector
+    fn selected_by(&self, selector: &str) -> bool {
+        let terms: Vec<&str> = selector
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .collect();
+        let (excludes, includes): (Vec<&str>, Vec<&str>) =
+            terms.into_iter().partition(|t| t.starts_with('!'));
+
+        (includes.is_empty() || includes.iter().any(|t| self.matches(t)))
+            && !excludes.iter().any(|t| self.matches(&t[1..]))
+    }
+
+    /// Check whether this host matches a single selector term
+    fn matches(&self, term: &str) -> bool {
+        if let Some(group) = term.strip_prefix('@') {
+            self.groups().iter().any(|g| g == group)
+        } else if let Some((key, value)) = term.split_once('=') {
+            match key {
+                "group" => self.groups().iter().any(|g| glob_match(value, g)),
+                _ => self.value(key).is_some_and(|v| glob_match(value, v)),
+            }
+        } else {
+            glob_match(term, self.name())
+        }
+    }
+}
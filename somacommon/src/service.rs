@@ -0,0 +1,103 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do with a systemd unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceOperation {
+    Status,
+    Start,
+    Stop,
+    Restart,
+    Enable,
+    Disable,
+}
+
+impl ServiceOperation {
+    pub const NAMES: &'static [&'static str] =
+        &["status", "start", "stop", "restart", "enable", "disable"];
+
+    /// Whether the operation changes the unit rather than only reporting on it
+    pub fn modifies(&self) -> bool {
+        *self != ServiceOperation::Status
+    }
+}
+
+impl fmt::Display for ServiceOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ServiceOperation::Status => "status",
+            ServiceOperation::Start => "start",
+            ServiceOperation::Stop => "stop",
+            ServiceOperation::Restart => "restart",
+            ServiceOperation::Enable => "enable",
+            ServiceOperation::Disable => "disable",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for ServiceOperation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(ServiceOperation::Status),
+            "start" => Ok(ServiceOperation::Start),
+            "stop" => Ok(ServiceOperation::Stop),
+            "restart" => Ok(ServiceOperation::Restart),
+            "enable" => Ok(ServiceOperation::Enable),
+            "disable" => Ok(ServiceOperation::Disable),
+            _ => Err(format!("Unknown service operation {}", s)),
+        }
+    }
+}
+
+/// State of a systemd unit as systemd reports it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnitState {
+    pub unit: String,
+    /// `loaded`, or `not-found` for units systemd does not know
+    pub load_state: String,
+    /// `active`, `inactive`, `failed`, ...
+    pub active_state: String,
+    /// `running`, `exited`, `dead`, ...
+    pub sub_state: String,
+    /// When the unit entered its current active state, as systemd formats it
+    pub since: String,
+    pub main_pid: Option<u32>,
+    /// `enabled`, `disabled`, `static`, ...
+    pub unit_file_state: String,
+}
+
+impl UnitState {
+    pub fn is_active(&self) -> bool {
+        self.active_state == "active"
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.unit_file_state == "enabled"
+    }
+}
+
+/// Outcome of a service operation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceResult {
+    /// State after the operation, or the current state when nothing was done
+    pub state: UnitState,
+    /// Whether the operation changed the unit, or would have with noaction
+    pub changed: bool,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations_pad_to_width() {
+        assert_eq!(format!("{:<8}|", ServiceOperation::Stop), "stop    |");
+        assert_eq!(format!("{:>8}|", ServiceOperation::Restart), " restart|");
+    }
+}
@@ -0,0 +1,160 @@
+//! Requests signed by the controller or an operator.
+//!
+//! soma signs each request with its Ed25519 key, together with the host it
+//! is meant for, a random nonce and the time. Agents given the matching
+//! public key check the signature and refuse requests made for another host,
+//! made too long ago, or seen before, so neither a relay nor anyone else
+//! passing a request on can alter or replay it. Each operator has a key of
+//! their own, so the key a request is signed with names who it is made for.
+
+use ed25519_dalek::{SIGNATURE_LENGTH, Signature, Signer, VerifyingKey};
+use serde_derive::Serialize;
+
+use crate::base64;
+use crate::protocol::Request;
+
+pub use ed25519_dalek::SigningKey;
+
+/// Length of a key, secret or public
+pub const KEY_LENGTH: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+
+/// Seconds a signed request's time may be from the agent's clock by default
+pub const DEFAULT_MAX_SKEW: u64 = 60;
+
+/// What a signature covers, serialized as JSON after a fixed prefix
+#[derive(Serialize)]
+struct Signable<'a> {
+    host: &'a str,
+    nonce: &'a str,
+    timestamp: u64,
+    request: &'a Request,
+}
+
+fn message(host: &str, nonce: &str, timestamp: u64, request: &Request) -> Vec<u8> {
+    let signable = Signable {
+        host,
+        nonce,
+        timestamp,
+        request,
+    };
+    let mut message = b"soma signed request\n".to_vec();
+    message.extend(serde_json::to_vec(&signable).expect("requests serialize"));
+    message
+}
+
+/// Sign a request for `host`, made at `timestamp` seconds since the epoch
+pub fn sign(
+    key: &SigningKey,
+    host: &str,
+    nonce: &str,
+    timestamp: u64,
+    request: Request,
+) -> Request {
+    let signature = key.sign(&message(host, nonce, timestamp, &request));
+    Request::Signed {
+        host: host.to_string(),
+        nonce: nonce.to_string(),
+        timestamp,
+        signature: base64::encode(&signature.to_bytes()),
+        request: Box::new(request),
+    }
+}
+
+/// Check the signature of a [`Request::Signed`] against a public key; any
+/// other request is not signed and fails
+pub fn verify(public_key: &[u8; KEY_LENGTH], request: &Request) -> bool {
+    let Request::Signed {
+        host,
+        nonce,
+        timestamp,
+        signature,
+        request,
+    } = request
+    else {
+        return false;
+    };
+    let Some(signature) = base64::decode(signature)
+        .ok()
+        .and_then(|s| <[u8; SIGNATURE_LENGTH]>::try_from(s).ok())
+    else {
+        return false;
+    };
+    let Ok(public_key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    public_key
+        .verify_strict(
+            &message(host, nonce, *timestamp, request),
+            &Signature::from_bytes(&signature),
+        )
+        .is_ok()
+}
+
+/// The public key agents check a signing key's signatures with
+pub fn public_key(key: &SigningKey) -> [u8; KEY_LENGTH] {
+    key.verifying_key().to_bytes()
+}
+
+/// A key, public or secret, as written in configuration files
+pub fn encode_key(key: &[u8; KEY_LENGTH]) -> String {
+    base64::encode(key)
+}
+
+/// Read a key written by [`encode_key`]
+pub fn decode_key(text: &str) -> Result<[u8; KEY_LENGTH], String> {
+    let bytes = base64::decode(text.trim())?;
+    <[u8; KEY_LENGTH]>::try_from(bytes)
+        .map_err(|bytes| format!("expected {} bytes, got {}", KEY_LENGTH, bytes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::Resource;
+
+    #[test]
+    fn signatures_cover_host_time_and_request() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let public = public_key(&key);
+        let request = Request::Apply {
+            resources: vec![Resource::Package {
+                name: "nginx".to_string(),
+                present: true,
+            }],
+            noaction: false,
+        };
+        let signed = sign(&key, "web-01", "00ff", 1000, request);
+        assert!(verify(&public, &signed));
+
+        // What the agent checks is what it reads off the wire
+        let line = serde_json::to_string(&signed).unwrap();
+        let received: Request = serde_json::from_str(&line).unwrap();
+        assert!(verify(&public, &received));
+
+        let Request::Signed {
+            host,
+            nonce,
+            timestamp,
+            signature,
+            request,
+        } = received
+        else {
+            unreachable!()
+        };
+        let altered = |host: &str, timestamp: u64| Request::Signed {
+            host: host.to_string(),
+            nonce: nonce.clone(),
+            timestamp,
+            signature: signature.clone(),
+            request: request.clone(),
+        };
+        assert!(verify(&public, &altered(&host, timestamp)));
+        assert!(!verify(&public, &altered("web-02", timestamp)));
+        assert!(!verify(&public, &altered(&host, timestamp + 1)));
+        assert!(!verify(&public, &Request::Status));
+        assert!(!verify(
+            &public_key(&SigningKey::from_bytes(&[4; 32])),
+            &signed
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::checks::{PluginResult, ProbeResult};
+use crate::service::UnitState;
+
+/// Overall health of a host, ordered from best to worst
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Healthy,
+    Warning,
+    Critical,
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Health::Healthy => "healthy",
+            Health::Warning => "warning",
+            Health::Critical => "critical",
+            Health::Unknown => "unknown",
+        };
+        f.pad(s)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    pub total_kb: u64,
+    pub available_kb: u64,
+}
+
+impl MemoryUsage {
+    pub fn used_percent(&self) -> f64 {
+        if self.total_kb == 0 {
+            return 0.0;
+        }
+        let used = self.total_kb.saturating_sub(self.available_kb);
+        used as f64 * 100.0 / self.total_kb as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MountUsage {
+    pub mount: String,
+    pub filesystem: String,
+    pub total_kb: u64,
+    pub used_kb: u64,
+}
+
+impl MountUsage {
+    pub fn used_percent(&self) -> f64 {
+        if self.total_kb == 0 {
+            return 0.0;
+        }
+        self.used_kb as f64 * 100.0 / self.total_kb as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InterfaceUsage {
+    pub name: String,
+    pub addresses: Vec<String>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub rss_kb: u64,
+    pub cpu_secs: f64,
+}
+
+/// A point-in-time status report produced by somasrv
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub hostname: String,
+    pub health: Health,
+    pub uptime_secs: u64,
+    pub cpus: usize,
+    pub load: LoadAverage,
+    pub memory: MemoryUsage,
+    pub mounts: Vec<MountUsage>,
+    pub interfaces: Vec<InterfaceUsage>,
+    /// The processes using the most memory
+    pub processes: Vec<ProcessInfo>,
+    /// Names of the actions the agent allows to be triggered remotely
+    pub actions: Vec<String>,
+    /// State of the systemd units the agent is configured to watch
+    #[serde(default)]
+    pub services: Vec<UnitState>,
+    /// Latest results of the agent's check plugins
+    #[serde(default)]
+    pub plugins: Vec<PluginResult>,
+    /// Latest results of the agent's built-in probes
+    #[serde(default)]
+    pub probes: Vec<ProbeResult>,
+}
+
+impl StatusReport {
+    /// Highest disk usage percentage over all mounts
+    pub fn disk_used_percent(&self) -> f64 {
+        self.mounts
+            .iter()
+            .map(MountUsage::used_percent)
+            .fold(0.0, f64::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_orders_by_severity() {
+        assert!(Health::Healthy < Health::Warning);
+        assert!(Health::Warning < Health::Critical);
+        assert!(Health::Critical < Health::Unknown);
+        assert_eq!(Health::Critical.to_string(), "critical");
+        assert_eq!(format!("{:<10}|", Health::Warning), "warning   |");
+    }
+
+    #[test]
+    fn usage_percentages() {
+        let report = StatusReport {
+            memory: MemoryUsage {
+                total_kb: 1000,
+                available_kb: 250,
+            },
+            mounts: vec![
+                MountUsage {
+                    mount: "/".to_string(),
+                    total_kb: 100,
+                    used_kb: 40,
+                    ..Default::default()
+                },
+                MountUsage {
+                    mount: "/var".to_string(),
+                    total_kb: 100,
+                    used_kb: 90,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(report.memory.used_percent(), 75.0);
+        assert_eq!(report.disk_used_percent(), 90.0);
+    }
+}
@@ -0,0 +1,155 @@
+use log::{info, warn};
+use somacommon::audit::{self, AuditLog, Record};
+use somacommon::protocol::{Request, Response};
+use somacommon::selector::Selectable;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, PolicyConfig};
+use crate::signing::{self, Signer};
+use crate::{facts, server};
+
+/// Operator recorded for requests not signed with an operator's key
+const UNKNOWN_OPERATOR: &str = "unknown";
+
+/// Make the audit log's key when the agent starts without one and the log
+/// has no entries yet, so it is never made while entries are being written.
+/// A log with entries whose key is gone is an error.
+pub fn start(cfg: &Config) -> Result<(), String> {
+    let log = AuditLog::new(&cfg.audit_log_path(), &cfg.audit_key_path());
+    let key = log.key_path().display();
+    match log.check_key() {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if fs::metadata(log.path()).is_ok_and(|m| m.len() > 0) {
+                return Err(format!(
+                    "Audit log {} has entries but its key {} is missing",
+                    log.path().display(),
+                    key
+                ));
+            }
+            log.create_key()
+                .map_err(|e| format!("Failed to create audit key {}: {}", key, e))?;
+            info!("Created audit key {}", key);
+            Ok(())
+        }
+        Err(e) => Err(format!("Failed to read audit key {}: {}", key, e)),
+    }
+}
+
+/// Answer a request from `origin`, writing it to the agent's own audit log
+/// when it changes, runs or reads something.
+///
+/// The log is kept apart from the controller's, so a controller that is
+/// compromised cannot erase what agents were asked to do. Requests without
+/// a valid signature, when the agent requires one, are refused, as are
+/// those the agent's access policy does not allow the operator whose key
+/// signed them, except requests to relay, which the agent they are for checks.
+pub fn handle(request: &Request, origin: &str, cfg: &Config) -> Response {
+    let signed = signing::check(cfg, request);
+    let operator = match &signed {
+        Ok(Some(Signer::Operator(operator))) => operator.as_str(),
+        _ => UNKNOWN_OPERATOR,
+    };
+    let request = request.inner();
+    let response = match &signed {
+        Err(e) => {
+            warn!("Refused request from {}: {}", origin, e);
+            Response::Error {
+                message: e.to_string(),
+            }
+        }
+        Ok(_) => match (&cfg.policy, request) {
+            (_, Request::Relay { .. }) | (None, _) => server::handle_request(request, cfg),
+            (Some(policy), _) => {
+                let host = PolicyHost::new(cfg, policy);
+                let selected = |selector: &str| host.selected_by(selector);
+                match policy
+                    .policy
+                    .check(operator, request, &host.name, &selected)
+                {
+                    Ok(()) => server::handle_request(request, cfg),
+                    Err(denial) => Response::Denied(denial),
+                }
+            }
+        },
+    };
+    if request.is_audited() || matches!(response, Response::Denied(_)) {
+        let (outcome, message) = audit::outcome(&response);
+        let target = match request {
+            Request::Relay { target, .. } => target.clone(),
+            _ => hostname(),
+        };
+        let record = Record {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            operator: operator.to_string(),
+            origin: origin.to_string(),
+            command: audit::command(request),
+            targets: vec![target],
+            parameters: audit::parameters(request),
+            outcome,
+            message,
+        };
+        let log = AuditLog::new(&cfg.audit_log_path(), &cfg.audit_key_path());
+        if let Err(e) = log.append(record) {
+            warn!("Failed to write audit log {}: {}", log.path().display(), e);
+        }
+    }
+    response
+}
+
+/// This host as the host selectors of its access policy's grants see it
+struct PolicyHost<'a> {
+    name: String,
+    policy: &'a PolicyConfig,
+    /// Gathered only when a selector looks up a fact
+    facts: OnceCell<BTreeMap<String, String>>,
+}
+
+impl<'a> PolicyHost<'a> {
+    fn new(cfg: &Config, policy: &'a PolicyConfig) -> Self {
+        PolicyHost {
+            name: cfg.host_name(),
+            policy,
+            facts: OnceCell::new(),
+        }
+    }
+}
+
+impl Selectable for PolicyHost<'_> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn groups(&self) -> &[String] {
+        &self.policy.groups
+    }
+
+    fn value(&self, key: &str) -> Option<&str> {
+        match key {
+            "name" => Some(&self.name),
+            _ => self
+                .policy
+                .vars
+                .get(key)
+                .or_else(|| {
+                    self.facts
+                        .get_or_init(|| facts::get(false).flatten())
+                        .get(key)
+                })
+                .map(String::as_str),
+        }
+    }
+}
+
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
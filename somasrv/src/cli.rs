@@ -1,8 +1,10 @@
-// use log::{debug, error, info, trace, warn};
-// use simplelog::*;
+use simplelog::LevelFilter;
+use somacommon::protocol::DEFAULT_PORT;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::config::ConfigError;
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = env!("CARGO_PKG_NAME"),
@@ -20,7 +22,7 @@ pub struct Cli {
 
     /// Set the IP and optionally port to listen on
     #[structopt(long, short)]
-    pub listen: String,
+    pub listen: Option<String>,
 
     /// Specify port to listen on
     #[structopt(long, short = "p")]
@@ -43,29 +45,31 @@ impl Cli {
 
     /// Validate the CLI arguments
     pub fn validate(&self) -> Result<(), String> {
+        let Some(listen) = &self.listen else {
+            return Ok(());
+        };
+
         // Check if both listen contains a port and --port is specified
-        if let Some(_port) = self.port {
-            if self.listen.contains(':') {
-                return Err(
-                    "Cannot specify both a port in --listen and --port option. Use only one."
-                        .to_string(),
-                );
-            }
+        if self.port.is_some() && listen.contains(':') {
+            return Err(
+                "Cannot specify both a port in --listen and --port option. Use only one."
+                    .to_string(),
+            );
         }
 
         // Validate listen address format
-        self.validate_listen_address()?;
+        self.validate_listen_address(listen)?;
 
         Ok(())
     }
 
     /// Validate the listen address format
-    fn validate_listen_address(&self) -> Result<(), String> {
-        if self.listen.contains(':') {
+    fn validate_listen_address(&self, listen: &str) -> Result<(), String> {
+        if listen.contains(':') {
             // Contains port, validate IP:PORT format
-            let parts: Vec<&str> = self.listen.rsplitn(2, ':').collect();
+            let parts: Vec<&str> = listen.rsplitn(2, ':').collect();
             if parts.len() != 2 {
-                return Err(format!("Invalid listen address format: {}", self.listen));
+                return Err(format!("Invalid listen address format: {}", listen));
             }
 
             let port_str = parts[0];
@@ -87,8 +91,8 @@ impl Cli {
             }
         } else {
             // No port specified, just validate IP
-            if !self.is_valid_ip(&self.listen) {
-                return Err(format!("Invalid IP address: {}", self.listen));
+            if !self.is_valid_ip(listen) {
+                return Err(format!("Invalid IP address: {}", listen));
             }
         }
 
@@ -100,7 +104,25 @@ impl Cli {
         ip.parse::<std::net::IpAddr>().is_ok()
     }
 
-    // Open the configuration file
-    // Set the logger to the specified level
-    // Validate the config file if requested to do so and then exit
+    /// The address to listen on, if the agent should serve requests
+    pub fn listen_address(&self) -> Option<String> {
+        let listen = self.listen.as_ref()?;
+        if listen.contains(':') {
+            Some(listen.clone())
+        } else {
+            Some(format!("{}:{}", listen, self.port.unwrap_or(DEFAULT_PORT)))
+        }
+    }
+
+    /// The log level requested with --loglevel
+    pub fn log_level(&self) -> Result<LevelFilter, ConfigError> {
+        match self.loglevel.as_str() {
+            "error" => Ok(LevelFilter::Error),
+            "warn" => Ok(LevelFilter::Warn),
+            "info" => Ok(LevelFilter::Info),
+            "debug" => Ok(LevelFilter::Debug),
+            "trace" => Ok(LevelFilter::Trace),
+            other => Err(ConfigError::InvalidLogLevel(other.to_string())),
+        }
+    }
 }
@@ -1,23 +1,370 @@
 use serde::{Deserialize, Serialize};
+use somacommon::audit::AUDIT_FILE;
+use somacommon::checks::JobKind;
+use somacommon::policy::Policy;
+use somacommon::signing::{self, DEFAULT_MAX_SKEW};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::audit;
+use crate::probes;
+use crate::schedule::Cron;
+
 const DEFAULT_CFG_PATH: &str = "/etc/soma.toml";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub logfile: PathBuf,
+    /// Limits used to derive the health level of the status report
+    pub thresholds: Thresholds,
+    /// Actions that may be triggered remotely, mapping a name to the command to run
+    pub actions: BTreeMap<String, Vec<String>>,
+    /// Dial out to a controller instead of, or as well as, listening
+    pub controller: Option<ControllerConfig>,
+    /// Pass requests from the controller on to agents on this host's segment
+    pub relay: Option<RelayConfig>,
+    /// systemd units to report on and manage
+    pub services: ServiceConfig,
+    /// Where files may be written by `soma copy` and read by `soma fetch`
+    pub files: FileConfig,
+    /// What desired-state documents may change beyond files and services
+    pub state: StateConfig,
+    /// Checks the agent can run, mapping a name to a command; exit status 0 passes
+    pub checks: BTreeMap<String, Vec<String>>,
+    /// Checks and actions the agent runs on its own, whether or not a controller is around
+    pub schedule: Vec<ScheduleEntry>,
+    /// Where the agent keeps what it must remember across restarts
+    pub state_dir: PathBuf,
+    /// Nagios-compatible check plugins, mapping a name to the command to run.
+    /// Status reports fold their latest states into their health.
+    pub plugins: BTreeMap<String, Vec<String>>,
+    /// Seconds a plugin may run before it is killed and reported unknown
+    pub plugin_timeout: u64,
+    /// Built-in checks, folded into the health of status reports like plugins
+    pub probes: Vec<ProbeConfig>,
+    /// Seconds between runs of the plugins and probes
+    pub check_interval: u64,
+    /// Command used for TLS connections by `https` and `tls` probes
+    pub openssl: Vec<String>,
+    /// Serve Prometheus metrics on /metrics over plain HTTP
+    pub http: Option<HttpConfig>,
+    /// Where requests that change, run or read something are recorded;
+    /// `audit.jsonl` in the state directory if not given
+    pub audit_log: Option<PathBuf>,
+    /// Secret the audit log's hashes are keyed with, made when somasrv starts
+    /// with a new log; the log's path with a `.key` extension if not given
+    pub audit_key: Option<PathBuf>,
+    /// Operators allowed to make requests and what they may do; anyone may do
+    /// anything when not given. Needs the operators' keys in `signing`, as
+    /// only the key a request is signed with names its operator, and then
+    /// every request must be signed.
+    pub policy: Option<PolicyConfig>,
+    /// Only act on changing, running or reading requests signed by the controller
+    pub signing: Option<SigningConfig>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             logfile: PathBuf::from("/var/log/somasrv.log"),
+            thresholds: Thresholds::default(),
+            actions: BTreeMap::new(),
+            controller: None,
+            relay: None,
+            services: ServiceConfig::default(),
+            files: FileConfig::default(),
+            state: StateConfig::default(),
+            checks: BTreeMap::new(),
+            schedule: Vec::new(),
+            state_dir: PathBuf::from("/var/lib/somasrv"),
+            plugins: BTreeMap::new(),
+            plugin_timeout: 30,
+            probes: Vec::new(),
+            check_interval: 60,
+            openssl: vec!["openssl".to_string()],
+            http: None,
+            audit_log: None,
+            audit_key: None,
+            policy: None,
+            signing: None,
+        }
+    }
+}
+
+/// Where to dial out to for agents that cannot accept inbound connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControllerConfig {
+    /// Address of `soma serve`, as host or host:port
+    pub endpoint: String,
+    /// This host's token, as printed by `soma agent-token <hostname>`
+    pub token: String,
+    /// Name to register as; defaults to the system hostname
+    pub hostname: Option<String>,
+    /// Seconds between heartbeats
+    #[serde(default = "default_heartbeat")]
+    pub heartbeat: u64,
+}
+
+/// Where the optional HTTP server listens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpConfig {
+    /// Address to listen on, as host:port
+    pub listen: String,
+}
+
+/// The access policy an agent enforces, and how its grants' host selectors
+/// see this host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    #[serde(flatten)]
+    pub policy: Policy,
+    /// Groups this host is in, as in the controller's inventory
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Key/value pairs for selectors, looked up before the host's facts
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+}
+
+/// The keys requests must be signed with, and how fresh they must be
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningConfig {
+    /// The controller's public key in base64, as printed by `soma keygen`;
+    /// requests signed with it are made for no operator in particular
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Operators' public keys by operator name. A request signed with one is
+    /// made for that operator, which is what audit logs record and access
+    /// policies check.
+    #[serde(default)]
+    pub operators: BTreeMap<String, String>,
+    /// Host name signed requests must be made for; the name registered with
+    /// the controller, or else the system hostname, if not given
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Seconds a request's signing time may be from the agent's clock
+    #[serde(default = "default_max_skew")]
+    pub max_skew: u64,
+}
+
+fn default_max_skew() -> u64 {
+    DEFAULT_MAX_SKEW
+}
+
+/// Which agents a relay passes requests on to, and for whom
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Shared secret the controller presents with each relayed request
+    pub token: String,
+    /// Agents that may be reached, as host or host:port patterns where `*` matches anything
+    pub targets: Vec<String>,
+}
+
+/// Which systemd units the agent reports on and may change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    /// Units whose state is included in every status report
+    pub watch: Vec<String>,
+    /// Units that may be started, stopped, restarted, enabled or disabled,
+    /// as patterns where `*` matches anything. Any unit may be queried.
+    pub manage: Vec<String>,
+    /// Command used to talk to systemd, e.g. `["systemctl", "--user"]`
+    pub systemctl: Vec<String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> ServiceConfig {
+        ServiceConfig {
+            watch: Vec::new(),
+            manage: Vec::new(),
+            systemctl: vec!["systemctl".to_string()],
+        }
+    }
+}
+
+/// Which parts of the filesystem remote requests may touch
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileConfig {
+    /// Directories under which files may be written. Nothing may be written when empty.
+    pub writable: Vec<PathBuf>,
+    /// Directories under which files may be read. Nothing may be read when empty.
+    pub readable: Vec<PathBuf>,
+}
+
+/// Packages and accounts desired-state documents may manage. Files are limited
+/// by the `[files]` writable prefixes and services by `[services]` manage.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StateConfig {
+    /// Packages that may be installed or removed, as patterns where `*` matches anything
+    pub packages: Vec<String>,
+    /// Users and groups that may be created or removed, as patterns
+    pub users: Vec<String>,
+}
+
+/// A check or action to run on a cron schedule or at a fixed interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    /// Name of a check from `[checks]`
+    #[serde(default)]
+    pub check: Option<String>,
+    /// Name of an action from `[actions]`
+    #[serde(default)]
+    pub action: Option<String>,
+    /// Five-field cron expression, evaluated in UTC
+    #[serde(default)]
+    pub cron: Option<String>,
+    /// Seconds between runs
+    #[serde(default)]
+    pub every: Option<u64>,
+    /// Up to this many seconds of random delay added to each run
+    #[serde(default)]
+    pub jitter: u64,
+    #[serde(default)]
+    pub catch_up: CatchUp,
+}
+
+impl ScheduleEntry {
+    /// The kind and name of the job this entry runs
+    pub fn job(&self) -> (JobKind, &str) {
+        match (&self.check, &self.action) {
+            (Some(check), _) => (JobKind::Check, check),
+            (None, Some(action)) => (JobKind::Action, action),
+            (None, None) => (JobKind::Check, ""),
+        }
+    }
+}
+
+/// What to do about runs missed while the agent was not running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CatchUp {
+    /// Carry on with the next scheduled run
+    #[default]
+    Skip,
+    /// Run once straight away if any run was missed
+    Once,
+    /// Make up every missed run, up to a limit
+    All,
+}
+
+/// A built-in probe, run from the agent's vantage point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeConfig {
+    pub name: String,
+    /// Seconds before the probe gives up and is critical
+    #[serde(default = "default_probe_timeout")]
+    pub timeout: u64,
+    #[serde(flatten)]
+    pub kind: ProbeKind,
+}
+
+/// What a probe checks, chosen by its `type`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// GET an http:// or https:// URL and check the status and, optionally, the body
+    Http {
+        url: String,
+        #[serde(default = "default_http_status")]
+        status: u16,
+        /// Regular expression the body must match
+        #[serde(default)]
+        body: Option<String>,
+        /// Accept any certificate for https:// URLs
+        #[serde(default)]
+        insecure: bool,
+    },
+    /// Open a TCP connection to host:port
+    Tcp { address: String },
+    /// Resolve a name, optionally requiring one address among the answers
+    Dns {
+        query: String,
+        #[serde(default)]
+        expect: Option<String>,
+    },
+    /// Check how many days the certificate served at host:port has left
+    Tls {
+        address: String,
+        #[serde(default = "default_tls_warning_days")]
+        warning_days: i64,
+        #[serde(default = "default_tls_critical_days")]
+        critical_days: i64,
+    },
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+fn default_http_status() -> u16 {
+    200
+}
+
+fn default_tls_warning_days() -> i64 {
+    21
+}
+
+fn default_tls_critical_days() -> i64 {
+    7
+}
+
+fn default_heartbeat() -> u64 {
+    30
+}
+
+/// Warning and critical limits; load is per CPU, memory and disk are percentages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    pub load_warning: f64,
+    pub load_critical: f64,
+    pub memory_warning: f64,
+    pub memory_critical: f64,
+    pub disk_warning: f64,
+    pub disk_critical: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds {
+            load_warning: 1.0,
+            load_critical: 2.0,
+            memory_warning: 85.0,
+            memory_critical: 95.0,
+            disk_warning: 85.0,
+            disk_critical: 95.0,
         }
     }
 }
 
 impl Config {
+    pub fn audit_log_path(&self) -> PathBuf {
+        self.audit_log
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join(AUDIT_FILE))
+    }
+
+    /// The name the controller knows this host by: the signing `host`, the
+    /// name registered with the controller, or else the system hostname
+    pub fn host_name(&self) -> String {
+        self.signing
+            .as_ref()
+            .and_then(|signing| signing.host.clone())
+            .or_else(|| self.controller.as_ref()?.hostname.clone())
+            .unwrap_or_else(audit::hostname)
+    }
+
+    pub fn audit_key_path(&self) -> PathBuf {
+        self.audit_key
+            .clone()
+            .unwrap_or_else(|| self.audit_log_path().with_extension("key"))
+    }
+
     pub fn from_file(pb: &Option<PathBuf>) -> Result<Self, ConfigError> {
         let path: &Path = match pb {
             Some(p) => p.as_ref(),
@@ -40,10 +387,170 @@ impl Config {
 
     /// Validate the configuration
     pub fn validate(&self) -> Result<(), ConfigError> {
+        for (name, command) in &self.actions {
+            if command.is_empty() {
+                return Err(ConfigError::InvalidAction(name.clone()));
+            }
+        }
+        if let Some(controller) = &self.controller {
+            if controller.endpoint.is_empty() || controller.token.is_empty() {
+                return Err(ConfigError::InvalidController(
+                    "endpoint and token are required".to_string(),
+                ));
+            }
+            if controller.heartbeat == 0 {
+                return Err(ConfigError::InvalidController(
+                    "heartbeat must be at least 1 second".to_string(),
+                ));
+            }
+        }
+        if self.services.systemctl.is_empty() {
+            return Err(ConfigError::InvalidServices(
+                "systemctl command cannot be empty".to_string(),
+            ));
+        }
+        let mut prefixes = self.files.writable.iter().chain(&self.files.readable);
+        if let Some(path) = prefixes.find(|p| !p.is_absolute()) {
+            return Err(ConfigError::InvalidFiles(format!(
+                "{} is not an absolute path",
+                path.display()
+            )));
+        }
+        for (name, command) in &self.checks {
+            if command.is_empty() {
+                return Err(ConfigError::InvalidSchedule(format!(
+                    "check {} has no command to run",
+                    name
+                )));
+            }
+        }
+        for (name, command) in &self.plugins {
+            if command.is_empty() {
+                return Err(ConfigError::InvalidPlugin(format!(
+                    "{} has no command to run",
+                    name
+                )));
+            }
+        }
+        if !self.plugins.is_empty() && self.plugin_timeout == 0 {
+            return Err(ConfigError::InvalidPlugin(
+                "plugin_timeout must be at least 1 second".to_string(),
+            ));
+        }
+        if self.check_interval == 0 {
+            let message = "check_interval must be at least 1 second".to_string();
+            if !self.plugins.is_empty() {
+                return Err(ConfigError::InvalidPlugin(message));
+            }
+            if !self.probes.is_empty() {
+                return Err(ConfigError::InvalidProbe(message));
+            }
+        }
+        let mut probes = BTreeSet::new();
+        for probe in &self.probes {
+            probes::validate(probe).map_err(ConfigError::InvalidProbe)?;
+            if !probes.insert(&probe.name) {
+                return Err(ConfigError::InvalidProbe(format!(
+                    "{} is defined more than once",
+                    probe.name
+                )));
+            }
+        }
+        if !self.probes.is_empty() && self.openssl.is_empty() {
+            return Err(ConfigError::InvalidProbe(
+                "openssl command cannot be empty".to_string(),
+            ));
+        }
+        if let Some(policy) = &self.policy {
+            policy
+                .policy
+                .validate()
+                .map_err(ConfigError::InvalidPolicy)?;
+            if self.signing.as_ref().is_none_or(|s| s.operators.is_empty()) {
+                return Err(ConfigError::InvalidPolicy(
+                    "needs [signing.operators], as only the key a request is signed with names its operator"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(signing) = &self.signing {
+            if signing.public_key.is_none() && signing.operators.is_empty() {
+                return Err(ConfigError::InvalidSigning(
+                    "needs a public_key or [signing.operators]".to_string(),
+                ));
+            }
+            if let Some(key) = &signing.public_key {
+                signing::decode_key(key)
+                    .map_err(|e| ConfigError::InvalidSigning(format!("public_key: {}", e)))?;
+            }
+            for (operator, key) in &signing.operators {
+                if operator.trim().is_empty() {
+                    return Err(ConfigError::InvalidSigning(
+                        "operator names cannot be empty".to_string(),
+                    ));
+                }
+                signing::decode_key(key).map_err(|e| {
+                    ConfigError::InvalidSigning(format!("key of operator {}: {}", operator, e))
+                })?;
+            }
+            if signing.max_skew == 0 {
+                return Err(ConfigError::InvalidSigning(
+                    "max_skew must be at least 1 second".to_string(),
+                ));
+            }
+        }
+        let mut jobs = BTreeSet::new();
+        for entry in &self.schedule {
+            entry_valid(self, entry).map_err(ConfigError::InvalidSchedule)?;
+            let (kind, name) = entry.job();
+            if !jobs.insert((kind, name)) {
+                return Err(ConfigError::InvalidSchedule(format!(
+                    "{} {} is scheduled more than once",
+                    kind, name
+                )));
+            }
+        }
+        if let Some(relay) = &self.relay {
+            if relay.token.is_empty() {
+                return Err(ConfigError::InvalidRelay("token is required".to_string()));
+            }
+            if relay.targets.is_empty() {
+                return Err(ConfigError::InvalidRelay(
+                    "at least one target is required".to_string(),
+                ));
+            }
+        }
         Ok(())
     }
 }
 
+/// Check a schedule entry names exactly one known job and exactly one way of timing it
+fn entry_valid(cfg: &Config, entry: &ScheduleEntry) -> Result<(), String> {
+    match (&entry.check, &entry.action) {
+        (Some(check), None) if !cfg.checks.contains_key(check) => {
+            return Err(format!("check {} is not in [checks]", check));
+        }
+        (None, Some(action)) if !cfg.actions.contains_key(action) => {
+            return Err(format!("action {} is not in [actions]", action));
+        }
+        (Some(_), None) | (None, Some(_)) => {}
+        _ => return Err("each entry needs one of check or action".to_string()),
+    }
+    let (kind, name) = entry.job();
+    match (&entry.cron, entry.every) {
+        (Some(cron), None) => cron
+            .parse::<Cron>()
+            .map(|_| ())
+            .map_err(|e| format!("{} {}: {}", kind, name, e)),
+        (None, Some(0)) => Err(format!(
+            "{} {}: every must be at least 1 second",
+            kind, name
+        )),
+        (None, Some(_)) => Ok(()),
+        _ => Err(format!("{} {} needs one of cron or every", kind, name)),
+    }
+}
+
 /// Configuration error types
 #[derive(Debug)]
 pub enum ConfigError {
@@ -52,6 +559,16 @@ pub enum ConfigError {
     ParseError(PathBuf, toml::de::Error),
     WriteError(PathBuf, std::io::Error),
     InvalidLogLevel(String),
+    InvalidAction(String),
+    InvalidController(String),
+    InvalidRelay(String),
+    InvalidServices(String),
+    InvalidFiles(String),
+    InvalidSchedule(String),
+    InvalidPlugin(String),
+    InvalidProbe(String),
+    InvalidPolicy(String),
+    InvalidSigning(String),
     WebrootNotDirectory(PathBuf),
     LogDirectoryNotDirectory(PathBuf),
     AlreadyInitialized,
@@ -90,6 +607,36 @@ impl std::fmt::Display for ConfigError {
             ConfigError::InvalidLogLevel(level) => {
                 write!(f, "Invalid log level: {}", level)
             }
+            ConfigError::InvalidAction(name) => {
+                write!(f, "Action {} has no command to run", name)
+            }
+            ConfigError::InvalidController(reason) => {
+                write!(f, "Invalid controller settings: {}", reason)
+            }
+            ConfigError::InvalidRelay(reason) => {
+                write!(f, "Invalid relay settings: {}", reason)
+            }
+            ConfigError::InvalidServices(reason) => {
+                write!(f, "Invalid services settings: {}", reason)
+            }
+            ConfigError::InvalidFiles(reason) => {
+                write!(f, "Invalid files settings: {}", reason)
+            }
+            ConfigError::InvalidSchedule(reason) => {
+                write!(f, "Invalid schedule: {}", reason)
+            }
+            ConfigError::InvalidPlugin(reason) => {
+                write!(f, "Invalid plugin: {}", reason)
+            }
+            ConfigError::InvalidProbe(reason) => {
+                write!(f, "Invalid probe: {}", reason)
+            }
+            ConfigError::InvalidPolicy(reason) => {
+                write!(f, "Invalid policy: {}", reason)
+            }
+            ConfigError::InvalidSigning(reason) => {
+                write!(f, "Invalid signing: {}", reason)
+            }
             ConfigError::WebrootNotDirectory(path) => {
                 write!(f, "Webroot path is not a directory: {}", path.display())
             }
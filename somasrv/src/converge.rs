@@ -0,0 +1,459 @@
+use log::info;
+use somacommon::glob_match;
+use somacommon::protocol::Response;
+use somacommon::resources::{Outcome, Resource, ResourceResult};
+use somacommon::service::ServiceOperation;
+use std::fs;
+use std::process::Command;
+
+use crate::config::{Config, StateConfig};
+use crate::files::{self, FileSpec};
+use crate::{packages, service};
+
+/// Most diff lines reported for one resource
+const MAX_DIFF_LINES: usize = 100;
+
+/// What converging a resource did: whether it changed, a summary, and a diff
+type Change = (bool, String, Vec<String>);
+
+/// Converge the host towards each resource in turn. A failed resource is
+/// reported and the rest are still applied.
+pub fn apply(cfg: &Config, resources: &[Resource], noaction: bool) -> Response {
+    let results = resources
+        .iter()
+        .map(|resource| {
+            let (outcome, message, diff) = match converge(cfg, resource, noaction) {
+                Ok((false, message, diff)) => (Outcome::Unchanged, message, diff),
+                Ok((true, message, diff)) => (Outcome::Changed, message, diff),
+                Err(message) => (Outcome::Failed, message, Vec::new()),
+            };
+            ResourceResult {
+                resource: resource.to_string(),
+                outcome,
+                message,
+                diff,
+            }
+        })
+        .collect();
+    Response::Apply { results }
+}
+
+fn converge(cfg: &Config, resource: &Resource, noaction: bool) -> Result<Change, String> {
+    match resource {
+        Resource::File {
+            path,
+            content,
+            owner,
+            group,
+            mode,
+        } => {
+            let spec = FileSpec {
+                path,
+                content: content.as_bytes(),
+                owner: owner.as_deref(),
+                group: group.as_deref(),
+                mode: *mode,
+                backup: true,
+            };
+            file(cfg, &spec, noaction)
+        }
+        Resource::Package { name, present } => package(&cfg.state, name, *present, noaction),
+        Resource::Service {
+            unit,
+            running,
+            enabled,
+        } => {
+            let mut operations = Vec::new();
+            match running {
+                Some(true) => operations.push(ServiceOperation::Start),
+                Some(false) => operations.push(ServiceOperation::Stop),
+                None => {}
+            }
+            match enabled {
+                Some(true) => operations.push(ServiceOperation::Enable),
+                Some(false) => operations.push(ServiceOperation::Disable),
+                None => {}
+            }
+            if operations.is_empty() {
+                operations.push(ServiceOperation::Status);
+            }
+            let mut changed = false;
+            let mut messages = Vec::new();
+            for operation in operations {
+                let result = service::run(&cfg.services, unit, operation, noaction)?;
+                changed |= result.changed;
+                messages.push(result.message);
+            }
+            Ok((changed, messages.join("; "), Vec::new()))
+        }
+        Resource::User {
+            name,
+            present,
+            system,
+            shell,
+        } => user(
+            &cfg.state,
+            name,
+            *present,
+            *system,
+            shell.as_deref(),
+            noaction,
+        ),
+        Resource::Group {
+            name,
+            present,
+            system,
+        } => group(&cfg.state, name, *present, *system, noaction),
+        Resource::Line {
+            path,
+            line,
+            pattern,
+            present,
+        } => line_in_file(cfg, path, line, pattern.as_deref(), *present, noaction),
+    }
+}
+
+fn file(cfg: &Config, spec: &FileSpec, noaction: bool) -> Result<Change, String> {
+    let current = files::current_content(&cfg.files, spec.path)?;
+    let result = files::put(&cfg.files, spec, noaction)?;
+    let diff = match current {
+        Some(current) if current != spec.content => line_diff(&current, spec.content),
+        None => line_diff(b"", spec.content),
+        _ => Vec::new(),
+    };
+    Ok((result.changed, result.message, diff))
+}
+
+/// Ensure a line is in a file or not, rewriting the file only when it changes
+fn line_in_file(
+    cfg: &Config,
+    path: &str,
+    line: &str,
+    pattern: Option<&str>,
+    present: bool,
+    noaction: bool,
+) -> Result<Change, String> {
+    let current = files::current_content(&cfg.files, path)?;
+    let text = match &current {
+        Some(content) => String::from_utf8(content.clone())
+            .map_err(|_| format!("{} is not a text file", path))?,
+        None if present => String::new(),
+        None => return Ok((false, format!("{} does not exist", path), Vec::new())),
+    };
+    let matches = |l: &str| l == line || pattern.is_some_and(|p| glob_match(p, l));
+
+    let mut lines: Vec<&str> = text.lines().collect();
+    if present {
+        if lines.contains(&line) {
+            return Ok((false, format!("{} has the line", path), Vec::new()));
+        }
+        match lines.iter().position(|l| matches(l)) {
+            Some(index) => lines[index] = line,
+            None => lines.push(line),
+        }
+    } else {
+        if !lines.iter().any(|l| matches(l)) {
+            return Ok((
+                false,
+                format!("{} does not have the line", path),
+                Vec::new(),
+            ));
+        }
+        lines.retain(|l| !matches(l));
+    }
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+
+    let spec = FileSpec {
+        path,
+        content: updated.as_bytes(),
+        owner: None,
+        group: None,
+        mode: None,
+        backup: true,
+    };
+    let result = files::put(&cfg.files, &spec, noaction)?;
+    Ok((
+        result.changed,
+        result.message,
+        line_diff(text.as_bytes(), updated.as_bytes()),
+    ))
+}
+
+fn package(cfg: &StateConfig, name: &str, present: bool, noaction: bool) -> Result<Change, String> {
+    if !valid_name(name) {
+        return Err(format!("Invalid package name {}", name));
+    }
+    if !cfg.packages.iter().any(|p| glob_match(p, name)) {
+        return Err(format!("Package {} may not be managed on this host", name));
+    }
+    let report = packages::find(name);
+    let installed = report.packages.iter().find(|p| p.name == name);
+    match (installed, present) {
+        (Some(package), true) => {
+            return Ok((
+                false,
+                format!("{} {} is installed", name, package.version),
+                Vec::new(),
+            ));
+        }
+        (None, false) => return Ok((false, format!("{} is not installed", name), Vec::new())),
+        _ => {}
+    }
+    let diff = match installed {
+        Some(package) => vec![format!("-{} {}", name, package.version)],
+        None => vec![format!("+{}", name)],
+    };
+    let verb = if present { "install" } else { "remove" };
+    if noaction {
+        return Ok((true, format!("Would {} {}", verb, name), diff));
+    }
+
+    let mut command = match report.manager.as_str() {
+        "dpkg" => {
+            let mut command = Command::new("apt-get");
+            command
+                .args(["-y", "-q", verb, "--", name])
+                .env("DEBIAN_FRONTEND", "noninteractive");
+            command
+        }
+        "rpm" => {
+            let mut command = Command::new("dnf");
+            command.args(["-y", "-q", verb, name]);
+            command
+        }
+        _ => return Err("No supported package manager on this host".to_string()),
+    };
+    info!("Package {} {}", verb, name);
+    run(&mut command)?;
+    Ok((true, format!("{} {}", capitalise(past(verb)), name), diff))
+}
+
+fn user(
+    cfg: &StateConfig,
+    name: &str,
+    present: bool,
+    system: bool,
+    shell: Option<&str>,
+    noaction: bool,
+) -> Result<Change, String> {
+    check_account(cfg, name)?;
+    let entry = account_entry("/etc/passwd", name);
+    let (program, verb) = match (&entry, present) {
+        (None, true) => ("useradd", "create"),
+        (Some(_), false) => ("userdel", "remove"),
+        (Some(fields), true) => match shell {
+            Some(shell) if fields.get(6).map(String::as_str) != Some(shell) => {
+                ("usermod", "modify")
+            }
+            _ => return Ok((false, format!("User {} exists", name), Vec::new())),
+        },
+        (None, false) => return Ok((false, format!("User {} does not exist", name), Vec::new())),
+    };
+    let mut command = Command::new(program);
+    if present {
+        if entry.is_none() && system {
+            command.arg("--system");
+        }
+        if let Some(shell) = shell {
+            command.args(["--shell", shell]);
+        }
+    }
+    command.args(["--", name]);
+    account_change(command, verb, "user", name, noaction)
+}
+
+fn group(
+    cfg: &StateConfig,
+    name: &str,
+    present: bool,
+    system: bool,
+    noaction: bool,
+) -> Result<Change, String> {
+    check_account(cfg, name)?;
+    let exists = account_entry("/etc/group", name).is_some();
+    let (mut command, verb) = match (exists, present) {
+        (false, true) => (Command::new("groupadd"), "create"),
+        (true, false) => (Command::new("groupdel"), "remove"),
+        (true, true) => return Ok((false, format!("Group {} exists", name), Vec::new())),
+        (false, false) => {
+            return Ok((false, format!("Group {} does not exist", name), Vec::new()));
+        }
+    };
+    if present && system {
+        command.arg("--system");
+    }
+    command.args(["--", name]);
+    account_change(command, verb, "group", name, noaction)
+}
+
+fn check_account(cfg: &StateConfig, name: &str) -> Result<(), String> {
+    let valid = name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_lowercase() || c == '_' || (i > 0 && (c.is_ascii_digit() || c == '-'))
+    });
+    if name.is_empty() || !valid {
+        return Err(format!("Invalid account name {}", name));
+    }
+    if !cfg.users.iter().any(|p| glob_match(p, name)) {
+        return Err(format!("Account {} may not be managed on this host", name));
+    }
+    Ok(())
+}
+
+/// Run useradd, usermod, userdel, groupadd or groupdel, or describe running it
+fn account_change(
+    mut command: Command,
+    verb: &str,
+    kind: &str,
+    name: &str,
+    noaction: bool,
+) -> Result<Change, String> {
+    let sign = if verb == "remove" { '-' } else { '+' };
+    let diff = vec![format!("{}{} {}", sign, kind, name)];
+    if noaction {
+        return Ok((true, format!("Would {} {} {}", verb, kind, name), diff));
+    }
+    info!("Account {} {} {}", verb, kind, name);
+    run(&mut command)?;
+    Ok((
+        true,
+        format!("{} {} {}", capitalise(kind), name, past(verb)),
+        diff,
+    ))
+}
+
+/// The colon-separated fields of a passwd or group entry
+fn account_entry(database: &str, name: &str) -> Option<Vec<String>> {
+    fs::read_to_string(database).ok()?.lines().find_map(|line| {
+        let fields: Vec<String> = line.split(':').map(str::to_string).collect();
+        (fields[0] == name).then_some(fields)
+    })
+}
+
+fn run(command: &mut Command) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Names safe to pass to package tools; a leading `-` would be read as an option
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._:".contains(c))
+}
+
+fn past(verb: &str) -> &str {
+    match verb {
+        "install" => "installed",
+        "remove" => "removed",
+        "create" => "created",
+        "modify" => "modified",
+        _ => verb,
+    }
+}
+
+fn capitalise(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Lines removed and added between two versions of a file, after trimming the
+/// lines they share at either end
+fn line_diff(old: &[u8], new: &[u8]) -> Vec<String> {
+    let (Ok(old), Ok(new)) = (std::str::from_utf8(old), std::str::from_utf8(new)) else {
+        return vec!["Binary content differs".to_string()];
+    };
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut diff: Vec<String> = old[prefix..old.len() - suffix]
+        .iter()
+        .map(|l| format!("-{}", l))
+        .chain(
+            new[prefix..new.len() - suffix]
+                .iter()
+                .map(|l| format!("+{}", l)),
+        )
+        .collect();
+    if diff.len() > MAX_DIFF_LINES {
+        let more = diff.len() - MAX_DIFF_LINES;
+        diff.truncate(MAX_DIFF_LINES);
+        diff.push(format!("... {} more lines", more));
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_changed_lines() {
+        assert_eq!(line_diff(b"a\nb\nc\n", b"a\nB\nc\n"), vec!["-b", "+B"]);
+        assert_eq!(line_diff(b"a\n", b"a\nd\n"), vec!["+d"]);
+        assert!(line_diff(b"same\n", b"same\n").is_empty());
+    }
+
+    #[test]
+    fn validates_account_names() {
+        let cfg = StateConfig {
+            users: vec!["app-*".to_string()],
+            ..Default::default()
+        };
+        assert!(check_account(&cfg, "app-web").is_ok());
+        assert!(check_account(&cfg, "root").is_err());
+        assert!(check_account(&cfg, "app-$(id)").is_err());
+        assert!(check_account(&cfg, "-app").is_err());
+    }
+
+    #[test]
+    fn refuses_files_that_are_symlinks() {
+        let dir = std::env::temp_dir().join(format!("somasrv-converge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("secret");
+        fs::write(&secret, "token=hunter2\n").unwrap();
+        let link = dir.join("app.conf");
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+        let mut cfg = Config::default();
+        cfg.files.writable = vec![dir.clone()];
+        let path = link.to_str().unwrap();
+
+        let spec = FileSpec {
+            path,
+            content: b"token=other\n",
+            owner: None,
+            group: None,
+            mode: None,
+            backup: false,
+        };
+        let refused = file(&cfg, &spec, true).unwrap_err();
+        assert!(refused.ends_with("is a symbolic link"), "{}", refused);
+        let refused = line_in_file(&cfg, path, "debug=1", None, true, true).unwrap_err();
+        assert!(refused.ends_with("is a symbolic link"), "{}", refused);
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "token=hunter2\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+use somacommon::facts::{BlockDevice, CpuFacts, DmiFacts, Facts, NetworkInterface, OsFacts};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::status;
+
+/// How long gathered facts are served from the cache before being gathered again
+const FACTS_TTL: Duration = Duration::from_secs(3600);
+
+/// Block devices that are not real disks
+const VIRTUAL_BLOCK_DEVICES: &[&str] = &["loop", "ram", "zram", "dm-", "md", "sr", "nbd"];
+
+static CACHE: Mutex<Option<(Instant, Facts)>> = Mutex::new(None);
+
+/// Facts for this machine, from the cache unless it is stale or `refresh` is set
+pub fn get(refresh: bool) -> Facts {
+    let mut cache = CACHE.lock().unwrap();
+    if let Some((at, facts)) = cache.as_ref()
+        && !refresh
+        && at.elapsed() < FACTS_TTL
+    {
+        return facts.clone();
+    }
+    let facts = gather();
+    *cache = Some((Instant::now(), facts.clone()));
+    facts
+}
+
+/// Gather facts for this machine
+pub fn gather() -> Facts {
+    Facts {
+        hostname: read_trimmed("/proc/sys/kernel/hostname"),
+        os: OsFacts {
+            kernel: read_trimmed("/proc/sys/kernel/osrelease"),
+            ..fs::read_to_string("/etc/os-release")
+                .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+                .map(|s| parse_os_release(&s))
+                .unwrap_or_default()
+        },
+        dmi: DmiFacts {
+            vendor: read_trimmed("/sys/class/dmi/id/sys_vendor"),
+            product: read_trimmed("/sys/class/dmi/id/product_name"),
+            serial: read_trimmed("/sys/class/dmi/id/product_serial"),
+            bios_version: read_trimmed("/sys/class/dmi/id/bios_version"),
+        },
+        cpu: fs::read_to_string("/proc/cpuinfo")
+            .map(|s| parse_cpuinfo(&s))
+            .unwrap_or_default(),
+        memory_kb: fs::read_to_string("/proc/meminfo")
+            .map(|s| parse_mem_total(&s))
+            .unwrap_or_default(),
+        block_devices: block_devices(Path::new("/sys/block")),
+        interfaces: network_interfaces(),
+        collected_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    }
+}
+
+fn read_trimmed(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn parse_os_release(content: &str) -> OsFacts {
+    let mut os = OsFacts::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value
+            .trim()
+            .trim_matches('"')
+            .trim_matches('\'')
+            .to_string();
+        match key.trim() {
+            "ID" => os.id = value,
+            "VERSION_ID" => os.version_id = value,
+            "PRETTY_NAME" => os.pretty_name = value,
+            _ => {}
+        }
+    }
+    os
+}
+
+fn parse_cpuinfo(content: &str) -> CpuFacts {
+    let mut cpu = CpuFacts::default();
+    let mut sockets = BTreeSet::new();
+    let mut cores = BTreeSet::new();
+    let mut socket = String::new();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "processor" => cpu.threads += 1,
+            "model name" if cpu.model.is_empty() => cpu.model = value.to_string(),
+            "physical id" => {
+                socket = value.to_string();
+                sockets.insert(socket.clone());
+            }
+            "core id" => {
+                cores.insert((socket.clone(), value.to_string()));
+            }
+            _ => {}
+        }
+    }
+    // Virtual machines and some architectures omit the topology fields
+    cpu.sockets = sockets.len().max(1);
+    cpu.cores = if cores.is_empty() {
+        cpu.threads
+    } else {
+        cores.len()
+    };
+    cpu
+}
+
+fn parse_mem_total(content: &str) -> u64 {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|kb| kb.parse().ok())
+        .unwrap_or_default()
+}
+
+fn block_devices(root: &Path) -> Vec<BlockDevice> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<BlockDevice> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            if VIRTUAL_BLOCK_DEVICES.iter().any(|p| name.starts_with(p)) {
+                return None;
+            }
+            let path = entry.path();
+            // The size file counts 512-byte sectors whatever the device's block size
+            let sectors: u64 = read_trimmed(path.join("size")).parse().ok()?;
+            Some(BlockDevice {
+                name,
+                size_bytes: sectors * 512,
+                model: read_trimmed(path.join("device/model")),
+                rotational: read_trimmed(path.join("queue/rotational")) == "1",
+            })
+        })
+        .collect();
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    devices
+}
+
+fn network_interfaces() -> Vec<NetworkInterface> {
+    let mut addresses = status::interface_addresses();
+    let Ok(entries) = fs::read_dir("/sys/class/net") else {
+        return Vec::new();
+    };
+    let mut interfaces: Vec<NetworkInterface> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            Some(NetworkInterface {
+                mac: read_trimmed(entry.path().join("address")),
+                addresses: addresses.remove(&name).unwrap_or_default(),
+                name,
+            })
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_os_release() {
+        let os = parse_os_release(
+            "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nNAME=\"Debian GNU/Linux\"\n\
+             VERSION_ID=\"12\"\nID=debian\n",
+        );
+        assert_eq!(os.id, "debian");
+        assert_eq!(os.version_id, "12");
+        assert_eq!(os.pretty_name, "Debian GNU/Linux 12 (bookworm)");
+    }
+
+    #[test]
+    fn counts_cpu_topology() {
+        let mut cpuinfo = String::new();
+        for (processor, socket, core) in [(0, 0, 0), (1, 0, 1), (2, 0, 0), (3, 0, 1)] {
+            cpuinfo.push_str(&format!(
+                "processor\t: {}\nmodel name\t: Example CPU\nphysical id\t: {}\ncore id\t\t: {}\n\n",
+                processor, socket, core
+            ));
+        }
+        let cpu = parse_cpuinfo(&cpuinfo);
+        assert_eq!(cpu.model, "Example CPU");
+        assert_eq!((cpu.sockets, cpu.cores, cpu.threads), (1, 2, 4));
+
+        let cpu = parse_cpuinfo("processor : 0\nprocessor : 1\n");
+        assert_eq!((cpu.sockets, cpu.cores, cpu.threads), (1, 2, 2));
+    }
+}
@@ -0,0 +1,550 @@
+use log::info;
+use somacommon::base64;
+use somacommon::digest;
+use somacommon::files::{CopyResult, FETCH_CHUNK, FileChunk};
+use somacommon::protocol::Response;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::FileConfig;
+
+/// Mode given to new files when none is asked for
+const DEFAULT_MODE: u32 = 0o644;
+
+/// A file to write and how it should end up
+pub struct FileSpec<'a> {
+    pub path: &'a str,
+    pub content: &'a [u8],
+    pub owner: Option<&'a str>,
+    pub group: Option<&'a str>,
+    /// Permission bits; a replaced file's are kept when not given
+    pub mode: Option<u32>,
+    /// Keep the replaced file alongside the new one
+    pub backup: bool,
+}
+
+/// Write a file sent by `soma copy`, once its content has been checked against `sha256`
+pub fn copy(cfg: &FileConfig, spec: &FileSpec, sha256: &str, noaction: bool) -> Response {
+    if digest::sha256_hex(spec.content) != sha256 {
+        return Response::Error {
+            message: format!("Content for {} does not match its checksum", spec.path),
+        };
+    }
+    match put(cfg, spec, noaction) {
+        Ok(result) => Response::Copy(result),
+        Err(message) => Response::Error { message },
+    }
+}
+
+/// Write a file under one of the writable prefixes, or report what would change with noaction
+pub fn put(cfg: &FileConfig, spec: &FileSpec, noaction: bool) -> Result<CopyResult, String> {
+    let dest = writable_path(cfg, spec.path)?;
+    let content = spec.content;
+    let sha256 = digest::sha256_hex(content);
+    let uid = spec
+        .owner
+        .map(|owner| lookup_id("/etc/passwd", owner).ok_or(format!("Unknown user {}", owner)))
+        .transpose()?;
+    let gid = spec
+        .group
+        .map(|group| lookup_id("/etc/group", group).ok_or(format!("Unknown group {}", group)))
+        .transpose()?;
+
+    // Everything about the current file goes through one descriptor that
+    // never follows a symlink, so a link planted there cannot redirect it
+    let current = open_existing(&dest).map_err(|e| match e.raw_os_error() {
+        Some(libc::ELOOP) => format!("{} is a symbolic link", spec.path),
+        _ => format!("Failed to open {}: {}", spec.path, e),
+    })?;
+    let existing = match &current {
+        Some(file) => {
+            let meta = file
+                .metadata()
+                .map_err(|e| format!("Failed to open {}: {}", spec.path, e))?;
+            if meta.is_dir() {
+                return Err(format!("{} is a directory", spec.path));
+            }
+            Some(meta)
+        }
+        None => None,
+    };
+    let mode = spec
+        .mode
+        .or(existing.as_ref().map(|m| m.mode() & 0o7777))
+        .unwrap_or(DEFAULT_MODE);
+    let same_content = current
+        .as_ref()
+        .is_some_and(|file| digest::sha256_reader(file).is_ok_and(|existing| existing == sha256));
+    let same_meta = existing.as_ref().is_some_and(|meta| {
+        meta.mode() & 0o7777 == mode
+            && uid.is_none_or(|uid| meta.uid() == uid)
+            && gid.is_none_or(|gid| meta.gid() == gid)
+    });
+
+    let result = |changed, backup, message| CopyResult {
+        path: spec.path.to_string(),
+        changed,
+        sha256: sha256.clone(),
+        backup,
+        message,
+    };
+    if same_content && same_meta {
+        return Ok(result(false, None, format!("{} is up to date", spec.path)));
+    }
+    if noaction {
+        let message = if same_content {
+            format!("Would update mode and ownership of {}", spec.path)
+        } else if existing.is_some() {
+            format!("Would replace {} ({} bytes)", spec.path, content.len())
+        } else {
+            format!("Would create {} ({} bytes)", spec.path, content.len())
+        };
+        return Ok(result(true, None, message));
+    }
+
+    if let Some(file) = current.as_ref().filter(|_| same_content) {
+        info!("Updating mode and ownership of {}", dest.display());
+        set_owner(file, uid, gid)
+            .and_then(|_| file.set_permissions(fs::Permissions::from_mode(mode)))
+            .map_err(|e| format!("Failed to update {}: {}", spec.path, e))?;
+        return Ok(result(
+            true,
+            None,
+            format!("Updated mode and ownership of {}", spec.path),
+        ));
+    }
+
+    info!("Writing {} ({} bytes)", dest.display(), content.len());
+    // Keep the replaced file's ownership unless told otherwise
+    let uid = uid.or(existing.as_ref().map(|m| m.uid()));
+    let gid = gid.or(existing.as_ref().map(|m| m.gid()));
+    let temp = write_temp(&dest, content, mode, uid, gid)
+        .map_err(|e| format!("Failed to write {}: {}", spec.path, e))?;
+    let backup = match &existing {
+        Some(_) if spec.backup => match keep_backup(&dest) {
+            Ok(backup) => Some(backup.display().to_string()),
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                return Err(format!("Failed to back up {}: {}", spec.path, e));
+            }
+        },
+        _ => None,
+    };
+    fs::rename(&temp, &dest).map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        format!("Failed to replace {}: {}", spec.path, e)
+    })?;
+    let message = match &backup {
+        Some(backup) => format!("Replaced {}, previous copy kept as {}", spec.path, backup),
+        None if existing.is_some() => format!("Replaced {}", spec.path),
+        None => format!("Created {}", spec.path),
+    };
+    Ok(result(true, backup, message))
+}
+
+/// The current content of a file under one of the writable prefixes, `None` when
+/// there is no such file yet. A symlink is refused rather than followed, as
+/// [`put`] would refuse to write it.
+pub fn current_content(cfg: &FileConfig, path: &str) -> Result<Option<Vec<u8>>, String> {
+    let dest = writable_path(cfg, path)?;
+    let read = || -> io::Result<Option<Vec<u8>>> {
+        let Some(mut file) = open_existing(&dest)? else {
+            return Ok(None);
+        };
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        Ok(Some(content))
+    };
+    read().map_err(|e| match e.raw_os_error() {
+        Some(libc::ELOOP) => format!("{} is a symbolic link", path),
+        _ => format!("Failed to read {}: {}", dest.display(), e),
+    })
+}
+
+/// Read up to [`FETCH_CHUNK`] bytes of a file under one of the readable prefixes
+pub fn fetch(
+    cfg: &FileConfig,
+    path: &str,
+    offset: u64,
+    length: Option<u64>,
+    tail: Option<u64>,
+) -> Response {
+    match read_chunk(cfg, path, offset, length, tail) {
+        Ok(chunk) => Response::Fetch(chunk),
+        Err(message) => Response::Error { message },
+    }
+}
+
+fn read_chunk(
+    cfg: &FileConfig,
+    path: &str,
+    offset: u64,
+    length: Option<u64>,
+    tail: Option<u64>,
+) -> Result<FileChunk, String> {
+    let source = readable_path(cfg, path)?;
+    let read = || -> io::Result<FileChunk> {
+        let mut file = File::open(&source)?;
+        let size = file.metadata()?.len();
+        let offset = match tail {
+            Some(lines) => tail_offset(&mut file, size, lines)?,
+            None => offset.min(size),
+        };
+        let length = length.unwrap_or(FETCH_CHUNK).min(FETCH_CHUNK);
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data)?;
+        Ok(FileChunk {
+            path: path.to_string(),
+            size,
+            offset,
+            data: base64::encode(&data),
+        })
+    };
+    read().map_err(|e| format!("Failed to read {}: {}", path, e))
+}
+
+/// Resolve a file to read, which must lie under a readable prefix once all
+/// symlinks are followed
+fn readable_path(cfg: &FileConfig, path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(format!("{} is not an absolute path", path.display()));
+    }
+    let denied = || format!("{} may not be read on this host", path.display());
+    // Say nothing about whether files outside the readable prefixes exist
+    let resolved = path.canonicalize().map_err(|_| denied())?;
+    let allowed = cfg
+        .readable
+        .iter()
+        .filter_map(|prefix| prefix.canonicalize().ok())
+        .any(|prefix| resolved.starts_with(prefix));
+    if !allowed {
+        return Err(denied());
+    }
+    if !resolved.is_file() {
+        return Err(format!("{} is not a regular file", path.display()));
+    }
+    Ok(resolved)
+}
+
+/// Offset of the start of the last `lines` lines, reading backwards from the end
+fn tail_offset<R: Read + Seek>(file: &mut R, size: u64, lines: u64) -> io::Result<u64> {
+    if lines == 0 {
+        return Ok(size);
+    }
+    let mut block = [0; 8192];
+    let mut end = size;
+    let mut newlines = 0;
+    // A final newline ends the last line rather than starting another
+    let mut skip_final = true;
+    while end > 0 {
+        let start = end.saturating_sub(block.len() as u64);
+        let buf = &mut block[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(buf)?;
+        for (i, byte) in buf.iter().enumerate().rev() {
+            let at = start + i as u64;
+            if *byte != b'\n' {
+                skip_final = false;
+                continue;
+            }
+            if skip_final && at == size - 1 {
+                skip_final = false;
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                return Ok(at + 1);
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Resolve a destination, which must lie under a writable prefix once symlinks
+/// in its directory are followed
+fn writable_path(cfg: &FileConfig, path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    if !path.is_absolute()
+        || path
+            .components()
+            .any(|c| matches!(c, Component::ParentDir | Component::CurDir))
+    {
+        return Err(format!("{} is not a plain absolute path", path.display()));
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(format!("{} does not name a file", path.display()));
+    };
+    let parent = parent
+        .canonicalize()
+        .map_err(|e| format!("Cannot use directory {}: {}", parent.display(), e))?;
+    let allowed = cfg
+        .writable
+        .iter()
+        .filter_map(|prefix| prefix.canonicalize().ok())
+        .any(|prefix| parent.starts_with(prefix));
+    if !allowed {
+        return Err(format!(
+            "{} may not be written on this host",
+            path.display()
+        ));
+    }
+    Ok(parent.join(name))
+}
+
+/// Open a file for reading without following a symlink in its last
+/// component, `None` when there is no such file
+pub fn open_existing(path: &Path) -> io::Result<Option<File>> {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A hidden file beside the destination, so the final rename stays on one
+/// filesystem, named apart from any other copy to the same destination
+fn temp_path(dest: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(
+        ".{}.soma-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Write the new content to a temp file of its own, returning its path
+fn write_temp(
+    dest: &Path,
+    content: &[u8],
+    mode: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<PathBuf> {
+    let temp = temp_path(dest);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)?;
+    let written = file
+        .write_all(content)
+        .and_then(|_| set_owner(&file, uid, gid))
+        // Set after chown, which clears setuid and setgid bits
+        .and_then(|_| file.set_permissions(fs::Permissions::from_mode(mode)))
+        .and_then(|_| file.sync_all());
+    match written {
+        Ok(()) => Ok(temp),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+fn set_owner(file: &File, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    let meta = file.metadata()?;
+    if uid.is_some_and(|uid| uid != meta.uid()) || gid.is_some_and(|gid| gid != meta.gid()) {
+        std::os::unix::fs::fchown(file, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// Keep the current file as `<path>.<seconds since epoch>.bak`, or
+/// `<path>.<seconds>.<n>.bak` when one was already kept that second, linking
+/// rather than copying where the filesystem allows. An existing backup is
+/// never written over.
+fn keep_backup(dest: &Path) -> io::Result<PathBuf> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut n = 0;
+    loop {
+        let mut name = dest.as_os_str().to_owned();
+        match n {
+            0 => name.push(format!(".{}.bak", now)),
+            _ => name.push(format!(".{}.{}.bak", now, n)),
+        }
+        let backup = PathBuf::from(name);
+        n += 1;
+        match fs::hard_link(dest, &backup) {
+            Ok(()) => return Ok(backup),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(_) => {}
+        }
+        match copy_new(dest, &backup) {
+            Ok(()) => return Ok(backup),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copy a file to a path that must not exist yet, keeping its permission bits
+fn copy_new(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mode = source.metadata()?.permissions().mode();
+    let mut copy = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode & 0o7777)
+        .open(to)?;
+    let copied = io::copy(&mut source, &mut copy).and_then(|_| copy.sync_all());
+    if copied.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    copied
+}
+
+/// A user or group id by name or number from a passwd-format file
+pub fn lookup_id(database: &str, name: &str) -> Option<u32> {
+    if let Ok(id) = name.parse() {
+        return Some(id);
+    }
+    parse_id(&fs::read_to_string(database).ok()?, name)
+}
+
+fn parse_id(content: &str, name: &str) -> Option<u32> {
+    content.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_ids_by_name() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nwww-data:x:33:33:www-data:/var/www:/usr/sbin/nologin\n";
+        assert_eq!(parse_id(passwd, "www-data"), Some(33));
+        assert_eq!(parse_id(passwd, "nobody"), None);
+    }
+
+    #[test]
+    fn finds_the_start_of_the_last_lines() {
+        let content = b"one\ntwo\nthree\n";
+        let tail = |lines| {
+            tail_offset(&mut io::Cursor::new(content), content.len() as u64, lines).unwrap()
+        };
+        assert_eq!(tail(1), 8);
+        assert_eq!(tail(2), 4);
+        assert_eq!(tail(3), 0);
+        assert_eq!(tail(10), 0);
+        assert_eq!(tail(0), content.len() as u64);
+
+        let unterminated = b"one\ntwo";
+        let offset = tail_offset(&mut io::Cursor::new(unterminated), 7, 1).unwrap();
+        assert_eq!(offset, 4);
+    }
+
+    #[test]
+    fn refuses_paths_outside_writable_prefixes() {
+        let dir = std::env::temp_dir();
+        let cfg = FileConfig {
+            writable: vec![dir.clone()],
+            ..Default::default()
+        };
+        let inside = dir.join("soma-test.conf");
+        assert!(writable_path(&cfg, inside.to_str().unwrap()).is_ok());
+        assert!(writable_path(&cfg, "/etc/passwd").is_err());
+        let escape = format!("{}/../etc/passwd", dir.display());
+        assert!(writable_path(&cfg, &escape).is_err());
+        assert!(writable_path(&FileConfig::default(), inside.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn refuses_to_write_through_a_symlink() {
+        let dir = std::env::temp_dir().join(format!("somasrv-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target.conf");
+        fs::write(&target, "kept\n").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        let link = dir.join("link.conf");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let cfg = FileConfig {
+            writable: vec![dir.clone()],
+            ..Default::default()
+        };
+        let spec = |content| FileSpec {
+            path: link.to_str().unwrap(),
+            content,
+            owner: None,
+            group: None,
+            mode: Some(0o666),
+            backup: false,
+        };
+
+        for content in [&b"replaced\n"[..], b"kept\n"] {
+            let refused = put(&cfg, &spec(content), false).unwrap_err();
+            assert!(refused.ends_with("is a symbolic link"), "{}", refused);
+        }
+        assert_eq!(fs::read_to_string(&target).unwrap(), "kept\n");
+        assert_eq!(fs::metadata(&target).unwrap().mode() & 0o7777, 0o600);
+        assert!(
+            fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn temp_files_are_named_apart() {
+        let dest = Path::new("/etc/app.conf");
+        assert_ne!(temp_path(dest), temp_path(dest));
+    }
+
+    #[test]
+    fn backups_taken_within_a_second_are_kept_apart() {
+        let dir = std::env::temp_dir().join(format!("somasrv-backups-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("app.conf");
+        fs::write(&dest, "first\n").unwrap();
+        let cfg = FileConfig {
+            writable: vec![dir.clone()],
+            ..Default::default()
+        };
+        let spec = |content| FileSpec {
+            path: dest.to_str().unwrap(),
+            content,
+            owner: None,
+            group: None,
+            mode: None,
+            backup: true,
+        };
+
+        let mut backups = Vec::new();
+        for content in [&b"second\n"[..], b"third\n"] {
+            let result = put(&cfg, &spec(content), false).unwrap();
+            backups.push(result.backup.unwrap());
+        }
+        assert_ne!(backups[0], backups[1]);
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "first\n");
+        assert_eq!(fs::read_to_string(&backups[1]).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "third\n");
+
+        let backup = keep_backup(&dest).unwrap();
+        copy_new(&dest, &backup).unwrap_err();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "third\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
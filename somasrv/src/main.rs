@@ -1,14 +1,104 @@
+pub mod audit;
 pub mod cli;
 pub mod config;
+pub mod converge;
+pub mod facts;
+pub mod files;
+pub mod metrics;
+pub mod packages;
+pub mod plugins;
+pub mod ports;
+pub mod probes;
+pub mod push;
+pub mod relay;
+pub mod schedule;
+pub mod server;
+pub mod service;
+pub mod signing;
+pub mod status;
 
 use cli::Cli;
-use config::Config;
+use config::{Config, ConfigError};
+use simplelog::{ColorChoice, TermLogger, TerminalMode};
+use somacommon::prometheus;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
 
 fn main() {
     // Parse command line arguments
     let cli = Cli::parse_args();
     let cfg = Config::from_file(&cli.config);
 
-    println!("{:?}", cli);
-    println!("{:?}", cfg);
+    // Only run when asked to listen somewhere, dial a controller, run
+    // scheduled jobs or serve metrics
+    let address = cli.listen_address();
+    let dials_out = cfg.as_ref().is_ok_and(|c| c.controller.is_some());
+    let scheduled = cfg.as_ref().is_ok_and(|c| !c.schedule.is_empty());
+    let exports = cfg.as_ref().is_ok_and(|c| c.http.is_some());
+    if address.is_none() && !dials_out && !scheduled && !exports {
+        return;
+    }
+
+    let cfg = match cfg {
+        Ok(cfg) => cfg,
+        Err(ConfigError::FileNotFound(_)) if cli.config.is_none() => Config::default(),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let level = cli.log_level().unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    TermLogger::init(
+        level,
+        simplelog::Config::default(),
+        TerminalMode::Stderr,
+        ColorChoice::Auto,
+    )
+    .unwrap_or_else(|e| eprintln!("Warning: Failed to initialise logging: {}", e));
+
+    signing::start();
+    if let Err(e) = audit::start(&cfg) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    let cfg = Arc::new(cfg);
+    if !cfg.plugins.is_empty() || !cfg.probes.is_empty() {
+        let cfg = Arc::clone(&cfg);
+        thread::spawn(move || status::run_checks(cfg));
+    }
+    if let Some(http) = &cfg.http {
+        let listener = TcpListener::bind(&http.listen).unwrap_or_else(|e| {
+            eprintln!("Error: Failed to listen on {}: {}", http.listen, e);
+            std::process::exit(1);
+        });
+        let cfg = Arc::clone(&cfg);
+        thread::spawn(move || prometheus::serve(listener, move || metrics::exposition(&cfg)));
+    }
+    if scheduled && (address.is_some() || dials_out) {
+        let cfg = Arc::clone(&cfg);
+        thread::spawn(move || schedule::run(cfg));
+    }
+    let Some(address) = address else {
+        match cfg.controller.clone() {
+            Some(controller) => push::run(cfg, controller),
+            None if scheduled => schedule::run(cfg),
+            // Only the metrics server has work to do
+            None => loop {
+                thread::park();
+            },
+        }
+    };
+    if let Some(controller) = cfg.controller.clone() {
+        let cfg = Arc::clone(&cfg);
+        thread::spawn(move || push::run(cfg, controller));
+    }
+    if let Err(e) = server::serve(&address, cfg) {
+        eprintln!("Error: Failed to listen on {}: {}", address, e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,330 @@
+use somacommon::checks::JobResult;
+use somacommon::prometheus::{Exposition, Family, MetricType};
+use somacommon::status::{Health, StatusReport};
+
+use crate::config::Config;
+use crate::{schedule, status};
+
+// The metric families exposed on /metrics. Dashboards and alerts are built
+// on these names and labels, so change them only with care.
+
+/// 1 for the health the status report was given, 0 for the others
+const HEALTH: Family = Family {
+    name: "somasrv_health",
+    kind: MetricType::Gauge,
+    help: "Health of the host, 1 for the current state and 0 for the others",
+    labels: &["state"],
+};
+const UPTIME: Family = Family {
+    name: "somasrv_uptime_seconds",
+    kind: MetricType::Gauge,
+    help: "Seconds since the host booted",
+    labels: &[],
+};
+const CPUS: Family = Family {
+    name: "somasrv_cpus",
+    kind: MetricType::Gauge,
+    help: "Number of CPUs available",
+    labels: &[],
+};
+/// `period` is `1m`, `5m` or `15m`
+const LOAD: Family = Family {
+    name: "somasrv_load_average",
+    kind: MetricType::Gauge,
+    help: "System load average over the period",
+    labels: &["period"],
+};
+const MEMORY_TOTAL: Family = Family {
+    name: "somasrv_memory_total_bytes",
+    kind: MetricType::Gauge,
+    help: "Total usable memory",
+    labels: &[],
+};
+const MEMORY_AVAILABLE: Family = Family {
+    name: "somasrv_memory_available_bytes",
+    kind: MetricType::Gauge,
+    help: "Memory available for new work without swapping",
+    labels: &[],
+};
+const FILESYSTEM_SIZE: Family = Family {
+    name: "somasrv_filesystem_size_bytes",
+    kind: MetricType::Gauge,
+    help: "Size of a mounted filesystem",
+    labels: &["mount", "fstype"],
+};
+const FILESYSTEM_USED: Family = Family {
+    name: "somasrv_filesystem_used_bytes",
+    kind: MetricType::Gauge,
+    help: "Space used on a mounted filesystem",
+    labels: &["mount", "fstype"],
+};
+const NETWORK_RECEIVE: Family = Family {
+    name: "somasrv_network_receive_bytes_total",
+    kind: MetricType::Counter,
+    help: "Bytes received on a network interface",
+    labels: &["interface"],
+};
+const NETWORK_TRANSMIT: Family = Family {
+    name: "somasrv_network_transmit_bytes_total",
+    kind: MetricType::Counter,
+    help: "Bytes sent on a network interface",
+    labels: &["interface"],
+};
+const SERVICE_ACTIVE: Family = Family {
+    name: "somasrv_service_active",
+    kind: MetricType::Gauge,
+    help: "Whether a watched systemd unit is active",
+    labels: &["unit"],
+};
+/// The plugin's exit code: 0 ok, 1 warning, 2 critical, 3 unknown
+const PLUGIN_STATE: Family = Family {
+    name: "somasrv_plugin_state",
+    kind: MetricType::Gauge,
+    help: "State of a check plugin as its exit code, 0 ok to 3 unknown",
+    labels: &["plugin"],
+};
+const PLUGIN_DURATION: Family = Family {
+    name: "somasrv_plugin_duration_seconds",
+    kind: MetricType::Gauge,
+    help: "How long a check plugin took to run",
+    labels: &["plugin"],
+};
+/// Each performance data value a plugin printed, with its label and unit as given
+const PLUGIN_PERFDATA: Family = Family {
+    name: "somasrv_plugin_perfdata",
+    kind: MetricType::Gauge,
+    help: "Performance data reported by a check plugin",
+    labels: &["plugin", "label", "unit"],
+};
+const PROBE_STATE: Family = Family {
+    name: "somasrv_probe_state",
+    kind: MetricType::Gauge,
+    help: "State of a probe, 0 ok to 3 unknown",
+    labels: &["probe", "kind"],
+};
+/// Only exposed for probes that got as far as timing an answer
+const PROBE_LATENCY: Family = Family {
+    name: "somasrv_probe_latency_seconds",
+    kind: MetricType::Gauge,
+    help: "How long a probe took to get its answer",
+    labels: &["probe", "kind"],
+};
+/// `kind` is `check` or `action`; -1 when the command could not run or was killed
+const JOB_EXIT_CODE: Family = Family {
+    name: "somasrv_job_exit_code",
+    kind: MetricType::Gauge,
+    help: "Exit code of the latest run of a scheduled job, -1 if it did not exit",
+    labels: &["kind", "name"],
+};
+const JOB_DURATION: Family = Family {
+    name: "somasrv_job_duration_seconds",
+    kind: MetricType::Gauge,
+    help: "How long the latest run of a scheduled job took",
+    labels: &["kind", "name"],
+};
+const JOB_LAST_RUN: Family = Family {
+    name: "somasrv_job_last_run_timestamp_seconds",
+    kind: MetricType::Gauge,
+    help: "When the latest run of a scheduled job started",
+    labels: &["kind", "name"],
+};
+
+/// The /metrics page: a fresh status report and the latest scheduled job results
+pub fn exposition(cfg: &Config) -> String {
+    render(&status::collect(cfg), &schedule::latest())
+}
+
+fn render(report: &StatusReport, jobs: &[JobResult]) -> String {
+    let mut page = Exposition::new();
+    let states = [
+        Health::Healthy,
+        Health::Warning,
+        Health::Critical,
+        Health::Unknown,
+    ];
+    page.add(
+        &HEALTH,
+        states
+            .iter()
+            .map(|state| (vec![state.to_string()], flag(*state == report.health))),
+    );
+    page.add_one(&UPTIME, report.uptime_secs as f64);
+    page.add_one(&CPUS, report.cpus as f64);
+    page.add(
+        &LOAD,
+        [
+            ("1m", report.load.one),
+            ("5m", report.load.five),
+            ("15m", report.load.fifteen),
+        ]
+        .map(|(period, value)| (vec![period.to_string()], value)),
+    );
+    page.add_one(&MEMORY_TOTAL, kb(report.memory.total_kb));
+    page.add_one(&MEMORY_AVAILABLE, kb(report.memory.available_kb));
+
+    let mounts = || {
+        report
+            .mounts
+            .iter()
+            .map(|m| (vec![m.mount.clone(), m.filesystem.clone()], m))
+    };
+    page.add(&FILESYSTEM_SIZE, mounts().map(|(l, m)| (l, kb(m.total_kb))));
+    page.add(&FILESYSTEM_USED, mounts().map(|(l, m)| (l, kb(m.used_kb))));
+    page.add(
+        &NETWORK_RECEIVE,
+        report
+            .interfaces
+            .iter()
+            .map(|i| (vec![i.name.clone()], i.rx_bytes as f64)),
+    );
+    page.add(
+        &NETWORK_TRANSMIT,
+        report
+            .interfaces
+            .iter()
+            .map(|i| (vec![i.name.clone()], i.tx_bytes as f64)),
+    );
+    page.add(
+        &SERVICE_ACTIVE,
+        report
+            .services
+            .iter()
+            .map(|s| (vec![s.unit.clone()], flag(s.is_active()))),
+    );
+
+    page.add(
+        &PLUGIN_STATE,
+        report
+            .plugins
+            .iter()
+            .map(|p| (vec![p.name.clone()], p.state.exit_code() as f64)),
+    );
+    page.add(
+        &PLUGIN_DURATION,
+        report
+            .plugins
+            .iter()
+            .map(|p| (vec![p.name.clone()], ms(p.duration_ms))),
+    );
+    page.add(
+        &PLUGIN_PERFDATA,
+        report.plugins.iter().flat_map(|p| {
+            p.perfdata.iter().map(|d| {
+                (
+                    vec![p.name.clone(), d.label.clone(), d.unit.clone()],
+                    d.value,
+                )
+            })
+        }),
+    );
+    page.add(
+        &PROBE_STATE,
+        report.probes.iter().map(|p| {
+            (
+                vec![p.name.clone(), p.kind.clone()],
+                p.state.exit_code() as f64,
+            )
+        }),
+    );
+    page.add(
+        &PROBE_LATENCY,
+        report.probes.iter().filter_map(|p| {
+            let latency = p.latency_ms?;
+            Some((vec![p.name.clone(), p.kind.clone()], ms(latency)))
+        }),
+    );
+
+    let job = |j: &JobResult| vec![j.kind.to_string(), j.name.clone()];
+    page.add(
+        &JOB_EXIT_CODE,
+        jobs.iter()
+            .map(|j| (job(j), j.exit_code.unwrap_or(-1) as f64)),
+    );
+    page.add(
+        &JOB_DURATION,
+        jobs.iter().map(|j| (job(j), ms(j.duration_ms))),
+    );
+    page.add(
+        &JOB_LAST_RUN,
+        jobs.iter().map(|j| (job(j), j.started as f64)),
+    );
+    page.into_text()
+}
+
+fn flag(set: bool) -> f64 {
+    if set { 1.0 } else { 0.0 }
+}
+
+fn kb(kb: u64) -> f64 {
+    kb as f64 * 1024.0
+}
+
+fn ms(ms: u64) -> f64 {
+    ms as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use somacommon::checks::{JobKind, Perfdata, PluginResult, PluginState};
+    use somacommon::status::MountUsage;
+
+    #[test]
+    fn renders_report_and_jobs() {
+        let report = StatusReport {
+            health: Health::Warning,
+            cpus: 2,
+            mounts: vec![MountUsage {
+                mount: "/".to_string(),
+                filesystem: "ext4".to_string(),
+                total_kb: 4,
+                used_kb: 1,
+            }],
+            plugins: vec![PluginResult {
+                name: "disk_raid".to_string(),
+                state: PluginState::Warning,
+                perfdata: vec![Perfdata {
+                    label: "degraded".to_string(),
+                    value: 1.0,
+                    ..Default::default()
+                }],
+                duration_ms: 1500,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let jobs = [JobResult {
+            name: "backup".to_string(),
+            kind: JobKind::Action,
+            started: 1_709_251_200,
+            duration_ms: 250,
+            scheduled: 1_709_251_200,
+            exit_code: None,
+            output: String::new(),
+            catch_up: false,
+        }];
+        let page = render(&report, &jobs);
+        for line in [
+            "somasrv_health{state=\"healthy\"} 0",
+            "somasrv_health{state=\"warning\"} 1",
+            "# TYPE somasrv_uptime_seconds gauge",
+            "somasrv_filesystem_used_bytes{mount=\"/\",fstype=\"ext4\"} 1024",
+            "somasrv_plugin_state{plugin=\"disk_raid\"} 1",
+            "somasrv_plugin_duration_seconds{plugin=\"disk_raid\"} 1.5",
+            "somasrv_plugin_perfdata{plugin=\"disk_raid\",label=\"degraded\",unit=\"\"} 1",
+            "somasrv_job_exit_code{kind=\"action\",name=\"backup\"} -1",
+            "somasrv_job_duration_seconds{kind=\"action\",name=\"backup\"} 0.25",
+            "somasrv_job_last_run_timestamp_seconds{kind=\"action\",name=\"backup\"} 1709251200",
+        ] {
+            assert!(
+                page.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                page
+            );
+        }
+        // Families without samples are left out entirely
+        assert!(!page.contains("somasrv_probe"));
+        assert!(!page.contains("somasrv_network"));
+    }
+}
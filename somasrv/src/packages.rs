@@ -0,0 +1,211 @@
+use log::debug;
+use somacommon::glob_match;
+use somacommon::packages::{Package, PackageReport};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Where dpkg records installed packages
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+
+/// Where the RPM database lives, in its current and older locations
+const RPM_DATABASES: &[&str] = &["/var/lib/rpm", "/usr/lib/sysimage/rpm"];
+
+/// List installed packages, keeping those matching `name` when given.
+///
+/// Pending upgrades are only counted for the full list, since that means
+/// simulating an upgrade of the whole host.
+pub fn list(name: Option<&str>) -> PackageReport {
+    let mut report = installed();
+    match name {
+        Some(name) => report.packages.retain(|p| glob_match(name, &p.name)),
+        None => {
+            report.upgradable = match report.manager.as_str() {
+                "dpkg" => apt_upgradable(),
+                "rpm" => dnf_upgradable(),
+                _ => None,
+            }
+        }
+    }
+    report.packages.sort();
+    report
+}
+
+/// The installed package called exactly `name`, if any, and the package
+/// database it was looked up in
+pub fn find(name: &str) -> PackageReport {
+    let mut report = installed();
+    report.packages.retain(|p| p.name == name);
+    report
+}
+
+/// Installed packages, without counting upgrades.
+///
+/// dpkg's status file is read directly. The RPM database is not a format
+/// worth parsing by hand, so `rpm -qa` is run instead; it only reads.
+fn installed() -> PackageReport {
+    if let Ok(content) = fs::read_to_string(DPKG_STATUS) {
+        PackageReport {
+            manager: "dpkg".to_string(),
+            packages: parse_dpkg_status(&content),
+            upgradable: None,
+        }
+    } else if RPM_DATABASES.iter().any(|p| Path::new(p).is_dir()) {
+        PackageReport {
+            manager: "rpm".to_string(),
+            packages: rpm_packages(),
+            upgradable: None,
+        }
+    } else {
+        PackageReport::default()
+    }
+}
+
+/// Installed packages from a dpkg status file
+fn parse_dpkg_status(content: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for stanza in content.split("\n\n") {
+        let mut package = Package::default();
+        let mut installed = false;
+        for line in stanza.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key {
+                "Package" => package.name = value.to_string(),
+                "Version" => package.version = value.to_string(),
+                "Architecture" => package.architecture = value.to_string(),
+                "Status" => installed = value.ends_with(" installed"),
+                _ => {}
+            }
+        }
+        if installed && !package.name.is_empty() {
+            packages.push(package);
+        }
+    }
+    packages
+}
+
+fn rpm_packages() -> Vec<Package> {
+    let output = Command::new("rpm")
+        .args([
+            "-qa",
+            "--qf",
+            "%{NAME}\\t%{EPOCHNUM}:%{VERSION}-%{RELEASE}\\t%{ARCH}\\n",
+        ])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_rpm_query(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            debug!(
+                "rpm -qa failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            Vec::new()
+        }
+        Err(e) => {
+            debug!("Cannot run rpm: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Tab-separated name, epoch:version-release and architecture, one package per line
+fn parse_rpm_query(content: &str) -> Vec<Package> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next()?;
+            let version = fields.next()?;
+            let architecture = fields.next()?;
+            Some(Package {
+                name: name.to_string(),
+                // Epoch 0 is the default and only clutters the version
+                version: version.strip_prefix("0:").unwrap_or(version).to_string(),
+                architecture: architecture.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Count upgrades apt would install, from its cached package lists.
+/// Simulation needs no lock and changes nothing.
+fn apt_upgradable() -> Option<usize> {
+    let output = Command::new("apt-get")
+        .args(["-s", "-q", "-o", "Debug::NoLocking=1", "dist-upgrade"])
+        .env("LC_ALL", "C")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.starts_with("Inst "))
+            .count(),
+    )
+}
+
+/// Count upgrades dnf knows of from its metadata cache, without refreshing it
+fn dnf_upgradable() -> Option<usize> {
+    let output = Command::new("dnf")
+        .args(["-q", "--cacheonly", "check-update"])
+        .output()
+        .ok()?;
+    // check-update exits 100 when updates are available and 0 when there are none
+    match output.status.code() {
+        Some(0) => Some(0),
+        Some(100) => Some(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|line| line.split_whitespace().count() == 3)
+                .count(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_installed_dpkg_packages() {
+        let packages = parse_dpkg_status(
+            "Package: openssl\nStatus: install ok installed\nArchitecture: amd64\n\
+             Version: 3.0.15-1~deb12u1\n\n\
+             Package: removed\nStatus: deinstall ok config-files\nArchitecture: all\n\
+             Version: 1.0\n",
+        );
+        assert_eq!(
+            packages,
+            vec![Package {
+                name: "openssl".to_string(),
+                version: "3.0.15-1~deb12u1".to_string(),
+                architecture: "amd64".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_rpm_query_output() {
+        let packages =
+            parse_rpm_query("openssl\t1:3.0.7-27.el9\tx86_64\nbash\t0:5.1.8-9.el9\tx86_64\n");
+        assert_eq!(packages[0].version, "1:3.0.7-27.el9");
+        assert_eq!(packages[1].version, "5.1.8-9.el9");
+    }
+
+    #[test]
+    fn only_full_listings_count_upgrades() {
+        let report = list(Some("no-such-package-*"));
+        assert!(report.packages.is_empty());
+        assert_eq!(report.upgradable, None);
+        assert!(find("no-such-package").packages.is_empty());
+        assert_eq!(find("no-such-package").upgradable, None);
+    }
+}
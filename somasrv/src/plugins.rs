@@ -0,0 +1,121 @@
+use log::{debug, warn};
+use somacommon::checks::{PluginResult, PluginState, parse_plugin_output};
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// How often a running plugin is checked for having finished
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Run a plugin, killing it once it takes longer than `timeout`
+pub fn run(name: &str, command: &[String], timeout: Duration) -> PluginResult {
+    debug!("Running plugin {}: {}", name, command.join(" "));
+    let started = Instant::now();
+    let (code, stdout, stderr) = match execute(command, None, timeout) {
+        Ok(done) => done,
+        Err(message) => (None, String::new(), message),
+    };
+    // Plugins report on stdout; stderr only helps when there is nothing else
+    let text = if stdout.trim().is_empty() {
+        stderr
+    } else {
+        stdout
+    };
+    let (output, long_output, perfdata) = parse_plugin_output(&text);
+    let state = PluginState::from_exit_code(code);
+    if state == PluginState::Unknown {
+        warn!("Plugin {} gave no usable result: {}", name, output);
+    }
+    PluginResult {
+        name: name.to_string(),
+        state,
+        output,
+        long_output,
+        perfdata,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// Run a command to completion or until the timeout, feeding it `input` if
+/// given, and return its exit code and output
+pub fn execute(
+    command: &[String],
+    input: Option<&[u8]>,
+    timeout: Duration,
+) -> Result<(Option<i32>, String, String), String> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", command[0], e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // Dropping stdin afterwards tells the command there is no more
+        let _ = stdin.write_all(input);
+    }
+    // Read output as it comes so a chatty plugin cannot block on a full pipe
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let started = Instant::now();
+    let code = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status.code()),
+            Ok(None) if started.elapsed() >= timeout => {
+                kill(&mut child);
+                break Err(format!(
+                    "{} timed out after {}s",
+                    command[0],
+                    timeout.as_secs()
+                ));
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                kill(&mut child);
+                break Err(format!("Failed to wait for {}: {}", command[0], e));
+            }
+        }
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    code.map(|code| (code, stdout, stderr))
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Run every configured plugin at once, in name order
+pub fn run_all(cfg: &Config) -> Vec<PluginResult> {
+    let timeout = Duration::from_secs(cfg.plugin_timeout);
+    thread::scope(|scope| {
+        let handles: Vec<_> = cfg
+            .plugins
+            .iter()
+            .map(|(name, command)| scope.spawn(move || run(name, command, timeout)))
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().ok())
+            .collect()
+    })
+}
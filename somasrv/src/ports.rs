@@ -0,0 +1,159 @@
+use somacommon::ports::{Listener, Protocol};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// TCP socket state for a listening socket, as printed in /proc/net/tcp
+const TCP_LISTEN: &str = "0A";
+
+/// UDP sockets that are bound but not connected show as TCP_CLOSE
+const UDP_UNCONNECTED: &str = "07";
+
+/// Sockets listening on this machine, with their owning processes where they can be seen
+pub fn listeners() -> Vec<Listener> {
+    let mut sockets = Vec::new();
+    for (path, protocol) in [
+        ("/proc/net/tcp", Protocol::Tcp),
+        ("/proc/net/tcp6", Protocol::Tcp),
+        ("/proc/net/udp", Protocol::Udp),
+        ("/proc/net/udp6", Protocol::Udp),
+    ] {
+        if let Ok(content) = fs::read_to_string(path) {
+            sockets.extend(parse_net_sockets(&content, protocol));
+        }
+    }
+
+    // Another user's file descriptors are unreadable without privileges, so
+    // those sockets are reported without an owner
+    let owners = socket_owners();
+    let mut listeners: Vec<Listener> = sockets
+        .into_iter()
+        .map(|(mut listener, inode)| {
+            if let Some((pid, name)) = owners.get(&inode) {
+                listener.pid = Some(*pid);
+                listener.process = Some(name.clone());
+            }
+            listener
+        })
+        .collect();
+    listeners.sort();
+    listeners.dedup();
+    listeners
+}
+
+/// Listening sockets and their inodes from one of the /proc/net socket tables
+fn parse_net_sockets(content: &str, protocol: Protocol) -> Vec<(Listener, u64)> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let (address, port) = parse_socket_address(fields[1])?;
+            let listening = match protocol {
+                Protocol::Tcp => fields[3] == TCP_LISTEN,
+                Protocol::Udp => fields[3] == UDP_UNCONNECTED && fields[2].ends_with(":0000"),
+            };
+            if !listening {
+                return None;
+            }
+            let inode = fields[9].parse().ok()?;
+            let listener = Listener {
+                protocol,
+                address,
+                port,
+                pid: None,
+                process: None,
+            };
+            Some((listener, inode))
+        })
+        .collect()
+}
+
+/// Decode `0100007F:1F90` style addresses. The kernel prints each 32-bit
+/// word of the address in host byte order.
+fn parse_socket_address(field: &str) -> Option<(String, u16)> {
+    let (address, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for chunk in address.as_bytes().chunks(8) {
+        let word = u32::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let address = match bytes.len() {
+        4 => Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_string(),
+        16 => {
+            let octets: [u8; 16] = bytes.try_into().ok()?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        _ => return None,
+    };
+    Some((address, port))
+}
+
+/// Map socket inodes to the pid and name of a process holding them open
+fn socket_owners() -> HashMap<u64, (u32, String)> {
+    let mut owners = HashMap::new();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return owners;
+    };
+    for entry in entries.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let name = fs::read_to_string(entry.path().join("comm"))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse::<u64>().ok());
+            if let Some(inode) = inode {
+                owners.entry(inode).or_insert_with(|| (pid, name.clone()));
+            }
+        }
+    }
+    owners
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tcp_listeners() {
+        let content = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+            0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0000000000000000 100 0 0 10 0\n\
+            1: 0100007F:A1B2 0100007F:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 4243 1 0000000000000000 20 4 30 10 -1\n";
+        let sockets = parse_net_sockets(content, Protocol::Tcp);
+        assert_eq!(sockets.len(), 1);
+        let (listener, inode) = &sockets[0];
+        if cfg!(target_endian = "little") {
+            assert_eq!(listener.address, "127.0.0.1");
+        }
+        assert_eq!(listener.port, 8080);
+        assert_eq!(*inode, 4242);
+    }
+
+    #[test]
+    fn parses_ipv6_any_address() {
+        let (address, port) =
+            parse_socket_address("00000000000000000000000000000000:0035").unwrap();
+        assert_eq!(address, "::");
+        assert_eq!(port, 53);
+    }
+}
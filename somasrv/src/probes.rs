@@ -0,0 +1,539 @@
+use log::debug;
+use regex::Regex;
+use somacommon::checks::{PluginState, ProbeResult};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, ProbeConfig, ProbeKind};
+use crate::plugins;
+
+/// Most of an HTTP response read before giving up on the rest
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+/// Check a probe's settings make sense before it is ever run
+pub fn validate(probe: &ProbeConfig) -> Result<(), String> {
+    if probe.name.is_empty() {
+        return Err("every probe needs a name".to_string());
+    }
+    if probe.timeout == 0 {
+        return Err(format!("{}: timeout must be at least 1 second", probe.name));
+    }
+    let checked = match &probe.kind {
+        ProbeKind::Http { url, body, .. } => parse_url(url).and_then(|_| match body {
+            Some(body) => Regex::new(body).map(|_| ()).map_err(|e| e.to_string()),
+            None => Ok(()),
+        }),
+        ProbeKind::Tcp { address } | ProbeKind::Tls { address, .. } => split_address(address)
+            .map(|_| ())
+            .ok_or_else(|| format!("{} is not host:port", address)),
+        ProbeKind::Dns { query, .. } if query.is_empty() => Err("query is empty".to_string()),
+        ProbeKind::Dns { .. } => Ok(()),
+    };
+    checked.map_err(|e| format!("{}: {}", probe.name, e))
+}
+
+/// Run every configured probe at once, in the order they are configured
+pub fn run_all(cfg: &Config) -> Vec<ProbeResult> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = cfg
+            .probes
+            .iter()
+            .map(|probe| scope.spawn(|| run(probe, &cfg.openssl)))
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().ok())
+            .collect()
+    })
+}
+
+/// Run one probe
+pub fn run(probe: &ProbeConfig, openssl: &[String]) -> ProbeResult {
+    let timeout = Duration::from_secs(probe.timeout);
+    let started = Instant::now();
+    let (kind, target, outcome) = match &probe.kind {
+        ProbeKind::Http {
+            url,
+            status,
+            body,
+            insecure,
+        } => (
+            "http",
+            url,
+            http(url, *status, body.as_deref(), *insecure, openssl, timeout),
+        ),
+        ProbeKind::Tcp { address } => ("tcp", address, tcp(address, timeout)),
+        ProbeKind::Dns { query, expect } => ("dns", query, dns(query, expect.as_deref(), timeout)),
+        ProbeKind::Tls {
+            address,
+            warning_days,
+            critical_days,
+        } => (
+            "tls",
+            address,
+            tls(address, *warning_days, *critical_days, openssl, timeout),
+        ),
+    };
+    let latency = started.elapsed().as_millis() as u64;
+    debug!("Probe {} of {}: {:?}", probe.name, target, outcome);
+    let (state, message, latency_ms) = match outcome {
+        Ok((state, message)) => (state, message, Some(latency)),
+        Err((state, message)) => (state, message, None),
+    };
+    ProbeResult {
+        name: probe.name.clone(),
+        kind: kind.to_string(),
+        target: target.clone(),
+        state,
+        message,
+        latency_ms,
+    }
+}
+
+/// A probe's state and message when it got an answer, or when it did not
+type Outcome = Result<(PluginState, String), (PluginState, String)>;
+
+/// The parts of a URL a probe needs
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    https: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_url(url: &str) -> Result<Url, String> {
+    let (https, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        return Err(format!("{} is not an http:// or https:// URL", url));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let default_port = if https { 443 } else { 80 };
+    let (host, port) = match split_address(authority) {
+        Some((host, port)) => (host, port),
+        // No port: a plain name or address, or an IPv6 address in brackets
+        None if !authority.is_empty()
+            && (!authority.contains(':')
+                || authority.starts_with('[') && authority.ends_with(']')) =>
+        {
+            (authority.trim_matches(['[', ']']).to_string(), default_port)
+        }
+        None => return Err(format!("{} has no usable host", url)),
+    };
+    Ok(Url {
+        https,
+        host,
+        port,
+        path: path.to_string(),
+    })
+}
+
+/// Split host:port, allowing an IPv6 host in brackets
+fn split_address(address: &str) -> Option<(String, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || (host.contains(':') && !address.starts_with('[')) {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+fn http(
+    url: &str,
+    expected: u16,
+    body: Option<&str>,
+    insecure: bool,
+    openssl: &[String],
+    timeout: Duration,
+) -> Outcome {
+    let url = parse_url(url).map_err(|e| (PluginState::Unknown, e))?;
+    let host_header = if url.host.contains(':') {
+        format!("[{}]:{}", url.host, url.port)
+    } else {
+        format!("{}:{}", url.host, url.port)
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: somasrv\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path, host_header
+    );
+    let response = if url.https {
+        let mut command = tls_client(openssl, &url.host, url.port);
+        command.push("-quiet".to_string());
+        if !insecure {
+            command.extend([
+                "-verify_return_error".to_string(),
+                "-verify_hostname".to_string(),
+                url.host.clone(),
+            ]);
+        }
+        match plugins::execute(&command, Some(request.as_bytes()), timeout) {
+            Ok((_, stdout, _)) if !stdout.is_empty() => stdout.into_bytes(),
+            Ok((_, _, stderr)) => {
+                let reason = stderr.lines().last().unwrap_or("no response").to_string();
+                return Err((PluginState::Critical, reason));
+            }
+            Err(e) => return Err((PluginState::Unknown, e)),
+        }
+    } else {
+        fetch_plain(&url, &request, timeout).map_err(|e| (PluginState::Critical, e))?
+    };
+    let (status, text) = parse_response(&response).map_err(|e| (PluginState::Critical, e))?;
+    Ok(check_response(status, &text, expected, body))
+}
+
+/// Send a request over a plain TCP connection and read the whole response
+fn fetch_plain(url: &Url, request: &str, timeout: Duration) -> Result<Vec<u8>, String> {
+    let mut stream = connect(&url.host, url.port, timeout)?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .and_then(|_| stream.write_all(request.as_bytes()))
+        .map_err(|e| format!("Failed to send request: {}", e))?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE)
+        .read_to_end(&mut response)
+        .map_err(|e| format!("Failed to read response: {}", e))?;
+    Ok(response)
+}
+
+/// Connect to the first of a host's addresses that answers
+fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, String> {
+    let addresses: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    let mut last_error = format!("{} has no addresses", host);
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = format!("Failed to connect to {}: {}", address, e),
+        }
+    }
+    Err(last_error)
+}
+
+/// Split an HTTP response into its status code and body, undoing chunked encoding
+fn parse_response(response: &[u8]) -> Result<(u16, String), String> {
+    let text = String::from_utf8_lossy(response);
+    let (head, body) = text
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "Incomplete HTTP response".to_string())?;
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| "Malformed HTTP status line".to_string())?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+    let body = if chunked {
+        decode_chunked(body)
+    } else {
+        body.to_string()
+    };
+    Ok((status, body))
+}
+
+fn decode_chunked(mut body: &str) -> String {
+    let mut decoded = String::new();
+    while let Some((size, rest)) = body.split_once("\r\n") {
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        if size == 0 || rest.len() < size {
+            decoded.push_str(rest.get(..size).unwrap_or(rest));
+            break;
+        }
+        decoded.push_str(&rest[..size]);
+        body = rest[size..].strip_prefix("\r\n").unwrap_or(&rest[size..]);
+    }
+    decoded
+}
+
+fn check_response(
+    status: u16,
+    text: &str,
+    expected: u16,
+    body: Option<&str>,
+) -> (PluginState, String) {
+    if status != expected {
+        return (
+            PluginState::Critical,
+            format!("HTTP {}, expected {}", status, expected),
+        );
+    }
+    if let Some(pattern) = body
+        && let Ok(regex) = Regex::new(pattern)
+        && !regex.is_match(text)
+    {
+        return (
+            PluginState::Critical,
+            format!("HTTP {}, body does not match {}", status, pattern),
+        );
+    }
+    (PluginState::Ok, format!("HTTP {}", status))
+}
+
+fn tcp(address: &str, timeout: Duration) -> Outcome {
+    let (host, port) = split_address(address).ok_or_else(|| {
+        (
+            PluginState::Unknown,
+            format!("{} is not host:port", address),
+        )
+    })?;
+    let stream = connect(&host, port, timeout).map_err(|e| (PluginState::Critical, e))?;
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| address.to_string());
+    Ok((PluginState::Ok, format!("Connected to {}", peer)))
+}
+
+fn dns(name: &str, expect: Option<&str>, timeout: Duration) -> Outcome {
+    // The system resolver has no timeout of its own, so wait for it on the side
+    let (sender, receiver) = mpsc::channel();
+    let lookup = name.to_string();
+    thread::spawn(move || {
+        let _ = sender.send((lookup.as_str(), 0).to_socket_addrs().map(|addresses| {
+            let mut ips: Vec<IpAddr> = addresses.map(|a| a.ip()).collect();
+            ips.dedup();
+            ips
+        }));
+    });
+    let ips = match receiver.recv_timeout(timeout) {
+        Ok(Ok(ips)) => ips,
+        Ok(Err(e)) => {
+            return Err((
+                PluginState::Critical,
+                format!("Failed to resolve {}: {}", name, e),
+            ));
+        }
+        Err(_) => {
+            return Err((
+                PluginState::Critical,
+                format!("Resolving {} timed out", name),
+            ));
+        }
+    };
+    let listed: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+    if let Some(expect) = expect
+        && !listed.iter().any(|ip| ip == expect)
+    {
+        return Ok((
+            PluginState::Critical,
+            format!("{} resolves to {}, not {}", name, listed.join(", "), expect),
+        ));
+    }
+    Ok((
+        PluginState::Ok,
+        format!("{} resolves to {}", name, listed.join(", ")),
+    ))
+}
+
+fn tls(
+    address: &str,
+    warning_days: i64,
+    critical_days: i64,
+    openssl: &[String],
+    timeout: Duration,
+) -> Outcome {
+    let (host, port) = split_address(address).ok_or_else(|| {
+        (
+            PluginState::Unknown,
+            format!("{} is not host:port", address),
+        )
+    })?;
+    let command = tls_client(openssl, &host, port);
+    let (_, stdout, stderr) =
+        plugins::execute(&command, None, timeout).map_err(|e| (PluginState::Unknown, e))?;
+    let Some(certificate) = pem_certificate(&stdout) else {
+        let reason = stderr
+            .lines()
+            .last()
+            .unwrap_or("no certificate")
+            .to_string();
+        return Err((PluginState::Critical, reason));
+    };
+
+    let mut command = openssl.to_vec();
+    command.extend(["x509", "-noout", "-enddate"].map(str::to_string));
+    let (_, stdout, _) = plugins::execute(&command, Some(certificate.as_bytes()), timeout)
+        .map_err(|e| (PluginState::Unknown, e))?;
+    let not_after = stdout
+        .trim()
+        .strip_prefix("notAfter=")
+        .and_then(parse_openssl_time)
+        .ok_or_else(|| {
+            (
+                PluginState::Unknown,
+                "Unreadable certificate expiry".to_string(),
+            )
+        })?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let days = (not_after - now).div_euclid(86400);
+    let state = if days < critical_days {
+        PluginState::Critical
+    } else if days < warning_days {
+        PluginState::Warning
+    } else {
+        PluginState::Ok
+    };
+    let message = if not_after < now {
+        format!("Certificate expired {} days ago", -days)
+    } else {
+        format!("Certificate expires in {} days", days)
+    };
+    Ok((state, message))
+}
+
+/// The openssl command line for a TLS connection to host:port
+fn tls_client(openssl: &[String], host: &str, port: u16) -> Vec<String> {
+    let mut command = openssl.to_vec();
+    let connect = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    command.extend(["s_client".to_string(), "-connect".to_string(), connect]);
+    // Server names are only sent for names, not addresses
+    if host.parse::<IpAddr>().is_err() {
+        command.extend(["-servername".to_string(), host.to_string()]);
+    }
+    command
+}
+
+/// The first PEM certificate in some text
+fn pem_certificate(text: &str) -> Option<String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let start = text.find(BEGIN)?;
+    let end = text[start..].find(END)? + start + END.len();
+    Some(format!("{}\n", &text[start..end]))
+}
+
+/// Parse openssl's `Mar  1 12:00:00 2025 GMT` into seconds since the Unix epoch
+fn parse_openssl_time(text: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let [month, day, time, year, ..] = fields[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let day: i64 = day.parse().ok()?;
+    let year: i64 = year.parse().ok()?;
+    let mut clock = time.split(':').map(|f| f.parse::<i64>());
+    let (hour, minute, second) = (
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+        clock.next()?.ok()?,
+    );
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Days since 1970-01-01 for a date, after Howard Hinnant's algorithm
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls_and_addresses() {
+        assert_eq!(
+            parse_url("https://example.com/health?full=1").unwrap(),
+            Url {
+                https: true,
+                host: "example.com".to_string(),
+                port: 443,
+                path: "/health?full=1".to_string(),
+            }
+        );
+        let url = parse_url("http://[::1]:8080").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("::1", 8080, "/")
+        );
+        assert!(parse_url("ftp://example.com/").is_err());
+        assert!(parse_url("http://:80/").is_err());
+
+        assert_eq!(
+            split_address("db-01:5432"),
+            Some(("db-01".to_string(), 5432))
+        );
+        assert_eq!(
+            split_address("[fe80::1]:443"),
+            Some(("fe80::1".to_string(), 443))
+        );
+        assert_eq!(split_address("fe80::1"), None);
+        assert_eq!(split_address("db-01"), None);
+    }
+
+    #[test]
+    fn checks_http_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         7\r\n{\"ok\": \r\n5\r\ntrue}\r\n0\r\n\r\n";
+        let (status, body) = parse_response(response).unwrap();
+        assert_eq!((status, body.as_str()), (200, "{\"ok\": true}"));
+        assert_eq!(
+            check_response(status, &body, 200, Some(r#""ok":\s*true"#)).0,
+            PluginState::Ok
+        );
+        assert_eq!(
+            check_response(status, &body, 200, Some("false")).1,
+            "HTTP 200, body does not match false"
+        );
+        assert_eq!(
+            check_response(503, "", 200, None).1,
+            "HTTP 503, expected 200"
+        );
+        assert!(parse_response(b"garbage").is_err());
+    }
+
+    #[test]
+    fn parses_certificate_expiry() {
+        assert_eq!(
+            parse_openssl_time("Mar  1 00:00:00 2024 GMT"),
+            Some(1_709_251_200)
+        );
+        assert_eq!(
+            parse_openssl_time("Feb 29 12:00:00 2028 GMT"),
+            Some(1_835_438_400)
+        );
+        assert_eq!(parse_openssl_time("Smarch 1 00:00:00 2024 GMT"), None);
+        let text = "CONNECTED\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\nrest";
+        assert_eq!(
+            pem_certificate(text).as_deref(),
+            Some("-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n")
+        );
+    }
+}
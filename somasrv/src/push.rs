@@ -0,0 +1,131 @@
+use log::{debug, info, warn};
+use somacommon::protocol::{self, AgentMessage, ControllerMessage, DEFAULT_CONTROLLER_PORT};
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::audit;
+use crate::config::{Config, ControllerConfig};
+
+/// Longest wait between attempts to reach the controller
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Keep a connection open to the controller, reconnecting with backoff
+/// whenever it drops. Never returns.
+pub fn run(cfg: Arc<Config>, controller: ControllerConfig) -> ! {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match session(&cfg, &controller, &mut backoff) {
+            Ok(()) => info!("Controller {} closed the connection", controller.endpoint),
+            Err(e) => warn!(
+                "Connection to controller {} failed: {}",
+                controller.endpoint, e
+            ),
+        }
+        debug!("Reconnecting in {}s", backoff.as_secs());
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn endpoint_address(endpoint: &str) -> String {
+    if endpoint.contains(':') {
+        endpoint.to_string()
+    } else {
+        format!("{}:{}", endpoint, DEFAULT_CONTROLLER_PORT)
+    }
+}
+
+/// Name to register with the controller
+fn hostname(controller: &ControllerConfig) -> String {
+    controller.hostname.clone().unwrap_or_else(|| {
+        fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    })
+}
+
+fn session(cfg: &Config, controller: &ControllerConfig, backoff: &mut Duration) -> io::Result<()> {
+    let stream = TcpStream::connect(endpoint_address(&controller.endpoint))?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = BufReader::new(stream);
+
+    let hello = AgentMessage::Hello {
+        hostname: hostname(controller),
+        token: controller.token.clone(),
+    };
+    protocol::write_message(&mut *writer.lock().unwrap(), &hello)?;
+    match protocol::read_message(&mut reader)? {
+        Some(ControllerMessage::Welcome) => {
+            info!("Connected to controller {}", controller.endpoint);
+            *backoff = Duration::from_secs(1);
+        }
+        Some(ControllerMessage::Rejected { message }) => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected greeting from controller",
+            ));
+        }
+    }
+
+    let closed = Arc::new(AtomicBool::new(false));
+    spawn_heartbeat(
+        Arc::clone(&writer),
+        Arc::clone(&closed),
+        Duration::from_secs(controller.heartbeat),
+    );
+
+    let result = serve_requests(cfg, &controller.endpoint, &writer, &mut reader);
+    closed.store(true, Ordering::SeqCst);
+    result
+}
+
+fn serve_requests(
+    cfg: &Config,
+    endpoint: &str,
+    writer: &Mutex<TcpStream>,
+    reader: &mut BufReader<TcpStream>,
+) -> io::Result<()> {
+    while let Some(message) = protocol::read_message::<_, ControllerMessage>(reader)? {
+        match message {
+            ControllerMessage::Request { id, request } => {
+                debug!("Controller requested {:?}", request);
+                let response = audit::handle(&request, endpoint, cfg);
+                let reply = AgentMessage::Reply {
+                    id,
+                    response: Box::new(response),
+                };
+                protocol::write_message(&mut *writer.lock().unwrap(), &reply)?;
+            }
+            ControllerMessage::Rejected { message } => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+            }
+            ControllerMessage::Welcome => {}
+        }
+    }
+    Ok(())
+}
+
+fn spawn_heartbeat(writer: Arc<Mutex<TcpStream>>, closed: Arc<AtomicBool>, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            if closed.load(Ordering::SeqCst) {
+                break;
+            }
+            let mut stream = writer.lock().unwrap();
+            if protocol::write_message(&mut *stream, &AgentMessage::Heartbeat).is_err() {
+                // Unblock the reader so the session ends and reconnects
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,128 @@
+use log::{info, warn};
+use somacommon::protocol::{self, Request, Response};
+use somacommon::{glob_match, tokens_match};
+use std::io::{self, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::config::RelayConfig;
+
+/// How long to wait for an agent on the local segment to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for that agent to answer
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pass a controller request on to an agent on this host's segment.
+///
+/// The relay only ever sends the request it was given, and only when the
+/// controller's token matches. It only relays requests signed by the
+/// controller, which the target checks, so it cannot make up requests of
+/// its own. It never relays a request that would itself be passed on, so a
+/// chain of relays cannot be built to escape the target allowlist.
+pub fn forward(cfg: &RelayConfig, token: &str, target: &str, request: &Request) -> Response {
+    if !tokens_match(token, &cfg.token) {
+        warn!("Refused to relay to {}: invalid token", target);
+        return error("Invalid relay token");
+    }
+    if !matches!(request, Request::Signed { .. }) {
+        warn!("Refused to relay to {}: request is not signed", target);
+        return error("Only requests signed by the controller are relayed");
+    }
+    if matches!(
+        request.inner(),
+        Request::Forward { .. } | Request::Relay { .. }
+    ) {
+        return error("Relayed requests cannot be passed on again");
+    }
+    if !cfg
+        .targets
+        .iter()
+        .any(|pattern| target_allowed(pattern, target))
+    {
+        warn!("Refused to relay to {}: not an allowed target", target);
+        return error(&format!("This relay does not reach {}", target));
+    }
+
+    info!("Relaying {:?} to {}", request, target);
+    send(target, request)
+        .unwrap_or_else(|e| error(&format!("Relay could not reach {}: {}", target, e)))
+}
+
+fn error(message: &str) -> Response {
+    Response::Error {
+        message: message.to_string(),
+    }
+}
+
+fn send(target: &str, request: &Request) -> io::Result<Response> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses resolved");
+    for addr in target.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                let mut writer = stream.try_clone()?;
+                protocol::write_message(&mut writer, request)?;
+                return protocol::read_message(&mut BufReader::new(stream))?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")
+                });
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Match a target against a pattern naming a host or a host:port
+fn target_allowed(pattern: &str, target: &str) -> bool {
+    let host = target
+        .rsplit_once(':')
+        .map_or(target, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    glob_match(pattern, target) || glob_match(pattern, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_match_hosts_and_ports() {
+        assert!(target_allowed("10.9.0.*", "10.9.0.3:7070"));
+        assert!(target_allowed("10.9.0.3:7070", "10.9.0.3:7070"));
+        assert!(!target_allowed("10.9.0.3:7070", "10.9.0.3:22"));
+        assert!(!target_allowed("10.9.0.*", "10.9.1.3:7070"));
+        assert!(target_allowed("lab-*", "lab-03:7070"));
+    }
+
+    #[test]
+    fn relays_refuse_bad_tokens_unsigned_requests_and_chains() {
+        let cfg = RelayConfig {
+            token: "secret".to_string(),
+            targets: vec!["*".to_string()],
+        };
+        let refused = forward(&cfg, "guess", "127.0.0.1:1", &Request::Status);
+        assert_eq!(refused, error("Invalid relay token"));
+
+        let refused = forward(&cfg, "secret", "127.0.0.1:1", &Request::Status);
+        assert_eq!(
+            refused,
+            error("Only requests signed by the controller are relayed")
+        );
+
+        let chained = Request::Signed {
+            host: "lab-03".to_string(),
+            nonce: "01".to_string(),
+            timestamp: 0,
+            signature: String::new(),
+            request: Box::new(Request::Relay {
+                token: "secret".to_string(),
+                target: "127.0.0.1:1".to_string(),
+                request: Box::new(Request::Status),
+            }),
+        };
+        let refused = forward(&cfg, "secret", "127.0.0.1:1", &chained);
+        assert_eq!(refused, error("Relayed requests cannot be passed on again"));
+    }
+}
@@ -0,0 +1,509 @@
+use log::{info, warn};
+use somacommon::checks::{JobKind, JobResult};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{CatchUp, Config, ScheduleEntry};
+use crate::server;
+
+/// File in the state directory holding the latest result of each job
+pub const RESULTS_FILE: &str = "schedule.json";
+
+/// Most missed runs made up for with `catch_up = "all"`
+const MAX_CATCH_UP: usize = 10;
+
+/// Longest stretch slept at once, so clock changes are noticed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Longest output kept from a run
+const MAX_OUTPUT: usize = 4096;
+
+static LATEST: Mutex<BTreeMap<(JobKind, String), JobResult>> = Mutex::new(BTreeMap::new());
+
+/// A parsed five-field cron expression: minute, hour, day of month, month
+/// and day of week, each as a bit set of the values it allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day of month and day of week were `*`, which changes how they combine
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "cron expression {:?} should have 5 fields, not {}",
+                s,
+                fields.len()
+            ));
+        };
+        let weekdays = parse_field(weekday, 0, 7)?;
+        let cron = Cron {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            // Both 0 and 7 are Sunday
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        };
+        if cron.next_after(0).is_none() {
+            return Err(format!("cron expression {:?} never matches", s));
+        }
+        Ok(cron)
+    }
+}
+
+/// Parse one cron field of comma-separated values, `a-b` ranges and `*`,
+/// each optionally stepped with `/n`
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u64>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step in cron field {:?}", field)),
+            },
+            None => (part, 1),
+        };
+        let value = |v: &str| match v.parse::<u64>() {
+            Ok(v) if (min..=max).contains(&v) => Ok(v),
+            _ => Err(format!(
+                "{:?} in cron field {:?} is not between {} and {}",
+                v, field, min, max
+            )),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // A stepped single value runs to the end of the range
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(format!("backwards range in cron field {:?}", field));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    /// The first time after `t` the expression matches, in seconds since the Unix epoch
+    pub fn next_after(&self, t: u64) -> Option<u64> {
+        let mut minute = t / 60 + 1;
+        // The calendar repeats every 400 years, so anything matching at all does by then
+        let limit = minute / 1440 + 146_097;
+        while minute / 1440 <= limit {
+            let day = minute / 1440;
+            if !self.day_matches(day) {
+                minute = (day + 1) * 1440;
+                continue;
+            }
+            let hour = minute % 1440 / 60;
+            if self.hours & 1 << hour == 0 {
+                minute = day * 1440 + (hour + 1) * 60;
+                continue;
+            }
+            if self.minutes & 1 << (minute % 60) != 0 {
+                return Some(minute * 60);
+            }
+            minute += 1;
+        }
+        None
+    }
+
+    fn day_matches(&self, day: u64) -> bool {
+        let (_, month, mday) = civil_date(day);
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        // 1970-01-01 was a Thursday
+        let by_day = self.days & 1 << mday != 0;
+        let by_weekday = self.weekdays & 1 << ((day + 4) % 7) != 0;
+        // As in cron, restricting both day fields matches either of them
+        match (self.any_day, self.any_weekday) {
+            (false, false) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        }
+    }
+}
+
+/// Year, month and day of the month for a count of days since 1970-01-01,
+/// after Howard Hinnant's algorithm
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// When a job runs
+#[derive(Debug, Clone)]
+pub enum Timing {
+    Cron(Cron),
+    /// Every this many seconds, counted on from the previous run
+    Every(u64),
+}
+
+impl Timing {
+    pub fn from_entry(entry: &ScheduleEntry) -> Result<Timing, String> {
+        match (&entry.cron, entry.every) {
+            (Some(cron), _) => cron.parse().map(Timing::Cron),
+            (None, Some(every)) => Ok(Timing::Every(every)),
+            (None, None) => Err("no cron or every".to_string()),
+        }
+    }
+
+    /// The first run due after `now`, counting on from the run scheduled at `prev`
+    fn first_after(&self, prev: u64, now: u64) -> u64 {
+        match self {
+            Timing::Every(every) if now < prev => prev + every,
+            Timing::Every(every) => prev + ((now - prev) / every + 1) * every,
+            Timing::Cron(cron) => cron.next_after(now.max(prev)).unwrap_or(u64::MAX),
+        }
+    }
+
+    /// Runs due after `prev` up to and including `now`, the latest `MAX_CATCH_UP` of them
+    fn missed(&self, prev: u64, now: u64) -> Vec<u64> {
+        match self {
+            Timing::Every(every) => {
+                let count = now.saturating_sub(prev) / every;
+                let first = count.saturating_sub(MAX_CATCH_UP as u64 - 1).max(1);
+                (first..=count).map(|k| prev + k * every).collect()
+            }
+            Timing::Cron(cron) => {
+                let mut missed = VecDeque::new();
+                let mut at = prev;
+                while let Some(next) = cron.next_after(at).filter(|next| *next <= now) {
+                    if missed.len() == MAX_CATCH_UP {
+                        missed.pop_front();
+                    }
+                    missed.push_back(next);
+                    at = next;
+                }
+                missed.into()
+            }
+        }
+    }
+}
+
+/// Runs to make up for on starting, and when the first regular run is due,
+/// given when the job was last scheduled to run
+pub fn plan(timing: &Timing, catch_up: CatchUp, last: Option<u64>, now: u64) -> (Vec<u64>, u64) {
+    let Some(last) = last else {
+        // Never run: intervals start straight away, cron waits for its time
+        let next = match timing {
+            Timing::Every(_) => now,
+            Timing::Cron(_) => timing.first_after(now, now),
+        };
+        return (Vec::new(), next);
+    };
+    let mut missed = match catch_up {
+        CatchUp::Skip => Vec::new(),
+        CatchUp::Once | CatchUp::All => timing.missed(last, now),
+    };
+    if catch_up == CatchUp::Once {
+        missed = missed.pop().into_iter().collect();
+    }
+    (missed, timing.first_after(last, now))
+}
+
+/// The latest result of every scheduled job
+pub fn latest() -> Vec<JobResult> {
+    LATEST.lock().unwrap().values().cloned().collect()
+}
+
+fn results_path(cfg: &Config) -> PathBuf {
+    cfg.state_dir.join(RESULTS_FILE)
+}
+
+/// Results stored by an earlier run of the agent. A missing or unreadable file means none.
+fn load(path: &Path) -> Vec<JobResult> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Store the latest results, replacing the file with a rename to avoid torn
+/// writes. Each save writes a temp file of its own.
+fn save(path: &Path, results: &[JobResult]) -> io::Result<()> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let content = serde_json::to_string_pretty(results).map_err(io::Error::other)?;
+    let temp = path.with_extension(format!(
+        "json.{}.{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .and_then(|()| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+fn record(cfg: &Config, result: JobResult) {
+    LATEST
+        .lock()
+        .unwrap()
+        .insert((result.kind, result.name.clone()), result);
+    // Jobs finishing together save one at a time, each taking the results
+    // as they stand then, so an older set never replaces a newer one
+    static SAVING: Mutex<()> = Mutex::new(());
+    let _saving = SAVING.lock().unwrap();
+    let results = latest();
+    let path = results_path(cfg);
+    if let Err(e) = save(&path, &results) {
+        warn!("Failed to store results in {}: {}", path.display(), e);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A random delay of up to `max` seconds
+fn jitter(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    hasher.finish() % (max + 1)
+}
+
+/// Run a scheduled check or action once
+fn run_job(cfg: &Config, entry: &ScheduleEntry, scheduled: u64, catch_up: bool) -> JobResult {
+    let (kind, name) = entry.job();
+    info!("Running scheduled {} {}", kind, name);
+    let started = now();
+    let clock = Instant::now();
+    let (exit_code, stdout, stderr) = match kind {
+        JobKind::Check => {
+            let command = &cfg.checks[name];
+            match Command::new(&command[0]).args(&command[1..]).output() {
+                Ok(output) => (
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stdout).into_owned(),
+                    String::from_utf8_lossy(&output.stderr).into_owned(),
+                ),
+                Err(e) => (
+                    None,
+                    String::new(),
+                    format!("Failed to run {}: {}", command[0], e),
+                ),
+            }
+        }
+        JobKind::Action => {
+            let result = server::run_action(name, &cfg.actions[name], false);
+            (result.exit_code, result.stdout, result.stderr)
+        }
+    };
+    let mut output = format!("{}\n{}", stdout.trim(), stderr.trim())
+        .trim()
+        .to_string();
+    if output.len() > MAX_OUTPUT {
+        let mut end = MAX_OUTPUT;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+    }
+    if exit_code != Some(0) {
+        warn!("Scheduled {} {} failed: {}", kind, name, output);
+    }
+    JobResult {
+        name: name.to_string(),
+        kind,
+        scheduled,
+        started,
+        duration_ms: clock.elapsed().as_millis() as u64,
+        exit_code,
+        output,
+        catch_up,
+    }
+}
+
+/// A scheduled job and when it next runs
+struct Job<'a> {
+    entry: &'a ScheduleEntry,
+    timing: Timing,
+    /// When the next run is scheduled
+    next: u64,
+    /// When the next run actually starts, after jitter
+    start: u64,
+}
+
+/// Run the configured jobs on their schedules, making up for runs missed
+/// while the agent was down as each job's catch-up policy says. Never returns.
+pub fn run(cfg: Arc<Config>) -> ! {
+    let path = results_path(&cfg);
+    let stored = load(&path);
+    let last_run: BTreeMap<(JobKind, &str), u64> = stored
+        .iter()
+        .map(|r| ((r.kind, r.name.as_str()), r.scheduled))
+        .collect();
+    {
+        let mut latest = LATEST.lock().unwrap();
+        for result in &stored {
+            latest.insert((result.kind, result.name.clone()), result.clone());
+        }
+    }
+
+    let mut jobs = Vec::new();
+    for entry in &cfg.schedule {
+        // Entries were checked when the configuration was loaded
+        let Ok(timing) = Timing::from_entry(entry) else {
+            continue;
+        };
+        let last = last_run.get(&entry.job()).copied();
+        let (missed, next) = plan(&timing, entry.catch_up, last, now());
+        for scheduled in missed {
+            record(&cfg, run_job(&cfg, entry, scheduled, true));
+        }
+        jobs.push(Job {
+            entry,
+            timing,
+            next,
+            start: next.saturating_add(jitter(entry.jitter)),
+        });
+    }
+
+    loop {
+        let Some(job) = jobs.iter_mut().min_by_key(|job| job.start) else {
+            loop {
+                thread::park();
+            }
+        };
+        let now = now();
+        if job.start > now {
+            thread::sleep(Duration::from_secs(job.start - now).min(MAX_SLEEP));
+            continue;
+        }
+        record(&cfg, run_job(&cfg, job.entry, job.next, false));
+        // Runs that fell due while this one was going are skipped
+        job.next = job.timing.first_after(job.next, self::now().max(job.next));
+        job.start = job.next.saturating_add(jitter(job.entry.jitter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-01 00:00 UTC, a Friday
+    const MARCH_1: u64 = 1_709_251_200;
+
+    #[test]
+    fn finds_next_cron_match() {
+        let cron: Cron = "30 2 * * *".parse().unwrap();
+        assert_eq!(cron.next_after(MARCH_1), Some(MARCH_1 + 2 * 3600 + 1800));
+
+        let cron: Cron = "*/15 * * * *".parse().unwrap();
+        assert_eq!(cron.next_after(MARCH_1 + 60), Some(MARCH_1 + 900));
+
+        // The first Monday, or the 15th
+        let cron: Cron = "0 0 15 * 1".parse().unwrap();
+        assert_eq!(cron.next_after(MARCH_1), Some(MARCH_1 + 3 * 86400));
+
+        let cron: Cron = "0 12 29 2 *".parse().unwrap();
+        assert_eq!(cron.next_after(MARCH_1), Some(1_835_438_400));
+
+        assert!("0 0 30 2 *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn plans_catch_up_runs() {
+        let hourly = Timing::Every(3600);
+        let now = MARCH_1 + 3 * 3600 + 60;
+
+        assert_eq!(
+            plan(&hourly, CatchUp::Skip, Some(MARCH_1), now),
+            (vec![], MARCH_1 + 4 * 3600)
+        );
+        assert_eq!(
+            plan(&hourly, CatchUp::Once, Some(MARCH_1), now),
+            (vec![MARCH_1 + 3 * 3600], MARCH_1 + 4 * 3600)
+        );
+        assert_eq!(
+            plan(&hourly, CatchUp::All, Some(MARCH_1), now),
+            (
+                vec![MARCH_1 + 3600, MARCH_1 + 2 * 3600, MARCH_1 + 3 * 3600],
+                MARCH_1 + 4 * 3600
+            )
+        );
+        assert_eq!(plan(&hourly, CatchUp::All, None, now), (vec![], now));
+
+        let daily = Timing::Cron("0 3 * * *".parse().unwrap());
+        let (missed, next) = plan(&daily, CatchUp::All, Some(MARCH_1), MARCH_1 + 30 * 86400);
+        assert_eq!(missed.len(), MAX_CATCH_UP);
+        assert_eq!(missed.last(), Some(&(MARCH_1 + 29 * 86400 + 3 * 3600)));
+        assert_eq!(next, MARCH_1 + 30 * 86400 + 3 * 3600);
+    }
+
+    #[test]
+    fn concurrent_saves_use_their_own_temp_files() {
+        let dir = std::env::temp_dir().join(format!("somasrv-schedule-{}", std::process::id()));
+        let path = dir.join(RESULTS_FILE);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        save(&path, &[]).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(load(&path), Vec::new());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
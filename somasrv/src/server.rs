@@ -0,0 +1,166 @@
+use log::{debug, info, warn};
+use somacommon::base64;
+use somacommon::protocol::{self, ActionResult, Request, Response};
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::{
+    audit, converge, facts, files, packages, plugins, ports, relay, schedule, service, status,
+};
+
+/// Listen on `address` and answer requests from soma until the process is stopped
+pub fn serve(address: &str, cfg: Arc<Config>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let cfg = Arc::clone(&cfg);
+                thread::spawn(move || {
+                    let peer = stream
+                        .peer_addr()
+                        .map(|a| a.to_string())
+                        .unwrap_or_default();
+                    if let Err(e) = handle_connection(stream, &cfg) {
+                        warn!("Connection from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept connection: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, cfg: &Config) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    debug!("Connection from {}", peer);
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    while let Some(request) = protocol::read_message::<_, Request>(&mut reader)? {
+        debug!("{} requested {:?}", peer, request);
+        let response = audit::handle(&request, &peer.to_string(), cfg);
+        protocol::write_message(&mut writer, &response)?;
+    }
+    debug!("Connection from {} closed", peer);
+    Ok(())
+}
+
+/// Produce the response to a single request
+pub fn handle_request(request: &Request, cfg: &Config) -> Response {
+    match request {
+        Request::Status => Response::Status(status::collect(cfg)),
+        Request::Action { name, noaction } => match cfg.actions.get(name) {
+            Some(command) => Response::Action(run_action(name, command, *noaction)),
+            None => Response::Error {
+                message: format!("Action {} is not allowed on this host", name),
+            },
+        },
+        Request::Facts { refresh } => Response::Facts(Box::new(facts::get(*refresh))),
+        Request::Packages { name } => Response::Packages(packages::list(name.as_deref())),
+        Request::Ports => Response::Ports {
+            listeners: ports::listeners(),
+        },
+        Request::Service {
+            unit,
+            operation,
+            noaction,
+        } => service::handle(&cfg.services, unit, *operation, *noaction),
+        Request::Copy {
+            path,
+            content,
+            sha256,
+            owner,
+            group,
+            mode,
+            backup,
+            noaction,
+        } => match base64::decode(content) {
+            Ok(content) => {
+                let spec = files::FileSpec {
+                    path,
+                    content: &content,
+                    owner: owner.as_deref(),
+                    group: group.as_deref(),
+                    mode: *mode,
+                    backup: *backup,
+                };
+                files::copy(&cfg.files, &spec, sha256, *noaction)
+            }
+            Err(message) => Response::Error { message },
+        },
+        Request::Fetch {
+            path,
+            offset,
+            length,
+            tail,
+        } => files::fetch(&cfg.files, path, *offset, *length, *tail),
+        Request::Apply {
+            resources,
+            noaction,
+        } => converge::apply(cfg, resources, *noaction),
+        Request::Plugin { name } => match cfg.plugins.get(name) {
+            Some(command) => Response::Plugin(plugins::run(
+                name,
+                command,
+                Duration::from_secs(cfg.plugin_timeout),
+            )),
+            None => Response::Error {
+                message: format!("Plugin {} is not configured on this host", name),
+            },
+        },
+        Request::Results => Response::Results {
+            results: schedule::latest(),
+        },
+        Request::Forward { target, .. } => Response::Error {
+            message: format!("This agent does not forward requests to {}", target),
+        },
+        Request::Relay {
+            token,
+            target,
+            request,
+        } => match &cfg.relay {
+            Some(relay) => relay::forward(relay, token, target, request),
+            None => Response::Error {
+                message: format!("This agent does not relay requests to {}", target),
+            },
+        },
+        Request::Signed { .. } => Response::Error {
+            message: "A request can only be signed once".to_string(),
+        },
+    }
+}
+
+pub fn run_action(name: &str, command: &[String], noaction: bool) -> ActionResult {
+    if noaction {
+        return ActionResult {
+            name: name.to_string(),
+            exit_code: None,
+            stdout: format!("Would run: {}", command.join(" ")),
+            stderr: String::new(),
+        };
+    }
+
+    info!("Running action {}: {}", name, command.join(" "));
+    match Command::new(&command[0]).args(&command[1..]).output() {
+        Ok(output) => ActionResult {
+            name: name.to_string(),
+            exit_code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        },
+        Err(e) => ActionResult {
+            name: name.to_string(),
+            exit_code: None,
+            stdout: String::new(),
+            stderr: format!("Failed to run {}: {}", command[0], e),
+        },
+    }
+}
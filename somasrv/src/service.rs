@@ -0,0 +1,185 @@
+use log::info;
+use somacommon::glob_match;
+use somacommon::protocol::Response;
+use somacommon::service::{ServiceOperation, ServiceResult, UnitState};
+use std::process::Command;
+
+use crate::config::ServiceConfig;
+
+/// Properties read from `systemctl show` to build a [`UnitState`]
+const PROPERTIES: &str =
+    "LoadState,ActiveState,SubState,ActiveEnterTimestamp,MainPID,UnitFileState";
+
+/// Report on or change a unit, with noaction reporting the change that would be made
+pub fn handle(
+    cfg: &ServiceConfig,
+    unit: &str,
+    operation: ServiceOperation,
+    noaction: bool,
+) -> Response {
+    match run(cfg, unit, operation, noaction) {
+        Ok(result) => Response::Service(result),
+        Err(message) => Response::Error { message },
+    }
+}
+
+/// Report on or change a unit, failing with a message for the caller
+pub fn run(
+    cfg: &ServiceConfig,
+    unit: &str,
+    operation: ServiceOperation,
+    noaction: bool,
+) -> Result<ServiceResult, String> {
+    if !valid_unit_name(unit) {
+        return Err(format!("Invalid unit name {}", unit));
+    }
+    if operation.modifies() && !cfg.manage.iter().any(|p| glob_match(p, unit)) {
+        return Err(format!("Unit {} may not be changed on this host", unit));
+    }
+
+    let state = unit_state(cfg, unit)?;
+    if state.load_state == "not-found" {
+        return Err(format!("Unit {} is not known on this host", unit));
+    }
+
+    let changes = match operation {
+        ServiceOperation::Status => false,
+        ServiceOperation::Start => !state.is_active(),
+        ServiceOperation::Stop => state.is_active(),
+        ServiceOperation::Restart => true,
+        ServiceOperation::Enable => !state.is_enabled(),
+        ServiceOperation::Disable => state.is_enabled(),
+    };
+    if !changes {
+        let message = match operation {
+            ServiceOperation::Status => describe(&state),
+            _ => format!("{}, nothing to {}", describe(&state), operation),
+        };
+        return Ok(ServiceResult {
+            state,
+            changed: false,
+            message,
+        });
+    }
+    if noaction {
+        let message = format!("Would {} {} ({})", operation, unit, describe(&state));
+        return Ok(ServiceResult {
+            state,
+            changed: true,
+            message,
+        });
+    }
+
+    info!("Service {} {}", operation, unit);
+    systemctl(cfg, &[&operation.to_string(), "--", unit])?;
+    let state = unit_state(cfg, unit)?;
+    Ok(ServiceResult {
+        message: format!("{} {}: {}", operation, unit, describe(&state)),
+        state,
+        changed: true,
+    })
+}
+
+/// States of the watched units, for the status report
+pub fn watched(cfg: &ServiceConfig) -> Vec<UnitState> {
+    cfg.watch
+        .iter()
+        .map(|unit| {
+            unit_state(cfg, unit).unwrap_or_else(|_| UnitState {
+                unit: unit.clone(),
+                active_state: "unknown".to_string(),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+fn describe(state: &UnitState) -> String {
+    format!(
+        "{} is {} ({}), {}",
+        state.unit, state.active_state, state.sub_state, state.unit_file_state
+    )
+}
+
+/// Unit names as systemd allows them; refusing a leading `-` keeps names from being read as options
+fn valid_unit_name(unit: &str) -> bool {
+    !unit.is_empty()
+        && !unit.starts_with('-')
+        && unit
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":-_.@\\".contains(c))
+}
+
+fn systemctl(cfg: &ServiceConfig, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(&cfg.systemctl[0])
+        .args(&cfg.systemctl[1..])
+        .args(args)
+        .env("LC_ALL", "C")
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", cfg.systemctl[0], e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "systemctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn unit_state(cfg: &ServiceConfig, unit: &str) -> Result<UnitState, String> {
+    let output = systemctl(cfg, &["show", "--property", PROPERTIES, "--", unit])?;
+    Ok(parse_show(unit, &output))
+}
+
+/// Parse the `Key=Value` lines printed by `systemctl show`
+fn parse_show(unit: &str, output: &str) -> UnitState {
+    let mut state = UnitState {
+        unit: unit.to_string(),
+        ..Default::default()
+    };
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.to_string();
+        match key {
+            "LoadState" => state.load_state = value,
+            "ActiveState" => state.active_state = value,
+            "SubState" => state.sub_state = value,
+            "ActiveEnterTimestamp" => state.since = value,
+            "MainPID" => state.main_pid = value.parse().ok().filter(|pid| *pid != 0),
+            "UnitFileState" => state.unit_file_state = value,
+            _ => {}
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_systemctl_show() {
+        let state = parse_show(
+            "nginx.service",
+            "LoadState=loaded\nActiveState=active\nSubState=running\n\
+             ActiveEnterTimestamp=Mon 2024-01-01 12:00:00 UTC\nMainPID=812\n\
+             UnitFileState=enabled\n",
+        );
+        assert!(state.is_active() && state.is_enabled());
+        assert_eq!(state.main_pid, Some(812));
+        assert_eq!(state.since, "Mon 2024-01-01 12:00:00 UTC");
+
+        let state = parse_show("gone.service", "LoadState=not-found\nMainPID=0\n");
+        assert_eq!(state.main_pid, None);
+    }
+
+    #[test]
+    fn rejects_option_like_unit_names() {
+        assert!(valid_unit_name("getty@tty1.service"));
+        assert!(!valid_unit_name("--now"));
+        assert!(!valid_unit_name("nginx; reboot"));
+    }
+}
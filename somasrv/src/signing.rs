@@ -0,0 +1,297 @@
+use somacommon::protocol::Request;
+use somacommon::signing;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, SigningConfig};
+
+/// Nonces of the signed requests accepted lately, with when they were signed
+static SEEN: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// When the agent started remembering nonces
+static STARTED: OnceLock<u64> = OnceLock::new();
+
+/// Whose key a request was signed with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signer {
+    Controller,
+    /// An operator named in `[signing.operators]`
+    Operator(String),
+}
+
+/// Why a request was refused
+#[derive(Debug, PartialEq)]
+pub enum SigningError {
+    Unsigned,
+    NoKey,
+    InvalidSignature,
+    WrongHost(String),
+    Stale(u64),
+    BeforeStart(u64),
+    Replayed(String),
+}
+
+impl std::fmt::Display for SigningError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigningError::Unsigned => write!(f, "Request is not signed"),
+            SigningError::NoKey => write!(
+                f,
+                "Request is signed but this agent has no public key to check it with"
+            ),
+            SigningError::InvalidSignature => {
+                write!(
+                    f,
+                    "Request signature does not match a key this agent trusts"
+                )
+            }
+            SigningError::WrongHost(host) => write!(f, "Request was signed for {}", host),
+            SigningError::Stale(timestamp) => write!(
+                f,
+                "Request was signed at {}, too far from this host's clock",
+                timestamp
+            ),
+            SigningError::BeforeStart(timestamp) => write!(
+                f,
+                "Request was signed at {}, before this agent started",
+                timestamp
+            ),
+            SigningError::Replayed(nonce) => {
+                write!(f, "Request with nonce {} was already received", nonce)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SigningError {}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Start remembering nonces. Requests signed earlier are refused from then
+/// on, since the nonces an earlier run accepted are forgotten.
+pub fn start() {
+    STARTED.get_or_init(now);
+}
+
+/// Check the signature on a request, when the agent has keys to check it
+/// with, returning whose key signed it.
+///
+/// Requests that change, run or read something must be signed, and with an
+/// access policy every request must be; others may be, and are then checked
+/// all the same. Requests to relay are left to the agent they are for, which
+/// checks them in full; the relay only looks up whose key signed them, for
+/// its own audit log. Without keys, signed requests are refused rather than
+/// taken on trust: soma signs everything it sends through a relay, so agents
+/// reached that way must be able to check it.
+pub fn check(cfg: &Config, request: &Request) -> Result<Option<Signer>, SigningError> {
+    let Some(signing) = &cfg.signing else {
+        return match request {
+            Request::Signed { .. } => Err(SigningError::NoKey),
+            _ => Ok(None),
+        };
+    };
+    let Request::Signed {
+        host,
+        nonce,
+        timestamp,
+        ..
+    } = request
+    else {
+        return match request {
+            Request::Relay { request, .. } => Ok(signer(signing, request)),
+            _ if request.is_audited() || cfg.policy.is_some() => Err(SigningError::Unsigned),
+            _ => Ok(None),
+        };
+    };
+
+    let signer = signer(signing, request).ok_or(SigningError::InvalidSignature)?;
+    let expected = cfg.host_name();
+    if *host != expected {
+        return Err(SigningError::WrongHost(host.clone()));
+    }
+    let now = now();
+    if timestamp.abs_diff(now) > signing.max_skew {
+        return Err(SigningError::Stale(*timestamp));
+    }
+    if *timestamp < *STARTED.get_or_init(|| now) {
+        return Err(SigningError::BeforeStart(*timestamp));
+    }
+
+    let mut seen = SEEN.lock().unwrap();
+    seen.retain(|_, signed| signed.saturating_add(signing.max_skew) >= now);
+    if seen.insert(nonce.clone(), *timestamp).is_some() {
+        return Err(SigningError::Replayed(nonce.clone()));
+    }
+    Ok(Some(signer))
+}
+
+/// The trusted key whose signature a request carries, if any
+fn signer(signing: &SigningConfig, request: &Request) -> Option<Signer> {
+    let verifies =
+        |key: &str| signing::decode_key(key).is_ok_and(|key| signing::verify(&key, request));
+    if let Some((operator, _)) = signing.operators.iter().find(|(_, key)| verifies(key)) {
+        return Some(Signer::Operator(operator.clone()));
+    }
+    signing
+        .public_key
+        .as_deref()
+        .filter(|key| verifies(key))
+        .map(|_| Signer::Controller)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PolicyConfig;
+    use somacommon::signing::{SigningKey, encode_key, public_key};
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[5; 32])
+    }
+
+    fn alice() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn config() -> Config {
+        Config {
+            signing: Some(SigningConfig {
+                public_key: Some(encode_key(&public_key(&key()))),
+                operators: BTreeMap::from([(
+                    "alice".to_string(),
+                    encode_key(&public_key(&alice())),
+                )]),
+                host: Some("web-01".to_string()),
+                max_skew: 60,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn plugin() -> Request {
+        Request::Plugin {
+            name: "disk".to_string(),
+        }
+    }
+
+    #[test]
+    fn refuses_replayed_nonces() {
+        start();
+        let cfg = config();
+        let request = signing::sign(&key(), "web-01", "replay-1", now(), plugin());
+        assert_eq!(check(&cfg, &request), Ok(Some(Signer::Controller)));
+        assert_eq!(
+            check(&cfg, &request),
+            Err(SigningError::Replayed("replay-1".to_string()))
+        );
+        let request = signing::sign(&key(), "web-01", "replay-2", now(), plugin());
+        assert_eq!(check(&cfg, &request), Ok(Some(Signer::Controller)));
+    }
+
+    #[test]
+    fn refuses_requests_outside_the_skew_window() {
+        start();
+        let cfg = config();
+        let now = now();
+        for timestamp in [now - 120, now + 120] {
+            let request = signing::sign(&key(), "web-01", "skew-1", timestamp, plugin());
+            assert_eq!(check(&cfg, &request), Err(SigningError::Stale(timestamp)));
+        }
+        let request = signing::sign(&key(), "web-01", "skew-2", now + 30, plugin());
+        assert_eq!(check(&cfg, &request), Ok(Some(Signer::Controller)));
+    }
+
+    #[test]
+    fn refuses_bad_signatures_and_unknown_keys() {
+        start();
+        let cfg = config();
+        let other = SigningKey::from_bytes(&[6; 32]);
+        let request = signing::sign(&other, "web-01", "key-1", now(), plugin());
+        assert_eq!(check(&cfg, &request), Err(SigningError::InvalidSignature));
+
+        let Request::Signed {
+            host,
+            nonce,
+            timestamp,
+            signature,
+            ..
+        } = signing::sign(&key(), "web-01", "key-2", now(), plugin())
+        else {
+            unreachable!();
+        };
+        let tampered = Request::Signed {
+            host,
+            nonce,
+            timestamp,
+            signature,
+            request: Box::new(Request::Plugin {
+                name: "load".to_string(),
+            }),
+        };
+        assert_eq!(check(&cfg, &tampered), Err(SigningError::InvalidSignature));
+
+        let request = signing::sign(&key(), "web-02", "key-3", now(), plugin());
+        assert_eq!(
+            check(&cfg, &request),
+            Err(SigningError::WrongHost("web-02".to_string()))
+        );
+
+        let request = signing::sign(&key(), "web-01", "key-4", now(), plugin());
+        assert_eq!(
+            check(&Config::default(), &request),
+            Err(SigningError::NoKey)
+        );
+    }
+
+    #[test]
+    fn requires_signatures_but_passes_relays_on() {
+        let cfg = config();
+        assert_eq!(check(&cfg, &plugin()), Err(SigningError::Unsigned));
+        assert_eq!(check(&cfg, &Request::Status), Ok(None));
+        let relay = Request::Relay {
+            token: "secret".to_string(),
+            target: "10.9.0.3:7070".to_string(),
+            request: Box::new(plugin()),
+        };
+        assert_eq!(check(&cfg, &relay), Ok(None));
+        assert_eq!(check(&Config::default(), &plugin()), Ok(None));
+
+        let signed = signing::sign(&alice(), "lab-03", "relay-1", now(), plugin());
+        let relay = Request::Relay {
+            token: "secret".to_string(),
+            target: "10.9.0.3:7070".to_string(),
+            request: Box::new(signed),
+        };
+        assert_eq!(
+            check(&cfg, &relay),
+            Ok(Some(Signer::Operator("alice".to_string())))
+        );
+    }
+
+    #[test]
+    fn operator_keys_name_their_operator() {
+        start();
+        let request = signing::sign(&alice(), "web-01", "operator-1", now(), plugin());
+        assert_eq!(
+            check(&config(), &request),
+            Ok(Some(Signer::Operator("alice".to_string())))
+        );
+
+        let cfg = Config {
+            policy: Some(PolicyConfig::default()),
+            ..config()
+        };
+        assert_eq!(check(&cfg, &Request::Status), Err(SigningError::Unsigned));
+        let request = signing::sign(&alice(), "web-01", "operator-2", now(), Request::Status);
+        assert_eq!(
+            check(&cfg, &request),
+            Ok(Some(Signer::Operator("alice".to_string())))
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use somacommon::checks::{PluginResult, ProbeResult};
+use somacommon::status::{
+    Health, InterfaceUsage, LoadAverage, MemoryUsage, MountUsage, ProcessInfo, StatusReport,
+};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{Config, Thresholds};
+use crate::{plugins, probes, service};
+
+/// Number of processes included in a status report
+const TOP_PROCESSES: usize = 10;
+
+/// Filesystem types that do not correspond to real storage
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "securityfs",
+    "squashfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+/// The latest results of the plugins and probes, served with status reports
+static CHECKS: Mutex<Option<(Vec<PluginResult>, Vec<ProbeResult>)>> = Mutex::new(None);
+
+/// Held while the plugins and probes run, so they never run twice at once
+static RUNNING: Mutex<()> = Mutex::new(());
+
+/// Run the plugins and probes every `check_interval` seconds, so status
+/// reports carry their latest results rather than waiting for them
+pub fn run_checks(cfg: Arc<Config>) {
+    loop {
+        let running = RUNNING.lock().unwrap();
+        let results = (plugins::run_all(&cfg), probes::run_all(&cfg));
+        *CHECKS.lock().unwrap() = Some(results);
+        drop(running);
+        thread::sleep(Duration::from_secs(cfg.check_interval));
+    }
+}
+
+/// The latest plugin and probe results, waiting for their first run
+fn latest_checks(cfg: &Config) -> (Vec<PluginResult>, Vec<ProbeResult>) {
+    if let Some(results) = CHECKS.lock().unwrap().clone() {
+        return results;
+    }
+    let _running = RUNNING.lock().unwrap();
+    let mut checks = CHECKS.lock().unwrap();
+    checks
+        .get_or_insert_with(|| (plugins::run_all(cfg), probes::run_all(cfg)))
+        .clone()
+}
+
+/// Gather a status report for this machine
+pub fn collect(cfg: &Config) -> StatusReport {
+    let (plugins, probes) = latest_checks(cfg);
+    let mut report = StatusReport {
+        hostname: read_trimmed("/proc/sys/kernel/hostname").unwrap_or_default(),
+        uptime_secs: read_trimmed("/proc/uptime")
+            .and_then(|s| parse_uptime(&s))
+            .unwrap_or(0),
+        cpus: std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        load: read_trimmed("/proc/loadavg")
+            .and_then(|s| parse_loadavg(&s))
+            .unwrap_or_default(),
+        memory: fs::read_to_string("/proc/meminfo")
+            .map(|s| parse_meminfo(&s))
+            .unwrap_or_default(),
+        mounts: fs::read_to_string("/proc/mounts")
+            .map(|s| mount_usage(&s))
+            .unwrap_or_default(),
+        interfaces: interfaces(),
+        processes: top_processes(),
+        actions: cfg.actions.keys().cloned().collect(),
+        services: service::watched(&cfg.services),
+        plugins,
+        probes,
+        ..Default::default()
+    };
+    report.health = evaluate(&report, &cfg.thresholds);
+    report
+}
+
+/// Derive the health level from the report and the configured thresholds.
+/// A watched service that is not active is a warning, and a plugin's or
+/// probe's state counts as its [`PluginState::health`](somacommon::checks::PluginState::health).
+pub fn evaluate(report: &StatusReport, thresholds: &Thresholds) -> Health {
+    let plugins = report.plugins.iter().map(|p| p.state);
+    let probes = report.probes.iter().map(|p| p.state);
+    plugins
+        .chain(probes)
+        .map(|state| state.health())
+        .max()
+        .unwrap_or(Health::Healthy)
+        .max(measured(report, thresholds))
+}
+
+/// Health from load, memory, disk and watched services alone
+fn measured(report: &StatusReport, thresholds: &Thresholds) -> Health {
+    let load = report.load.five / report.cpus.max(1) as f64;
+    let memory = report.memory.used_percent();
+    let disk = report.disk_used_percent();
+
+    if load >= thresholds.load_critical
+        || memory >= thresholds.memory_critical
+        || disk >= thresholds.disk_critical
+    {
+        Health::Critical
+    } else if load >= thresholds.load_warning
+        || memory >= thresholds.memory_warning
+        || disk >= thresholds.disk_warning
+        || report.services.iter().any(|s| !s.is_active())
+    {
+        Health::Warning
+    } else {
+        Health::Healthy
+    }
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn parse_uptime(content: &str) -> Option<u64> {
+    let secs: f64 = content.split_whitespace().next()?.parse().ok()?;
+    Some(secs as u64)
+}
+
+fn parse_loadavg(content: &str) -> Option<LoadAverage> {
+    let mut fields = content.split_whitespace();
+    Some(LoadAverage {
+        one: fields.next()?.parse().ok()?,
+        five: fields.next()?.parse().ok()?,
+        fifteen: fields.next()?.parse().ok()?,
+    })
+}
+
+fn parse_meminfo(content: &str) -> MemoryUsage {
+    let mut memory = MemoryUsage::default();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let key = fields.next().unwrap_or_default();
+        let value: u64 = fields
+            .next()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default();
+        match key {
+            "MemTotal:" => memory.total_kb = value,
+            "MemAvailable:" => memory.available_kb = value,
+            _ => {}
+        }
+    }
+    memory
+}
+
+/// Mount point and filesystem type of every real filesystem in /proc/mounts
+fn parse_mounts(content: &str) -> Vec<(String, String)> {
+    let mut seen = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || PSEUDO_FILESYSTEMS.contains(&fields[2]) {
+            continue;
+        }
+        // Mount points escape spaces and other characters as octal
+        let mount = fields[1].replace("\\040", " ");
+        if !seen.iter().any(|(m, _)| *m == mount) {
+            seen.push((mount, fields[2].to_string()));
+        }
+    }
+    seen
+}
+
+fn mount_usage(content: &str) -> Vec<MountUsage> {
+    parse_mounts(content)
+        .into_iter()
+        .filter_map(|(mount, filesystem)| {
+            let (total_kb, used_kb) = statvfs(&mount)?;
+            Some(MountUsage {
+                mount,
+                filesystem,
+                total_kb,
+                used_kb,
+            })
+        })
+        .collect()
+}
+
+/// Total and used space in kilobytes of the filesystem holding `path`
+pub fn statvfs(path: &str) -> Option<(u64, u64)> {
+    let cpath = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let frsize = stat.f_frsize as u64;
+    let total = stat.f_blocks as u64 * frsize / 1024;
+    let free = stat.f_bfree as u64 * frsize / 1024;
+    Some((total, total.saturating_sub(free)))
+}
+
+/// Per-interface received and transmitted byte counters from /proc/net/dev
+fn parse_net_dev(content: &str) -> Vec<(String, u64, u64)> {
+    content
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            let fields: Vec<u64> = counters
+                .split_whitespace()
+                .filter_map(|f| f.parse().ok())
+                .collect();
+            if fields.len() < 9 {
+                return None;
+            }
+            Some((name.trim().to_string(), fields[0], fields[8]))
+        })
+        .collect()
+}
+
+fn interfaces() -> Vec<InterfaceUsage> {
+    let mut addresses = interface_addresses();
+    fs::read_to_string("/proc/net/dev")
+        .map(|s| parse_net_dev(&s))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, rx_bytes, tx_bytes)| InterfaceUsage {
+            addresses: addresses.remove(&name).unwrap_or_default(),
+            name,
+            rx_bytes,
+            tx_bytes,
+        })
+        .collect()
+}
+
+/// IPv4 and IPv6 addresses of each interface, keyed by interface name
+pub fn interface_addresses() -> BTreeMap<String, Vec<String>> {
+    let mut result: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return result;
+    }
+
+    let mut cursor = ifap;
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let address = match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).to_string()
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr).to_string()
+            }
+            _ => continue,
+        };
+        result.entry(name).or_default().push(address);
+    }
+
+    unsafe { libc::freeifaddrs(ifap) };
+    result
+}
+
+/// Parse /proc/<pid>/stat into a process entry, given the page size and clock ticks
+fn parse_proc_stat(pid: u32, content: &str, page_kb: u64, ticks: f64) -> Option<ProcessInfo> {
+    // The command name is in parentheses and may itself contain spaces or parentheses
+    let start = content.find('(')?;
+    let end = content.rfind(')')?;
+    let name = content[start + 1..end].to_string();
+    let fields: Vec<&str> = content[end + 1..].split_whitespace().collect();
+    // Fields after the name start at field 3 (state), so utime (14) is index 11
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss: u64 = fields.get(21)?.parse().ok()?;
+    Some(ProcessInfo {
+        pid,
+        name,
+        rss_kb: rss * page_kb,
+        cpu_secs: (utime + stime) as f64 / ticks,
+    })
+}
+
+fn top_processes() -> Vec<ProcessInfo> {
+    let page_kb = (unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64 / 1024).max(1);
+    let ticks = (unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64).max(1.0);
+
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut processes: Vec<ProcessInfo> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let content = fs::read_to_string(entry.path().join("stat")).ok()?;
+            parse_proc_stat(pid, &content, page_kb, ticks)
+        })
+        .collect();
+    processes.sort_by_key(|p| std::cmp::Reverse(p.rss_kb));
+    processes.truncate(TOP_PROCESSES);
+    processes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use somacommon::checks::{PluginResult, PluginState, ProbeResult};
+
+    #[test]
+    fn parses_loadavg_and_meminfo() {
+        let load = parse_loadavg("0.52 0.58 0.59 1/467 12345\n").unwrap();
+        assert_eq!(load.five, 0.58);
+
+        let memory = parse_meminfo("MemTotal: 16000 kB\nMemFree: 1000 kB\nMemAvailable: 4000 kB\n");
+        assert_eq!(memory.total_kb, 16000);
+        assert_eq!(memory.available_kb, 4000);
+    }
+
+    #[test]
+    fn skips_pseudo_filesystems() {
+        let mounts = parse_mounts(
+            "proc /proc proc rw 0 0\n\
+             /dev/sda1 / ext4 rw 0 0\n\
+             tmpfs /run tmpfs rw 0 0\n\
+             /dev/sdb1 /srv/my\\040data xfs rw 0 0\n",
+        );
+        assert_eq!(
+            mounts,
+            vec![
+                ("/".to_string(), "ext4".to_string()),
+                ("/srv/my data".to_string(), "xfs".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_proc_stat_with_awkward_names() {
+        let stat = "42 (my (odd) proc) S 1 42 42 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 1 0 100 1000000 300 18446744073709551615";
+        let process = parse_proc_stat(42, stat, 4, 100.0).unwrap();
+        assert_eq!(process.name, "my (odd) proc");
+        assert_eq!(process.rss_kb, 1200);
+        assert_eq!(process.cpu_secs, 3.0);
+    }
+
+    #[test]
+    fn health_follows_thresholds() {
+        let thresholds = Thresholds::default();
+        let mut report = StatusReport {
+            cpus: 2,
+            memory: MemoryUsage {
+                total_kb: 100,
+                available_kb: 50,
+            },
+            ..Default::default()
+        };
+        assert_eq!(evaluate(&report, &thresholds), Health::Healthy);
+        report.load.five = 2.5;
+        assert_eq!(evaluate(&report, &thresholds), Health::Warning);
+        report.memory.available_kb = 1;
+        assert_eq!(evaluate(&report, &thresholds), Health::Critical);
+    }
+
+    #[test]
+    fn plugins_and_probes_fold_into_health() {
+        let thresholds = Thresholds::default();
+        let mut report = StatusReport {
+            cpus: 1,
+            plugins: vec![PluginResult {
+                state: PluginState::Unknown,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(evaluate(&report, &thresholds), Health::Warning);
+        report.plugins[0].state = PluginState::Critical;
+        assert_eq!(evaluate(&report, &thresholds), Health::Critical);
+        report.plugins[0].state = PluginState::Ok;
+        assert_eq!(evaluate(&report, &thresholds), Health::Healthy);
+        report.probes.push(ProbeResult {
+            state: PluginState::Warning,
+            ..Default::default()
+        });
+        assert_eq!(evaluate(&report, &thresholds), Health::Warning);
+    }
+}